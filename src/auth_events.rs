@@ -0,0 +1,124 @@
+//! Structured `log` events for the steps of the authentication flow.
+//!
+//! Unlike the optional `tracing` instrumentation, these events are
+//! always emitted (through the `log` facade used by Tide) and carry
+//! key-value pairs that structured loggers, such as Tide's JSON logger,
+//! can index. As with the `tracing` events, tokens are never logged.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::Instant;
+use tide::log::Level;
+
+/// Steps of the authentication flow that are logged by the middleware;
+/// see
+/// [`with_auth_event_log_level()`](crate::OpenIdConnectMiddleware::with_auth_event_log_level).
+///
+/// Every event includes an `event` key with the snake_case name of the
+/// event and a `provider` key with the [provider
+/// id](crate::OpenIdConnectMiddleware::with_provider_id) (or the issuer
+/// URL, if the middleware does not have a provider id).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AuthEvent {
+    /// The browser was redirected to the provider's authorization
+    /// endpoint. Includes a `silent` key indicating whether this is a
+    /// silent (`prompt=none`) login. Logged at `Debug` by default.
+    RedirectInitiated,
+
+    /// The provider redirected the browser back to the middleware's
+    /// callback URL. Logged at `Debug` by default.
+    CallbackReceived,
+
+    /// The authorization code was exchanged for tokens. Includes a
+    /// `duration_ms` key with the duration of the token request. Logged
+    /// at `Debug` by default.
+    TokenExchangeSucceeded,
+
+    /// The authorization code could not be exchanged for tokens.
+    /// Includes `duration_ms` and `error` keys. Logged at `Warn` by
+    /// default.
+    TokenExchangeFailed,
+
+    /// The session was marked as authenticated. Includes a `sub` key
+    /// with the user's subject identifier. Logged at `Info` by default.
+    SessionWritten,
+}
+
+impl AuthEvent {
+    /// Returns the value of the `event` key.
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Self::RedirectInitiated => "redirect_initiated",
+            Self::CallbackReceived => "callback_received",
+            Self::TokenExchangeSucceeded => "token_exchange_succeeded",
+            Self::TokenExchangeFailed => "token_exchange_failed",
+            Self::SessionWritten => "session_written",
+        }
+    }
+
+    /// Returns the log message for the event.
+    pub(crate) fn message(self) -> &'static str {
+        match self {
+            Self::RedirectInitiated => "Redirecting browser to the authorization endpoint.",
+            Self::CallbackReceived => "Received authorization callback.",
+            Self::TokenExchangeSucceeded => "Exchanged authorization code for tokens.",
+            Self::TokenExchangeFailed => "Token exchange failed.",
+            Self::SessionWritten => "Session is now authenticated.",
+        }
+    }
+
+    fn default_level(self) -> Option<Level> {
+        match self {
+            Self::RedirectInitiated | Self::CallbackReceived | Self::TokenExchangeSucceeded => {
+                Some(Level::Debug)
+            }
+            Self::TokenExchangeFailed => Some(Level::Warn),
+            Self::SessionWritten => Some(Level::Info),
+        }
+    }
+}
+
+/// Log levels of the authentication events, with overrides for the
+/// events whose level has been changed from the default.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuthEventLevels {
+    overrides: HashMap<AuthEvent, Option<Level>>,
+}
+
+impl AuthEventLevels {
+    pub(crate) fn set(&mut self, event: AuthEvent, level: Option<Level>) {
+        self.overrides.insert(event, level);
+    }
+
+    /// Returns the level at which the event is logged, or `None` if the
+    /// event is not logged at all.
+    pub(crate) fn get(&self, event: AuthEvent) -> Option<Level> {
+        self.overrides
+            .get(&event)
+            .copied()
+            .unwrap_or_else(|| event.default_level())
+    }
+}
+
+/// Returns the number of milliseconds since `started`, for the
+/// `duration_ms` key.
+pub(crate) fn elapsed_ms(started: Instant) -> u64 {
+    u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Logs an authentication event at its configured level; for example:
+/// `log_auth_event!(self.auth_event_levels, AuthEvent::SessionWritten, provider, { sub: subject })`
+macro_rules! log_auth_event {
+    ($levels:expr, $event:expr, $provider:expr, { $($key:ident : $value:expr),* $(,)? }) => {
+        if let Some(level) = $levels.get($event) {
+            tide::log::log!(level, "{}", $event.message(), {
+                event: $event.name(),
+                provider: $provider,
+                $($key: $value,)*
+            });
+        }
+    };
+}
+
+pub(crate) use log_auth_event;
@@ -0,0 +1,91 @@
+//! Additional (usually provider-specific) authorization request
+//! parameters.
+
+use serde::Deserialize;
+
+/// Parameters that are set by the middleware itself, and which
+/// therefore cannot be overridden by [`ExtraAuthorizationParams`].
+const RESERVED_PARAMS: [&str; 11] = [
+    "client_id",
+    "code_challenge",
+    "code_challenge_method",
+    "nonce",
+    "redirect_uri",
+    "request",
+    "request_uri",
+    "response_mode",
+    "response_type",
+    "scope",
+    "state",
+];
+
+/// Arbitrary key-value pairs that are appended to the query string of
+/// every authorization request (or included in the [pushed
+/// authorization request](crate::PushedAuthorizationRequests)), for
+/// providers that support non-standard parameters.
+///
+/// Parameters that are controlled by the middleware (`state`, `nonce`,
+/// `redirect_uri`, `scope`, etc.) are ignored, since overriding them
+/// would break (or weaken) the login process.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct ExtraAuthorizationParams(pub Vec<(String, String)>);
+
+impl ExtraAuthorizationParams {
+    /// Returns the parameters that may be added to the authorization
+    /// request, logging a warning for each reserved parameter.
+    pub(crate) fn allowed_params(&self) -> Vec<(String, String)> {
+        self.0
+            .iter()
+            .filter(|(name, _)| {
+                let reserved = RESERVED_PARAMS.contains(&name.as_str());
+                if reserved {
+                    tide::log::warn!(
+                        "Ignoring extra authorization parameter that is set by the middleware: {}",
+                        name
+                    );
+                }
+                !reserved
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Authorization request extensions supported by Azure AD (Microsoft
+/// Entra ID), which can be converted into [`ExtraAuthorizationParams`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AzureAdAuthorizationExtensions {
+    domain_hint: String,
+    identity_provider: Option<String>,
+}
+
+impl AzureAdAuthorizationExtensions {
+    /// Creates a new instance with the given `domain_hint`, which skips
+    /// Azure AD's home realm discovery and sends the user straight to
+    /// the sign-in page of the given domain (`contoso.com`, for
+    /// example).
+    pub fn new(domain_hint: &str) -> Self {
+        Self {
+            domain_hint: domain_hint.to_string(),
+            identity_provider: None,
+        }
+    }
+
+    /// Sets the `identity_provider` parameter, which selects the
+    /// external identity provider (`Google`, for example) that is used
+    /// to sign in to an Azure AD B2C tenant.
+    pub fn with_identity_provider(mut self, identity_provider: &str) -> Self {
+        self.identity_provider = Some(identity_provider.to_string());
+        self
+    }
+}
+
+impl From<AzureAdAuthorizationExtensions> for ExtraAuthorizationParams {
+    fn from(extensions: AzureAdAuthorizationExtensions) -> Self {
+        let mut params = vec![("domain_hint".to_string(), extensions.domain_hint)];
+        if let Some(identity_provider) = extensions.identity_provider {
+            params.push(("identity_provider".to_string(), identity_provider));
+        }
+        Self(params)
+    }
+}
@@ -0,0 +1,264 @@
+//! OpenID Connect [Back-Channel Logout].
+//!
+//! Identity Providers that support back-channel logout notify the
+//! application when a user logs out of the provider (or of another
+//! application that uses the same provider) by POSTing a signed
+//! *logout token* directly to the application, without involving the
+//! browser. Setting the
+//! [`backchannel_logout_path`](crate::Config::backchannel_logout_path)
+//! config option tells the middleware to accept those requests at the
+//! given path; that path's full URL must also be registered with the
+//! provider as the client's `backchannel_logout_uri`.
+//!
+//! The middleware verifies the logout token (its signature, issuer,
+//! audience, issue time, `jti`, and `events` claim) and then calls the
+//! application's [`LogoutHandler`], which must invalidate the sessions
+//! belonging to the subject named in the token -- or, if the token
+//! includes a provider session id (`sid`), the sessions established by
+//! that provider session. Logout tokens may identify the user by their
+//! subject, by their `sid`, or by both, but must include at least one
+//! of the two. The middleware cannot invalidate the sessions on its
+//! own, because the request comes from the provider, and not from the
+//! browser whose session must be destroyed.
+//!
+//! Handlers that cannot find the sessions themselves can instead record
+//! the logout, and report it from
+//! [`is_logged_out()`](LogoutHandler::is_logged_out), which the
+//! middleware checks on each authenticated request, clearing the
+//! sessions that have been logged out. [`MemoryLogoutHandler`] does this
+//! in memory, which only works if every request is handled by the same
+//! process; applications that run multiple instances must record the
+//! logouts in a shared store instead.
+//!
+//! [Back-Channel Logout]: https://openid.net/specs/openid-connect-backchannel-1_0.html
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Deserialize;
+
+/// Event type that identifies a logout token.
+const BACKCHANNEL_LOGOUT_EVENT: &str = "http://schemas.openid.net/event/backchannel-logout";
+
+/// Maximum age of a logout token; older tokens are rejected, and the
+/// ids of newer tokens are remembered (for this long) in order to
+/// detect replays.
+const LOGOUT_TOKEN_MAX_AGE: Duration = Duration::minutes(5);
+
+/// Invalidates sessions in response to back-channel logout requests.
+#[tide::utils::async_trait]
+pub trait LogoutHandler: Send + Sync {
+    /// Invalidates all of the sessions belonging to the subject (the
+    /// Identity Provider-specific user id, which is also available to
+    /// authenticated requests as
+    /// [`user_id()`](crate::OpenIdConnectRequestExt::user_id)).
+    async fn logout_by_subject(&self, sub: &str) -> tide::Result<()>;
+
+    /// Invalidates the sessions that were established by the given
+    /// provider session (the `sid` claim of the ID token, which is
+    /// available to authenticated requests as the `sid`
+    /// [claim](crate::OpenIdConnectRequestExt::claim)). The subject is
+    /// `None` if the logout token only identifies the provider session.
+    ///
+    /// Defaults to invalidating all of the subject's sessions, and so
+    /// rejects logout tokens without a subject (with a `501 Not
+    /// Implemented`); handlers that can find sessions by their `sid`
+    /// must implement this method.
+    async fn logout_by_session_id(&self, sub: Option<&str>, sid: &str) -> tide::Result<()> {
+        let _ = sid;
+        match sub {
+            Some(sub) => self.logout_by_subject(sub).await,
+            None => Err(tide::Error::from_str(
+                tide::StatusCode::NotImplemented,
+                "Logout tokens without a subject are not supported.",
+            )),
+        }
+    }
+
+    /// Returns `true` if the session of the subject that was
+    /// authenticated at `authenticated_at` (by the provider session
+    /// `sid`, if known) has since been logged out, in which case the
+    /// middleware clears the session. Called for every authenticated
+    /// request.
+    ///
+    /// Defaults to `false`, for handlers that invalidate the sessions
+    /// themselves.
+    async fn is_logged_out(
+        &self,
+        sub: &str,
+        sid: Option<&str>,
+        authenticated_at: DateTime<Utc>,
+    ) -> tide::Result<bool> {
+        let _ = (sub, sid, authenticated_at);
+        Ok(false)
+    }
+}
+
+/// Records back-channel logouts in memory, and reports the sessions
+/// that were authenticated before them as
+/// [logged out](LogoutHandler::is_logged_out).
+#[derive(Debug)]
+pub struct MemoryLogoutHandler {
+    retention: Duration,
+    subjects: Mutex<HashMap<String, DateTime<Utc>>>,
+    /// Logouts by provider session id, which identifies the session
+    /// without its subject.
+    sessions: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl MemoryLogoutHandler {
+    /// Creates a new handler that remembers each logout for `retention`,
+    /// which must be at least as long as the longest session.
+    pub fn new(retention: StdDuration) -> Self {
+        Self {
+            retention: Duration::from_std(retention).unwrap_or(Duration::MAX),
+            subjects: Mutex::default(),
+            sessions: Mutex::default(),
+        }
+    }
+
+    fn record<K>(&self, logouts: &Mutex<HashMap<K, DateTime<Utc>>>, key: K)
+    where
+        K: Eq + std::hash::Hash,
+    {
+        let now = Utc::now();
+        let mut logouts = logouts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Forget logouts that are older than every remaining session.
+        logouts.retain(|_, logged_out_at| {
+            logged_out_at
+                .checked_add_signed(self.retention)
+                .is_none_or(|forget_at| forget_at > now)
+        });
+        logouts.insert(key, now);
+    }
+}
+
+#[tide::utils::async_trait]
+impl LogoutHandler for MemoryLogoutHandler {
+    async fn logout_by_subject(&self, sub: &str) -> tide::Result<()> {
+        self.record(&self.subjects, sub.to_string());
+        Ok(())
+    }
+
+    async fn logout_by_session_id(&self, _sub: Option<&str>, sid: &str) -> tide::Result<()> {
+        self.record(&self.sessions, sid.to_string());
+        Ok(())
+    }
+
+    async fn is_logged_out(
+        &self,
+        sub: &str,
+        sid: Option<&str>,
+        authenticated_at: DateTime<Utc>,
+    ) -> tide::Result<bool> {
+        let logged_out_after = |logged_out_at: Option<&DateTime<Utc>>| {
+            logged_out_at.is_some_and(|at| *at >= authenticated_at)
+        };
+        if logged_out_after(
+            self.subjects
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .get(sub),
+        ) {
+            return Ok(true);
+        }
+        Ok(sid.is_some_and(|sid| {
+            logged_out_after(
+                self.sessions
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .get(sid),
+            )
+        }))
+    }
+}
+
+/// Claims of a logout token, other than the registered claims (which
+/// are verified like those of any other JWT). Unlike an ID token, a
+/// logout token need not include a subject, since it may identify the
+/// provider session instead.
+#[derive(Debug, Deserialize)]
+pub(crate) struct LogoutTokenClaims {
+    #[serde(default)]
+    sub: Option<String>,
+    #[serde(default)]
+    sid: Option<String>,
+    events: HashMap<String, serde_json::Value>,
+    jti: String,
+    iat: i64,
+    #[serde(default)]
+    nonce: Option<serde_json::Value>,
+}
+
+impl LogoutTokenClaims {
+    /// Returns `true` if the token includes a `nonce`, which logout
+    /// tokens must not.
+    pub(crate) fn has_nonce(&self) -> bool {
+        self.nonce.is_some()
+    }
+}
+
+/// Sessions that a (verified) logout token logs out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LogoutTarget {
+    /// All of the subject's sessions.
+    Subject(String),
+
+    /// The sessions established by the provider session, whose subject
+    /// may not be known.
+    Session { sub: Option<String>, sid: String },
+}
+
+/// Logout token ids that have already been processed, along with the
+/// time at which they can be forgotten.
+#[derive(Debug, Default)]
+pub(crate) struct LogoutTokenIds {
+    seen: Mutex<HashMap<String, DateTime<Utc>>>,
+}
+
+impl LogoutTokenIds {
+    /// Verifies the logout token claims that are not covered by the JWT
+    /// verification (or by the nonce check), and then records the
+    /// token id so that the token cannot be replayed, returning the
+    /// sessions that the token logs out.
+    pub(crate) fn verify(&self, claims: LogoutTokenClaims) -> Result<LogoutTarget, String> {
+        if !claims.events.contains_key(BACKCHANNEL_LOGOUT_EVENT) {
+            return Err("Logout token does not contain a back-channel logout event.".to_string());
+        }
+        let target = match (claims.sub, claims.sid) {
+            (sub, Some(sid)) => LogoutTarget::Session { sub, sid },
+            (Some(sub), None) => LogoutTarget::Subject(sub),
+            (None, None) => {
+                return Err("Logout token contains neither a subject nor a session id.".to_string())
+            }
+        };
+
+        let now = Utc::now();
+        let issue_time = Utc
+            .timestamp_opt(claims.iat, 0)
+            .single()
+            .ok_or_else(|| "Logout token has an invalid issue time.".to_string())?;
+        if issue_time < now - LOGOUT_TOKEN_MAX_AGE || issue_time > now + LOGOUT_TOKEN_MAX_AGE {
+            return Err("Logout token was not issued recently.".to_string());
+        }
+
+        let mut seen = self
+            .seen
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        seen.retain(|_, expiration| *expiration > now);
+        if seen
+            .insert(claims.jti.clone(), issue_time + LOGOUT_TOKEN_MAX_AGE)
+            .is_some()
+        {
+            return Err("Logout token has already been used.".to_string());
+        }
+
+        Ok(target)
+    }
+}
@@ -0,0 +1,411 @@
+//! Resource server support: authenticates API requests with the JWT
+//! access tokens ([RFC 9068]) that the provider issues, which clients
+//! send in the `Authorization: Bearer` header ([RFC 6750]). JWTs must be
+//! typed as access tokens (`typ: at+jwt`); ID tokens, which the provider
+//! signs with the same keys, are rejected.
+//!
+//! Opaque (non-JWT) access tokens are authenticated by way of [token
+//! introspection](crate::Config::introspection), if configured.
+//!
+//! [RFC 9068]: https://datatracker.ietf.org/doc/html/rfc9068
+//! [RFC 6750]: https://datatracker.ietf.org/doc/html/rfc6750
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use chrono::{TimeZone, Utc};
+use openidconnect::core::CoreJwsSigningAlgorithm;
+use openidconnect::IssuerUrl;
+use serde::Deserialize;
+use tide::http::headers::{AUTHORIZATION, WWW_AUTHENTICATE};
+use tide::{Middleware, Next, Request, Response, StatusCode};
+
+use crate::challenge::{bearer_challenge, ChallengeError};
+use crate::discovery::ProviderDiscovery;
+use crate::error::OidcError;
+use crate::introspection::TokenIntrospector;
+use crate::issuer::IssuerVerification;
+use crate::jwks::JwksCache;
+use crate::jwt::{header_type, unverified_subject, verify_jwt, JwtError};
+use crate::middleware::AuthorizationPolicy;
+use crate::redirect_strategy::{AccessDeniedHandler, UnauthenticatedHandler};
+use crate::request_ext::{LazyAuthInfo, OpenIdConnectRequestExtData, SessionAuthInfo};
+use crate::security_events::{remote_ip, SecurityEvent, SecurityEventSink};
+use crate::token_exchange::TokenExchanger;
+use crate::{Claims, Config, OpenIdConnectMiddleware};
+
+/// Scopes of an access token, which providers encode either as a
+/// space-separated string or as a list of strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scopes {
+    String(String),
+    List(Vec<String>),
+}
+
+impl Scopes {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::String(scopes) => scopes.split_whitespace().map(str::to_string).collect(),
+            Self::List(scopes) => scopes,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AccessTokenClaims {
+    sub: String,
+    exp: Option<i64>,
+    iat: Option<i64>,
+    scope: Option<Scopes>,
+    scp: Option<Scopes>,
+}
+
+/// Reasons for which a bearer token can be rejected.
+#[derive(Debug, thiserror::Error)]
+enum BearerTokenError {
+    #[error(transparent)]
+    Jwt(#[from] JwtError),
+
+    #[error("token is not active")]
+    Inactive,
+
+    #[error(transparent)]
+    Introspection(#[from] OidcError),
+}
+
+/// Responds to unauthenticated API requests with a `401 Unauthorized`
+/// and a `WWW-Authenticate: Bearer realm="..."` challenge.
+struct BearerChallenge {
+    realm: String,
+}
+
+#[tide::utils::async_trait]
+impl UnauthenticatedHandler for BearerChallenge {
+    async fn unauthenticated(&self, _req: &tide::http::Request) -> tide::Result<Response> {
+        Ok(Response::builder(StatusCode::Unauthorized)
+            .header(WWW_AUTHENTICATE, bearer_challenge(Some(&self.realm), None))
+            .build())
+    }
+}
+
+/// Responds to requests that presented an invalid bearer token with a
+/// `401 Unauthorized` and a `WWW-Authenticate: Bearer realm="...",
+/// error="invalid_token", error_description="..."` challenge.
+struct InvalidTokenChallenge {
+    realm: String,
+    description: String,
+}
+
+#[tide::utils::async_trait]
+impl UnauthenticatedHandler for InvalidTokenChallenge {
+    async fn unauthenticated(&self, _req: &tide::http::Request) -> tide::Result<Response> {
+        Ok(invalid_token_response(&self.realm, &self.description))
+    }
+}
+
+fn invalid_token_response(realm: &str, description: &str) -> Response {
+    Response::builder(StatusCode::Unauthorized)
+        .header(
+            WWW_AUTHENTICATE,
+            bearer_challenge(Some(realm), Some(ChallengeError::InvalidToken(description))),
+        )
+        .build()
+}
+
+/// Bearer token middleware, which authenticates API requests with the
+/// JWT access tokens issued by the provider.
+///
+/// Requests with an `Authorization: Bearer` header are authenticated
+/// by verifying the access token's signature (with the provider's JSON
+/// Web Key Set), issuer, audience (the client id or one of the
+/// [`additional_audiences`](crate::Config::additional_audiences)),
+/// expiration time and "not before" time. Authenticated requests get
+/// the same request extension as the ones authenticated by
+/// [`OpenIdConnectMiddleware`], with the scopes taken from the token's
+/// `scope` (or `scp`) claim and all of the token's claims available
+/// through [`claim()`](crate::OpenIdConnectRequestExt::claim), and so
+/// the [route guards](crate::OpenIdConnectRouteExt) work for both
+/// kinds of requests. Requests with an invalid token are rejected with
+/// a `401 Unauthorized` and a `WWW-Authenticate: Bearer
+/// error="invalid_token"` challenge, whose `error_description` says why
+/// the token was rejected.
+///
+/// Opaque access tokens (tokens that are not JWTs) are rejected in the
+/// same way, unless the middleware has been configured to
+/// [introspect](crate::Config::introspection) them.
+///
+/// Requests without a bearer token are passed through as
+/// unauthenticated, and protected routes respond to them with a `401
+/// Unauthorized` and a `WWW-Authenticate: Bearer` challenge (without an
+/// error, per RFC 6750) -- unless
+/// the middleware is installed after an [`OpenIdConnectMiddleware`], in
+/// which case browser sessions keep working as before:
+///
+/// ```no_run
+/// # use tide_openidconnect::{Config, OpenIdConnectMiddleware};
+/// # async fn example(config: Config) {
+/// let middleware = OpenIdConnectMiddleware::new(&config).await;
+/// let bearer = middleware.bearer_middleware();
+///
+/// let mut app = tide::new();
+/// app.with(middleware);
+/// app.with(bearer);
+/// # }
+/// ```
+pub struct OpenIdConnectBearerMiddleware {
+    pub(crate) provider_id: Option<String>,
+    pub(crate) issuer_url: IssuerUrl,
+    pub(crate) issuer_verification: IssuerVerification,
+    pub(crate) audiences: Vec<String>,
+    pub(crate) realm: String,
+    pub(crate) signing_algs: Option<Vec<CoreJwsSigningAlgorithm>>,
+    pub(crate) clock_skew_tolerance: chrono::Duration,
+    pub(crate) discovery: Arc<ProviderDiscovery>,
+    pub(crate) jwks: Arc<JwksCache>,
+    pub(crate) introspector: Option<Arc<TokenIntrospector>>,
+    pub(crate) token_exchanger: Arc<TokenExchanger>,
+    pub(crate) access_denied_handler: Arc<dyn AccessDeniedHandler>,
+    pub(crate) authorization_policy: AuthorizationPolicy,
+    pub(crate) security_event_sink: Option<Arc<dyn SecurityEventSink>>,
+    pub(crate) login_path: String,
+}
+
+impl std::fmt::Debug for OpenIdConnectBearerMiddleware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpenIdConnectBearerMiddleware")
+            .field("provider_id", &self.provider_id)
+            .field("issuer_url", &self.issuer_url)
+            .field("issuer_verification", &self.issuer_verification)
+            .field("audiences", &self.audiences)
+            .field("realm", &self.realm)
+            .field("signing_algs", &self.signing_algs)
+            .field("clock_skew_tolerance", &self.clock_skew_tolerance)
+            .field("introspection", &self.introspector.is_some())
+            .field("authorization_policy", &self.authorization_policy)
+            .finish()
+    }
+}
+
+impl OpenIdConnectBearerMiddleware {
+    /// Creates a new bearer token middleware, discovering the provider
+    /// with the given configuration. Applications that also install an
+    /// [`OpenIdConnectMiddleware`] should use
+    /// [`bearer_middleware()`](OpenIdConnectMiddleware::bearer_middleware)
+    /// instead, which shares the provider metadata and keys with that
+    /// middleware.
+    pub async fn new(config: &Config) -> Self {
+        OpenIdConnectMiddleware::new(config)
+            .await
+            .bearer_middleware()
+    }
+
+    /// Returns the authentication data of a request that has no
+    /// authenticated session, but which presented the given bearer
+    /// token: requests with an invalid token are unauthenticated, and
+    /// protected routes respond to them with an `invalid_token`
+    /// challenge.
+    pub(crate) async fn fallback_auth_state(
+        &self,
+        token: &str,
+        ip: Option<IpAddr>,
+    ) -> OpenIdConnectRequestExtData {
+        match self.authenticate(token).await {
+            Ok(auth_state) => auth_state,
+            Err(error) => {
+                tide::log::warn!("Rejected bearer token: {}", error);
+                self.report_rejected_token(token, &error, ip).await;
+                OpenIdConnectRequestExtData::Unauthenticated {
+                    redirect_strategy: Arc::new(InvalidTokenChallenge {
+                        realm: self.realm.clone(),
+                        description: error.to_string(),
+                    }),
+                    login_path: self.login_path.clone(),
+                }
+            }
+        }
+    }
+
+    /// Reports a rejected token to the security event sink (if any) if
+    /// the token's signature could not be verified.
+    async fn report_rejected_token(
+        &self,
+        token: &str,
+        error: &BearerTokenError,
+        ip: Option<IpAddr>,
+    ) {
+        match (&self.security_event_sink, error) {
+            (Some(security_event_sink), BearerTokenError::Jwt(error))
+                if error.is_signature_error() =>
+            {
+                security_event_sink
+                    .emit(SecurityEvent::InvalidTokenSignature {
+                        sub: unverified_subject(token),
+                        ip,
+                    })
+                    .await;
+            }
+            _ => {}
+        }
+    }
+
+    /// Verifies the given access token and returns the request's
+    /// authentication data. JWT access tokens are verified locally;
+    /// other tokens are introspected (if enabled).
+    async fn authenticate(
+        &self,
+        token: &str,
+    ) -> Result<OpenIdConnectRequestExtData, BearerTokenError> {
+        let claims = match (
+            self.verify_jwt_access_token(token).await,
+            &self.introspector,
+        ) {
+            (Err(JwtError::Malformed), Some(introspector)) => introspector
+                .introspect(token)
+                .await?
+                .ok_or(BearerTokenError::Inactive)?,
+            (result, _) => result?,
+        };
+
+        let token_claims: AccessTokenClaims =
+            serde_json::from_value(serde_json::Value::Object(claims.clone()))
+                .map_err(|_| JwtError::Invalid("missing or malformed access token claims"))?;
+        let scopes = token_claims
+            .scope
+            .or(token_claims.scp)
+            .map(Scopes::into_vec)
+            .unwrap_or_default();
+
+        Ok(OpenIdConnectRequestExtData::Authenticated {
+            auth_info: LazyAuthInfo::new(SessionAuthInfo {
+                provider_id: self.provider_id.clone(),
+                issuer: self.issuer_url.clone(),
+                client_id: self.token_exchanger.client_id.to_string(),
+                subject: token_claims.sub,
+                access_token: token.to_string(),
+                scopes,
+                issued_at: token_claims
+                    .iat
+                    .and_then(|iat| Utc.timestamp_opt(iat, 0).single())
+                    .unwrap_or_else(Utc::now),
+                expires_at: token_claims
+                    .exp
+                    .and_then(|exp| Utc.timestamp_opt(exp, 0).single()),
+            }),
+            claims,
+            userinfo: None,
+            id_token: None,
+            access_denied_handler: Arc::clone(&self.access_denied_handler),
+            token_exchanger: Arc::clone(&self.token_exchanger),
+            session_key: None,
+            refresher: None,
+            realm: self.realm.clone(),
+            redirect_strategy: Arc::new(BearerChallenge {
+                realm: self.realm.clone(),
+            }),
+            login_path: self.login_path.clone(),
+        })
+    }
+
+    /// Verifies the given JWT access token and returns its claims.
+    async fn verify_jwt_access_token(&self, token: &str) -> Result<Claims, JwtError> {
+        let audiences: Vec<&str> = self.audiences.iter().map(String::as_str).collect();
+        let signing_algs = self
+            .signing_algs
+            .clone()
+            .unwrap_or_else(|| self.discovery.id_token_signing_algs());
+        let verify = || {
+            verify_jwt(
+                token,
+                &self.jwks.keys(),
+                &signing_algs,
+                &self.issuer_url,
+                self.issuer_verification,
+                &audiences,
+                self.clock_skew_tolerance,
+            )
+        };
+        let claims = match verify() {
+            // The provider may have rotated its keys; try again with the
+            // new key set.
+            Err(JwtError::UnknownKey) if self.jwks.refresh().await => verify(),
+            result => result,
+        }?;
+
+        // ID tokens are signed by the same keys and (usually) intended
+        // for the same audience, but must not be used as access tokens;
+        // only JWTs that are explicitly typed as access tokens (RFC
+        // 9068, Section 4) are accepted.
+        let is_access_token = header_type(token).is_some_and(|typ| {
+            typ.eq_ignore_ascii_case("at+jwt") || typ.eq_ignore_ascii_case("application/at+jwt")
+        });
+        if !is_access_token {
+            return Err(JwtError::Invalid("JWT is not typed as an access token"));
+        }
+        if claims.contains_key("nonce") {
+            return Err(JwtError::Invalid("ID tokens are not access tokens"));
+        }
+        Ok(claims)
+    }
+}
+
+/// Returns the bearer token from the request's `Authorization` header,
+/// if any.
+pub(crate) fn bearer_token<State>(req: &Request<State>) -> Option<&str> {
+    let authorization = req.header(AUTHORIZATION)?.last().as_str();
+    let (scheme, token) = authorization.split_once(' ')?;
+    Some(token.trim()).filter(|_| scheme.eq_ignore_ascii_case("Bearer"))
+}
+
+#[tide::utils::async_trait]
+impl<State> Middleware<State> for OpenIdConnectBearerMiddleware
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        match bearer_token(&req) {
+            // Tokens cannot be verified until the provider metadata (and
+            // signing keys) have been loaded.
+            Some(_) if self.discovery.ensure_loaded(&self.jwks).await.is_err() => {
+                return Ok(Response::builder(StatusCode::ServiceUnavailable)
+                    .body(OidcError::ProviderUnavailable.to_string())
+                    .build());
+            }
+            Some(token) => match self.authenticate(token).await {
+                Ok(auth_state) => {
+                    // Enforce the authorization policy of the interactive
+                    // middleware (if any) on API requests, too.
+                    if let Some(reason) = self.authorization_policy.failure(&auth_state) {
+                        tide::log::debug!(
+                            "Authenticated request failed the authorization policy: {}",
+                            reason
+                        );
+                        return self
+                            .access_denied_handler
+                            .handle(req.as_ref(), reason)
+                            .await;
+                    }
+                    req.set_ext(auth_state);
+                }
+                Err(error) => {
+                    tide::log::warn!("Rejected bearer token: {}", error);
+                    self.report_rejected_token(token, &error, remote_ip(req.as_ref()))
+                        .await;
+                    return Ok(invalid_token_response(&self.realm, &error.to_string()));
+                }
+            },
+            // Leave the authentication status set by an interactive
+            // middleware (if any) alone.
+            None if req.ext::<OpenIdConnectRequestExtData>().is_some() => {}
+            None => {
+                req.set_ext(OpenIdConnectRequestExtData::Unauthenticated {
+                    redirect_strategy: Arc::new(BearerChallenge {
+                        realm: self.realm.clone(),
+                    }),
+                    login_path: self.login_path.clone(),
+                });
+            }
+        }
+        Ok(next.run(req).await)
+    }
+}
@@ -0,0 +1,203 @@
+//! Forwarding of the authenticated user's access token to upstream
+//! services, for applications that act as a Backend For Frontend (BFF):
+//! the browser only ever holds the session cookie, and the application
+//! calls the upstream APIs on the user's behalf.
+
+use tide::{Middleware, Next, Request};
+
+use crate::dpop::DpopConfig;
+use crate::error::OidcError;
+use crate::request_ext::{
+    refresh_expired_session, OpenIdConnectRequestExt, OpenIdConnectRequestExtInternal,
+};
+
+/// The app's [`surf::Client`], set up to forward the access token of
+/// the current request.
+#[derive(Clone, Debug)]
+pub(crate) struct UpstreamClient(pub(crate) surf::Client);
+
+/// Adds the authenticated user's access token to the outbound requests
+/// made with the application's [`surf::Client`], which is taken from
+/// the app state (by way of `AsRef<surf::Client>`).
+///
+/// The middleware must be added *after* the
+/// [`OpenIdConnectMiddleware`](crate::OpenIdConnectMiddleware), and
+/// handlers then get a copy of the client, with the `Authorization:
+/// Bearer <token>` header added to each of its requests, from the
+/// [`upstream_client()`](OpenIdConnectRequestExt::upstream_client)
+/// request extension. The client itself (and thus its connection pool)
+/// is shared by all of the requests; only sessions that are
+/// authenticated get a client.
+///
+/// The access token is only ever sent to the
+/// [allowed hosts](Self::with_allowed_host), and only over `https`;
+/// requests to any other host fail with an
+/// [`OidcError::UpstreamNotAllowed`] error, and requests to any other
+/// URL with an [`OidcError::InsecureUpstream`] error, unless
+/// [insecure upstreams](Self::with_insecure_upstreams) are allowed.
+///
+/// If [DPoP](crate::Config::dpop) is enabled, the access token is bound
+/// to the middleware's key, and is sent with the `DPoP` authorization
+/// scheme along with a fresh proof (in the `DPoP` header) for each
+/// request.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use tide_openidconnect::{BearerForwardingMiddleware, Config, OpenIdConnectMiddleware, OpenIdConnectRequestExt};
+/// #[derive(Clone)]
+/// struct State {
+///     upstream: surf::Client,
+/// }
+///
+/// impl AsRef<surf::Client> for State {
+///     fn as_ref(&self) -> &surf::Client {
+///         &self.upstream
+///     }
+/// }
+///
+/// # async fn example(config: Config, upstream: surf::Client) {
+/// let mut app = tide::with_state(State { upstream });
+/// app.with(OpenIdConnectMiddleware::new(&config).await);
+/// app.with(BearerForwardingMiddleware::new().with_allowed_host("orders.example.com"));
+/// app.at("/orders").get(|req: tide::Request<State>| async move {
+///     let client = req.upstream_client().ok_or_else(|| {
+///         tide::Error::from_str(tide::StatusCode::Unauthorized, "Not logged in.")
+///     })?;
+///     let orders = client
+///         .get("https://orders.example.com/orders")
+///         .recv_string()
+///         .await?;
+///     Ok(orders)
+/// });
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct BearerForwardingMiddleware {
+    allowed_hosts: Vec<String>,
+    allow_insecure_upstreams: bool,
+}
+
+impl BearerForwardingMiddleware {
+    /// Creates a middleware that does not forward the access token to
+    /// any host until hosts are [allowed](Self::with_allowed_host), and
+    /// then only to `https` URLs.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows the access token to be forwarded to the given host (such
+    /// as `api.example.com`), which must match the host of the request
+    /// URL exactly (ignoring case). Can be called multiple times to
+    /// allow several hosts.
+    pub fn with_allowed_host(mut self, host: &str) -> Self {
+        self.allowed_hosts.push(host.to_ascii_lowercase());
+        self
+    }
+
+    /// Allows (or disallows) the access token to be forwarded to
+    /// non-`https` URLs, such as upstream services on the same host (or
+    /// in the same cluster) that are reached over plain HTTP.
+    ///
+    /// Defaults to `false`.
+    pub fn with_insecure_upstreams(mut self, allow_insecure_upstreams: bool) -> Self {
+        self.allow_insecure_upstreams = allow_insecure_upstreams;
+        self
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State> Middleware<State> for BearerForwardingMiddleware
+where
+    State: AsRef<surf::Client> + Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // Expired access tokens are refreshed before they are forwarded.
+        if let Some(res) = refresh_expired_session(&mut req).await? {
+            return Ok(res);
+        }
+        if let Some(access_token) = req.access_token() {
+            let client = req.state().as_ref().clone().with(ForwardAccessToken {
+                access_token,
+                dpop: req.dpop_config().cloned(),
+                allowed_hosts: self.allowed_hosts.clone(),
+                allow_insecure_upstreams: self.allow_insecure_upstreams,
+            });
+            req.set_ext(UpstreamClient(client));
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Client middleware that adds the access token to each request.
+struct ForwardAccessToken {
+    access_token: String,
+    dpop: Option<DpopConfig>,
+    allowed_hosts: Vec<String>,
+    allow_insecure_upstreams: bool,
+}
+
+impl std::fmt::Debug for ForwardAccessToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ForwardAccessToken")
+            .field("access_token", &"[redacted]")
+            .field("dpop", &self.dpop)
+            .field("allowed_hosts", &self.allowed_hosts)
+            .field("allow_insecure_upstreams", &self.allow_insecure_upstreams)
+            .finish()
+    }
+}
+
+#[surf::utils::async_trait]
+impl surf::middleware::Middleware for ForwardAccessToken {
+    async fn handle(
+        &self,
+        mut req: surf::Request,
+        client: surf::Client,
+        next: surf::middleware::Next<'_>,
+    ) -> surf::Result<surf::Response> {
+        let origin = || req.url().origin().ascii_serialization();
+        let host = req
+            .url()
+            .host_str()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if !self.allowed_hosts.contains(&host) {
+            return Err(surf::Error::new(
+                surf::StatusCode::InternalServerError,
+                OidcError::UpstreamNotAllowed(origin()),
+            ));
+        }
+        if req.url().scheme() != "https" && !self.allow_insecure_upstreams {
+            return Err(surf::Error::new(
+                surf::StatusCode::InternalServerError,
+                OidcError::InsecureUpstream(origin()),
+            ));
+        }
+
+        match &self.dpop {
+            Some(dpop) => {
+                let proof = dpop
+                    .proof(req.method().as_ref(), req.url(), Some(&self.access_token))
+                    .map_err(|error| {
+                        surf::Error::new(
+                            surf::StatusCode::InternalServerError,
+                            OidcError::Dpop(error),
+                        )
+                    })?;
+                req.insert_header(
+                    surf::http::headers::AUTHORIZATION,
+                    format!("DPoP {}", self.access_token),
+                );
+                req.insert_header("DPoP", proof);
+            }
+            None => {
+                req.insert_header(
+                    surf::http::headers::AUTHORIZATION,
+                    format!("Bearer {}", self.access_token),
+                );
+            }
+        }
+        next.run(req, client).await
+    }
+}
@@ -0,0 +1,70 @@
+//! `WWW-Authenticate` challenges for the `401 Unauthorized` and `403
+//! Forbidden` responses to API requests ([RFC 6750, Section 3]).
+//!
+//! [RFC 6750, Section 3]: https://datatracker.ietf.org/doc/html/rfc6750#section-3
+
+/// Request extension that records why the request's credentials (an
+/// access token, or an authenticated session) were rejected, which is
+/// reported as an `invalid_token` error in the challenge.
+#[derive(Debug, Clone)]
+pub(crate) struct RejectedCredentials {
+    pub(crate) description: String,
+}
+
+impl RejectedCredentials {
+    pub(crate) fn new(description: impl ToString) -> Self {
+        Self {
+            description: description.to_string(),
+        }
+    }
+}
+
+/// Error reported in a challenge, per [RFC 6750, Section
+/// 3.1](https://datatracker.ietf.org/doc/html/rfc6750#section-3.1).
+/// Requests that did not include any credentials get a challenge
+/// without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChallengeError<'a> {
+    /// The access token (or session) is expired, revoked, malformed or
+    /// otherwise invalid; includes a description of the failure.
+    InvalidToken(&'a str),
+
+    /// The request requires higher privileges than the access token
+    /// provides; includes the scopes that the request requires.
+    InsufficientScope(&'a [String]),
+}
+
+/// Returns the value of a `WWW-Authenticate` header with a `Bearer`
+/// challenge.
+pub(crate) fn bearer_challenge(realm: Option<&str>, error: Option<ChallengeError<'_>>) -> String {
+    let mut params = vec![];
+    if let Some(realm) = realm {
+        params.push(format!("realm=\"{}\"", quote(realm)));
+    }
+    match error {
+        Some(ChallengeError::InvalidToken(description)) => {
+            params.push("error=\"invalid_token\"".to_string());
+            params.push(format!("error_description=\"{}\"", quote(description)));
+        }
+        Some(ChallengeError::InsufficientScope(scopes)) => {
+            params.push("error=\"insufficient_scope\"".to_string());
+            params.push(format!("scope=\"{}\"", quote(&scopes.join(" "))));
+        }
+        None => {}
+    }
+
+    if params.is_empty() {
+        "Bearer".to_string()
+    } else {
+        format!("Bearer {}", params.join(", "))
+    }
+}
+
+/// Removes the characters that RFC 6750 does not allow in the
+/// challenge's (quoted) attribute values.
+fn quote(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| matches!(c, ' '..='~') && *c != '"' && *c != '\\')
+        .collect()
+}
@@ -0,0 +1,490 @@
+//! Client-Initiated Backchannel Authentication ([CIBA]).
+//!
+//! CIBA authenticates a user without redirecting their browser: the
+//! application asks the provider to authenticate the user (identified
+//! by a login hint), the provider does so out-of-band -- with a push
+//! notification to the user's phone, for example -- and the application
+//! polls the provider's token endpoint until the user has approved (or
+//! denied) the request.
+//!
+//! [`CibaClient::authenticate()`] starts the authentication and returns
+//! a [`CibaRequest`], which is then passed to [`poll_for_token()`]:
+//!
+//! ```no_run
+//! use tide_openidconnect::ciba_flow::{poll_for_token, CibaAuthentication, CibaClient};
+//! use tide_openidconnect::OAuth2TokenResponse;
+//!
+//! # async fn example(config: tide_openidconnect::Config) -> Result<(), tide_openidconnect::OidcError> {
+//! let client = CibaClient::from_config(&config).await?;
+//! let request = client
+//!     .authenticate(&CibaAuthentication {
+//!         login_hint: Some("alice@example.com".to_string()),
+//!         binding_message: Some("Approve transfer #4711".to_string()),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! let token_response = poll_for_token(&request).await?;
+//! let access_token = token_response.access_token();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only the poll mode of CIBA is supported; the provider must not be
+//! configured to ping (or push tokens to) the client. The ID token in
+//! the token response is verified in the same way as the middleware
+//! verifies JWTs from the provider (signature, issuer, audience, and
+//! expiration time); if it includes the `auth_req_id` claim, the claim
+//! must match the request that was polled.
+//!
+//! [CIBA]: https://openid.net/specs/openid-client-initiated-backchannel-authentication-core-1_0.html
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::client_auth::{ClientAuth, TokenEndpointAuthMethod};
+use crate::error::OidcError;
+use crate::insecure_http::check_config_urls;
+use crate::instrument::{event, instrument};
+use crate::isahc::HttpClient;
+use crate::issuer::IssuerVerification;
+use crate::jwks::JwksCache;
+use crate::jwt::{verify_jwt, JwtError};
+use crate::middleware::{allowed_id_token_signing_algs, Config};
+use crate::provider_metadata::{discover_provider_metadata, ProviderMetadata};
+use http::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use openidconnect::core::CoreJwsSigningAlgorithm;
+use openidconnect::{
+    url::{form_urlencoded, Url},
+    ClientId, ClientSecret, HttpRequest, HttpResponse, IssuerUrl, TokenUrl,
+};
+use serde::Deserialize;
+
+/// `grant_type` for CIBA token requests.
+const CIBA_GRANT_TYPE: &str = "urn:openid:params:grant-type:ciba";
+
+/// ID token claim that binds the ID token to the authentication request.
+const AUTH_REQ_ID_CLAIM: &str = "urn:openid:params:jwt:claim:auth_req_id";
+
+/// Polling interval used if the provider does not specify one.
+const DEFAULT_POLLING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Amount by which the polling interval is increased whenever the
+/// provider asks the client to slow down.
+const SLOW_DOWN_INCREMENT: Duration = Duration::from_secs(5);
+
+/// Token response returned once the user has approved the
+/// authentication request.
+pub type TokenResponse = openidconnect::core::CoreTokenResponse;
+
+/// Parameters of a backchannel authentication request. Exactly one of
+/// the hints that identify the user must be set.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CibaAuthentication {
+    /// Scopes to request in addition to `openid`, which is always
+    /// requested.
+    pub scopes: Vec<String>,
+
+    /// Identifier of the user, such as their email address or phone
+    /// number.
+    pub login_hint: Option<String>,
+
+    /// ID token previously issued to the client for the user.
+    pub id_token_hint: Option<String>,
+
+    /// Short message that is shown both by the application and on the
+    /// user's authentication device, so that the user can tell that
+    /// they are approving the right request.
+    pub binding_message: Option<String>,
+
+    /// Lifetime of the authentication request that the client would
+    /// like the provider to use.
+    pub requested_expiry: Option<Duration>,
+}
+
+/// Successful response to a backchannel authentication request.
+#[derive(Deserialize)]
+struct AuthenticationResponse {
+    auth_req_id: String,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+/// Error response from the provider's endpoints.
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Client credentials and provider endpoints shared by a client and
+/// its requests.
+#[derive(Debug)]
+struct CibaClientInner {
+    http_client: HttpClient,
+    client_id: ClientId,
+    client_secret: Option<ClientSecret>,
+    client_auth: Arc<ClientAuth>,
+    auth_method: TokenEndpointAuthMethod,
+    backchannel_authentication_endpoint: Url,
+    token_url: TokenUrl,
+    id_token_verifier: IdTokenVerifier,
+}
+
+/// Provider keys and settings with which the ID tokens in CIBA token
+/// responses are verified.
+#[derive(Debug)]
+pub(crate) struct IdTokenVerifier {
+    pub(crate) issuer_url: IssuerUrl,
+    pub(crate) issuer_verification: IssuerVerification,
+    pub(crate) jwks: Arc<JwksCache>,
+    pub(crate) signing_algs: Vec<CoreJwsSigningAlgorithm>,
+    pub(crate) clock_skew_tolerance: chrono::Duration,
+}
+
+/// Starts backchannel authentication requests at the provider.
+#[derive(Debug, Clone)]
+pub struct CibaClient {
+    inner: Arc<CibaClientInner>,
+}
+
+impl CibaClient {
+    /// Creates a new client from the middleware's configuration.
+    ///
+    /// Requests the Identity Provider's metadata in order to find the
+    /// provider's backchannel authentication and token endpoints.
+    /// Clients that were [registered
+    /// dynamically](Config::dynamic_client_registration) must instead be
+    /// created from the
+    /// [middleware](crate::OpenIdConnectMiddleware::ciba_client).
+    pub async fn from_config(config: &Config) -> Result<Self, OidcError> {
+        check_config_urls(config)?;
+        let http_client = HttpClient::new(&config.http_client, &config.http_timeouts)
+            .map_err(|error| OidcError::HttpClient(error.to_string()))?;
+        let provider_metadata = instrument!(
+            discover_provider_metadata(
+                &http_client,
+                &config.issuer_url,
+                config.issuer_verification
+            ),
+            "oidc.discovery",
+            issuer = %config.issuer_url.as_str(),
+        )
+        .await
+        .map_err(OidcError::Discovery)?;
+        let id_token_verifier = IdTokenVerifier {
+            issuer_url: config.issuer_url.clone(),
+            issuer_verification: config.issuer_verification,
+            jwks: Arc::new(JwksCache::new(
+                http_client.clone(),
+                Some(provider_metadata.jwks_uri().clone()),
+                provider_metadata.jwks().clone(),
+            )),
+            signing_algs: allowed_id_token_signing_algs(
+                provider_metadata.id_token_signing_alg_values_supported(),
+            ),
+            clock_skew_tolerance: chrono::Duration::from_std(config.clock_skew_tolerance)
+                .unwrap_or_else(|_| chrono::Duration::zero()),
+        };
+        Self::new(
+            http_client,
+            config.client_id.clone(),
+            Some(config.client_secret.clone()),
+            Arc::new(ClientAuth::default()),
+            config.token_endpoint_auth_method,
+            &provider_metadata,
+            id_token_verifier,
+        )
+    }
+
+    /// Creates a new client for the given provider, returning an error
+    /// if the provider does not advertise a backchannel authentication
+    /// endpoint (or a token endpoint).
+    pub(crate) fn new(
+        http_client: HttpClient,
+        client_id: ClientId,
+        client_secret: Option<ClientSecret>,
+        client_auth: Arc<ClientAuth>,
+        auth_method: Option<TokenEndpointAuthMethod>,
+        provider_metadata: &ProviderMetadata,
+        id_token_verifier: IdTokenVerifier,
+    ) -> Result<Self, OidcError> {
+        let backchannel_authentication_endpoint = provider_metadata
+            .additional_metadata()
+            .backchannel_authentication_endpoint
+            .clone()
+            .ok_or_else(|| {
+                OidcError::Discovery(
+                    "OpenID Connect provider does not advertise a backchannel_authentication_endpoint."
+                        .to_string(),
+                )
+            })?;
+        let token_url = provider_metadata.token_endpoint().cloned().ok_or_else(|| {
+            OidcError::Discovery(
+                "OpenID Connect provider does not advertise a token_endpoint.".to_string(),
+            )
+        })?;
+        Ok(Self {
+            inner: Arc::new(CibaClientInner {
+                http_client,
+                client_id,
+                client_secret,
+                client_auth,
+                auth_method: TokenEndpointAuthMethod::resolve(
+                    auth_method,
+                    provider_metadata.token_endpoint_auth_methods_supported(),
+                ),
+                backchannel_authentication_endpoint,
+                token_url,
+                id_token_verifier,
+            }),
+        })
+    }
+
+    /// Asks the provider to authenticate the user, returning the
+    /// pending request that can then be [polled](poll_for_token) until
+    /// the user has approved or denied it.
+    pub async fn authenticate(
+        &self,
+        authentication: &CibaAuthentication,
+    ) -> Result<CibaRequest, OidcError> {
+        let scopes = std::iter::once("openid")
+            .chain(authentication.scopes.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let mut params = vec![("scope", scopes)];
+        if let Some(login_hint) = &authentication.login_hint {
+            params.push(("login_hint", login_hint.clone()));
+        }
+        if let Some(id_token_hint) = &authentication.id_token_hint {
+            params.push(("id_token_hint", id_token_hint.clone()));
+        }
+        if let Some(binding_message) = &authentication.binding_message {
+            params.push(("binding_message", binding_message.clone()));
+        }
+        if let Some(requested_expiry) = authentication.requested_expiry {
+            params.push(("requested_expiry", requested_expiry.as_secs().to_string()));
+        }
+
+        let started_at = Instant::now();
+        let response = instrument!(
+            self.inner.post(
+                &self.inner.backchannel_authentication_endpoint,
+                params
+            ),
+            "oidc.ciba_authentication",
+            client_id = %self.inner.client_id.as_str(),
+        )
+        .await
+        .map_err(OidcError::BackchannelAuthentication)?;
+        if !response.status_code.is_success() {
+            return Err(OidcError::BackchannelAuthentication(error_message(
+                &response,
+            )));
+        }
+
+        let response: AuthenticationResponse = serde_json::from_slice(&response.body)
+            .map_err(|e| OidcError::BackchannelAuthentication(e.to_string()))?;
+        Ok(CibaRequest {
+            client: Arc::clone(&self.inner),
+            auth_req_id: response.auth_req_id,
+            expires_at: started_at + Duration::from_secs(response.expires_in),
+            interval: response
+                .interval
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_POLLING_INTERVAL),
+        })
+    }
+}
+
+/// Pending backchannel authentication request, as returned by
+/// [`CibaClient::authenticate()`].
+#[derive(Debug, Clone)]
+pub struct CibaRequest {
+    client: Arc<CibaClientInner>,
+    auth_req_id: String,
+    expires_at: Instant,
+    interval: Duration,
+}
+
+impl CibaRequest {
+    /// Returns the provider's identifier for the request.
+    pub fn auth_req_id(&self) -> &str {
+        &self.auth_req_id
+    }
+
+    /// Returns the time at which the request expires, after which the
+    /// user can no longer approve it.
+    pub fn expires_at(&self) -> Instant {
+        self.expires_at
+    }
+}
+
+/// Polls the provider's token endpoint until the user has approved the
+/// authentication request, returning the provider's token response.
+///
+/// The token endpoint is polled at the interval requested by the
+/// provider (which is increased whenever the provider asks the client to
+/// slow down). Returns an [`OidcError::TokenRequest`] error if the user
+/// denies the request, if the request expires before the user approves
+/// it, or if the token response does not include a valid ID token.
+pub async fn poll_for_token(request: &CibaRequest) -> Result<TokenResponse, OidcError> {
+    let client = &request.client;
+    let mut interval = request.interval;
+    loop {
+        if Instant::now() >= request.expires_at {
+            return Err(OidcError::TokenRequest(
+                "backchannel authentication request has expired".to_string(),
+            ));
+        }
+
+        let response = instrument!(
+            client.post(
+                client.token_url.url(),
+                vec![
+                    ("grant_type", CIBA_GRANT_TYPE.to_string()),
+                    ("auth_req_id", request.auth_req_id.clone()),
+                ],
+            ),
+            "oidc.ciba_token",
+            client_id = %client.client_id.as_str(),
+        )
+        .await
+        .map_err(OidcError::TokenRequest)?;
+        if response.status_code.is_success() {
+            let token_response: TokenResponse = serde_json::from_slice(&response.body)
+                .map_err(|e| OidcError::TokenRequest(e.to_string()))?;
+            client
+                .verify_id_token(&token_response, &request.auth_req_id)
+                .await?;
+            return Ok(token_response);
+        }
+
+        match serde_json::from_slice::<ErrorResponse>(&response.body) {
+            Ok(error) if error.error == "authorization_pending" => {}
+            Ok(error) if error.error == "slow_down" => {
+                interval += SLOW_DOWN_INCREMENT;
+            }
+            _ => return Err(OidcError::TokenRequest(error_message(&response))),
+        }
+        event!(
+            debug,
+            "Backchannel authentication request is still pending."
+        );
+        async_std::task::sleep(interval).await;
+    }
+}
+
+impl CibaClientInner {
+    /// Verifies the ID token in the token response for the given
+    /// authentication request.
+    async fn verify_id_token(
+        &self,
+        token_response: &TokenResponse,
+        auth_req_id: &str,
+    ) -> Result<(), OidcError> {
+        let id_token = token_response
+            .extra_fields()
+            .id_token()
+            .ok_or_else(|| {
+                OidcError::TokenRequest("token response does not include an ID token".to_string())
+            })?
+            .to_string();
+        let verifier = &self.id_token_verifier;
+        let verify = || {
+            verify_jwt(
+                &id_token,
+                &verifier.jwks.keys(),
+                &verifier.signing_algs,
+                &verifier.issuer_url,
+                verifier.issuer_verification,
+                &[self.client_id.as_str()],
+                verifier.clock_skew_tolerance,
+            )
+        };
+        let claims = match verify() {
+            // The provider may have rotated its keys; try again with the
+            // new key set.
+            Err(JwtError::UnknownKey) if verifier.jwks.refresh().await => verify(),
+            result => result,
+        }
+        .map_err(|error| OidcError::TokenRequest(format!("invalid ID token: {}", error)))?;
+
+        // Providers only have to include the claim in the push mode, but
+        // an ID token that was issued for another request is never
+        // accepted.
+        match claims.get(AUTH_REQ_ID_CLAIM) {
+            Some(claim) if claim.as_str() != Some(auth_req_id) => Err(OidcError::TokenRequest(
+                "invalid ID token: issued for another authentication request".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends the given (form-encoded) parameters to the given endpoint,
+    /// authenticating the client.
+    async fn post(
+        &self,
+        url: &Url,
+        mut params: Vec<(&'static str, String)>,
+    ) -> Result<HttpResponse, String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        match self.client_auth.as_ref() {
+            ClientAuth::ClientSecret => {
+                if let Some(client_secret) = &self.client_secret {
+                    self.auth_method.add_credentials(
+                        &self.client_id,
+                        client_secret,
+                        &mut headers,
+                        &mut params,
+                    )?;
+                } else {
+                    params.push(("client_id", self.client_id.to_string()));
+                }
+            }
+            client_auth => {
+                // Client assertions are addressed to the token endpoint,
+                // which the backchannel authentication endpoint also
+                // accepts as their audience.
+                params.push(("client_id", self.client_id.to_string()));
+                params.extend(
+                    client_auth.assertion_params(&self.client_id, self.token_url.as_str())?,
+                );
+            }
+        }
+
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .finish();
+        self.http_client
+            .request(HttpRequest {
+                url: url.clone(),
+                method: http::Method::POST,
+                headers,
+                body: body.into_bytes(),
+            })
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Describes an error response from the provider, using the OAuth 2.0
+/// error code (and description) if the response includes one.
+fn error_message(response: &HttpResponse) -> String {
+    match serde_json::from_slice::<ErrorResponse>(&response.body) {
+        Ok(ErrorResponse {
+            error,
+            error_description: Some(description),
+        }) => format!("{}: {}", error, description),
+        Ok(ErrorResponse { error, .. }) => error,
+        Err(_) => format!(
+            "request failed with HTTP status {}: {}",
+            response.status_code,
+            String::from_utf8_lossy(&response.body)
+        ),
+    }
+}
@@ -0,0 +1,413 @@
+//! Client Credentials grant, for machine-to-machine API access.
+//!
+//! Services that call other APIs on their own behalf (and not on behalf
+//! of a user) can obtain access tokens from the provider by way of the
+//! OAuth 2.0 [Client Credentials Grant]. [`ClientCredentialsTokenSource`]
+//! requests those tokens, and caches each token until shortly before it
+//! expires, so that callers can simply ask the [`TokenSource`] for a
+//! token before every API call.
+//!
+//! [`ClientCredentialsClient`] does the same for any number of scope
+//! sets, and can be created from the same [`Config`] as the (user-facing)
+//! [middleware](crate::OpenIdConnectMiddleware) -- or from the middleware
+//! itself -- so that background jobs can share the application's client
+//! credentials.
+//!
+//! [Client Credentials Grant]: https://datatracker.ietf.org/doc/html/rfc6749#section-4.4
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::client::Client;
+use crate::client_auth::{ClientAuth, TokenEndpointAuthMethod};
+use crate::dpop::DpopConfig;
+use crate::error::OidcError;
+use crate::insecure_http::check_config_urls;
+use crate::instrument::{event, instrument};
+use crate::isahc::{HttpClient, HttpClientConfig, HttpTimeouts};
+use crate::issuer::IssuerVerification;
+use crate::middleware::Config;
+use crate::provider_metadata::discover_provider_metadata;
+use async_std::sync::Mutex;
+use openidconnect::{
+    AccessToken, ClientId, ClientSecret, IssuerUrl, OAuth2TokenResponse, Scope, TokenUrl,
+};
+use serde::Deserialize;
+
+/// Amount of time before a token's expiration at which the token is
+/// considered to be expired, which prevents callers from using a token
+/// that expires while their request is in flight.
+const TOKEN_EXPIRATION_MARGIN: Duration = Duration::from_secs(60);
+
+/// Source of access tokens.
+#[tide::utils::async_trait]
+pub trait TokenSource: Send + Sync {
+    /// Returns a valid access token, requesting a new token from the
+    /// provider if necessary.
+    async fn token(&self) -> Result<AccessToken, OidcError>;
+}
+
+/// Client Credentials configuration.
+#[derive(Debug, Deserialize)]
+pub struct ClientCredentialsConfig {
+    /// Issuer URL used to retrieve the OpenID Connect provider
+    /// metadata (and thus, the token endpoint).
+    pub issuer_url: IssuerUrl,
+
+    /// Our Client ID, as generated by the OpenID Connect provider.
+    pub client_id: ClientId,
+
+    /// Our Client Secret, as generated by the OpenID Connect provider.
+    pub client_secret: ClientSecret,
+
+    /// Scopes to request from the provider.
+    ///
+    /// Defaults to no scopes (in which case the provider grants its
+    /// default scopes).
+    #[serde(default)]
+    pub scopes: Vec<String>,
+
+    /// Configuration for the HTTP client used to make requests to the
+    /// provider.
+    ///
+    /// Defaults to connecting to the provider directly.
+    #[serde(default)]
+    pub http_client: HttpClientConfig,
+
+    /// Connect and request timeouts for the requests to the provider.
+    ///
+    /// Defaults to 10 seconds for both.
+    #[serde(default)]
+    pub http_timeouts: HttpTimeouts,
+
+    /// How the issuer URL is compared with the issuer in the provider's
+    /// discovery document.
+    ///
+    /// Defaults to [`IssuerVerification::NormalizeTrailingSlash`].
+    #[serde(default)]
+    pub issuer_verification: IssuerVerification,
+}
+
+/// Access token (and the time at which it must be replaced) obtained
+/// from the provider.
+#[derive(Debug)]
+struct CachedToken {
+    access_token: AccessToken,
+    refresh_at: Option<Instant>,
+}
+
+/// Cached token for a single set of scopes; the (async) lock is held
+/// while a new token is requested, so that concurrent callers wait for
+/// (and then share) a single token, instead of all requesting their own
+/// token.
+type TokenSlot = Arc<Mutex<Option<CachedToken>>>;
+
+/// Obtains tokens for any number of scope sets using the Client
+/// Credentials grant, caching each token until shortly before it
+/// expires.
+///
+/// # Examples
+///
+/// ```no_run
+/// use tide_openidconnect::client_credentials::ClientCredentialsClient;
+///
+/// # async_std::task::block_on(async {
+/// let config = tide_openidconnect::Config {
+///     // ... set/load config ...
+/// #   issuer_url: tide_openidconnect::IssuerUrl::new("https://your-tenant-name.us.auth0.com/".to_string()).unwrap(),
+/// #   client_id: tide_openidconnect::ClientId::new("app-id-goes-here".to_string()),
+/// #   client_secret: tide_openidconnect::ClientSecret::new("app-secret-goes-here".to_string()),
+/// #   redirect_url: tide_openidconnect::RedirectUrl::new("https://your.cool.site/callback".to_string()).unwrap(),
+/// #   additional_audiences: vec![],
+/// #   require_azp: false,
+/// #   clock_skew_tolerance: std::time::Duration::from_secs(60),
+/// #   idp_logout_url: None,
+/// #   idp_logout_id_token_hint: false,
+/// #   post_logout_redirect_url: None,
+/// #   retain_id_token: false,
+/// #   dynamic_client_registration: None,
+/// #   backchannel_logout_path: None,
+/// #   frontchannel_logout_path: None,
+/// #   fetch_userinfo: false,
+/// #   extra_authorization_params: Default::default(),
+/// #   dpop: None,
+/// #   access_denied_handler: None,
+/// #   token_refresh_failed_handler: None,
+/// #   provider_endpoints: None,
+/// #   realm: None,
+/// #   introspection: None,
+/// #   claims_locales: None,
+/// #   redirect_strategy: Default::default(),
+/// #   csp_policy: None,
+/// #   http_client: Default::default(),
+/// #   http_timeouts: Default::default(),
+/// #   allow_insecure_http: false,
+/// #   verify_on_startup: true,
+/// #   session_encryption_keys: vec![],
+/// #   session_ttl: Default::default(),
+/// #   issuer_verification: Default::default(),
+/// #   token_endpoint_auth_method: None,
+/// };
+/// let client = ClientCredentialsClient::from_config(&config).await?;
+/// let access_token = client.get_token(&["inventory:read"]).await?;
+/// # Ok::<(), tide_openidconnect::OidcError>(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct ClientCredentialsClient {
+    http_client: HttpClient,
+    client_id: ClientId,
+    client_secret: Option<ClientSecret>,
+    client_auth: Arc<ClientAuth>,
+    auth_method: TokenEndpointAuthMethod,
+    token_url: TokenUrl,
+    dpop: Option<DpopConfig>,
+    tokens: std::sync::Mutex<HashMap<Vec<String>, TokenSlot>>,
+}
+
+impl ClientCredentialsClient {
+    /// Creates a new client from the middleware's configuration.
+    ///
+    /// Requests the Identity Provider's metadata in order to find the
+    /// provider's token endpoint, but does not request a token until
+    /// the first call to [`get_token()`](Self::get_token). Note that
+    /// clients that were [registered
+    /// dynamically](Config::dynamic_client_registration) must instead
+    /// be created from the
+    /// [middleware](crate::OpenIdConnectMiddleware::client_credentials_client).
+    pub async fn from_config(config: &Config) -> Result<Self, OidcError> {
+        check_config_urls(config)?;
+        let http_client = HttpClient::new(&config.http_client, &config.http_timeouts)
+            .map_err(|error| OidcError::HttpClient(error.to_string()))?;
+        let (token_url, auth_method) = match &config.provider_endpoints {
+            Some(endpoints) => (
+                endpoints.token_endpoint.clone(),
+                TokenEndpointAuthMethod::resolve(config.token_endpoint_auth_method, None),
+            ),
+            None => {
+                discover_token_url(
+                    &http_client,
+                    &config.issuer_url,
+                    config.issuer_verification,
+                    config.token_endpoint_auth_method,
+                )
+                .await?
+            }
+        };
+        Ok(Self::new(
+            http_client,
+            config.client_id.clone(),
+            Some(config.client_secret.clone()),
+            Arc::new(ClientAuth::default()),
+            auth_method,
+            token_url,
+            config.dpop.clone(),
+        ))
+    }
+
+    pub(crate) fn new(
+        http_client: HttpClient,
+        client_id: ClientId,
+        client_secret: Option<ClientSecret>,
+        client_auth: Arc<ClientAuth>,
+        auth_method: TokenEndpointAuthMethod,
+        token_url: TokenUrl,
+        dpop: Option<DpopConfig>,
+    ) -> Self {
+        Self {
+            http_client,
+            client_id,
+            client_secret,
+            client_auth,
+            auth_method,
+            token_url,
+            dpop,
+            tokens: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a valid access token for the given scopes (in any
+    /// order), requesting a new token from the provider if there is no
+    /// cached token for those scopes, or if the cached token is about
+    /// to expire.
+    pub async fn get_token(&self, scopes: &[impl AsRef<str>]) -> Result<AccessToken, OidcError> {
+        let mut scopes: Vec<String> = scopes.iter().map(|s| s.as_ref().to_string()).collect();
+        scopes.sort();
+        scopes.dedup();
+
+        let slot = Arc::clone(
+            self.tokens
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .entry(scopes.clone())
+                .or_default(),
+        );
+        let mut token = slot.lock().await;
+        match &*token {
+            Some(cached_token)
+                if cached_token
+                    .refresh_at
+                    .is_none_or(|refresh_at| Instant::now() < refresh_at) =>
+            {
+                Ok(cached_token.access_token.clone())
+            }
+            _ => {
+                let cached_token = self.request_token(&scopes).await?;
+                let access_token = cached_token.access_token.clone();
+                *token = Some(cached_token);
+                Ok(access_token)
+            }
+        }
+    }
+
+    /// Requests a new token from the provider.
+    async fn request_token(&self, scopes: &[String]) -> Result<CachedToken, OidcError> {
+        let client_secret = match *self.client_auth {
+            ClientAuth::ClientSecret => self.client_secret.clone(),
+            ClientAuth::PrivateKeyJwt(_) => None,
+        };
+        let client = Client::new(
+            self.client_id.clone(),
+            client_secret,
+            // The issuer and authorization endpoint are not used by the
+            // Client Credentials grant.
+            IssuerUrl::from_url(self.token_url.url().clone()),
+            openidconnect::AuthUrl::from_url(self.token_url.url().clone()),
+            Some(self.token_url.clone()),
+            None,
+            Default::default(),
+        )
+        .set_auth_type(self.auth_method.auth_type());
+        let mut token_request = client
+            .exchange_client_credentials()
+            .add_scopes(scopes.iter().map(|scope| Scope::new(scope.clone())));
+        for (name, value) in self
+            .client_auth
+            .assertion_params(&self.client_id, self.token_url.as_str())
+            .map_err(OidcError::TokenRequest)?
+        {
+            token_request = token_request.add_extra_param(name, value);
+        }
+        let token_response = instrument!(
+            token_request.request_async(|request| {
+                self.http_client
+                    .request_with_dpop(self.dpop.as_ref(), request)
+            }),
+            "oidc.client_credentials",
+            client_id = %self.client_id.as_str(),
+        )
+        .await
+        .map_err(|error| {
+            event!(warn, error = %error, "Client Credentials token request failed.");
+            OidcError::TokenRequest(error.to_string())
+        })?;
+
+        Ok(CachedToken {
+            access_token: token_response.access_token().clone(),
+            refresh_at: token_response.expires_in().map(|expires_in| {
+                Instant::now() + expires_in.saturating_sub(TOKEN_EXPIRATION_MARGIN)
+            }),
+        })
+    }
+}
+
+/// Requests the Identity Provider's metadata and returns its token
+/// endpoint.
+async fn discover_token_url(
+    http_client: &HttpClient,
+    issuer_url: &IssuerUrl,
+    issuer_verification: IssuerVerification,
+    auth_method: Option<TokenEndpointAuthMethod>,
+) -> Result<(TokenUrl, TokenEndpointAuthMethod), OidcError> {
+    let provider_metadata = instrument!(
+        discover_provider_metadata(http_client, issuer_url, issuer_verification),
+        "oidc.discovery",
+        issuer = %issuer_url.as_str(),
+    )
+    .await
+    .map_err(OidcError::Discovery)?;
+    let token_url = provider_metadata.token_endpoint().cloned().ok_or_else(|| {
+        OidcError::Discovery(
+            "OpenID Connect provider does not advertise a token_endpoint.".to_string(),
+        )
+    })?;
+    Ok((
+        token_url,
+        TokenEndpointAuthMethod::resolve(
+            auth_method,
+            provider_metadata.token_endpoint_auth_methods_supported(),
+        ),
+    ))
+}
+
+/// [`TokenSource`] that obtains tokens using the Client Credentials
+/// grant.
+///
+/// # Examples
+///
+/// ```no_run
+/// use tide_openidconnect::client_credentials::{
+///     ClientCredentialsConfig, ClientCredentialsTokenSource, TokenSource,
+/// };
+///
+/// # async_std::task::block_on(async {
+/// let config = ClientCredentialsConfig {
+///     // ... set/load config ...
+/// #   issuer_url: tide_openidconnect::IssuerUrl::new("https://your-tenant-name.us.auth0.com/".to_string()).unwrap(),
+/// #   client_id: tide_openidconnect::ClientId::new("app-id-goes-here".to_string()),
+/// #   client_secret: tide_openidconnect::ClientSecret::new("app-secret-goes-here".to_string()),
+/// #   scopes: vec!["api".to_string()],
+/// #   http_client: Default::default(),
+/// #   http_timeouts: Default::default(),
+/// #   issuer_verification: Default::default(),
+/// };
+/// let token_source = ClientCredentialsTokenSource::new(&config).await?;
+/// let access_token = token_source.token().await?;
+/// # Ok::<(), tide_openidconnect::OidcError>(())
+/// # });
+/// ```
+#[derive(Debug)]
+pub struct ClientCredentialsTokenSource {
+    client: ClientCredentialsClient,
+    scopes: Vec<String>,
+}
+
+impl ClientCredentialsTokenSource {
+    /// Create a new instance.
+    ///
+    /// Requests the Identity Provider's metadata in order to find the
+    /// provider's token endpoint, but does not request a token until
+    /// the first call to [`token()`](TokenSource::token).
+    pub async fn new(config: &ClientCredentialsConfig) -> Result<Self, OidcError> {
+        let http_client = HttpClient::new(&config.http_client, &config.http_timeouts)
+            .map_err(|error| OidcError::HttpClient(error.to_string()))?;
+        let (token_url, auth_method) = discover_token_url(
+            &http_client,
+            &config.issuer_url,
+            config.issuer_verification,
+            None,
+        )
+        .await?;
+
+        Ok(Self {
+            client: ClientCredentialsClient::new(
+                http_client,
+                config.client_id.clone(),
+                Some(config.client_secret.clone()),
+                Arc::new(ClientAuth::default()),
+                auth_method,
+                token_url,
+                None,
+            ),
+            scopes: config.scopes.clone(),
+        })
+    }
+}
+
+#[tide::utils::async_trait]
+impl TokenSource for ClientCredentialsTokenSource {
+    async fn token(&self) -> Result<AccessToken, OidcError> {
+        self.client.get_token(&self.scopes).await
+    }
+}
@@ -0,0 +1,538 @@
+//! Builder for the middleware [`Config`], which validates the
+//! configuration before the middleware is created.
+
+use std::sync::Arc;
+
+use openidconnect::url::Url;
+use openidconnect::{ClientId, ClientSecret, IssuerUrl, RedirectUrl};
+
+use crate::authorization_params::ExtraAuthorizationParams;
+use crate::client_auth::TokenEndpointAuthMethod;
+use crate::dpop::DpopConfig;
+use crate::error::{ConfigError, OidcError};
+use crate::hooks::TokenRefreshFailedHandler;
+use crate::insecure_http::check_config_urls;
+use crate::introspection::IntrospectionConfig;
+use crate::isahc::{HttpClientConfig, HttpTimeouts};
+use crate::issuer::IssuerVerification;
+use crate::middleware::{
+    default_clock_skew_tolerance, default_verify_on_startup, Config, SessionTtl,
+};
+use crate::provider_metadata::ProviderEndpoints;
+use crate::redirect_strategy::{AccessDeniedHandler, RedirectStrategyKind};
+use crate::registration::DynamicClientRegistration;
+use crate::session_encryption::SessionEncryptionKey;
+
+impl Config {
+    /// Returns a [`ConfigBuilder`], which validates the configuration
+    /// when it is [built](ConfigBuilder::build).
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+}
+
+/// Builder for the middleware [`Config`], obtained from
+/// [`Config::builder()`].
+///
+/// Each setter corresponds to the [`Config`] field of the same name,
+/// and unset fields take the same defaults as a deserialized
+/// configuration. Unlike a struct literal,
+/// [`build()`](Self::build) checks the configuration up front and
+/// describes the first problem that it finds:
+///
+/// ```
+/// use tide_openidconnect::{Config, ConfigError};
+///
+/// let config = Config::builder()
+///     .issuer_url("https://idp.example/")
+///     .client_id("app")
+///     .client_secret("secret")
+///     .app_url("https://app.example/")
+///     .redirect_url("/callback")
+///     .build()
+///     .unwrap();
+/// assert_eq!(config.redirect_url.as_str(), "https://app.example/callback");
+///
+/// match Config::builder()
+///     .issuer_url("https://idp.example/")
+///     .client_secret("secret")
+///     .redirect_url("https://app.example/callback")
+///     .build()
+/// {
+///     Err(ConfigError::Missing(field)) => assert_eq!(field, "client_id"),
+///     result => panic!("Unexpected result: {:?}", result),
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct ConfigBuilder {
+    issuer_url: Option<String>,
+    issuer_verification: IssuerVerification,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    token_endpoint_auth_method: Option<TokenEndpointAuthMethod>,
+    app_url: Option<String>,
+    redirect_url: Option<String>,
+    additional_audiences: Vec<String>,
+    require_azp: bool,
+    clock_skew_tolerance: Option<std::time::Duration>,
+    idp_logout_url: Option<String>,
+    idp_logout_id_token_hint: bool,
+    post_logout_redirect_url: Option<String>,
+    retain_id_token: bool,
+    dynamic_client_registration: Option<DynamicClientRegistration>,
+    backchannel_logout_path: Option<String>,
+    frontchannel_logout_path: Option<String>,
+    fetch_userinfo: bool,
+    extra_authorization_params: ExtraAuthorizationParams,
+    claims_locales: Option<Vec<String>>,
+    redirect_strategy: RedirectStrategyKind,
+    csp_policy: Option<String>,
+    http_client: HttpClientConfig,
+    http_timeouts: HttpTimeouts,
+    allow_insecure_http: bool,
+    verify_on_startup: Option<bool>,
+    session_encryption_keys: Vec<SessionEncryptionKey>,
+    session_ttl: SessionTtl,
+    introspection: Option<IntrospectionConfig>,
+    realm: Option<String>,
+    dpop: Option<DpopConfig>,
+    access_denied_handler: Option<Arc<dyn AccessDeniedHandler>>,
+    token_refresh_failed_handler: Option<Arc<dyn TokenRefreshFailedHandler>>,
+    provider_endpoints: Option<ProviderEndpoints>,
+}
+
+impl ConfigBuilder {
+    /// Sets the [`issuer_url`](Config::issuer_url), which is required.
+    /// It must be an absolute URL without a query or fragment.
+    pub fn issuer_url(mut self, issuer_url: impl Into<String>) -> Self {
+        self.issuer_url = Some(issuer_url.into());
+        self
+    }
+
+    /// Sets the [`issuer_verification`](Config::issuer_verification).
+    pub fn issuer_verification(mut self, issuer_verification: IssuerVerification) -> Self {
+        self.issuer_verification = issuer_verification;
+        self
+    }
+
+    /// Sets the [`client_id`](Config::client_id), which is required
+    /// (and must not be empty) unless [dynamic client
+    /// registration](Self::dynamic_client_registration) is used.
+    pub fn client_id(mut self, client_id: impl Into<String>) -> Self {
+        self.client_id = Some(client_id.into());
+        self
+    }
+
+    /// Sets the [`client_secret`](Config::client_secret), which is
+    /// required unless [dynamic client
+    /// registration](Self::dynamic_client_registration) is used.
+    pub fn client_secret(mut self, client_secret: impl Into<String>) -> Self {
+        self.client_secret = Some(client_secret.into());
+        self
+    }
+
+    /// Sets the
+    /// [`token_endpoint_auth_method`](Config::token_endpoint_auth_method).
+    pub fn token_endpoint_auth_method(mut self, method: TokenEndpointAuthMethod) -> Self {
+        self.token_endpoint_auth_method = Some(method);
+        self
+    }
+
+    /// Sets the application's base URL, against which a relative
+    /// [redirect URL](Self::redirect_url) or [post-logout redirect
+    /// URL](Self::post_logout_redirect_url) is resolved, and under
+    /// which both URLs must then be. This is not part of the
+    /// [`Config`] itself.
+    pub fn app_url(mut self, app_url: impl Into<String>) -> Self {
+        self.app_url = Some(app_url.into());
+        self
+    }
+
+    /// Sets the [`redirect_url`](Config::redirect_url), which is
+    /// required. Either an absolute URL, or a URL relative to the
+    /// [application's URL](Self::app_url).
+    pub fn redirect_url(mut self, redirect_url: impl Into<String>) -> Self {
+        self.redirect_url = Some(redirect_url.into());
+        self
+    }
+
+    /// Sets the [`additional_audiences`](Config::additional_audiences).
+    pub fn additional_audiences(mut self, additional_audiences: Vec<String>) -> Self {
+        self.additional_audiences = additional_audiences;
+        self
+    }
+
+    /// Sets [`require_azp`](Config::require_azp).
+    pub fn require_azp(mut self, require_azp: bool) -> Self {
+        self.require_azp = require_azp;
+        self
+    }
+
+    /// Sets the [`clock_skew_tolerance`](Config::clock_skew_tolerance).
+    pub fn clock_skew_tolerance(mut self, clock_skew_tolerance: std::time::Duration) -> Self {
+        self.clock_skew_tolerance = Some(clock_skew_tolerance);
+        self
+    }
+
+    /// Sets the [`idp_logout_url`](Config::idp_logout_url), which must
+    /// be an absolute URL.
+    pub fn idp_logout_url(mut self, idp_logout_url: impl Into<String>) -> Self {
+        self.idp_logout_url = Some(idp_logout_url.into());
+        self
+    }
+
+    /// Sets [`idp_logout_id_token_hint`](Config::idp_logout_id_token_hint),
+    /// which requires an [`idp_logout_url`](Self::idp_logout_url).
+    pub fn idp_logout_id_token_hint(mut self, idp_logout_id_token_hint: bool) -> Self {
+        self.idp_logout_id_token_hint = idp_logout_id_token_hint;
+        self
+    }
+
+    /// Sets the [`post_logout_redirect_url`](Config::post_logout_redirect_url),
+    /// which requires an [`idp_logout_url`](Self::idp_logout_url). As
+    /// with the [redirect URL](Self::redirect_url), it may be relative
+    /// to the [application's URL](Self::app_url).
+    pub fn post_logout_redirect_url(mut self, post_logout_redirect_url: impl Into<String>) -> Self {
+        self.post_logout_redirect_url = Some(post_logout_redirect_url.into());
+        self
+    }
+
+    /// Sets [`retain_id_token`](Config::retain_id_token).
+    pub fn retain_id_token(mut self, retain_id_token: bool) -> Self {
+        self.retain_id_token = retain_id_token;
+        self
+    }
+
+    /// Sets the
+    /// [`dynamic_client_registration`](Config::dynamic_client_registration).
+    pub fn dynamic_client_registration(mut self, registration: DynamicClientRegistration) -> Self {
+        self.dynamic_client_registration = Some(registration);
+        self
+    }
+
+    /// Sets the [`backchannel_logout_path`](Config::backchannel_logout_path),
+    /// which must start with `/`.
+    pub fn backchannel_logout_path(mut self, path: impl Into<String>) -> Self {
+        self.backchannel_logout_path = Some(path.into());
+        self
+    }
+
+    /// Sets the [`frontchannel_logout_path`](Config::frontchannel_logout_path),
+    /// which must start with `/`.
+    pub fn frontchannel_logout_path(mut self, path: impl Into<String>) -> Self {
+        self.frontchannel_logout_path = Some(path.into());
+        self
+    }
+
+    /// Sets [`fetch_userinfo`](Config::fetch_userinfo).
+    pub fn fetch_userinfo(mut self, fetch_userinfo: bool) -> Self {
+        self.fetch_userinfo = fetch_userinfo;
+        self
+    }
+
+    /// Sets the
+    /// [`extra_authorization_params`](Config::extra_authorization_params).
+    pub fn extra_authorization_params(mut self, params: ExtraAuthorizationParams) -> Self {
+        self.extra_authorization_params = params;
+        self
+    }
+
+    /// Sets the [`claims_locales`](Config::claims_locales).
+    pub fn claims_locales(mut self, claims_locales: Vec<String>) -> Self {
+        self.claims_locales = Some(claims_locales);
+        self
+    }
+
+    /// Sets the [`redirect_strategy`](Config::redirect_strategy).
+    pub fn redirect_strategy(mut self, redirect_strategy: RedirectStrategyKind) -> Self {
+        self.redirect_strategy = redirect_strategy;
+        self
+    }
+
+    /// Sets the [`csp_policy`](Config::csp_policy).
+    pub fn csp_policy(mut self, csp_policy: impl Into<String>) -> Self {
+        self.csp_policy = Some(csp_policy.into());
+        self
+    }
+
+    /// Sets the [`http_client`](Config::http_client) configuration.
+    pub fn http_client(mut self, http_client: HttpClientConfig) -> Self {
+        self.http_client = http_client;
+        self
+    }
+
+    /// Sets the [`http_timeouts`](Config::http_timeouts).
+    pub fn http_timeouts(mut self, http_timeouts: HttpTimeouts) -> Self {
+        self.http_timeouts = http_timeouts;
+        self
+    }
+
+    /// Sets [`allow_insecure_http`](Config::allow_insecure_http).
+    pub fn allow_insecure_http(mut self, allow_insecure_http: bool) -> Self {
+        self.allow_insecure_http = allow_insecure_http;
+        self
+    }
+
+    /// Sets [`verify_on_startup`](Config::verify_on_startup), which
+    /// only takes effect when the application listens on the
+    /// middleware's
+    /// [`startup_listener()`](crate::OpenIdConnectMiddleware::startup_listener).
+    pub fn verify_on_startup(mut self, verify_on_startup: bool) -> Self {
+        self.verify_on_startup = Some(verify_on_startup);
+        self
+    }
+
+    /// Sets the
+    /// [`session_encryption_keys`](Config::session_encryption_keys).
+    pub fn session_encryption_keys(mut self, keys: Vec<SessionEncryptionKey>) -> Self {
+        self.session_encryption_keys = keys;
+        self
+    }
+
+    /// Sets the [`session_ttl`](Config::session_ttl).
+    pub fn session_ttl(mut self, session_ttl: SessionTtl) -> Self {
+        self.session_ttl = session_ttl;
+        self
+    }
+
+    /// Sets the [`introspection`](Config::introspection) configuration.
+    pub fn introspection(mut self, introspection: IntrospectionConfig) -> Self {
+        self.introspection = Some(introspection);
+        self
+    }
+
+    /// Sets the [`realm`](Config::realm).
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = Some(realm.into());
+        self
+    }
+
+    /// Sets the [`dpop`](Config::dpop) configuration.
+    pub fn dpop(mut self, dpop: DpopConfig) -> Self {
+        self.dpop = Some(dpop);
+        self
+    }
+
+    /// Sets the [`access_denied_handler`](Config::access_denied_handler).
+    pub fn access_denied_handler(mut self, handler: Arc<dyn AccessDeniedHandler>) -> Self {
+        self.access_denied_handler = Some(handler);
+        self
+    }
+
+    /// Sets the
+    /// [`token_refresh_failed_handler`](Config::token_refresh_failed_handler).
+    pub fn token_refresh_failed_handler(
+        mut self,
+        handler: Arc<dyn TokenRefreshFailedHandler>,
+    ) -> Self {
+        self.token_refresh_failed_handler = Some(handler);
+        self
+    }
+
+    /// Sets the [`provider_endpoints`](Config::provider_endpoints).
+    pub fn provider_endpoints(mut self, provider_endpoints: ProviderEndpoints) -> Self {
+        self.provider_endpoints = Some(provider_endpoints);
+        self
+    }
+
+    /// Validates the configuration, returning the first problem that
+    /// was found:
+    ///
+    /// - the issuer URL, client credentials, and redirect URL are
+    ///   required (the client credentials are not, if [dynamic client
+    ///   registration](Self::dynamic_client_registration) is used);
+    /// - URLs must be valid and absolute (or relative to the
+    ///   [application's URL](Self::app_url), and then under it), and
+    ///   the issuer URL must not include a query or fragment;
+    /// - logout paths must start with `/`;
+    /// - options that depend on another option (such as the
+    ///   [`post_logout_redirect_url`](Self::post_logout_redirect_url),
+    ///   which depends on the [`idp_logout_url`](Self::idp_logout_url))
+    ///   require that option; and
+    /// - plain-HTTP URLs are only allowed for loopback addresses,
+    ///   unless [`allow_insecure_http`](Self::allow_insecure_http) is
+    ///   set.
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let issuer_url = self.issuer_url.ok_or(ConfigError::Missing("issuer_url"))?;
+        let issuer_url = IssuerUrl::new(issuer_url).map_err(|error| ConfigError::InvalidUrl {
+            field: "issuer_url",
+            reason: error.to_string(),
+        })?;
+        if issuer_url.url().query().is_some() || issuer_url.url().fragment().is_some() {
+            return Err(ConfigError::InvalidUrl {
+                field: "issuer_url",
+                reason: "an issuer URL must not include a query or fragment".to_string(),
+            });
+        }
+
+        // The client credentials are replaced by those of the dynamic
+        // registration (if any).
+        let registered = self.dynamic_client_registration.is_some();
+        let client_id = match self.client_id {
+            Some(client_id) if !client_id.trim().is_empty() => client_id,
+            _ if registered => String::new(),
+            _ => return Err(ConfigError::Missing("client_id")),
+        };
+        let client_secret = match self.client_secret {
+            Some(client_secret) => client_secret,
+            None if registered => String::new(),
+            None => return Err(ConfigError::Missing("client_secret")),
+        };
+        // TODO: The middleware does not support public clients (without a
+        // client secret) or PKCE yet. Once it does, reject configurations
+        // that disable PKCE for a public client with a `Conflict` error.
+
+        let app_url = self
+            .app_url
+            .map(|app_url| {
+                Url::parse(&app_url).map_err(|error| ConfigError::InvalidUrl {
+                    field: "app_url",
+                    reason: error.to_string(),
+                })
+            })
+            .transpose()?;
+        let redirect_url = self
+            .redirect_url
+            .ok_or(ConfigError::Missing("redirect_url"))?;
+        let redirect_url = app_redirect_url("redirect_url", &redirect_url, app_url.as_ref())?;
+
+        let idp_logout_url = match self.idp_logout_url {
+            Some(idp_logout_url) => {
+                Url::parse(&idp_logout_url).map_err(|error| ConfigError::InvalidUrl {
+                    field: "idp_logout_url",
+                    reason: error.to_string(),
+                })?;
+                Some(idp_logout_url)
+            }
+            None if self.idp_logout_id_token_hint => {
+                return Err(ConfigError::Conflict(
+                    "`idp_logout_id_token_hint` requires an `idp_logout_url`".to_string(),
+                ))
+            }
+            None if self.post_logout_redirect_url.is_some() => {
+                return Err(ConfigError::Conflict(
+                    "`post_logout_redirect_url` requires an `idp_logout_url`".to_string(),
+                ))
+            }
+            None => None,
+        };
+        let post_logout_redirect_url = self
+            .post_logout_redirect_url
+            .map(|url| app_redirect_url("post_logout_redirect_url", &url, app_url.as_ref()))
+            .transpose()?;
+
+        for (field, path) in [
+            ("backchannel_logout_path", &self.backchannel_logout_path),
+            ("frontchannel_logout_path", &self.frontchannel_logout_path),
+        ] {
+            if let Some(path) = path.as_ref().filter(|path| !path.starts_with('/')) {
+                return Err(ConfigError::InvalidPath {
+                    field,
+                    path: path.clone(),
+                });
+            }
+        }
+
+        let config = Config {
+            issuer_url,
+            issuer_verification: self.issuer_verification,
+            client_id: ClientId::new(client_id),
+            client_secret: ClientSecret::new(client_secret),
+            token_endpoint_auth_method: self.token_endpoint_auth_method,
+            redirect_url,
+            additional_audiences: self.additional_audiences,
+            require_azp: self.require_azp,
+            clock_skew_tolerance: self
+                .clock_skew_tolerance
+                .unwrap_or_else(default_clock_skew_tolerance),
+            idp_logout_url,
+            idp_logout_id_token_hint: self.idp_logout_id_token_hint,
+            post_logout_redirect_url,
+            retain_id_token: self.retain_id_token,
+            dynamic_client_registration: self.dynamic_client_registration,
+            backchannel_logout_path: self.backchannel_logout_path,
+            frontchannel_logout_path: self.frontchannel_logout_path,
+            fetch_userinfo: self.fetch_userinfo,
+            extra_authorization_params: self.extra_authorization_params,
+            claims_locales: self.claims_locales,
+            redirect_strategy: self.redirect_strategy,
+            csp_policy: self.csp_policy,
+            http_client: self.http_client,
+            http_timeouts: self.http_timeouts,
+            allow_insecure_http: self.allow_insecure_http,
+            verify_on_startup: self
+                .verify_on_startup
+                .unwrap_or_else(default_verify_on_startup),
+            session_encryption_keys: self.session_encryption_keys,
+            session_ttl: self.session_ttl,
+            introspection: self.introspection,
+            realm: self.realm,
+            dpop: self.dpop,
+            access_denied_handler: self.access_denied_handler,
+            token_refresh_failed_handler: self.token_refresh_failed_handler,
+            provider_endpoints: self.provider_endpoints,
+        };
+        check_config_urls(&config).map_err(|error| {
+            ConfigError::InsecureUrl(match error {
+                OidcError::InsecureUrl(message) => message,
+                error => error.to_string(),
+            })
+        })?;
+        Ok(config)
+    }
+}
+
+/// Parses a redirect URL, which is either absolute or relative to the
+/// application's URL (if any), and which must then be under that URL.
+fn app_redirect_url(
+    field: &'static str,
+    url: &str,
+    app_url: Option<&Url>,
+) -> Result<RedirectUrl, ConfigError> {
+    let invalid_url = |error: openidconnect::url::ParseError| ConfigError::InvalidUrl {
+        field,
+        reason: error.to_string(),
+    };
+    let resolved = match (Url::parse(url), app_url) {
+        (Ok(url), _) => url,
+        (Err(openidconnect::url::ParseError::RelativeUrlWithoutBase), Some(app_url)) => {
+            app_url.join(url).map_err(invalid_url)?
+        }
+        (Err(openidconnect::url::ParseError::RelativeUrlWithoutBase), None) => {
+            return Err(ConfigError::RelativeUrl {
+                field,
+                url: url.to_string(),
+            })
+        }
+        (Err(error), _) => return Err(invalid_url(error)),
+    };
+    if let Some(app_url) = app_url {
+        if resolved.origin() != app_url.origin() || !is_under_path(&resolved, app_url) {
+            return Err(ConfigError::OutsideApp {
+                field,
+                url: resolved.to_string(),
+                app_url: app_url.to_string(),
+            });
+        }
+    }
+    Ok(RedirectUrl::from_url(resolved))
+}
+
+/// Returns whether the URL's path is the application URL's path, or
+/// below it. Paths are compared by whole segments, so that an
+/// application at `/app` does not also claim `/application`.
+fn is_under_path(url: &Url, app_url: &Url) -> bool {
+    // The empty segment of a trailing slash ("/app/") does not have to
+    // be matched.
+    let segments = |url: &Url| {
+        let mut segments: Vec<String> = url
+            .path_segments()
+            .map(|segments| segments.map(str::to_string).collect())
+            .unwrap_or_default();
+        if segments.last().is_some_and(|segment| segment.is_empty()) {
+            segments.pop();
+        }
+        segments
+    };
+    segments(url).starts_with(&segments(app_url))
+}
@@ -0,0 +1,408 @@
+//! Provider discovery document caching.
+
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::error::OidcError;
+use crate::instrument::{event, instrument};
+use crate::isahc::HttpClient;
+use crate::issuer::IssuerVerification;
+use crate::jwks::JwksCache;
+use crate::middleware::allowed_id_token_signing_algs;
+use crate::provider_metadata::{discover_provider_metadata, ProviderEndpoints, ProviderMetadata};
+use openidconnect::core::CoreJwsSigningAlgorithm;
+use openidconnect::IssuerUrl;
+
+/// Minimum amount of time between two failure-triggered discovery
+/// document refreshes; this prevents a misbehaving provider (or an
+/// attacker replaying bad authorization codes) from causing the
+/// middleware to hammer the provider's discovery endpoint.
+const DISCOVERY_REFRESH_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Determines how long the middleware caches the provider's discovery
+/// document (`/.well-known/openid-configuration`) before fetching it
+/// again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscoveryCacheConfig {
+    /// Interval at which the discovery document (and the provider's
+    /// JSON Web Key Set) is refreshed in the background. The previous
+    /// document continues to be used if a refresh fails.
+    pub ttl: Duration,
+
+    /// Whether or not the discovery document is also refreshed
+    /// (immediately, instead of waiting for the next interval) when a
+    /// request to one of the provider's endpoints fails, which is
+    /// often the first sign that the provider has changed its
+    /// configuration.
+    pub refresh_on_failure: bool,
+}
+
+/// Determines how a [lazily-created](crate::OpenIdConnectMiddleware::new_lazy)
+/// middleware retries provider discovery while the provider is
+/// unreachable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyDiscoveryConfig {
+    /// Amount of time to wait after the first failed discovery attempt
+    /// before trying again. The wait doubles after each consecutive
+    /// failure.
+    pub initial_backoff: Duration,
+
+    /// Maximum amount of time to wait between two discovery attempts.
+    pub max_backoff: Duration,
+
+    /// Maximum number of discovery attempts that can be in flight at
+    /// the same time. Requests that arrive while this many attempts are
+    /// in flight (or while waiting for the next attempt) fail
+    /// immediately instead of waiting for the provider.
+    pub max_concurrent_attempts: usize,
+}
+
+impl Default for LazyDiscoveryConfig {
+    /// Waits one second after the first failure, doubling up to one
+    /// minute, with a single attempt in flight at a time.
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_concurrent_attempts: 1,
+        }
+    }
+}
+
+/// State of the discovery attempts of a lazily-created middleware.
+#[derive(Debug, Default)]
+struct DiscoveryAttempts {
+    in_flight: usize,
+    consecutive_failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// An in-flight discovery attempt, which is counted against the
+/// concurrent attempt limit until it is dropped (even if the request
+/// that made the attempt is cancelled).
+struct DiscoveryAttempt<'a> {
+    attempts: &'a Mutex<DiscoveryAttempts>,
+}
+
+impl Drop for DiscoveryAttempt<'_> {
+    fn drop(&mut self) {
+        self.attempts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .in_flight -= 1;
+    }
+}
+
+/// Provider's discovery document, which can be re-fetched when the
+/// provider changes its configuration.
+#[derive(Debug)]
+pub(crate) struct ProviderDiscovery {
+    http_client: HttpClient,
+    issuer_url: IssuerUrl,
+    issuer_verification: IssuerVerification,
+    /// Endpoints from which the metadata is built instead of being
+    /// discovered (and which are never re-fetched), if
+    /// [configured](crate::Config::provider_endpoints).
+    endpoints: Option<ProviderEndpoints>,
+    /// Current metadata, which is `None` until a lazily-created
+    /// middleware has been able to reach the provider.
+    metadata: RwLock<Option<ProviderMetadata>>,
+    lazy_discovery: LazyDiscoveryConfig,
+    attempts: Mutex<DiscoveryAttempts>,
+    last_refresh: Mutex<Option<Instant>>,
+    last_success: Mutex<Instant>,
+}
+
+impl ProviderDiscovery {
+    /// Creates a new cache from the discovery document that was
+    /// retrieved (or built) when the middleware was created, or an
+    /// empty cache that is filled by [`ensure_loaded`](Self::ensure_loaded).
+    pub(crate) fn new(
+        http_client: HttpClient,
+        issuer_url: IssuerUrl,
+        issuer_verification: IssuerVerification,
+        endpoints: Option<ProviderEndpoints>,
+        metadata: Option<ProviderMetadata>,
+        lazy_discovery: LazyDiscoveryConfig,
+    ) -> Self {
+        Self {
+            http_client,
+            issuer_url,
+            issuer_verification,
+            endpoints,
+            metadata: RwLock::new(metadata),
+            lazy_discovery,
+            attempts: Mutex::new(DiscoveryAttempts::default()),
+            last_refresh: Mutex::new(None),
+            last_success: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Returns a new cache with the same metadata (if any), which uses
+    /// the given HTTP client for all subsequent requests.
+    pub(crate) fn with_http_client(&self, http_client: HttpClient) -> Self {
+        Self::new(
+            http_client,
+            self.issuer_url.clone(),
+            self.issuer_verification,
+            self.endpoints.clone(),
+            self.metadata
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone(),
+            self.lazy_discovery,
+        )
+    }
+
+    /// Returns a copy of the current discovery document, or an error if
+    /// the provider has not been reachable yet.
+    pub(crate) fn metadata(&self) -> Result<ProviderMetadata, OidcError> {
+        self.metadata
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+            .ok_or(OidcError::ProviderUnavailable)
+    }
+
+    /// Returns the ID token signing algorithms that the provider says
+    /// it will use *and* that we consider to be safe; empty if the
+    /// provider has not been reachable yet.
+    pub(crate) fn id_token_signing_algs(&self) -> Vec<CoreJwsSigningAlgorithm> {
+        self.metadata
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .as_ref()
+            .map(|metadata| {
+                allowed_id_token_signing_algs(metadata.id_token_signing_alg_values_supported())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Loads the discovery document if it has not been loaded yet,
+    /// returning an error if the provider is unreachable. Failed
+    /// attempts are retried with exponential backoff, and requests made
+    /// while waiting for the next attempt (or while too many attempts
+    /// are in flight) fail without contacting the provider.
+    pub(crate) async fn ensure_loaded(&self, jwks: &JwksCache) -> Result<(), OidcError> {
+        if self
+            .metadata
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .is_some()
+        {
+            return Ok(());
+        }
+
+        let _attempt = self.start_attempt().ok_or(OidcError::ProviderUnavailable)?;
+        match fetch_provider_metadata(
+            &self.http_client,
+            &self.issuer_url,
+            self.issuer_verification,
+            self.endpoints.as_ref(),
+        )
+        .await
+        {
+            Ok(metadata) => {
+                event!(info, "Loaded OpenID Connect provider metadata.");
+                warn_if_no_signing_algs(&metadata);
+                self.store(metadata, jwks);
+                self.attempts
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .consecutive_failures = 0;
+                Ok(())
+            }
+            Err(error) => {
+                let backoff = self.schedule_retry();
+                tide::log::warn!("{} (retrying in {:?})", error, backoff);
+                Err(error)
+            }
+        }
+    }
+
+    /// Starts a discovery attempt, unless the next attempt is not due
+    /// yet or too many attempts are already in flight.
+    fn start_attempt(&self) -> Option<DiscoveryAttempt<'_>> {
+        let mut attempts = self
+            .attempts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let waiting = matches!(attempts.retry_at, Some(retry_at) if Instant::now() < retry_at);
+        if waiting || attempts.in_flight >= self.lazy_discovery.max_concurrent_attempts {
+            return None;
+        }
+        attempts.in_flight += 1;
+        Some(DiscoveryAttempt {
+            attempts: &self.attempts,
+        })
+    }
+
+    /// Records a failed discovery attempt, returning the amount of time
+    /// until the next attempt.
+    fn schedule_retry(&self) -> Duration {
+        let mut attempts = self
+            .attempts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let backoff = self
+            .lazy_discovery
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempts.consecutive_failures))
+            .min(self.lazy_discovery.max_backoff);
+        attempts.consecutive_failures = attempts.consecutive_failures.saturating_add(1);
+        attempts.retry_at = Some(Instant::now() + backoff);
+        backoff
+    }
+
+    /// Replaces the discovery document (and the key set that came with
+    /// it).
+    fn store(&self, metadata: ProviderMetadata, jwks: &JwksCache) {
+        jwks.replace(metadata.jwks_uri().clone(), metadata.jwks().clone());
+        *self
+            .metadata
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(metadata);
+        *self
+            .last_success
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+    }
+
+    /// Returns the amount of time since the discovery document was last
+    /// (successfully) fetched from the provider.
+    pub(crate) fn age(&self) -> Duration {
+        self.last_success
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .elapsed()
+    }
+
+    /// Re-fetches the discovery document (and, as part of that, the
+    /// provider's JSON Web Key Set) from the provider. If the refresh
+    /// fails, the last good copy is kept.
+    pub(crate) async fn refresh(&self, jwks: &JwksCache) -> Result<(), OidcError> {
+        *self
+            .last_refresh
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Instant::now());
+
+        // Configured metadata never changes (once it has been built),
+        // but the provider can still rotate its signing keys.
+        let loaded = self
+            .metadata
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .is_some();
+        if loaded && self.endpoints.is_some() {
+            if !jwks.refresh().await {
+                return Err(OidcError::Discovery(
+                    "Unable to refresh JSON Web Key Set".to_string(),
+                ));
+            }
+            *self
+                .last_success
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+            return Ok(());
+        }
+
+        match fetch_provider_metadata(
+            &self.http_client,
+            &self.issuer_url,
+            self.issuer_verification,
+            self.endpoints.as_ref(),
+        )
+        .await
+        {
+            Ok(metadata) => {
+                event!(debug, "Refreshed OpenID Connect provider metadata.");
+                self.store(metadata, jwks);
+                Ok(())
+            }
+            Err(error) => {
+                tide::log::warn!("Unable to refresh provider metadata. {}", error);
+                Err(error)
+            }
+        }
+    }
+
+    /// Re-fetches the discovery document after a request to one of the
+    /// provider's endpoints failed, unless the document was refreshed
+    /// too recently.
+    pub(crate) async fn refresh_after_failure(&self, jwks: &JwksCache) -> bool {
+        let recently_refreshed = matches!(
+            *self
+                .last_refresh
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            Some(instant) if instant.elapsed() < DISCOVERY_REFRESH_COOLDOWN
+        );
+        if recently_refreshed {
+            tide::log::warn!(
+                "Skipping OpenID Connect provider metadata refresh; last refresh was too recent."
+            );
+            return false;
+        }
+
+        self.refresh(jwks).await.is_ok()
+    }
+}
+
+/// Fetches the provider's discovery document, or builds the provider
+/// metadata from the configured endpoints (fetching the signing keys if
+/// necessary).
+pub(crate) async fn fetch_provider_metadata(
+    http_client: &HttpClient,
+    issuer_url: &IssuerUrl,
+    issuer_verification: IssuerVerification,
+    endpoints: Option<&ProviderEndpoints>,
+) -> Result<ProviderMetadata, OidcError> {
+    match endpoints {
+        Some(endpoints) => endpoints
+            .provider_metadata(http_client, issuer_url)
+            .await
+            .map_err(OidcError::Discovery),
+        None => instrument!(
+            discover_provider_metadata(http_client, issuer_url, issuer_verification),
+            "oidc.discovery",
+            issuer = %issuer_url.as_str(),
+        )
+        .await
+        .map_err(OidcError::Discovery),
+    }
+}
+
+/// Warns that all logins will be rejected if the provider does not
+/// advertise any of the ID token signing algorithms that we accept.
+pub(crate) fn warn_if_no_signing_algs(metadata: &ProviderMetadata) {
+    if allowed_id_token_signing_algs(metadata.id_token_signing_alg_values_supported()).is_empty() {
+        tide::log::warn!(
+            "OpenID Connect provider does not advertise any supported ID token signing algorithms; all logins will be rejected."
+        );
+    }
+}
+
+/// Spawns the task that periodically refreshes the discovery document.
+/// The task only holds weak references to the caches, and exits once
+/// the middleware has been dropped.
+pub(crate) fn spawn_refresh_task(
+    discovery: &Arc<ProviderDiscovery>,
+    jwks: &Arc<JwksCache>,
+    ttl: Duration,
+) {
+    let discovery = Arc::downgrade(discovery);
+    let jwks = Arc::downgrade(jwks);
+    async_std::task::spawn(async move {
+        loop {
+            async_std::task::sleep(ttl).await;
+            match (discovery.upgrade(), jwks.upgrade()) {
+                (Some(discovery), Some(jwks)) => {
+                    // Failures have already been logged, and the last
+                    // good copy is kept until the next attempt.
+                    let _ = discovery.refresh(&jwks).await;
+                }
+                _ => break,
+            }
+        }
+    });
+}
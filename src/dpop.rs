@@ -0,0 +1,176 @@
+//! Demonstrating Proof of Possession ([DPoP], RFC 9449), which binds
+//! the access tokens issued to the middleware to a key pair that only
+//! the application holds, so that stolen access tokens are useless.
+//!
+//! When [enabled](crate::Config::dpop), the middleware includes a DPoP
+//! proof (a JWT signed with the application's private key) in every
+//! request to the provider's token endpoint, and sends the access token
+//! with the `DPoP` authorization scheme (along with a proof) to the
+//! provider's UserInfo endpoint. Handlers that forward the user's
+//! access token to downstream APIs must do the same (which the
+//! [`BearerForwardingMiddleware`](crate::BearerForwardingMiddleware)
+//! does for them), with a proof from the
+//! [`dpop_proof()`](crate::OpenIdConnectRequestExt::dpop_proof)
+//! request extension:
+//!
+//! ```no_run
+//! use tide::http::{Method, Url};
+//! use tide_openidconnect::OpenIdConnectRequestExt;
+//!
+//! # async fn handler(req: tide::Request<()>) -> tide::Result {
+//! let url = Url::parse("https://api.example.com/inventory")?;
+//! let mut api_request = surf::get(url.clone());
+//! if let Some(proof) = req.dpop_proof(Method::Get, &url)? {
+//!     api_request = api_request
+//!         .header("Authorization", format!("DPoP {}", req.access_token().unwrap_or_default()))
+//!         .header("DPoP", proof);
+//! }
+//! # Ok(api_request.recv_string().await?.into())
+//! # }
+//! ```
+//!
+//! [DPoP]: https://datatracker.ietf.org/doc/html/rfc9449
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use http::header::{HeaderValue, AUTHORIZATION};
+use openidconnect::{
+    core::{CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey},
+    url::Url,
+    HttpRequest, PrivateSigningKey,
+};
+use rand::RngCore;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+/// Name of the header that carries the DPoP proof.
+const DPOP_HEADER: &str = "DPoP";
+
+/// Private key with which the middleware signs its DPoP proofs.
+///
+/// The trait is implemented for [`CoreRsaPrivateSigningKey`] (which
+/// signs with `RS256`); applications that keep their keys elsewhere
+/// (in a hardware security module, for example) can implement it
+/// themselves.
+pub trait DpopSigningKey: Send + Sync {
+    /// Algorithm with which the proofs are signed.
+    fn alg(&self) -> CoreJwsSigningAlgorithm;
+
+    /// Public key, as a JSON Web Key, which is included in every proof.
+    fn public_jwk(&self) -> serde_json::Value;
+
+    /// Signs the given message (the proof's JWS signing input).
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+impl DpopSigningKey for CoreRsaPrivateSigningKey {
+    fn alg(&self) -> CoreJwsSigningAlgorithm {
+        CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256
+    }
+
+    fn public_jwk(&self) -> serde_json::Value {
+        serde_json::to_value(self.as_verification_key()).unwrap_or_default()
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        PrivateSigningKey::sign(self, &self.alg(), message).map_err(|e| e.to_string())
+    }
+}
+
+/// DPoP configuration; see [`Config::dpop`](crate::Config::dpop).
+#[derive(Clone)]
+pub struct DpopConfig {
+    /// Key with which the DPoP proofs are signed. The access tokens
+    /// that the provider issues are bound to this key.
+    pub private_key: Arc<dyn DpopSigningKey>,
+}
+
+impl std::fmt::Debug for DpopConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DpopConfig")
+            .field("private_key", &"[redacted]")
+            .field("alg", &self.private_key.alg())
+            .finish()
+    }
+}
+
+impl DpopConfig {
+    /// Creates a proof for a single request with the given method to
+    /// the given URL, which (for requests to resource servers) also
+    /// covers the access token that is sent with the request.
+    pub(crate) fn proof(
+        &self,
+        method: &str,
+        url: &Url,
+        access_token: Option<&str>,
+    ) -> Result<String, String> {
+        let alg = serde_json::to_value(self.private_key.alg()).map_err(|e| e.to_string())?;
+        let header = json!({
+            "typ": "dpop+jwt",
+            "alg": alg,
+            "jwk": self.private_key.public_jwk(),
+        });
+
+        // The proof covers the URL without its query and fragment.
+        let mut htu = url.clone();
+        htu.set_query(None);
+        htu.set_fragment(None);
+        let mut jti = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut jti);
+        let mut claims = json!({
+            "jti": base64::encode_config(jti, base64::URL_SAFE_NO_PAD),
+            "htm": method,
+            "htu": htu.as_str(),
+            "iat": Utc::now().timestamp(),
+        });
+        if let Some(access_token) = access_token {
+            claims["ath"] = json!(base64::encode_config(
+                Sha256::digest(access_token.as_bytes()),
+                base64::URL_SAFE_NO_PAD
+            ));
+        }
+
+        let signing_input = format!(
+            "{}.{}",
+            base64::encode_config(header.to_string(), base64::URL_SAFE_NO_PAD),
+            base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD),
+        );
+        let signature = self.private_key.sign(signing_input.as_bytes())?;
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    /// Adds a proof to a request to the provider. Requests that carry a
+    /// bearer access token (UserInfo requests) are switched to the DPoP
+    /// authorization scheme, and their proof covers the access token.
+    pub(crate) fn add_proof(&self, request: &mut HttpRequest) -> Result<(), String> {
+        let access_token = request
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::to_string);
+        if let Some(access_token) = &access_token {
+            request.headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("DPoP {}", access_token))
+                    .map_err(|e| e.to_string())?,
+            );
+        }
+
+        let proof = self.proof(
+            request.method.as_str(),
+            &request.url,
+            access_token.as_deref(),
+        )?;
+        request.headers.insert(
+            DPOP_HEADER,
+            HeaderValue::from_str(&proof).map_err(|e| e.to_string())?,
+        );
+        Ok(())
+    }
+}
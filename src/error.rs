@@ -0,0 +1,166 @@
+//! Errors returned by the crate's non-middleware APIs.
+
+/// Error returned when the crate is unable to obtain tokens (or other
+/// information) from the OpenID Connect provider.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum OidcError {
+    /// The HTTP client used to make requests to the provider could not
+    /// be initialized (for example, because of an invalid proxy URL).
+    #[error("Unable to initialize HTTP client: {0}")]
+    HttpClient(String),
+
+    /// The provider's metadata could not be retrieved.
+    #[error("Unable to load OpenID Connect provider metadata: {0}")]
+    Discovery(String),
+
+    /// The provider's metadata has not been loaded yet, because a
+    /// [lazily-created](crate::OpenIdConnectMiddleware::new_lazy)
+    /// middleware has not been able to reach the provider.
+    #[error("OpenID Connect provider is unavailable; its metadata has not been loaded yet")]
+    ProviderUnavailable,
+
+    /// The client could not be registered with the provider by way of
+    /// [dynamic client registration](crate::Config::dynamic_client_registration).
+    #[error("Unable to dynamically register OpenID Connect client: {0}")]
+    Registration(String),
+
+    /// The provider's token endpoint did not return a token.
+    #[error("Token request failed: {0}")]
+    TokenRequest(String),
+
+    /// The provider's backchannel authentication endpoint did not accept
+    /// a [CIBA](crate::ciba_flow) authentication request.
+    #[error("Backchannel authentication request failed: {0}")]
+    BackchannelAuthentication(String),
+
+    /// The provider's introspection endpoint did not return an
+    /// introspection result.
+    #[error("Token introspection failed: {0}")]
+    Introspection(String),
+
+    /// A [DPoP](crate::Config::dpop) proof could not be created.
+    #[error("Unable to create DPoP proof: {0}")]
+    Dpop(String),
+
+    /// The operation requires an authenticated request, but the request
+    /// has not been authenticated.
+    #[error("Request is not authenticated")]
+    NotAuthenticated,
+
+    /// The request has no session, because Tide's
+    /// [`SessionMiddleware`](tide::sessions::SessionMiddleware) was not
+    /// installed before the OpenID Connect middleware.
+    #[error(
+        "Request has no session; install tide::sessions::SessionMiddleware \
+         (with `app.with(...)`) before OpenIdConnectMiddleware"
+    )]
+    MissingSessionMiddleware,
+
+    /// The request was denied access to a route because it was not
+    /// granted all of the route's required scopes; includes the missing
+    /// scopes.
+    #[error("Request lacks the required scopes: {}", .0.join(" "))]
+    InsufficientScope(Vec<String>),
+
+    /// The request was denied access to a route by the route's claims
+    /// predicate.
+    #[error("Access denied")]
+    AccessDenied,
+
+    /// The request was denied because the user does not have the given
+    /// [required claim](crate::OpenIdConnectMiddleware::with_required_claim)
+    /// (or does not have the required value).
+    #[error("Request lacks the required claim: {0}")]
+    RequiredClaim(String),
+
+    /// The application rejected the user (for example, in a
+    /// [`PostAuthHook`](crate::hooks::PostAuthHook)).
+    #[error("Authentication was rejected: {0}")]
+    Rejected(String),
+
+    /// The [`BearerForwardingMiddleware`](crate::BearerForwardingMiddleware)
+    /// refused to send the access token to the given non-`https` origin.
+    #[error("Refusing to forward the access token to an insecure upstream: {0}")]
+    InsecureUpstream(String),
+
+    /// The [`BearerForwardingMiddleware`](crate::BearerForwardingMiddleware)
+    /// refused to send the access token to the given origin, whose host
+    /// has not been [allowed](crate::BearerForwardingMiddleware::with_allowed_host).
+    #[error("Refusing to forward the access token to an upstream that is not allowed: {0}")]
+    UpstreamNotAllowed(String),
+
+    /// The configuration includes a plain-HTTP URL (which names the
+    /// configuration field, and how to allow such URLs) without
+    /// [allowing insecure HTTP](crate::Config::allow_insecure_http).
+    #[error("Insecure URL in configuration: {0}")]
+    InsecureUrl(String),
+
+    /// The private key for [`private_key_jwt` client
+    /// authentication](crate::PrivateKeyJwt) could not be parsed.
+    #[error("Invalid client assertion signing key: {0}")]
+    InvalidSigningKey(String),
+}
+
+/// Error returned by [`ConfigBuilder::build()`](crate::ConfigBuilder::build)
+/// when the middleware configuration is incomplete or invalid.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// A required field was not set (or is empty).
+    #[error("`{0}` is required")]
+    Missing(&'static str),
+
+    /// A URL could not be parsed, or is not acceptable for its field.
+    #[error("`{field}` is not a valid URL: {reason}")]
+    InvalidUrl {
+        /// Name of the field.
+        field: &'static str,
+        /// Why the URL is invalid.
+        reason: String,
+    },
+
+    /// A redirect URL is relative, but no [application
+    /// URL](crate::ConfigBuilder::app_url) was set to resolve it
+    /// against.
+    #[error(
+        "`{field}` `{url}` is relative; set `app_url` to resolve it against the application's URL"
+    )]
+    RelativeUrl {
+        /// Name of the field.
+        field: &'static str,
+        /// The relative URL.
+        url: String,
+    },
+
+    /// A redirect URL is not under the [application
+    /// URL](crate::ConfigBuilder::app_url).
+    #[error("`{field}` `{url}` is not under the application's URL `{app_url}`")]
+    OutsideApp {
+        /// Name of the field.
+        field: &'static str,
+        /// The (resolved) redirect URL.
+        url: String,
+        /// The application's URL.
+        app_url: String,
+    },
+
+    /// A path does not start with `/`.
+    #[error("`{field}` `{path}` must be an absolute path (starting with `/`)")]
+    InvalidPath {
+        /// Name of the field.
+        field: &'static str,
+        /// The path.
+        path: String,
+    },
+
+    /// A URL uses plain HTTP, but [`Config::allow_insecure_http`](crate::Config::allow_insecure_http)
+    /// is not set.
+    #[error("{0}")]
+    InsecureUrl(String),
+
+    /// Two options cannot be used together, or an option requires
+    /// another option that was not set.
+    #[error("Conflicting options: {0}")]
+    Conflict(String),
+}
@@ -0,0 +1,65 @@
+//! OpenID Connect [Front-Channel Logout].
+//!
+//! Identity Providers that support front-channel logout notify the
+//! application when a user logs out of the provider by *rendering* the
+//! application's front-channel logout URL in an invisible `<iframe>` on
+//! the provider's logout page:
+//!
+//! ```html
+//! <iframe src="https://your.cool.site/frontchannel-logout?iss=https%3A%2F%2Fprovider.example%2F&sid=08a5019c"></iframe>
+//! ```
+//!
+//! The browser loads that URL along with the application's session
+//! cookie, which allows the middleware to find the user's session and
+//! log them out of the application as well. Setting the
+//! [`frontchannel_logout_path`](crate::Config::frontchannel_logout_path)
+//! config option tells the middleware to handle those requests at the
+//! given path; that path's full URL must also be registered with the
+//! provider as the client's `frontchannel_logout_uri`.
+//!
+//! The provider includes its issuer (`iss`) and the user's provider
+//! session id (`sid`) in the URL. The middleware rejects requests from
+//! other issuers, and only logs out the session if its `sid` -- which
+//! is captured from the ID token when the user logs in -- matches the
+//! `sid` in the request. The middleware always responds with a
+//! transparent 1×1 PNG image, since the response is never shown to the
+//! user.
+//!
+//! Note that the `<iframe>` is a cross-site request, and so the browser
+//! will only include the session cookie if the session middleware is
+//! configured with the `SameSite::None` security policy (which must
+//! then be used in conjunction with `Secure` cookies). Applications that
+//! cannot use that policy should use [back-channel
+//! logout](crate::backchannel_logout) instead.
+//!
+//! [Front-Channel Logout]: https://openid.net/specs/openid-connect-frontchannel-1_0.html
+
+use serde::Deserialize;
+use tide::{http::mime, Response, StatusCode};
+
+/// Transparent 1×1 PNG image.
+const TRANSPARENT_PIXEL: [u8; 68] = [
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0b, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x60, 0x00, 0x02, 0x00,
+    0x00, 0x05, 0x00, 0x01, 0xe9, 0xfa, 0xdc, 0xd8, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44,
+    0xae, 0x42, 0x60, 0x82,
+];
+
+/// Query parameters included in front-channel logout requests.
+#[derive(Debug, Deserialize)]
+pub(crate) struct FrontchannelLogoutRequest {
+    pub(crate) iss: Option<String>,
+    pub(crate) sid: Option<String>,
+}
+
+/// Returns the response to a front-channel logout request, which must
+/// not be cached by the browser.
+pub(crate) fn pixel_response() -> Response {
+    Response::builder(StatusCode::Ok)
+        .header("Cache-Control", "no-cache, no-store")
+        .header("Pragma", "no-cache")
+        .content_type(mime::PNG)
+        .body(&TRANSPARENT_PIXEL[..])
+        .build()
+}
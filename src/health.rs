@@ -0,0 +1,127 @@
+//! Readiness checks for the middleware's connection to the provider.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::discovery::ProviderDiscovery;
+use crate::error::OidcError;
+use crate::jwks::JwksCache;
+
+/// Reports whether or not the middleware is able to authenticate
+/// requests, which is to say that it has the provider's metadata and
+/// signing keys, and that those are reasonably up-to-date. Obtained from
+/// [`OpenIdConnectMiddleware::health_check`](crate::OpenIdConnectMiddleware::health_check),
+/// and can be cloned into (for example) a `/readyz` route.
+///
+/// # Examples
+///
+/// ```no_run
+/// # async_std::task::block_on(async {
+/// # let config = tide_openidconnect::Config {
+/// #   issuer_url: tide_openidconnect::IssuerUrl::new("https://your-tenant-name.us.auth0.com/".to_string()).unwrap(),
+/// #   client_id: tide_openidconnect::ClientId::new("app-id-goes-here".to_string()),
+/// #   client_secret: tide_openidconnect::ClientSecret::new("app-secret-goes-here".to_string()),
+/// #   redirect_url: tide_openidconnect::RedirectUrl::new("https://your.cool.site/callback".to_string()).unwrap(),
+/// #   additional_audiences: vec![],
+/// #   require_azp: false,
+/// #   clock_skew_tolerance: std::time::Duration::from_secs(60),
+/// #   idp_logout_url: None,
+/// #   idp_logout_id_token_hint: false,
+/// #   post_logout_redirect_url: None,
+/// #   retain_id_token: false,
+/// #   dynamic_client_registration: None,
+/// #   backchannel_logout_path: None,
+/// #   frontchannel_logout_path: None,
+/// #   fetch_userinfo: false,
+/// #   extra_authorization_params: Default::default(),
+/// #   dpop: None,
+/// #   access_denied_handler: None,
+/// #   token_refresh_failed_handler: None,
+/// #   provider_endpoints: None,
+/// #   realm: None,
+/// #   introspection: None,
+/// #   claims_locales: None,
+/// #   redirect_strategy: Default::default(),
+/// #   csp_policy: None,
+/// #   http_client: Default::default(),
+/// #   http_timeouts: Default::default(),
+/// #   allow_insecure_http: false,
+/// #   verify_on_startup: true,
+/// #   session_encryption_keys: vec![],
+/// #   session_ttl: Default::default(),
+/// #   issuer_verification: Default::default(),
+/// #   token_endpoint_auth_method: None,
+/// # };
+/// let mut app = tide::new();
+/// let middleware = tide_openidconnect::OpenIdConnectMiddleware::new(&config).await;
+/// let health_check = middleware.health_check();
+/// app.with(middleware);
+///
+/// app.at("/readyz").get(move |_| {
+///     let health_check = health_check.clone();
+///     async move {
+///         Ok(if health_check.is_ready() {
+///             tide::StatusCode::Ok
+///         } else {
+///             tide::StatusCode::ServiceUnavailable
+///         })
+///     }
+/// });
+/// # })
+/// ```
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    discovery: Arc<ProviderDiscovery>,
+    jwks: Arc<JwksCache>,
+    max_age: Option<Duration>,
+}
+
+impl HealthCheck {
+    /// Creates a new health check; the metadata is considered stale
+    /// once it is older than `max_age` (if provided).
+    pub(crate) fn new(
+        discovery: Arc<ProviderDiscovery>,
+        jwks: Arc<JwksCache>,
+        max_age: Option<Duration>,
+    ) -> Self {
+        Self {
+            discovery,
+            jwks,
+            max_age,
+        }
+    }
+
+    /// Returns `true` if the middleware's provider signing keys have
+    /// been loaded and the provider metadata is not stale, `false`
+    /// otherwise. This check uses the cached metadata and does not
+    /// contact the provider, and so is cheap enough to be used for
+    /// frequent readiness probes.
+    ///
+    /// Metadata only becomes stale when the middleware has been
+    /// configured with a [discovery
+    /// cache](crate::OpenIdConnectMiddleware::with_discovery_cache),
+    /// and then only once two consecutive refreshes have failed.
+    pub fn is_ready(&self) -> bool {
+        let has_keys = !self.jwks.keys().keys().is_empty();
+        let is_fresh = self
+            .max_age
+            .is_none_or(|max_age| self.discovery.age() <= max_age);
+        has_keys && is_fresh
+    }
+
+    /// Re-fetches the provider metadata (and signing keys), returning
+    /// `true` if the provider is reachable and the middleware is
+    /// [ready](Self::is_ready), `false` otherwise. The previous
+    /// metadata continues to be used if the provider is unreachable.
+    pub async fn check(&self) -> bool {
+        self.refresh_metadata().await.is_ok() && self.is_ready()
+    }
+
+    /// Re-fetches the provider metadata (and signing keys) immediately,
+    /// for example from an admin endpoint, returning the error if the
+    /// provider is unreachable (in which case the previous metadata
+    /// continues to be used).
+    pub async fn refresh_metadata(&self) -> Result<(), OidcError> {
+        self.discovery.refresh(&self.jwks).await
+    }
+}
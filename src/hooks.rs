@@ -0,0 +1,91 @@
+//! Hooks that run application-specific logic at points in the
+//! authentication process.
+
+use std::sync::Arc;
+
+use crate::error::OidcError;
+use crate::redirect_strategy::UnauthenticatedHandler;
+
+/// Runs after a user has successfully authenticated, but before the
+/// session is marked as authenticated; see
+/// [`with_post_auth_hook()`](crate::OpenIdConnectMiddleware::with_post_auth_hook).
+///
+/// The hook receives the callback request, which includes the
+/// request's extensions (and so the Tide session, as
+/// `req.ext_mut().get_mut::<tide::sessions::Session>()`), along with
+/// all of the claims from the verified ID token. Typical hooks create
+/// or update the user's record in the application's database.
+#[tide::utils::async_trait]
+pub trait PostAuthHook: Send + Sync {
+    /// Called with the callback request and the ID token's claims.
+    /// Returning an error aborts the login: the session is not
+    /// authenticated, and the user is shown an error page (`403
+    /// Forbidden` for [`OidcError::Rejected`], `500 Internal Server
+    /// Error` otherwise).
+    async fn on_authenticated(
+        &self,
+        req: &mut tide::http::Request,
+        claims: &serde_json::Value,
+    ) -> Result<(), OidcError>;
+}
+
+/// Runs before an authenticated session is cleared by a logout, or
+/// when the provider logs a user out through [back-channel
+/// logout](crate::backchannel_logout); see
+/// [`with_pre_logout_hook()`](crate::OpenIdConnectMiddleware::with_pre_logout_hook).
+/// Typical hooks revoke tokens that the application has stored, or
+/// record the logout in an audit log.
+#[tide::utils::async_trait]
+pub trait PreLogoutHook: Send + Sync {
+    /// Called with the subject (the Identity Provider-specific user id)
+    /// of the user that is logging out. Errors are logged, but do not
+    /// prevent the logout, so that users never remain logged in
+    /// because of a failing hook.
+    async fn before_logout(&self, subject: &str) -> Result<(), OidcError>;
+}
+
+/// Runs when the access token of an authenticated session has expired
+/// and could not be refreshed (because the provider rejected the
+/// refresh token, or could not be reached); see
+/// [`Config::token_refresh_failed_handler`](crate::Config::token_refresh_failed_handler).
+///
+/// The session's authentication state has already been removed by the
+/// time the handler runs, and the handler's response is returned in
+/// place of the application's. Typical handlers clear the rest of the
+/// application's session state and show a "Your session has expired"
+/// page that links to the login path.
+#[tide::utils::async_trait]
+pub trait TokenRefreshFailedHandler: Send + Sync {
+    /// Returns the response to the given request, whose session's
+    /// tokens could not be refreshed for the given reason. The request
+    /// includes its extensions, and so the Tide session (as
+    /// `req.ext_mut().get_mut::<tide::sessions::Session>()`).
+    async fn handle(&self, req: &mut tide::http::Request, error: OidcError) -> tide::Result;
+}
+
+impl std::fmt::Debug for dyn TokenRefreshFailedHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TokenRefreshFailedHandler")
+    }
+}
+
+/// Handles failed token refreshes by clearing the session (as a logout
+/// would) and handling the request as unauthenticated, which sends
+/// browsers back through the login process; used if the application
+/// has not configured a [`TokenRefreshFailedHandler`].
+pub(crate) struct RestartLogin {
+    pub(crate) redirect_strategy: Arc<dyn UnauthenticatedHandler>,
+    pub(crate) destroy_session: bool,
+}
+
+#[tide::utils::async_trait]
+impl TokenRefreshFailedHandler for RestartLogin {
+    async fn handle(&self, req: &mut tide::http::Request, _error: OidcError) -> tide::Result {
+        if self.destroy_session {
+            if let Some(session) = req.ext_mut().get_mut::<tide::sessions::Session>() {
+                session.destroy();
+            }
+        }
+        self.redirect_strategy.unauthenticated(req).await
+    }
+}
@@ -0,0 +1,70 @@
+//! Rejection of plain-HTTP issuer, redirect, and provider endpoint URLs,
+//! which are only allowed for loopback addresses unless the
+//! configuration explicitly allows them.
+
+use crate::error::OidcError;
+use crate::middleware::Config;
+use openidconnect::url::{Host, Url};
+
+/// Returns an error naming the first of the configuration's URLs that
+/// uses plain HTTP (and is not a loopback URL), unless
+/// [`Config::allow_insecure_http`] is set.
+pub(crate) fn check_config_urls(config: &Config) -> Result<(), OidcError> {
+    if config.allow_insecure_http {
+        return Ok(());
+    }
+
+    check_url("issuer_url", config.issuer_url.url())?;
+    check_url("redirect_url", config.redirect_url.url())?;
+    if let Some(idp_logout_url) = &config.idp_logout_url {
+        if let Ok(url) = Url::parse(idp_logout_url) {
+            check_url("idp_logout_url", &url)?;
+        }
+    }
+    if let Some(post_logout_redirect_url) = &config.post_logout_redirect_url {
+        check_url("post_logout_redirect_url", post_logout_redirect_url.url())?;
+    }
+    if let Some(endpoints) = &config.provider_endpoints {
+        check_url(
+            "provider_endpoints.authorization_endpoint",
+            endpoints.authorization_endpoint.url(),
+        )?;
+        check_url(
+            "provider_endpoints.token_endpoint",
+            endpoints.token_endpoint.url(),
+        )?;
+        check_url("provider_endpoints.jwks_uri", endpoints.jwks_uri.url())?;
+        if let Some(userinfo_endpoint) = &endpoints.userinfo_endpoint {
+            check_url(
+                "provider_endpoints.userinfo_endpoint",
+                userinfo_endpoint.url(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn check_url(name: &str, url: &Url) -> Result<(), OidcError> {
+    if url.scheme() == "http" && !is_loopback(url) {
+        return Err(OidcError::InsecureUrl(format!(
+            "{} `{}` does not use https; set `Config::allow_insecure_http` to \
+             allow plain-HTTP URLs",
+            name, url
+        )));
+    }
+    Ok(())
+}
+
+/// Returns `true` if the URL's host is `localhost` (or one of its
+/// subdomains) or a loopback IP address.
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(Host::Domain(domain)) => {
+            let domain = domain.to_ascii_lowercase();
+            domain == "localhost" || domain.ends_with(".localhost")
+        }
+        Some(Host::Ipv4(address)) => address.is_loopback(),
+        Some(Host::Ipv6(address)) => address.is_loopback(),
+        None => false,
+    }
+}
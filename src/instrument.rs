@@ -0,0 +1,36 @@
+//! Optional `tracing` instrumentation.
+//!
+//! These macros expand to the equivalent `tracing` calls when the
+//! `tracing` feature is enabled, and to nothing (or to the
+//! uninstrumented future) when it is not, which keeps the `#[cfg]`
+//! noise out of the middleware itself.
+//!
+//! Events must never include tokens (access, refresh, or ID tokens);
+//! the CSRF state is included so that the steps of a single login
+//! attempt can be correlated across the logs, as is the nonce, but only
+//! when [nonce logging](crate::OpenIdConnectMiddleware::with_nonce_logging)
+//! has been enabled.
+
+/// Instruments a future with a new `INFO`-level span; for example:
+/// `instrument!(fut, "oidc.callback", issuer = %self.issuer_url.as_str())`
+macro_rules! instrument {
+    ($fut:expr, $($span:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument($fut, tracing::info_span!($($span)+));
+        #[cfg(not(feature = "tracing"))]
+        let fut = $fut;
+        fut
+    }};
+}
+
+/// Emits a `tracing` event at the given level; for example:
+/// `event!(debug, state = %csrf_token.secret(), "Redirecting browser.")`
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+    };
+}
+
+pub(crate) use event;
+pub(crate) use instrument;
@@ -0,0 +1,268 @@
+//! OAuth 2.0 Token Introspection ([RFC 7662]), which allows the [bearer
+//! token middleware](crate::OpenIdConnectBearerMiddleware) to
+//! authenticate API requests that present opaque (non-JWT) access
+//! tokens.
+//!
+//! [RFC 7662]: https://datatracker.ietf.org/doc/html/rfc7662
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::client_auth::{ClientAuth, TokenEndpointAuthMethod};
+use crate::discovery::ProviderDiscovery;
+use crate::error::OidcError;
+use crate::instrument::{event, instrument};
+use crate::isahc::HttpClient;
+use crate::middleware::Claims;
+use chrono::{DateTime, TimeZone, Utc};
+use http::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use openidconnect::{
+    url::{form_urlencoded, Url},
+    ClientId, ClientSecret, HttpRequest,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// Configuration for introspecting opaque access tokens; see
+/// [`Config::introspection`](crate::Config::introspection).
+#[derive(Debug, Clone, Deserialize)]
+pub struct IntrospectionConfig {
+    /// Client id with which the middleware authenticates to the
+    /// introspection endpoint, which is often a separate (resource
+    /// server) client from the one used to log users in.
+    pub client_id: ClientId,
+
+    /// Client secret with which the middleware authenticates to the
+    /// introspection endpoint, unless [another client authentication
+    /// method](crate::OpenIdConnectMiddleware::with_introspection_client_auth)
+    /// is used.
+    pub client_secret: ClientSecret,
+
+    /// URL of the introspection endpoint, for providers that do not
+    /// advertise their `introspection_endpoint` in the discovery
+    /// document.
+    ///
+    /// Defaults to the endpoint in the discovery document.
+    #[serde(default)]
+    pub endpoint: Option<Url>,
+
+    /// Maximum amount of time for which introspection results are
+    /// cached. Results for active tokens are never cached beyond the
+    /// token's expiration time.
+    ///
+    /// Defaults to 5 minutes.
+    #[serde(default = "default_cache_ttl")]
+    pub cache_ttl: Duration,
+}
+
+fn default_cache_ttl() -> Duration {
+    Duration::from_secs(300)
+}
+
+/// Introspection result, which is cached until `expires_at`.
+#[derive(Debug)]
+struct CachedIntrospection {
+    /// Claims of the active token, or `None` if the token is not active.
+    claims: Option<Claims>,
+    expires_at: DateTime<Utc>,
+}
+
+/// Introspects opaque access tokens, caching the results by (a hash of)
+/// the token.
+#[derive(Debug)]
+pub(crate) struct TokenIntrospector {
+    http_client: HttpClient,
+    discovery: Arc<ProviderDiscovery>,
+    config: IntrospectionConfig,
+    auth_method: Option<TokenEndpointAuthMethod>,
+    client_auth: Arc<ClientAuth>,
+    cache: Mutex<HashMap<[u8; 32], CachedIntrospection>>,
+}
+
+impl TokenIntrospector {
+    pub(crate) fn new(
+        http_client: HttpClient,
+        discovery: Arc<ProviderDiscovery>,
+        config: IntrospectionConfig,
+        auth_method: Option<TokenEndpointAuthMethod>,
+    ) -> Self {
+        Self {
+            http_client,
+            discovery,
+            config,
+            auth_method,
+            client_auth: Arc::new(ClientAuth::default()),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a new introspector (with an empty cache) that uses the
+    /// given HTTP client and provider metadata.
+    pub(crate) fn with_http_client(
+        &self,
+        http_client: HttpClient,
+        discovery: Arc<ProviderDiscovery>,
+    ) -> Self {
+        Self {
+            client_auth: Arc::clone(&self.client_auth),
+            ..Self::new(
+                http_client,
+                discovery,
+                self.config.clone(),
+                self.auth_method,
+            )
+        }
+    }
+
+    /// Returns a new introspector (with an empty cache) that
+    /// authenticates with the given method.
+    pub(crate) fn with_client_auth(&self, client_auth: ClientAuth) -> Self {
+        Self {
+            client_auth: Arc::new(client_auth),
+            ..Self::new(
+                self.http_client.clone(),
+                Arc::clone(&self.discovery),
+                self.config.clone(),
+                self.auth_method,
+            )
+        }
+    }
+
+    /// Returns the claims of the given token (`sub`, `scope`, `exp`,
+    /// etc.) if the provider says that the token is active, or `None`
+    /// if it is not.
+    pub(crate) async fn introspect(&self, token: &str) -> Result<Option<Claims>, OidcError> {
+        // Only the token's hash is kept in memory, so that the cache
+        // does not hold on to a collection of valid access tokens.
+        let key: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+        let now = Utc::now();
+        if let Some(cached) = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&key)
+            .filter(|cached| cached.expires_at > now)
+        {
+            event!(debug, "Using cached introspection result.");
+            return Ok(cached.claims.clone());
+        }
+
+        let response = instrument!(
+            self.request_introspection(token),
+            "oidc.introspection",
+            client_id = %self.config.client_id.as_str(),
+        )
+        .await?;
+
+        // Tokens that have already expired are not active, regardless
+        // of what the provider says.
+        let expires_at = response
+            .get("exp")
+            .and_then(serde_json::Value::as_i64)
+            .and_then(|exp| Utc.timestamp_opt(exp, 0).single());
+        let active = response.get("active") == Some(&serde_json::Value::Bool(true))
+            && expires_at.is_none_or(|expires_at| expires_at > now);
+        let cached = CachedIntrospection {
+            expires_at: match expires_at {
+                Some(expires_at) if active => expires_at.min(now + self.cache_ttl()),
+                _ => now + self.cache_ttl(),
+            },
+            claims: Some(response).filter(|_| active),
+        };
+
+        // Expired results are evicted whenever a new result is added to
+        // the cache.
+        let claims = cached.claims.clone();
+        let mut cache = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        cache.retain(|_, cached| cached.expires_at > now);
+        cache.insert(key, cached);
+        Ok(claims)
+    }
+
+    fn cache_ttl(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.config.cache_ttl)
+            .unwrap_or_else(|_| chrono::Duration::zero())
+    }
+
+    async fn request_introspection(&self, token: &str) -> Result<Claims, OidcError> {
+        let endpoint = match &self.config.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => self
+                .discovery
+                .metadata()?
+                .additional_metadata()
+                .introspection_endpoint
+                .clone()
+                .ok_or_else(|| {
+                    OidcError::Introspection(
+                        "provider does not have an introspection endpoint".to_string(),
+                    )
+                })?,
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        let mut params = vec![
+            ("token", token.to_string()),
+            ("token_type_hint", "access_token".to_string()),
+        ];
+        let metadata = self.discovery.metadata()?;
+        match self.client_auth.as_ref() {
+            ClientAuth::ClientSecret => TokenEndpointAuthMethod::resolve(
+                self.auth_method,
+                metadata.token_endpoint_auth_methods_supported(),
+            )
+            .add_credentials(
+                &self.config.client_id,
+                &self.config.client_secret,
+                &mut headers,
+                &mut params,
+            )
+            .map_err(OidcError::Introspection)?,
+            client_auth => {
+                // Client assertions are addressed to the token endpoint,
+                // which identifies the provider (RFC 7523, section 3).
+                let audience = metadata
+                    .token_endpoint()
+                    .map(|url| url.as_str())
+                    .unwrap_or_default();
+                params.push(("client_id", self.config.client_id.to_string()));
+                params.extend(
+                    client_auth
+                        .assertion_params(&self.config.client_id, audience)
+                        .map_err(OidcError::Introspection)?,
+                );
+            }
+        }
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .finish();
+        let response = self
+            .http_client
+            .request(HttpRequest {
+                url: endpoint,
+                method: http::Method::POST,
+                headers,
+                body: body.into_bytes(),
+            })
+            .await
+            .map_err(|e| OidcError::Introspection(e.to_string()))?;
+        if !response.status_code.is_success() {
+            return Err(OidcError::Introspection(format!(
+                "introspection failed with HTTP status {}: {}",
+                response.status_code,
+                String::from_utf8_lossy(&response.body)
+            )));
+        }
+
+        serde_json::from_slice(&response.body).map_err(|e| OidcError::Introspection(e.to_string()))
+    }
+}
@@ -1,7 +1,27 @@
+use crate::dpop::DpopConfig;
+use crate::instrument::{event, instrument};
 use futures_lite::{io::Cursor, AsyncRead};
-use isahc::{config::RedirectPolicy, prelude::*, HttpClient, Request};
-use once_cell::sync::Lazy;
-use openidconnect::{HttpRequest, HttpResponse};
+use isahc::{
+    auth::{Authentication, Credentials},
+    config::{CaCertificate, RedirectPolicy},
+    prelude::*,
+    Request,
+};
+use openidconnect::{url::Url, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Delay before the first retry of a failed request to the provider,
+/// which doubles after each attempt.
+const INITIAL_RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Maximum delay between two attempts of a request to the provider.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Default [connect](HttpTimeouts::connect) and
+/// [request](HttpTimeouts::request) timeouts.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
 
 ///
 /// Error type returned by failed Isahc HTTP requests.
@@ -17,42 +37,316 @@ pub(crate) enum Error {
     /// I/O error.
     #[error("I/O error")]
     Io(#[source] std::io::Error),
+    /// Invalid proxy URL.
+    #[error("Invalid proxy URL")]
+    Proxy(#[source] http::uri::InvalidUri),
+    /// The DPoP proof could not be created.
+    #[error("Unable to create DPoP proof: {0}")]
+    Dpop(String),
+}
+
+impl Error {
+    /// Returns `true` if the request timed out (either while connecting
+    /// to the provider, or while waiting for its response).
+    pub(crate) fn is_timeout(&self) -> bool {
+        matches!(self, Error::Isahc(error) if error.is_timeout())
+    }
+}
+
+/// Timeouts for the requests to the OpenID Connect provider (discovery,
+/// JSON Web Key Set, token exchange, UserInfo, etc.), which keep a hung
+/// provider from hanging the requests of the application's users.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct HttpTimeouts {
+    /// Maximum amount of time to wait for a connection to the provider
+    /// (or to the [proxy](HttpClientConfig::proxy)) to be established.
+    ///
+    /// Defaults to 10 seconds.
+    #[serde(default = "default_timeout")]
+    pub connect: Duration,
+
+    /// Maximum amount of time that a single request to the provider may
+    /// take, including connecting to the provider. Requests that are
+    /// [retried](crate::OpenIdConnectMiddleware::with_retries) get this
+    /// long for each attempt.
+    ///
+    /// Defaults to 10 seconds.
+    #[serde(default = "default_timeout")]
+    pub request: Duration,
+}
+
+impl Default for HttpTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_TIMEOUT,
+            request: DEFAULT_TIMEOUT,
+        }
+    }
 }
 
-// Isahc recommends that you create a single client per "area of application"
-// and reuse that client through your code. We have to create a global client
-// instance (instead of putting the client in a struct and then having the
-// `http_client` function be a trait function) because we need to pass the
-// bare `http_client` function to the oauth2-rs crate and we cannot close
-// over `self` when doing that.
-static HTTP_CLIENT: Lazy<HttpClient> = Lazy::new(|| {
-    HttpClient::builder()
-        .redirect_policy(RedirectPolicy::None)
-        .build()
-        .expect("Unable to initialize Isahc client.")
-});
-
-pub(crate) async fn http_client(openid_request: HttpRequest) -> Result<HttpResponse, Error> {
-    let mut request_builder = Request::builder()
-        .method(openid_request.method)
-        .uri(openid_request.url.as_str());
-    for (name, value) in &openid_request.headers {
-        request_builder = request_builder.header(name.as_str(), value.as_bytes());
+fn default_timeout() -> Duration {
+    DEFAULT_TIMEOUT
+}
+
+/// Configuration for the HTTP client used to make requests to the
+/// OpenID Connect provider (discovery, JSON Web Key Set, token exchange,
+/// UserInfo, etc.).
+#[derive(Clone, Default, Deserialize)]
+pub struct HttpClientConfig {
+    /// Optional URL of the HTTP proxy through which all requests to the
+    /// provider are sent.
+    pub proxy: Option<Url>,
+
+    /// Optional username and password used to authenticate with the
+    /// [proxy](Self::proxy), which are sent (using HTTP Basic
+    /// authentication) in the `Proxy-Authorization` header.
+    pub proxy_credentials: Option<(String, String)>,
+
+    /// Optional PEM bundle of the certificate authorities that are
+    /// trusted to sign the provider's TLS certificate, such as an
+    /// internal CA. The bundle is used *instead* of the system's root
+    /// certificates, and so must also include those if the provider (or
+    /// the [proxy](Self::proxy)) presents a publicly-issued certificate.
+    #[serde(default)]
+    pub ca_certificates: Option<PathBuf>,
+
+    /// Headers that are added to every request to the provider, such as
+    /// the headers that an egress proxy requires.
+    #[serde(default)]
+    pub default_headers: Vec<(String, String)>,
+
+    /// Optional client to use for *all* requests to the provider
+    /// (including the initial discovery request), instead of one built
+    /// from the rest of this configuration, which is then ignored. See
+    /// [`HttpClient`] for the settings that the client should have.
+    ///
+    /// This option cannot be deserialized, and so must be set in code.
+    #[serde(skip)]
+    pub client: Option<HttpClient>,
+}
+
+impl std::fmt::Debug for HttpClientConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HttpClientConfig")
+            .field("proxy", &self.proxy)
+            .field(
+                "proxy_credentials",
+                &self
+                    .proxy_credentials
+                    .as_ref()
+                    .map(|(username, _)| (username, "[redacted]")),
+            )
+            .field("ca_certificates", &self.ca_certificates)
+            .field(
+                "default_headers",
+                &self
+                    .default_headers
+                    .iter()
+                    .map(|(name, _)| (name, "[redacted]"))
+                    .collect::<Vec<_>>(),
+            )
+            .field("client", &self.client)
+            .finish()
     }
-    let request = request_builder
-        .body(openid_request.body)
-        .map_err(Error::Http)?;
+}
 
-    let response = HTTP_CLIENT
-        .send_async(request)
+/// HTTP client used to make requests to the OpenID Connect provider.
+///
+/// Isahc recommends that you create a single client per "area of
+/// application" and reuse that client through your code; each instance
+/// of the middleware creates its own client (since each instance can be
+/// configured with a different proxy), which is then shared with the
+/// middleware's caches. The client is cheap to clone.
+///
+/// Applications that need more control over the connection to the
+/// provider -- client certificates for mutual TLS, connection pool
+/// limits, etc. -- can build their own Isahc client and inject it into
+/// the middleware, either in the [configuration](HttpClientConfig::client)
+/// or [afterwards](crate::OpenIdConnectMiddleware::with_http_client). That client should be configured with
+/// [`RedirectPolicy::None`](isahc::config::RedirectPolicy::None), since
+/// the middleware does not expect the provider's endpoints to redirect.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    client: isahc::HttpClient,
+    timeout: Option<Duration>,
+    retries: u32,
+}
+
+impl From<isahc::HttpClient> for HttpClient {
+    fn from(client: isahc::HttpClient) -> Self {
+        Self {
+            client,
+            timeout: None,
+            retries: 0,
+        }
+    }
+}
+
+impl HttpClient {
+    /// Creates a new client with the given configuration and timeouts,
+    /// or returns the configuration's injected client (to which only the
+    /// request timeout applies).
+    pub(crate) fn new(config: &HttpClientConfig, timeouts: &HttpTimeouts) -> Result<Self, Error> {
+        if let Some(client) = &config.client {
+            return Ok(client.clone().with_timeout(Some(timeouts.request)));
+        }
+
+        let mut builder = isahc::HttpClient::builder()
+            .redirect_policy(RedirectPolicy::None)
+            .connect_timeout(timeouts.connect);
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(Some(
+                proxy.as_str().parse::<http::Uri>().map_err(Error::Proxy)?,
+            ));
+        }
+        if let Some((username, password)) = &config.proxy_credentials {
+            builder = builder
+                .proxy_authentication(Authentication::basic())
+                .proxy_credentials(Credentials::new(username, password));
+        }
+        if let Some(ca_certificates) = &config.ca_certificates {
+            builder = builder.ssl_ca_certificate(CaCertificate::file(ca_certificates));
+        }
+        for (name, value) in &config.default_headers {
+            builder = builder.default_header(name.as_str(), value.as_str());
+        }
+
+        let client: Self = builder.build().map_err(Error::Isahc)?.into();
+        Ok(client.with_timeout(Some(timeouts.request)))
+    }
+
+    /// Returns a copy of the client that fails requests which take
+    /// longer than `timeout` (or never times out requests, if `None`).
+    pub(crate) fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Returns a copy of the client that retries failed requests up to
+    /// `retries` times.
+    pub(crate) fn with_retries(self, retries: u32) -> Self {
+        Self { retries, ..self }
+    }
+
+    /// Returns a copy of `other` with this client's timeout and retries.
+    pub(crate) fn with_settings_of(&self, other: Self) -> Self {
+        Self {
+            timeout: self.timeout,
+            retries: self.retries,
+            ..other
+        }
+    }
+
+    /// Sends the request to the provider; this function is passed to
+    /// the openidconnect-rs crate (by way of a closure) as its HTTP
+    /// client.
+    pub(crate) async fn request(&self, openid_request: HttpRequest) -> Result<HttpResponse, Error> {
+        self.request_with_dpop(None, openid_request).await
+    }
+
+    /// Sends the request to the provider, along with a DPoP proof if
+    /// [DPoP](crate::Config::dpop) is enabled.
+    ///
+    /// Idempotent (`GET` and `HEAD`) requests that fail with a transient
+    /// error -- a connection error, a timeout, or a `5xx` response --
+    /// are retried (with a fresh DPoP proof, since proofs cannot be
+    /// replayed) up to the configured number of times, backing off
+    /// exponentially between attempts. Other requests, such as the token
+    /// exchange, are never retried: the provider may already have
+    /// consumed the authorization code (or refresh token, or client
+    /// assertion) of a request whose response was lost. Responses with
+    /// any other status code, such as a `400` with an `invalid_grant`
+    /// error, are returned as-is.
+    pub(crate) async fn request_with_dpop(
+        &self,
+        dpop: Option<&DpopConfig>,
+        openid_request: HttpRequest,
+    ) -> Result<HttpResponse, Error> {
+        let retries = if is_idempotent(&openid_request.method) {
+            self.retries
+        } else {
+            0
+        };
+        let mut backoff = INITIAL_RETRY_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            let mut request = openid_request.clone();
+            if let Some(dpop) = dpop {
+                dpop.add_proof(&mut request).map_err(Error::Dpop)?;
+            }
+            let result = self.send(request).await;
+            let transient = match &result {
+                Ok(response) => response.status_code.is_server_error(),
+                Err(Error::Isahc(error)) => error.is_network() || error.is_timeout(),
+                Err(_) => false,
+            };
+            if !transient || attempt >= retries {
+                return result;
+            }
+
+            attempt += 1;
+            event!(
+                debug,
+                attempt = attempt,
+                url = %openid_request.url,
+                "Retrying failed request to OpenID Connect provider."
+            );
+            async_std::task::sleep(backoff).await;
+            backoff = backoff.saturating_mul(2).min(MAX_RETRY_BACKOFF);
+        }
+    }
+
+    /// Sends a single request to the provider.
+    async fn send(&self, openid_request: HttpRequest) -> Result<HttpResponse, Error> {
+        let mut request_builder = Request::builder()
+            .method(openid_request.method)
+            .uri(openid_request.url.as_str());
+        if let Some(timeout) = self.timeout {
+            request_builder = request_builder.timeout(timeout);
+        }
+        for (name, value) in &openid_request.headers {
+            request_builder = request_builder.header(name.as_str(), value.as_bytes());
+        }
+        let request = request_builder
+            .body(openid_request.body)
+            .map_err(Error::Http)?;
+
+        let response = match instrument!(
+            self.client.send_async(request),
+            "oidc.http",
+            url = %openid_request.url,
+        )
         .await
-        .map_err(Error::Isahc)?;
+        {
+            Ok(response) => response,
+            Err(error) => {
+                if error.is_timeout() {
+                    event!(
+                        warn,
+                        url = %openid_request.url,
+                        "Request to OpenID Connect provider timed out."
+                    );
+                }
+                return Err(Error::Isahc(error));
+            }
+        };
+        event!(
+            debug,
+            status = %response.status(),
+            "Received response from OpenID Connect provider."
+        );
+
+        Ok(HttpResponse {
+            status_code: response.status(),
+            headers: response.headers().to_owned(),
+            body: to_bytes(response.into_body()).await?,
+        })
+    }
+}
 
-    Ok(HttpResponse {
-        status_code: response.status(),
-        headers: response.headers().to_owned(),
-        body: to_bytes(response.into_body()).await?,
-    })
+/// Returns `true` if requests with the given method can safely be sent
+/// more than once.
+fn is_idempotent(method: &http::Method) -> bool {
+    *method == http::Method::GET || *method == http::Method::HEAD
 }
 
 async fn to_bytes<R>(reader: R) -> Result<Vec<u8>, Error>
@@ -0,0 +1,37 @@
+//! Comparison of the configured issuer URL with the issuer identifiers
+//! returned by the provider: the discovery document's `issuer` field and
+//! the `iss` claim of the tokens that it issues.
+
+use openidconnect::IssuerUrl;
+use serde::Deserialize;
+
+/// Determines how the [configured issuer URL](crate::Config::issuer_url)
+/// is compared with the issuer in the provider's discovery document and
+/// the `iss` claim of its tokens.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum IssuerVerification {
+    /// The issuers must be identical, as required by the OpenID Connect
+    /// specifications.
+    Strict,
+
+    /// Issuers that only differ by a trailing slash are treated as the
+    /// same issuer. Providers with path-bearing issuers (such as
+    /// `https://example.okta.com/oauth2/default`, or Keycloak's
+    /// `https://kc.example.com/realms/main`) are easily configured with
+    /// (or advertise) a trailing slash that the other side lacks.
+    #[default]
+    NormalizeTrailingSlash,
+}
+
+impl IssuerVerification {
+    /// Returns `true` if the given issuer identifier is that of the
+    /// configured issuer.
+    pub(crate) fn matches(self, issuer_url: &IssuerUrl, issuer: &str) -> bool {
+        match self {
+            Self::Strict => issuer_url.as_str() == issuer,
+            Self::NormalizeTrailingSlash => {
+                issuer_url.as_str().trim_end_matches('/') == issuer.trim_end_matches('/')
+            }
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::instrument::{event, instrument};
+use crate::isahc::HttpClient;
+use openidconnect::{core::CoreJsonWebKeySet, JsonWebKeySetUrl};
+
+/// Minimum amount of time between two JSON Web Key Set refreshes; this
+/// prevents an attacker from using ID tokens with made-up key ids in
+/// order to hammer the provider's JWKS endpoint.
+const JWKS_REFRESH_COOLDOWN: Duration = Duration::from_secs(30);
+
+/// Provider's JSON Web Key Set, which can be re-fetched when the
+/// provider rotates its signing keys.
+#[derive(Debug)]
+pub(crate) struct JwksCache {
+    http_client: HttpClient,
+    /// URL of the key set, which is `None` until a lazily-created
+    /// middleware has discovered the provider.
+    url: RwLock<Option<JsonWebKeySetUrl>>,
+    keys: RwLock<CoreJsonWebKeySet>,
+    last_refresh: Mutex<Option<Instant>>,
+}
+
+impl JwksCache {
+    /// Creates a new cache from the key set that was retrieved as
+    /// part of provider discovery. The initial fetch does not count
+    /// towards the refresh cool-down, so the first refresh is always
+    /// allowed.
+    pub(crate) fn new(
+        http_client: HttpClient,
+        url: Option<JsonWebKeySetUrl>,
+        keys: CoreJsonWebKeySet,
+    ) -> Self {
+        Self {
+            http_client,
+            url: RwLock::new(url),
+            keys: RwLock::new(keys),
+            last_refresh: Mutex::new(None),
+        }
+    }
+
+    /// Returns a new cache with the same key set, which uses the given
+    /// HTTP client for all subsequent refreshes.
+    pub(crate) fn with_http_client(&self, http_client: HttpClient) -> Self {
+        Self::new(
+            http_client,
+            self.url
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone(),
+            self.keys(),
+        )
+    }
+
+    /// Returns a copy of the current key set.
+    pub(crate) fn keys(&self) -> CoreJsonWebKeySet {
+        self.keys
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Replaces the key set (and its URL) with the key set that was
+    /// retrieved as part of a provider metadata refresh.
+    pub(crate) fn replace(&self, url: JsonWebKeySetUrl, keys: CoreJsonWebKeySet) {
+        *self
+            .url
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(url);
+        *self
+            .keys
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = keys;
+    }
+
+    /// Re-fetches the key set from the provider, returning `true` if
+    /// the key set was refreshed and `false` if the refresh was skipped
+    /// (because of the cool-down, or because the provider has not been
+    /// discovered yet) or failed.
+    pub(crate) async fn refresh(&self) -> bool {
+        let url = match self
+            .url
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+        {
+            Some(url) => url,
+            None => return false,
+        };
+        {
+            let mut last_refresh = self
+                .last_refresh
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if matches!(*last_refresh, Some(instant) if instant.elapsed() < JWKS_REFRESH_COOLDOWN) {
+                tide::log::warn!("Skipping JSON Web Key Set refresh; last refresh was too recent.");
+                return false;
+            }
+            *last_refresh = Some(Instant::now());
+        }
+
+        match instrument!(
+            CoreJsonWebKeySet::fetch_async(&url, |request| self.http_client.request(request)),
+            "oidc.jwks_fetch",
+            url = %url.as_str(),
+        )
+        .await
+        {
+            Ok(keys) => {
+                event!(
+                    debug,
+                    keys = keys.keys().len(),
+                    "Refreshed JSON Web Key Set."
+                );
+                *self
+                    .keys
+                    .write()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()) = keys;
+                true
+            }
+            Err(error) => {
+                tide::log::warn!("Unable to refresh JSON Web Key Set: {}", error);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Verification of the JWTs -- other than ID tokens, which are verified
+//! by the `openidconnect` crate -- that are signed by the provider, such
+//! as JWT authorization responses, JWT access tokens, and logout tokens.
+
+use chrono::{TimeZone, Utc};
+use openidconnect::core::{CoreJsonWebKeySet, CoreJwsSigningAlgorithm};
+use openidconnect::{IssuerUrl, JsonWebKey, JsonWebKeyId};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::issuer::IssuerVerification;
+use crate::middleware::Claims;
+
+/// Reasons for which a JWT can be rejected.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum JwtError {
+    /// None of the provider's keys match the JWT's key id, which may
+    /// mean that the provider has rotated its keys.
+    #[error("no signing key matches the JWT's key id")]
+    UnknownKey,
+
+    /// The token is not a JWT at all (and so may be an opaque token).
+    #[error("malformed JWT")]
+    Malformed,
+
+    /// None of the provider's (matching) keys verify the signature.
+    #[error("invalid signature")]
+    InvalidSignature,
+
+    #[error("{0}")]
+    Invalid(&'static str),
+}
+
+#[derive(Deserialize)]
+struct JwtHeader {
+    alg: CoreJwsSigningAlgorithm,
+    kid: Option<JsonWebKeyId>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audiences {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct RegisteredClaims {
+    iss: String,
+    aud: Audiences,
+    exp: i64,
+    nbf: Option<i64>,
+}
+
+fn decode_segment<T: DeserializeOwned>(segment: &str) -> Result<T, JwtError> {
+    base64::decode_config(segment, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or(JwtError::Malformed)
+}
+
+impl JwtError {
+    /// Returns `true` if the JWT was rejected because its signature
+    /// could not be verified with any of the provider's keys.
+    pub(crate) fn is_signature_error(&self) -> bool {
+        matches!(self, Self::UnknownKey | Self::InvalidSignature)
+    }
+}
+
+/// Returns the `sub` claim of a JWT *without* verifying the JWT, for
+/// the purpose of reporting a JWT that failed verification.
+pub(crate) fn unverified_subject(jwt: &str) -> Option<String> {
+    unverified_claim(jwt, "sub")
+}
+
+/// Returns the `nonce` claim of a JWT *without* verifying the JWT, for
+/// the purpose of debugging a nonce mismatch.
+#[cfg(feature = "tracing")]
+pub(crate) fn unverified_nonce(jwt: &str) -> Option<String> {
+    unverified_claim(jwt, "nonce")
+}
+
+/// Returns the `typ` header parameter of a JWT (such as `at+jwt` for
+/// JWT access tokens), if any.
+pub(crate) fn header_type(jwt: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct TypedHeader {
+        typ: Option<String>,
+    }
+    let header: TypedHeader = decode_segment(jwt.split('.').next()?).ok()?;
+    header.typ
+}
+
+fn unverified_claim(jwt: &str, name: &str) -> Option<String> {
+    let claims: Claims = decode_segment(jwt.split('.').nth(1)?).ok()?;
+    claims.get(name)?.as_str().map(str::to_string)
+}
+
+/// Verifies the signature, issuer, audience, expiration time and (if
+/// present) "not before" time of a JWT, and returns all of its claims.
+/// The JWT must be intended for one of the given audiences.
+pub(crate) fn verify_jwt(
+    jwt: &str,
+    keys: &CoreJsonWebKeySet,
+    allowed_algs: &[CoreJwsSigningAlgorithm],
+    issuer_url: &IssuerUrl,
+    issuer_verification: IssuerVerification,
+    audiences: &[&str],
+    clock_skew_tolerance: chrono::Duration,
+) -> Result<Claims, JwtError> {
+    let claims = verify_signature(jwt, keys, allowed_algs)?;
+    verify_registered_claims(
+        &claims,
+        Some((issuer_url, issuer_verification)),
+        audiences,
+        clock_skew_tolerance,
+    )?;
+    Ok(claims)
+}
+
+/// Verifies the signature of a JWT with one of the provider's keys,
+/// using one of the algorithms that we consider to be safe, and returns
+/// all of its claims. None of the claims are verified.
+pub(crate) fn verify_signature(
+    jwt: &str,
+    keys: &CoreJsonWebKeySet,
+    allowed_algs: &[CoreJwsSigningAlgorithm],
+) -> Result<Claims, JwtError> {
+    let (signing_input, signature) = jwt.rsplit_once('.').ok_or(JwtError::Malformed)?;
+    let (header, claims) = signing_input.split_once('.').ok_or(JwtError::Malformed)?;
+
+    let header: JwtHeader = decode_segment(header)?;
+    if !allowed_algs.contains(&header.alg) {
+        return Err(JwtError::Invalid("unsupported signature algorithm"));
+    }
+    let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| JwtError::Malformed)?;
+    let candidate_keys: Vec<_> = keys
+        .keys()
+        .iter()
+        .filter(|key| header.kid.is_none() || key.key_id() == header.kid.as_ref())
+        .collect();
+    if candidate_keys.is_empty() {
+        return Err(JwtError::UnknownKey);
+    }
+    if !candidate_keys.iter().any(|key| {
+        key.verify_signature(&header.alg, signing_input.as_bytes(), &signature)
+            .is_ok()
+    }) {
+        return Err(JwtError::InvalidSignature);
+    }
+
+    decode_segment(claims)
+}
+
+/// Returns all of the claims of a JWT *without* verifying its
+/// signature, for the [test-only](crate::verifier_config) verification
+/// overrides.
+pub(crate) fn unverified_claims(jwt: &str) -> Result<Claims, JwtError> {
+    decode_segment(jwt.split('.').nth(1).ok_or(JwtError::Malformed)?)
+}
+
+/// Verifies that a JWT's claims say that it was issued by our provider
+/// (unless `issuer` is `None`), for one of the given audiences, and
+/// that it is currently valid.
+pub(crate) fn verify_registered_claims(
+    claims: &Claims,
+    issuer: Option<(&IssuerUrl, IssuerVerification)>,
+    audiences: &[&str],
+    clock_skew_tolerance: chrono::Duration,
+) -> Result<(), JwtError> {
+    let registered: RegisteredClaims =
+        serde_json::from_value(serde_json::Value::Object(claims.clone()))
+            .map_err(|_| JwtError::Invalid("missing or malformed registered claims"))?;
+    if let Some((issuer_url, issuer_verification)) = issuer {
+        if !issuer_verification.matches(issuer_url, &registered.iss) {
+            return Err(JwtError::Invalid("unexpected issuer"));
+        }
+    }
+    let audience_matches = match &registered.aud {
+        Audiences::Single(aud) => audiences.contains(&aud.as_str()),
+        Audiences::Multiple(auds) => auds.iter().any(|aud| audiences.contains(&aud.as_str())),
+    };
+    if !audience_matches {
+        return Err(JwtError::Invalid("unexpected audience"));
+    }
+    let now = Utc::now();
+    match Utc.timestamp_opt(registered.exp, 0).single() {
+        Some(expires_at) if expires_at + clock_skew_tolerance > now => {}
+        _ => return Err(JwtError::Invalid("JWT has expired")),
+    }
+    if let Some(nbf) = registered.nbf {
+        if now
+            .checked_add_signed(clock_skew_tolerance)
+            .is_some_and(|now| nbf > now.timestamp())
+        {
+            return Err(JwtError::Invalid("JWT is not yet valid"));
+        }
+    }
+
+    Ok(())
+}
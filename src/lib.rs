@@ -11,16 +11,92 @@
     clippy::unwrap_used
 )]
 
+mod auth_events;
+mod authorization_params;
+pub mod backchannel_logout;
+mod bearer;
+mod bearer_forwarding;
+mod challenge;
+pub mod ciba_flow;
+mod client;
+mod client_auth;
+pub mod client_credentials;
+mod config_builder;
+mod discovery;
+mod dpop;
+mod error;
+pub mod frontchannel_logout;
+mod health;
+pub mod hooks;
+mod insecure_http;
+mod instrument;
+mod introspection;
 mod isahc;
+mod issuer;
+mod jwks;
+mod jwt;
+pub mod login_hint;
 mod middleware;
+pub mod nonce;
+mod par;
+mod provider_metadata;
+mod providers;
 pub mod redirect_strategy;
+pub mod registration;
 mod request_ext;
+mod request_object;
+mod require_authenticated;
+mod response_mode;
 mod route_ext;
+pub mod security_events;
+mod session_encryption;
+pub mod session_management;
+mod startup_check;
+pub mod state_store;
+mod token_exchange;
+mod verifier_config;
 
+pub use crate::auth_events::AuthEvent;
+pub use crate::authorization_params::{AzureAdAuthorizationExtensions, ExtraAuthorizationParams};
+pub use crate::bearer::OpenIdConnectBearerMiddleware;
+pub use crate::bearer_forwarding::BearerForwardingMiddleware;
+pub use crate::client_auth::{ClientAuth, PrivateKeyJwt, TokenEndpointAuthMethod};
+pub use crate::config_builder::ConfigBuilder;
+pub use crate::discovery::{DiscoveryCacheConfig, LazyDiscoveryConfig};
+pub use crate::dpop::{DpopConfig, DpopSigningKey};
+pub use crate::error::{ConfigError, OidcError};
+pub use crate::health::HealthCheck;
+pub use crate::introspection::IntrospectionConfig;
+pub use crate::isahc::{HttpClient, HttpClientConfig, HttpTimeouts};
+pub use crate::issuer::IssuerVerification;
+pub use crate::middleware::Claims;
 pub use crate::middleware::Config;
+pub use crate::middleware::LogoutMode;
 pub use crate::middleware::OpenIdConnectMiddleware;
-pub use crate::request_ext::OpenIdConnectRequestExt;
+pub use crate::middleware::SessionTtl;
+pub use crate::par::PushedAuthorizationRequests;
+pub use crate::provider_metadata::ProviderEndpoints;
+pub use crate::providers::OpenIdConnectProviders;
+pub use crate::request_ext::{AuthInfo, OpenIdConnectRequestExt};
+pub use crate::request_object::RequestObjectSigning;
+pub use crate::require_authenticated::{
+    require_authenticated, LoginRequired, RequireAuthenticated,
+};
+pub use crate::response_mode::ResponseMode;
 pub use crate::route_ext::OpenIdConnectRouteExt;
+pub use crate::session_encryption::SessionEncryptionKey;
+pub use crate::startup_check::StartupCheckListener;
+pub use crate::token_exchange::{ExchangeRequest, ExchangedToken};
+#[cfg(feature = "insecure-test-overrides")]
+pub use crate::verifier_config::VerifierConfig;
 
 #[doc(no_inline)]
-pub use openidconnect::{ClientId, ClientSecret, IssuerUrl, RedirectUrl};
+pub use openidconnect::core::{
+    CoreGenderClaim, CoreJsonWebKeySet, CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey,
+};
+#[doc(no_inline)]
+pub use openidconnect::{
+    AccessToken, AdditionalClaims, AuthUrl, ClientId, ClientSecret, EmptyAdditionalClaims,
+    IssuerUrl, JsonWebKeyId, JsonWebKeySetUrl, OAuth2TokenResponse, RedirectUrl, StandardClaims,
+    TokenUrl, UserInfoUrl,
+};
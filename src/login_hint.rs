@@ -0,0 +1,42 @@
+//! Login hints.
+//!
+//! A login hint tells the Identity Provider which account the user
+//! wants to sign in with (usually an email address), which allows the
+//! provider to pre-fill its login form. The middleware asks the
+//! configured [`LoginHintExtractor`] for a hint when the browser
+//! navigates to the login path, and includes the hint in the
+//! authorization request as the `login_hint` parameter; see [OpenID
+//! Connect Core 1.0, Section
+//! 3.1.2.1](https://openid.net/specs/openid-connect-core-1_0.html#AuthRequest).
+
+use tide::http::Request;
+
+/// Extracts the login hint from an incoming login request.
+pub trait LoginHintExtractor: Send + Sync {
+    /// Returns the login hint for the given login request, or `None` if
+    /// the request does not include a login hint.
+    fn extract(&self, req: &Request) -> Option<String>;
+}
+
+/// Uses the value of the given query parameter of the login request
+/// (`/login?login_hint=user@example.com`, for example) as the login
+/// hint. Empty values are ignored.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryParamLoginHint(pub &'static str);
+
+impl Default for QueryParamLoginHint {
+    /// Uses the `login_hint` query parameter.
+    fn default() -> Self {
+        Self("login_hint")
+    }
+}
+
+impl LoginHintExtractor for QueryParamLoginHint {
+    fn extract(&self, req: &Request) -> Option<String> {
+        req.url()
+            .query_pairs()
+            .find(|(name, _)| name == self.0)
+            .map(|(_, value)| value.into_owned())
+            .filter(|value| !value.is_empty())
+    }
+}
@@ -1,17 +1,137 @@
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
 
-use crate::isahc::http_client;
-use crate::redirect_strategy::{HttpRedirect, RedirectStrategy};
-use crate::request_ext::OpenIdConnectRequestExtData;
+use crate::auth_events::{elapsed_ms, log_auth_event, AuthEvent, AuthEventLevels};
+use crate::authorization_params::ExtraAuthorizationParams;
+use crate::backchannel_logout::{LogoutHandler, LogoutTarget, LogoutTokenClaims, LogoutTokenIds};
+use crate::bearer::{bearer_token, OpenIdConnectBearerMiddleware};
+use crate::challenge::{bearer_challenge, ChallengeError, RejectedCredentials};
+use crate::ciba_flow::{CibaClient, IdTokenVerifier};
+use crate::client::{Client, IdToken, IdTokenClaims, OtherClaims};
+use crate::client_auth::{ClientAuth, TokenEndpointAuthMethod};
+use crate::client_credentials::ClientCredentialsClient;
+use crate::discovery::{
+    fetch_provider_metadata, spawn_refresh_task, warn_if_no_signing_algs, DiscoveryCacheConfig,
+    LazyDiscoveryConfig, ProviderDiscovery,
+};
+use crate::dpop::DpopConfig;
+use crate::error::OidcError;
+use crate::frontchannel_logout::{pixel_response, FrontchannelLogoutRequest};
+use crate::health::HealthCheck;
+use crate::hooks::{PostAuthHook, PreLogoutHook, RestartLogin, TokenRefreshFailedHandler};
+use crate::insecure_http::check_config_urls;
+use crate::instrument::{event, instrument};
+use crate::introspection::{IntrospectionConfig, TokenIntrospector};
+use crate::isahc::{HttpClient, HttpClientConfig, HttpTimeouts};
+use crate::issuer::IssuerVerification;
+use crate::jwks::JwksCache;
+#[cfg(feature = "tracing")]
+use crate::jwt::unverified_nonce;
+use crate::jwt::{
+    unverified_claims, unverified_subject, verify_registered_claims, verify_signature, JwtError,
+};
+use crate::login_hint::LoginHintExtractor;
+use crate::nonce::{NonceConfig, MIN_NONCE_BYTES};
+use crate::par::{push_authorization_request, PushedAuthorizationRequests};
+use crate::provider_metadata::{ProviderEndpoints, ProviderMetadata};
+use crate::redirect_strategy::{
+    AccessDeniedHandler, AccessDeniedResponses, RedirectStrategy, RedirectStrategyHandler,
+    RedirectStrategyKind, UnauthenticatedHandler,
+};
+use crate::registration::DynamicClientRegistration;
+use crate::request_ext::{
+    refresh_expired_session, AuthDecision, LazyAuthInfo, LoginUrlBuilder, LoginUrlBuilderHandle,
+    OpenIdConnectRequestExtData, OpenIdConnectRequestExtInternal, SessionAuthInfo,
+    SessionRefresher,
+};
+use crate::request_object::RequestObjectSigning;
+use crate::require_authenticated::LoginRequired;
+use crate::response_mode::{
+    verify_jwt_response, AuthorizationResponse, JwtAuthorizationResponse, ResponseMode,
+};
+use crate::security_events::{remote_ip, SecurityEvent, SecurityEventSink};
+use crate::session_encryption::{SessionEncryption, SessionEncryptionKey};
+use crate::session_management::{script_response, SessionCheck, SessionManagementConfig};
+use crate::startup_check::StartupCheckListener;
+use crate::state_store::OidcStateStore;
+use crate::token_exchange::{TokenExchangeCache, TokenExchanger};
+use crate::verifier_config::VerifierConfig;
+use chrono::{DateTime, Utc};
+use openidconnect::url::Url;
 use openidconnect::{
-    core::{CoreClient, CoreProviderMetadata, CoreResponseType},
-    AccessToken, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken,
-    IssuerUrl, Nonce, OAuth2TokenResponse, RedirectUrl, Scope, SubjectIdentifier,
+    core::{
+        CoreAuthPrompt, CoreErrorResponseType, CoreGenderClaim, CoreIdTokenVerifier,
+        CoreJsonWebKeySet, CoreJsonWebKeyType, CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm, CoreResponseType,
+    },
+    AccessToken, AccessTokenHash, AdditionalClaims, AuthenticationFlow, ClaimsVerificationError,
+    ClientId, ClientSecret, CsrfToken, EmptyAdditionalClaims, IssuerUrl, LanguageTag, LoginHint,
+    Nonce, NonceVerifier, OAuth2TokenResponse, RedirectUrl, RefreshToken, RequestTokenError, Scope,
+    SignatureVerificationError, SubjectIdentifier, UserInfoClaims, UserInfoError,
 };
 use serde::{Deserialize, Serialize};
-use tide::{http::Method, Middleware, Next, Redirect, Request, StatusCode};
+use sha2::{Digest, Sha256};
+use tide::http::cookies::{Cookie, SameSite};
+use tide::http::headers::WWW_AUTHENTICATE;
+use tide::{
+    http::Method, log::Level, sessions::Session, Middleware, Next, Redirect, Request, StatusCode,
+};
+
+/// Default prefix of the middleware's session keys.
+const DEFAULT_SESSION_KEY_PREFIX: &str = "tide.";
+
+/// Returns the session key under which the middleware stores its state.
+fn session_key(prefix: &str, provider_id: Option<&str>) -> String {
+    match provider_id {
+        Some(provider_id) => format!("{}oidc.{}", prefix, provider_id),
+        None => format!("{}oidc", prefix),
+    }
+}
+
+/// Authorization errors that the provider returns when a silent
+/// (`prompt=none`) login cannot be completed without interacting with
+/// the user; see [OpenID Connect Core 1.0, Section
+/// 3.1.2.6](https://openid.net/specs/openid-connect-core-1_0.html#AuthError).
+const SILENT_LOGIN_ERRORS: [&str; 4] = [
+    "login_required",
+    "interaction_required",
+    "consent_required",
+    "account_selection_required",
+];
+
+/// ID token signature algorithms that the middleware is willing to
+/// accept, regardless of what the provider advertises or the
+/// application configures. Note that `none` and the symmetric (`HS*`)
+/// algorithms are deliberately absent from this list.
+const ALLOWED_ID_TOKEN_SIGNING_ALGS: [CoreJwsSigningAlgorithm; 9] = [
+    CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+    CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha384,
+    CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha512,
+    CoreJwsSigningAlgorithm::RsaSsaPssSha256,
+    CoreJwsSigningAlgorithm::RsaSsaPssSha384,
+    CoreJwsSigningAlgorithm::RsaSsaPssSha512,
+    CoreJwsSigningAlgorithm::EcdsaP256Sha256,
+    CoreJwsSigningAlgorithm::EcdsaP384Sha384,
+    CoreJwsSigningAlgorithm::EdDsaEd25519,
+];
 
-const SESSION_KEY: &str = "tide.oidc";
+/// Default (and minimum) number of random bytes in the `state`
+/// parameter, which matches the 128 bits of entropy used by the
+/// openidconnect-rs crate.
+const DEFAULT_ENTROPY_BYTES: u32 = 16;
+
+/// Time after which a [state store](crate::state_store) may discard a
+/// pending login that has not completed.
+const PENDING_AUTH_TTL: std::time::Duration = std::time::Duration::from_secs(30 * 60);
+
+/// Time during which a replayed callback for the login that established
+/// the session is redirected to the landing page (if [duplicate
+/// callbacks are tolerated](OpenIdConnectMiddleware::with_tolerate_duplicate_callback)).
+const DUPLICATE_CALLBACK_WINDOW: chrono::Duration = chrono::Duration::minutes(5);
 
 /// Middleware configuration.
 #[derive(Debug, Deserialize)]
@@ -21,16 +141,73 @@ pub struct Config {
     /// expected issuer.
     pub issuer_url: IssuerUrl,
 
+    /// How the [`issuer_url`](Self::issuer_url) is compared with the
+    /// issuer in the provider's discovery document and with the `iss`
+    /// claim of its tokens.
+    ///
+    /// Defaults to [`IssuerVerification::NormalizeTrailingSlash`].
+    #[serde(default)]
+    pub issuer_verification: IssuerVerification,
+
     /// Our Client ID, as generated by the OpenID Connect provider.
     pub client_id: ClientId,
 
     /// Our Client Secret, as generated by the OpenID Connect provider.
     pub client_secret: ClientSecret,
 
+    /// How the [`client_secret`](Self::client_secret) is sent to the
+    /// provider's token endpoint (for code exchanges, token refreshes,
+    /// token exchanges, and Client Credentials and CIBA grants), and to
+    /// its Pushed Authorization Request and backchannel authentication
+    /// endpoints. Token introspection sends the [introspection
+    /// credentials](Self::introspection) with the same method.
+    ///
+    /// Defaults to the method advertised in the provider's
+    /// `token_endpoint_auth_methods_supported` metadata, preferring
+    /// [`ClientSecretBasic`](TokenEndpointAuthMethod::ClientSecretBasic).
+    #[serde(default)]
+    pub token_endpoint_auth_method: Option<TokenEndpointAuthMethod>,
+
     /// URL to which the OpenID Connect provider will redirect authenticated
     /// requests; must be a URL registered with the provider.
     pub redirect_url: RedirectUrl,
 
+    /// Additional audiences (besides our [`client_id`](Self::client_id))
+    /// that are trusted to appear in the ID token's `aud` claim, which is
+    /// necessary for providers that issue ID tokens to several related
+    /// clients at once. ID tokens are rejected if their audience does
+    /// not include our client id, or if it includes any audience that is
+    /// not in this list.
+    ///
+    /// Per the OpenID Connect specification, ID tokens with multiple
+    /// audiences must include an `azp` (authorized party) claim, and the
+    /// `azp` claim (if present) must always be equal to our client id.
+    ///
+    /// Defaults to no additional audiences.
+    #[serde(default)]
+    pub additional_audiences: Vec<String>,
+
+    /// Whether or not the ID token must *always* include an `azp`
+    /// (authorized party) claim that is equal to our
+    /// [`client_id`](Self::client_id), even if the token only has a
+    /// single audience.
+    ///
+    /// Defaults to `false`, in which case the `azp` claim is only
+    /// required for ID tokens with [multiple
+    /// audiences](Self::additional_audiences).
+    #[serde(default)]
+    pub require_azp: bool,
+
+    /// Amount of clock skew between the middleware and the provider
+    /// that is tolerated when checking the ID token's `exp` (expiration
+    /// time) and `nbf` (not before) claims; ID tokens are accepted for
+    /// this long after they expire, and this long before they become
+    /// valid.
+    ///
+    /// Defaults to 60 seconds.
+    #[serde(default = "default_clock_skew_tolerance")]
+    pub clock_skew_tolerance: std::time::Duration,
+
     /// Optional URL used to log the user out of the identity provider
     /// as part of the application logout process. If provided, the
     /// browser will be redirected to this URL *after* clearing the auth
@@ -47,44 +224,860 @@ pub struct Config {
     /// cases the value of this parameter will be the full URL to your
     /// site's
     /// [`logout_landing_path`](OpenIdConnectMiddleware::with_logout_landing_path).
+    /// Alternatively, the middleware can add the standard
+    /// `post_logout_redirect_uri` parameter for you, and handle the
+    /// return from the provider: see
+    /// [`post_logout_redirect_url`](Self::post_logout_redirect_url).
     ///
     /// Finally, identity providers often require you to register the
     /// logout URL in their configuration, usually in the same place where
     /// you register your [redirect URL](Self::redirect_url).
     pub idp_logout_url: Option<String>,
+
+    /// Whether or not the user's ID token is included in the
+    /// [`idp_logout_url`](Self::idp_logout_url) as the `id_token_hint`
+    /// query parameter, which some providers require in order to
+    /// identify the session that is being logged out. Enabling this
+    /// option stores the (serialized) ID token in the session, which is
+    /// [encrypted](Self::session_encryption_keys) along with the rest of
+    /// the session state.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub idp_logout_id_token_hint: bool,
+
+    /// URL to which the provider returns the browser after an
+    /// RP-initiated logout. If provided, the
+    /// [`idp_logout_url`](Self::idp_logout_url) is extended with this
+    /// URL (as the `post_logout_redirect_uri` query parameter) and a
+    /// random `state`, and the middleware handles the return at this
+    /// URL's path: it verifies the `state` and then redirects the
+    /// browser to the [logout landing
+    /// path](OpenIdConnectMiddleware::with_logout_landing_path).
+    ///
+    /// The `state` is kept in a short-lived `<session key>.logout`
+    /// cookie (scoped to this URL's path), since the logout may destroy
+    /// the session. Returns whose `state` does not match the
+    /// logout are rejected with a `400 Bad Request` response.
+    ///
+    /// As with the [redirect URL](Self::redirect_url), providers usually
+    /// require this URL to be registered in their configuration.
+    #[serde(default)]
+    pub post_logout_redirect_url: Option<RedirectUrl>,
+
+    /// Whether or not the user's (serialized) ID token is retained in
+    /// the session, so that it can be forwarded to downstream services
+    /// by way of the
+    /// [`id_token_raw()`](crate::OpenIdConnectRequestExt::id_token_raw)
+    /// request extension. Note that this meaningfully increases the
+    /// size of the session state.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub retain_id_token: bool,
+
+    /// Optional [Dynamic Client Registration](crate::registration)
+    /// configuration. If provided, the middleware registers itself with
+    /// the provider on first startup and uses the resulting (stored)
+    /// credentials instead of the [`client_id`](Self::client_id) and
+    /// [`client_secret`](Self::client_secret) fields, which are
+    /// ignored.
+    ///
+    /// This option cannot be deserialized, and so must be set in code.
+    #[serde(skip)]
+    pub dynamic_client_registration: Option<DynamicClientRegistration>,
+
+    /// Optional path at which the middleware will accept [Back-Channel
+    /// Logout](crate::backchannel_logout) requests from the provider.
+    /// The full URL to this path must be registered with the provider
+    /// as the client's `backchannel_logout_uri`, and the application
+    /// must also configure a [logout
+    /// handler](OpenIdConnectMiddleware::with_logout_handler).
+    pub backchannel_logout_path: Option<String>,
+
+    /// Optional path at which the middleware will handle [Front-Channel
+    /// Logout](crate::frontchannel_logout) requests from the provider.
+    /// The full URL to this path must be registered with the provider
+    /// as the client's `frontchannel_logout_uri`.
+    pub frontchannel_logout_path: Option<String>,
+
+    /// Whether or not the middleware fetches the user's claims from the
+    /// provider's UserInfo endpoint after the user logs in, which is
+    /// necessary for providers that only return some profile
+    /// attributes from that endpoint, and not in the ID token. The
+    /// claims are then available to requests through the
+    /// [`userinfo()`](crate::OpenIdConnectRequestExt::userinfo) request
+    /// extension.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub fetch_userinfo: bool,
+
+    /// Additional parameters that are included in every authorization
+    /// request, such as the `domain_hint` supported by Azure AD (see
+    /// [`AzureAdAuthorizationExtensions`](crate::AzureAdAuthorizationExtensions)).
+    ///
+    /// Defaults to no additional parameters.
+    #[serde(default)]
+    pub extra_authorization_params: ExtraAuthorizationParams,
+
+    /// Preferred languages (BCP 47 language tags, such as `fr-CA`) in
+    /// which the provider should return the user's claims, most
+    /// preferred first. The languages are included in the authorization
+    /// request as the `claims_locales` parameter, and in UserInfo
+    /// requests as the `Accept-Language` header.
+    ///
+    /// Defaults to `None`, which lets the provider choose.
+    #[serde(default)]
+    pub claims_locales: Option<Vec<String>>,
+
+    /// How unauthenticated requests to [protected
+    /// routes](crate::OpenIdConnectRouteExt) are handled: by
+    /// redirecting the browser to the login path, or (for API routes)
+    /// with a JSON `401 Unauthorized` response. Applications that need a
+    /// different strategy can provide their own handler with
+    /// [`RedirectStrategyKind::custom()`].
+    ///
+    /// Defaults to [`RedirectStrategyKind::HttpRedirect`].
+    #[serde(default)]
+    pub redirect_strategy: RedirectStrategyKind,
+
+    /// `Content-Security-Policy` header that is sent with the login
+    /// route's redirect to the provider's authorization endpoint, so
+    /// that the response body cannot run scripts if the browser renders
+    /// it instead of following the redirect.
+    ///
+    /// Defaults to `None`, in which case the policy blocks all content
+    /// (including inline scripts) and only allows connections to the
+    /// provider's origin: `default-src 'none'; connect-src <origin>`.
+    #[serde(default)]
+    pub csp_policy: Option<String>,
+
+    /// Configuration for the HTTP client used to make requests to the
+    /// provider, including the (optional) HTTP proxy through which
+    /// those requests are sent, the trusted certificate authorities,
+    /// and any default headers; or else the application's own client.
+    ///
+    /// Defaults to connecting to the provider directly.
+    #[serde(default)]
+    pub http_client: HttpClientConfig,
+
+    /// Connect and request timeouts for the requests to the provider.
+    /// A token exchange (or UserInfo request) that times out fails the
+    /// callback request with a `504 Gateway Timeout` response.
+    ///
+    /// Defaults to 10 seconds for both.
+    #[serde(default)]
+    pub http_timeouts: HttpTimeouts,
+
+    /// Allows plain-HTTP (`http://`) issuer, redirect, IdP logout, and
+    /// [provider endpoint](Self::provider_endpoints) URLs, which are
+    /// otherwise rejected (with an [`OidcError::InsecureUrl`] error)
+    /// when the middleware is created, so that a typo cannot silently
+    /// downgrade the security of the login flow. Loopback URLs
+    /// (`localhost`, `127.0.0.1`, `[::1]`) are always allowed, for
+    /// local development against a provider running on the same
+    /// machine.
+    ///
+    /// Defaults to `false`.
+    #[serde(default)]
+    pub allow_insecure_http: bool,
+
+    /// Fetch (and parse) the provider's discovery document and JSON Web
+    /// Key Set when the application starts listening on a [startup
+    /// listener](OpenIdConnectMiddleware::startup_listener), so that an
+    /// unreachable or misconfigured provider fails `listen()` instead
+    /// of the first login.
+    ///
+    /// **This only applies to `listen()` calls that are passed the
+    /// middleware's [`startup_listener()`](OpenIdConnectMiddleware::startup_listener)**;
+    /// a plain `app.listen("127.0.0.1:8000")` does not know about the
+    /// middleware, and so cannot check the provider. Without the startup
+    /// listener, the provider is instead verified when the middleware is
+    /// [created](OpenIdConnectMiddleware::try_new) -- or, for a
+    /// [lazily-created](OpenIdConnectMiddleware::new_lazy) middleware,
+    /// by the first request, which also logs a warning that the provider
+    /// was not verified at startup.
+    ///
+    /// Defaults to `true`.
+    #[serde(default = "default_verify_on_startup")]
+    pub verify_on_startup: bool,
+
+    /// Optional keys used to encrypt the middleware's session state
+    /// (which includes the access token and the ID token claims) before
+    /// it is written to the session store, which is useful when the
+    /// session store is shared with other applications. The state is
+    /// encrypted with AES-256-GCM, and so any tampering with the
+    /// encrypted state is detected, in which case the session is
+    /// treated as not being logged in.
+    ///
+    /// The state is always encrypted with the first key, but can be
+    /// decrypted with any of the keys, which allows keys to be rotated
+    /// by adding a new key to the front of the list (and then removing
+    /// the old key once the sessions encrypted with that key have
+    /// expired).
+    ///
+    /// Defaults to no keys, in which case the session state is stored
+    /// unencrypted.
+    #[serde(default)]
+    pub session_encryption_keys: Vec<SessionEncryptionKey>,
+
+    /// Maximum lifetime of an authenticated session, after which the
+    /// session is treated as not being logged in (and the user must log
+    /// in again).
+    ///
+    /// Defaults to [`SessionTtl::FromToken`].
+    #[serde(default)]
+    pub session_ttl: SessionTtl,
+
+    /// Optional configuration for introspecting ([RFC
+    /// 7662](https://datatracker.ietf.org/doc/html/rfc7662)) opaque
+    /// access tokens, which allows the [bearer token
+    /// middleware](crate::OpenIdConnectBearerMiddleware) to authenticate
+    /// API requests with access tokens that are not JWTs (and so cannot
+    /// be verified locally). Tokens that the provider reports as
+    /// `active` are authenticated, with the subject, scopes and
+    /// expiration time taken from the introspection result.
+    ///
+    /// Defaults to `None`, in which case opaque access tokens are
+    /// rejected.
+    #[serde(default)]
+    pub introspection: Option<IntrospectionConfig>,
+
+    /// Realm (the protection space) included in the `WWW-Authenticate`
+    /// challenges of the `401 Unauthorized` responses to API requests
+    /// (from the [bearer token
+    /// middleware](crate::OpenIdConnectBearerMiddleware) and the
+    /// [`Unauthorized401`](crate::redirect_strategy::Unauthorized401)
+    /// strategy), and of the `403 Forbidden` responses to requests that
+    /// lack a required scope.
+    ///
+    /// Defaults to `None`, in which case the client id is used as the
+    /// realm.
+    #[serde(default)]
+    pub realm: Option<String>,
+
+    /// Optional [DPoP](crate::dpop) configuration. If provided, the
+    /// middleware proves possession of the configured key in its
+    /// requests to the provider's token endpoint, so that the access
+    /// tokens that the provider issues are bound to that key, and
+    /// handlers must then include a
+    /// [proof](crate::OpenIdConnectRequestExt::dpop_proof) whenever
+    /// they use the access token.
+    ///
+    /// This option cannot be deserialized, and so must be set in code.
+    #[serde(skip)]
+    pub dpop: Option<DpopConfig>,
+
+    /// Optional handler that generates the response to authenticated
+    /// requests that are denied access to a [protected
+    /// route](crate::OpenIdConnectRouteExt), because they lack a
+    /// required scope or because the route's claims predicate denied
+    /// them. The handler receives the request and the reason for the
+    /// denial, and so can (for example) redirect browsers to an
+    /// application page that explains the denial.
+    ///
+    /// Defaults to `None`, in which case denied requests get the
+    /// [insufficient scope](OpenIdConnectMiddleware::with_insufficient_scope_response)
+    /// and [access denied](OpenIdConnectMiddleware::with_access_denied_response)
+    /// responses: `403 Forbidden`, with a JSON body that describes the
+    /// error.
+    ///
+    /// This option cannot be deserialized, and so must be set in code.
+    #[serde(skip)]
+    pub access_denied_handler: Option<Arc<dyn AccessDeniedHandler>>,
+
+    /// Optional handler for requests whose session's access token has
+    /// expired and could not be refreshed. Sessions are refreshed with
+    /// the refresh token (if the provider issued one) once the access
+    /// token expires, on the first request to a route that requires
+    /// authentication, and the handler receives the request and the
+    /// reason that the refresh failed.
+    ///
+    /// Defaults to `None`, in which case the session is cleared (or
+    /// destroyed, if [logouts destroy the
+    /// session](OpenIdConnectMiddleware::with_logout_destroys_session)),
+    /// and the request gets the same response as any unauthenticated
+    /// request to a protected route: a redirect to the [login
+    /// path](OpenIdConnectMiddleware::with_login_path), or a `401
+    /// Unauthorized` with the [`Unauthorized401`](crate::redirect_strategy::Unauthorized401)
+    /// strategy.
+    ///
+    /// This option cannot be deserialized, and so must be set in code.
+    #[serde(skip)]
+    pub token_refresh_failed_handler: Option<Arc<dyn TokenRefreshFailedHandler>>,
+
+    /// Optional provider endpoints (and signing keys), which are used
+    /// instead of the provider's discovery document. The discovery
+    /// document is then never fetched, which also means that
+    /// [dynamic client registration](Self::dynamic_client_registration)
+    /// is not available.
+    ///
+    /// Defaults to `None`, in which case the endpoints are discovered
+    /// from the [`issuer_url`](Self::issuer_url).
+    #[serde(default)]
+    pub provider_endpoints: Option<ProviderEndpoints>,
+}
+
+pub(crate) fn default_clock_skew_tolerance() -> std::time::Duration {
+    std::time::Duration::from_secs(60)
+}
+
+pub(crate) fn default_verify_on_startup() -> bool {
+    true
+}
+
+/// Determines how long an authenticated session lasts before the user
+/// has to log in again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum SessionTtl {
+    /// The session expires when the access token expires, as reported
+    /// by the provider's `expires_in` token response field, unless the
+    /// provider also issued a refresh token, in which case the session
+    /// is extended each time the access token is refreshed. Sessions
+    /// for access tokens without an expiration time do not expire.
+    #[default]
+    FromToken,
+
+    /// The session expires after a fixed amount of time (measured from
+    /// the time at which the user logged in), regardless of when the
+    /// access token expires. This is useful for applications that must
+    /// force users to log in again after a certain amount of time.
+    Fixed(std::time::Duration),
+}
+
+/// Determines where the browser is sent after the logout route has
+/// cleared the session's authentication state.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogoutMode {
+    /// The browser is redirected to the
+    /// [`idp_logout_url`](Config::idp_logout_url), if one is configured,
+    /// so that the user is also logged out of the Identity Provider.
+    /// Otherwise, the browser is redirected to the [logout landing
+    /// path](OpenIdConnectMiddleware::with_logout_landing_path).
+    #[default]
+    IdpInitiated,
+
+    /// Only the application's session is cleared, and the browser is
+    /// always redirected to the [logout landing
+    /// path](OpenIdConnectMiddleware::with_logout_landing_path), leaving
+    /// the user logged in at the Identity Provider. Note that the next
+    /// login will therefore usually complete without prompting the user
+    /// for their credentials.
+    LocalOnly,
+}
+
+/// Route at which the middleware handles a request itself, instead of
+/// passing it on to the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MiddlewareRoute<'a> {
+    Login {
+        silent: bool,
+        variant: Option<&'a str>,
+    },
+    Callback,
+    BackchannelLogout,
+    FrontchannelLogout,
+    SessionCheck,
+    Logout(LogoutMode),
+    PostLogout,
+}
+
+/// State of a single login attempt, which is used to validate the
+/// authorization callback.
+#[derive(Debug, Deserialize, Serialize)]
+struct PendingAuth {
+    csrf_token: CsrfToken,
+    nonce: Nonce,
+    redirect_url: Option<RedirectUrl>,
+    silent: bool,
+    /// Local path to send the browser to after logging in, instead of
+    /// the login landing path.
+    #[serde(default)]
+    return_to: Option<String>,
+    /// [Login variant](OpenIdConnectMiddleware::with_login_variant)
+    /// that started the login, if any.
+    #[serde(default)]
+    variant: Option<String>,
+    /// Scopes that the session had already been granted, which a
+    /// step-up login for [per-route
+    /// scopes](OpenIdConnectMiddleware::with_per_route_scopes) requests
+    /// again.
+    #[serde(default)]
+    granted_scopes: Vec<Scope>,
+}
+
+/// State of a single login attempt that is kept in the
+/// [`OidcStateStore`] (the nonce is stored separately).
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredAuth {
+    redirect_url: Option<RedirectUrl>,
+    silent: bool,
+    return_to: Option<String>,
+    #[serde(default)]
+    variant: Option<String>,
+    #[serde(default)]
+    granted_scopes: Vec<Scope>,
 }
 
+/// Login that established an authenticated session, which is used to
+/// recognize replays of that login's callback.
+#[derive(Debug, Deserialize, Serialize)]
+struct CompletedLogin {
+    state: String,
+    completed_at: DateTime<Utc>,
+    return_to: Option<String>,
+}
+
+// The state only ever lives briefly on the stack while it is read from
+// (or written to) the session, and so the size of its variants does not
+// matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Deserialize, Serialize)]
 enum MiddlewareSessionState {
-    PreAuth(CsrfToken, Nonce),
-    PostAuth(SubjectIdentifier, AccessToken, Vec<Scope>),
+    PreAuth {
+        /// Login attempts that have not yet completed, oldest first.
+        pending: Vec<PendingAuth>,
+    },
+    PreAuthStored {
+        /// CSRF states of the login attempts (which are kept in the
+        /// [`OidcStateStore`]) that have not yet completed, oldest
+        /// first.
+        states: Vec<CsrfToken>,
+    },
+    PostAuth {
+        subject: SubjectIdentifier,
+        access_token: AccessToken,
+        scopes: Vec<Scope>,
+        issued_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+        claims: Claims,
+        userinfo: Option<Claims>,
+        #[serde(default)]
+        id_token: Option<String>,
+        #[serde(default)]
+        session_expires_at: Option<DateTime<Utc>>,
+        /// Provider session state, for [session
+        /// management](crate::session_management).
+        #[serde(default)]
+        session_state: Option<String>,
+        /// Login that established the session, which is only recorded
+        /// if duplicate callbacks are tolerated.
+        #[serde(default)]
+        completed_login: Option<Box<CompletedLogin>>,
+        /// Refresh token (if the provider issued one), which is used to
+        /// obtain a new access token once the current one expires.
+        #[serde(default)]
+        refresh_token: Option<RefreshToken>,
+        /// Path with [per-route
+        /// scopes](OpenIdConnectMiddleware::with_per_route_scopes) whose
+        /// scopes the login requested (as the path it returned to), so
+        /// that requests to that path do not log in again if the
+        /// provider did not grant all of them.
+        #[serde(default)]
+        step_up_path: Option<String>,
+    },
+}
+
+impl MiddlewareSessionState {
+    /// Returns the number of login attempts that have not yet completed.
+    fn pending_len(&self) -> usize {
+        match self {
+            Self::PreAuth { pending } => pending.len(),
+            Self::PreAuthStored { states } => states.len(),
+            Self::PostAuth { .. } => 0,
+        }
+    }
+}
+
+/// Tokens that the provider issued in exchange for a refresh token.
+#[derive(Clone)]
+struct RefreshedTokens {
+    access_token: AccessToken,
+    scopes: Option<Vec<Scope>>,
+    expires_at: Option<DateTime<Utc>>,
+    refresh_token: Option<RefreshToken>,
+}
+
+/// Outcome of the most recent refresh with a given refresh token, and
+/// when it completed.
+type RefreshSlot = async_std::sync::Mutex<Option<(Instant, RefreshedTokens)>>;
+
+/// How long the outcome of a refresh is shared with the requests that
+/// still present the old refresh token (because they were sent before
+/// the session was updated).
+const REFRESH_SHARING_WINDOW: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Recent token refreshes, keyed by (a hash of) the refresh token that
+/// was used. Concurrent requests of the same session wait for, and
+/// share, a single refresh: providers that rotate refresh tokens reject
+/// every use of the old refresh token but the first. Refreshes are only
+/// shared within this process.
+#[derive(Default)]
+struct TokenRefreshes(std::sync::Mutex<HashMap<String, Arc<RefreshSlot>>>);
+
+impl TokenRefreshes {
+    /// Returns the slot of the given refresh token, forgetting the
+    /// refreshes that are no longer shared.
+    fn slot(&self, refresh_token: &RefreshToken) -> Arc<RefreshSlot> {
+        let mut slots = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        slots.retain(|_, slot| match slot.try_lock() {
+            Some(refreshed) => refreshed
+                .as_ref()
+                .is_some_and(|(at, _)| at.elapsed() < REFRESH_SHARING_WINDOW),
+            // The refresh is in progress.
+            None => true,
+        });
+        Arc::clone(slots.entry(Self::key(refresh_token)).or_default())
+    }
+
+    /// Forgets a failed refresh, so that the next request tries again.
+    fn forget(&self, refresh_token: &RefreshToken) {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(&Self::key(refresh_token));
+    }
+
+    /// Returns the key of a refresh token, which is hashed so that the
+    /// refresh tokens themselves are not kept any longer than needed.
+    fn key(refresh_token: &RefreshToken) -> String {
+        base64::encode_config(
+            Sha256::digest(refresh_token.secret().as_bytes()),
+            base64::URL_SAFE_NO_PAD,
+        )
+    }
+}
+
+/// ID token (or UserInfo) claims, as persisted in the session.
+pub type Claims = serde_json::Map<String, serde_json::Value>;
+
+/// Computes the redirect URL from the incoming login request.
+type RedirectUrlFn = dyn Fn(&tide::http::Request) -> Option<RedirectUrl> + Send + Sync;
+
+/// Creates the response to an authenticated request that was not
+/// granted all of the scopes required by the route; receives the
+/// missing scopes.
+pub(crate) type InsufficientScopeFn = dyn Fn(&[String]) -> tide::Response + Send + Sync;
+
+/// Creates the response to an authenticated request that was denied by
+/// the route's claims predicate (or the middleware's authorization
+/// policy); receives the reason for the denial.
+pub(crate) type AccessDeniedFn = dyn Fn(&OidcError) -> tide::Response + Send + Sync;
+
+/// Scopes and claims that every request authenticated by a middleware
+/// must have, which the middleware shares with its [bearer
+/// middleware](OpenIdConnectMiddleware::bearer_middleware).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AuthorizationPolicy {
+    required_scopes: Vec<String>,
+    required_claims: Vec<(String, serde_json::Value)>,
+    failure_details: bool,
+}
+
+impl AuthorizationPolicy {
+    /// Checks the authenticated request against the [required
+    /// scopes](OpenIdConnectMiddleware::with_required_scope) and
+    /// [claims](OpenIdConnectMiddleware::with_required_claim), returning
+    /// the reason for denying the request if any of them are not met.
+    pub(crate) fn failure(&self, auth_state: &OpenIdConnectRequestExtData) -> Option<OidcError> {
+        let mut missing_claim = None;
+        let decision = auth_state.decide(&self.required_scopes, |claims| {
+            missing_claim = self
+                .required_claims
+                .iter()
+                .find(|(claim, value)| claims.get(claim) != Some(value))
+                .map(|(claim, _)| claim.clone());
+            missing_claim.is_none()
+        });
+        match decision {
+            AuthDecision::Allow | AuthDecision::Unauthenticated => None,
+            _ if !self.failure_details => Some(OidcError::AccessDenied),
+            AuthDecision::InsufficientScope(missing_scopes) => {
+                Some(OidcError::InsufficientScope(missing_scopes))
+            }
+            AuthDecision::Forbidden => {
+                Some(missing_claim.map_or(OidcError::AccessDenied, OidcError::RequiredClaim))
+            }
+        }
+    }
 }
 
 /// Open ID Connect Middleware.
-pub struct OpenIdConnectMiddleware {
+///
+/// The middleware is generic over the type of the provider-specific
+/// (non-standard) claims in the ID token, which defaults to no
+/// additional claims; see
+/// [`new_with_additional_claims`](Self::new_with_additional_claims).
+pub struct OpenIdConnectMiddleware<AC = EmptyAdditionalClaims> {
+    provider_id: Option<String>,
+    session_key_prefix: String,
+    session_key: String,
+    realm: String,
+    session_encryption: Option<SessionEncryption>,
+    issuer_url: IssuerUrl,
+    issuer_verification: IssuerVerification,
+    client_id: ClientId,
+    client_secret: Option<ClientSecret>,
+    client_auth: Arc<ClientAuth>,
+    token_endpoint_auth_method: Option<TokenEndpointAuthMethod>,
     login_path: String,
+    /// Named login routes (under the login path), and the scopes that
+    /// each one requests instead of the default scopes.
+    login_variants: Vec<(String, Vec<Scope>)>,
+    /// Scopes that (authenticated) requests to specific paths require
+    /// in addition to the scopes that were requested at login.
+    route_scopes: HashMap<String, Vec<Scope>>,
+    silent_login_path: Option<String>,
+    silent_login_failure_path: String,
     redirect_url: RedirectUrl,
+    redirect_url_fn: Option<Arc<RedirectUrlFn>>,
+    login_hint_extractor: Option<Arc<dyn LoginHintExtractor>>,
+    extra_authorization_params: Vec<(String, String)>,
+    claims_locales: Vec<String>,
+    additional_audiences: Vec<String>,
+    require_azp: bool,
+    clock_skew_tolerance: chrono::Duration,
+    allowed_redirect_hosts: Vec<String>,
     scopes: Vec<Scope>,
+    /// Configured ID token signing algorithms, or `None` to accept the
+    /// ones advertised by the provider.
+    id_token_signing_algs: Option<Vec<CoreJwsSigningAlgorithm>>,
+    at_hash_validation: bool,
+    at_hash_required: bool,
+    state_entropy_bytes: u32,
+    nonce_config: NonceConfig,
+    log_nonces: bool,
+    verifier_config: VerifierConfig,
+    max_pending_auth: usize,
+    tolerate_duplicate_callback: bool,
+    bearer_fallback: bool,
+    response_mode: ResponseMode,
+    persisted_claims: Option<Vec<String>>,
     login_landing_path: String,
     logout_path: String,
+    logout_mode: LogoutMode,
+    local_logout_path: Option<String>,
     logout_destroys_session: bool,
     idp_logout_url: Option<String>,
+    idp_logout_id_token_hint: bool,
+    post_logout_redirect_url: Option<RedirectUrl>,
+    cookie_domain: Option<String>,
+    cookie_path: Option<String>,
+    retain_id_token: bool,
     logout_landing_path: String,
-    client: CoreClient,
-    redirect_strategy: Arc<dyn RedirectStrategy>,
+    backchannel_logout_path: Option<String>,
+    frontchannel_logout_path: Option<String>,
+    fetch_userinfo: bool,
+    session_ttl: SessionTtl,
+    logout_handler: Option<Arc<dyn LogoutHandler>>,
+    state_store: Option<Arc<dyn OidcStateStore>>,
+    post_auth_hook: Option<Arc<dyn PostAuthHook>>,
+    pre_logout_hook: Option<Arc<dyn PreLogoutHook>>,
+    security_event_sink: Option<Arc<dyn SecurityEventSink>>,
+    logout_token_ids: Arc<LogoutTokenIds>,
+    token_exchange_cache: Arc<TokenExchangeCache>,
+    token_refreshes: Arc<TokenRefreshes>,
+    additional_claims: PhantomData<fn() -> AC>,
+    pushed_authorization_requests: Option<PushedAuthorizationRequests>,
+    request_object_signing: Option<Arc<RequestObjectSigning>>,
+    http_client: HttpClient,
+    discovery: Arc<ProviderDiscovery>,
+    discovery_cache: Option<DiscoveryCacheConfig>,
+    verify_on_startup: bool,
+    /// Whether the provider has been verified, either by creating the
+    /// middleware, by a startup listener, or by the first request.
+    provider_verified: Arc<AtomicBool>,
+    session_management: Option<SessionManagementConfig>,
+    jwks: Arc<JwksCache>,
+    introspector: Option<Arc<TokenIntrospector>>,
+    dpop: Option<DpopConfig>,
+    redirect_strategy: Arc<dyn UnauthenticatedHandler>,
+    /// Kind of the redirect strategy, unless the application has set
+    /// its own strategy; used to recreate the strategy when the login
+    /// path changes.
+    redirect_strategy_kind: Option<RedirectStrategyKind>,
+    csp_policy: Option<String>,
+    insufficient_scope_response: Arc<InsufficientScopeFn>,
+    access_denied_response: Arc<AccessDeniedFn>,
+    access_denied_handler: Option<Arc<dyn AccessDeniedHandler>>,
+    token_refresh_failed_handler: Option<Arc<dyn TokenRefreshFailedHandler>>,
+    authorization_policy: AuthorizationPolicy,
+    auth_event_levels: AuthEventLevels,
+    /// Snapshot of the (fully configured) middleware that builds login
+    /// URLs and refreshes sessions on behalf of request handlers and
+    /// route guards; created on first use.
+    shared: OnceLock<Arc<Self>>,
+}
+
+// Not derived, since that would require the additional claims type to
+// implement `Clone` as well.
+impl<AC> Clone for OpenIdConnectMiddleware<AC> {
+    fn clone(&self) -> Self {
+        Self {
+            provider_id: self.provider_id.clone(),
+            session_key_prefix: self.session_key_prefix.clone(),
+            session_key: self.session_key.clone(),
+            realm: self.realm.clone(),
+            session_encryption: self.session_encryption.clone(),
+            issuer_url: self.issuer_url.clone(),
+            issuer_verification: self.issuer_verification,
+            client_id: self.client_id.clone(),
+            client_secret: self.client_secret.clone(),
+            client_auth: Arc::clone(&self.client_auth),
+            token_endpoint_auth_method: self.token_endpoint_auth_method,
+            login_path: self.login_path.clone(),
+            login_variants: self.login_variants.clone(),
+            route_scopes: self.route_scopes.clone(),
+            silent_login_path: self.silent_login_path.clone(),
+            silent_login_failure_path: self.silent_login_failure_path.clone(),
+            redirect_url: self.redirect_url.clone(),
+            redirect_url_fn: self.redirect_url_fn.clone(),
+            login_hint_extractor: self.login_hint_extractor.clone(),
+            extra_authorization_params: self.extra_authorization_params.clone(),
+            claims_locales: self.claims_locales.clone(),
+            additional_audiences: self.additional_audiences.clone(),
+            require_azp: self.require_azp,
+            clock_skew_tolerance: self.clock_skew_tolerance,
+            allowed_redirect_hosts: self.allowed_redirect_hosts.clone(),
+            scopes: self.scopes.clone(),
+            id_token_signing_algs: self.id_token_signing_algs.clone(),
+            at_hash_validation: self.at_hash_validation,
+            at_hash_required: self.at_hash_required,
+            state_entropy_bytes: self.state_entropy_bytes,
+            nonce_config: self.nonce_config.clone(),
+            log_nonces: self.log_nonces,
+            verifier_config: self.verifier_config,
+            max_pending_auth: self.max_pending_auth,
+            tolerate_duplicate_callback: self.tolerate_duplicate_callback,
+            bearer_fallback: self.bearer_fallback,
+            response_mode: self.response_mode,
+            persisted_claims: self.persisted_claims.clone(),
+            login_landing_path: self.login_landing_path.clone(),
+            logout_path: self.logout_path.clone(),
+            logout_mode: self.logout_mode,
+            local_logout_path: self.local_logout_path.clone(),
+            logout_destroys_session: self.logout_destroys_session,
+            idp_logout_url: self.idp_logout_url.clone(),
+            idp_logout_id_token_hint: self.idp_logout_id_token_hint,
+            post_logout_redirect_url: self.post_logout_redirect_url.clone(),
+            cookie_domain: self.cookie_domain.clone(),
+            cookie_path: self.cookie_path.clone(),
+            retain_id_token: self.retain_id_token,
+            logout_landing_path: self.logout_landing_path.clone(),
+            backchannel_logout_path: self.backchannel_logout_path.clone(),
+            frontchannel_logout_path: self.frontchannel_logout_path.clone(),
+            fetch_userinfo: self.fetch_userinfo,
+            session_ttl: self.session_ttl,
+            logout_handler: self.logout_handler.clone(),
+            state_store: self.state_store.clone(),
+            post_auth_hook: self.post_auth_hook.clone(),
+            pre_logout_hook: self.pre_logout_hook.clone(),
+            security_event_sink: self.security_event_sink.clone(),
+            logout_token_ids: Arc::clone(&self.logout_token_ids),
+            token_exchange_cache: Arc::clone(&self.token_exchange_cache),
+            token_refreshes: Arc::clone(&self.token_refreshes),
+            additional_claims: PhantomData,
+            pushed_authorization_requests: self.pushed_authorization_requests,
+            request_object_signing: self.request_object_signing.clone(),
+            http_client: self.http_client.clone(),
+            discovery: Arc::clone(&self.discovery),
+            discovery_cache: self.discovery_cache,
+            verify_on_startup: self.verify_on_startup,
+            provider_verified: Arc::clone(&self.provider_verified),
+            session_management: self.session_management.clone(),
+            jwks: Arc::clone(&self.jwks),
+            introspector: self.introspector.clone(),
+            dpop: self.dpop.clone(),
+            redirect_strategy: Arc::clone(&self.redirect_strategy),
+            redirect_strategy_kind: self.redirect_strategy_kind.clone(),
+            csp_policy: self.csp_policy.clone(),
+            insufficient_scope_response: Arc::clone(&self.insufficient_scope_response),
+            access_denied_response: Arc::clone(&self.access_denied_response),
+            access_denied_handler: self.access_denied_handler.clone(),
+            token_refresh_failed_handler: self.token_refresh_failed_handler.clone(),
+            authorization_policy: self.authorization_policy.clone(),
+            auth_event_levels: self.auth_event_levels.clone(),
+            shared: OnceLock::new(),
+        }
+    }
 }
 
-impl std::fmt::Debug for OpenIdConnectMiddleware {
+impl<AC> std::fmt::Debug for OpenIdConnectMiddleware<AC> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("OpenIdConnectMiddleware")
+            .field("provider_id", &self.provider_id)
+            .field("session_key", &self.session_key)
+            .field("issuer_url", &self.issuer_url)
+            .field("issuer_verification", &self.issuer_verification)
+            .field("client_id", &self.client_id)
+            .field("realm", &self.realm)
+            .field("client_auth", &self.client_auth)
+            .field(
+                "token_endpoint_auth_method",
+                &self.token_endpoint_auth_method,
+            )
             .field("login_path", &self.login_path)
+            .field("login_variants", &self.login_variants)
+            .field("route_scopes", &self.route_scopes)
+            .field("silent_login_path", &self.silent_login_path)
+            .field("silent_login_failure_path", &self.silent_login_failure_path)
             .field("scopes", &self.scopes)
+            .field("id_token_signing_algs", &self.id_token_signing_algs)
+            .field("at_hash_validation", &self.at_hash_validation)
+            .field("at_hash_required", &self.at_hash_required)
+            .field("state_entropy_bytes", &self.state_entropy_bytes)
+            .field("nonce_config", &self.nonce_config)
+            .field("log_nonces", &self.log_nonces)
+            .field("verifier_config", &self.verifier_config)
+            .field("max_pending_auth", &self.max_pending_auth)
+            .field(
+                "tolerate_duplicate_callback",
+                &self.tolerate_duplicate_callback,
+            )
+            .field("bearer_fallback", &self.bearer_fallback)
+            .field("persisted_claims", &self.persisted_claims)
+            .field("authorization_policy", &self.authorization_policy)
             .field("redirect_url", &self.redirect_url)
+            .field(
+                "extra_authorization_params",
+                &self.extra_authorization_params,
+            )
+            .field("claims_locales", &self.claims_locales)
+            .field("additional_audiences", &self.additional_audiences)
+            .field("require_azp", &self.require_azp)
+            .field("clock_skew_tolerance", &self.clock_skew_tolerance)
+            .field("allowed_redirect_hosts", &self.allowed_redirect_hosts)
+            .field("csp_policy", &self.csp_policy)
+            .field(
+                "pushed_authorization_requests",
+                &self.pushed_authorization_requests,
+            )
+            .field("request_object_signing", &self.request_object_signing)
+            .field("dpop", &self.dpop)
+            .field("discovery_cache", &self.discovery_cache)
+            .field("verify_on_startup", &self.verify_on_startup)
+            .field("session_management", &self.session_management)
             .field("login_landing_path", &self.login_landing_path)
             .field("idp_logout_url", &self.idp_logout_url)
+            .field("idp_logout_id_token_hint", &self.idp_logout_id_token_hint)
+            .field("post_logout_redirect_url", &self.post_logout_redirect_url)
+            .field("cookie_domain", &self.cookie_domain)
+            .field("cookie_path", &self.cookie_path)
+            .field("retain_id_token", &self.retain_id_token)
             .field("logout_path", &self.logout_path)
+            .field("logout_mode", &self.logout_mode)
+            .field("local_logout_path", &self.local_logout_path)
             .field("logout_destroys_session", &self.logout_destroys_session)
             .field("logout_landing_path", &self.logout_landing_path)
+            .field("backchannel_logout_path", &self.backchannel_logout_path)
+            .field("frontchannel_logout_path", &self.frontchannel_logout_path)
+            .field("fetch_userinfo", &self.fetch_userinfo)
+            .field("session_ttl", &self.session_ttl)
+            .field("auth_event_levels", &self.auth_event_levels)
             .finish()
     }
 }
@@ -99,18 +1092,57 @@ impl OpenIdConnectMiddleware {
     ///
     /// Panics if the OpenID Connect provider metadata could not be
     /// retrieved or does not match the configured
-    /// [`issuer_url`](Config::issuer_url).
+    /// [`issuer_url`](Config::issuer_url), or if [dynamic client
+    /// registration](Config::dynamic_client_registration) is enabled
+    /// and the client could not be registered. Use
+    /// [`try_new`](Self::try_new) to handle those errors instead, or
+    /// [`new_lazy`](Self::new_lazy) to start without the provider.
     ///
     /// # Defaults
     ///
     /// The defaults for OpenIdConnectMiddleware are:
-    /// - redirect strategy: [`HttpRedirect`](crate::redirect_strategy::HttpRedirect)
+    /// - provider id: none
+    /// - session key prefix: `tide.`
+    /// - redirect strategy: from the [`Config`]; see
+    ///   [`RedirectStrategyKind`](crate::redirect_strategy::RedirectStrategyKind)
+    /// - insufficient scope response: `403 Forbidden`
+    /// - access denied response: `403 Forbidden`
+    /// - client authentication: [`ClientAuth::ClientSecret`]
     /// - login path: `/login`
+    /// - silent login path: none (silent logins are disabled)
+    /// - silent login failure path: `/`
+    /// - login hint extractor: none
     /// - scopes: `["openid"]`
+    /// - ID token signing algorithms: those advertised by the provider,
+    ///   limited to the asymmetric algorithms supported by the middleware
+    /// - `at_hash` validation: `true`
+    /// - `at_hash` required: `false`
+    /// - `state` entropy: 16 bytes
+    /// - `nonce`: 16 random bytes from a
+    ///   [`CsrngNonceGenerator`](crate::nonce::CsrngNonceGenerator)
+    /// - maximum pending logins: 1
+    /// - state store: none (pending logins are kept in the session)
+    /// - post-authentication hook: none
+    /// - pre-logout hook: none
+    /// - tolerate duplicate callbacks: `false`
+    /// - bearer token fallback: `false`
+    /// - response mode: [`ResponseMode::Query`]
+    /// - persisted claims: all ID token claims
+    /// - required scopes and claims: none
+    /// - authorization failure details: `false`
+    /// - pushed authorization requests: disabled
+    /// - request objects: disabled
+    /// - discovery cache: none (the provider metadata is only fetched
+    ///   at startup)
+    /// - session management: disabled
     /// - login landing path: `/`
     /// - logout path: `/logout`
+    /// - logout mode: [`LogoutMode::IdpInitiated`]
+    /// - local logout path: none
     /// - logout destroys session: `true`
     /// - logout landing path: `/`
+    /// - authentication event log levels: as documented for each
+    ///   [`AuthEvent`]
     ///
     /// # Examples
     ///
@@ -123,8 +1155,36 @@ impl OpenIdConnectMiddleware {
     /// #   issuer_url: tide_openidconnect::IssuerUrl::new("https://your-tenant-name.us.auth0.com/".to_string()).unwrap(),
     /// #   client_id: tide_openidconnect::ClientId::new("app-id-goes-here".to_string()),
     /// #   client_secret: tide_openidconnect::ClientSecret::new("app-secret-goes-here".to_string()),
-    /// #   redirect_url: tide_openidconnect::RedirectUrl::new("http://your.cool.site/callback".to_string()).unwrap(),
+    /// #   redirect_url: tide_openidconnect::RedirectUrl::new("https://your.cool.site/callback".to_string()).unwrap(),
+    /// #   additional_audiences: vec![],
+    /// #   require_azp: false,
+    /// #   clock_skew_tolerance: std::time::Duration::from_secs(60),
     /// #   idp_logout_url: None,
+    /// #   idp_logout_id_token_hint: false,
+    /// #   post_logout_redirect_url: None,
+    /// #   retain_id_token: false,
+    /// #   dynamic_client_registration: None,
+    /// #   backchannel_logout_path: None,
+    /// #   frontchannel_logout_path: None,
+    /// #   fetch_userinfo: false,
+    /// #   extra_authorization_params: Default::default(),
+    /// #   dpop: None,
+    /// #   access_denied_handler: None,
+    /// #   token_refresh_failed_handler: None,
+    /// #   provider_endpoints: None,
+    /// #   realm: None,
+    /// #   introspection: None,
+    /// #   claims_locales: None,
+    /// #   redirect_strategy: Default::default(),
+    /// #   csp_policy: None,
+    /// #   http_client: Default::default(),
+    /// #   http_timeouts: Default::default(),
+    /// #   allow_insecure_http: false,
+    /// #   verify_on_startup: true,
+    /// #   session_encryption_keys: vec![],
+    /// #   session_ttl: Default::default(),
+    /// #   issuer_verification: Default::default(),
+    /// #   token_endpoint_auth_method: None,
     /// };
     /// let middleware = tide_openidconnect::OpenIdConnectMiddleware::new(&config)
     ///     .await
@@ -132,38 +1192,412 @@ impl OpenIdConnectMiddleware {
     /// # })
     /// ```
     pub async fn new(config: &Config) -> Self {
-        // Get the OpenID Connect provider metadata.
-        let provider_metadata =
-            CoreProviderMetadata::discover_async(config.issuer_url.clone(), http_client)
-                .await
-                .expect("Unable to load OpenID Connect provider metadata.");
+        Self::new_with_additional_claims(config).await
+    }
 
-        // Create the OpenID Connect client.
-        let client = CoreClient::from_provider_metadata(
-            provider_metadata,
+    /// Create a new instance, returning an error (instead of panicking)
+    /// if the configuration includes an [insecure
+    /// URL](Config::allow_insecure_http), the provider metadata could
+    /// not be retrieved, or the client could not be registered.
+    ///
+    /// See [`new`](OpenIdConnectMiddleware::new) for more information.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use tide_openidconnect::{Config, OpenIdConnectMiddleware};
+    /// # async fn example(config: Config) -> Result<(), tide_openidconnect::OidcError> {
+    /// let middleware = OpenIdConnectMiddleware::try_new(&config).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn try_new(config: &Config) -> Result<Self, OidcError> {
+        Self::try_new_with_additional_claims(config).await
+    }
+
+    /// Create a new instance without contacting the provider, which
+    /// allows the application to start while the provider is
+    /// unreachable. The provider metadata is instead loaded by the
+    /// first request, with failed attempts being retried (by later
+    /// requests) according to `lazy_discovery`.
+    ///
+    /// Until the metadata has been loaded, the middleware is not able
+    /// to authenticate requests: public routes are passed on to the
+    /// application as unauthenticated requests, while the middleware's
+    /// own routes (login, callback, logout, ...) and [routes that
+    /// require authentication](crate::OpenIdConnectRouteExt) respond
+    /// with `503 Service Unavailable`.
+    ///
+    /// Returns an error if the configuration includes an [insecure
+    /// URL](Config::allow_insecure_http), if the HTTP client could not
+    /// be initialized, or if [dynamic client
+    /// registration](Config::dynamic_client_registration) is enabled,
+    /// since the client id must be known up front.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use tide_openidconnect::{Config, LazyDiscoveryConfig, OpenIdConnectMiddleware};
+    /// # fn example(config: Config) -> Result<(), tide_openidconnect::OidcError> {
+    /// let middleware = OpenIdConnectMiddleware::new_lazy(&config, LazyDiscoveryConfig::default())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_lazy(
+        config: &Config,
+        lazy_discovery: LazyDiscoveryConfig,
+    ) -> Result<Self, OidcError> {
+        Self::new_lazy_with_additional_claims(config, lazy_discovery)
+    }
+}
+
+impl<AC> OpenIdConnectMiddleware<AC>
+where
+    AC: AdditionalClaims,
+{
+    /// Create a new instance that requires the ID token to include the
+    /// provider-specific claims in `AC`, which are then available to
+    /// requests through the
+    /// [`additional_claims()`](crate::OpenIdConnectRequestExt::additional_claims)
+    /// request extension. Logins fail if the ID token's claims cannot be
+    /// deserialized into `AC`.
+    ///
+    /// See [`new`](OpenIdConnectMiddleware::new) for more information.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use serde::{Deserialize, Serialize};
+    /// use tide_openidconnect::{self, AdditionalClaims};
+    ///
+    /// #[derive(Debug, Deserialize, Serialize)]
+    /// struct GroupClaims {
+    ///     groups: Vec<String>,
+    /// }
+    /// impl AdditionalClaims for GroupClaims {}
+    ///
+    /// # async_std::task::block_on(async {
+    /// let config = tide_openidconnect::Config {
+    ///     // ... set/load config ...
+    /// #   issuer_url: tide_openidconnect::IssuerUrl::new("https://your-tenant-name.us.auth0.com/".to_string()).unwrap(),
+    /// #   client_id: tide_openidconnect::ClientId::new("app-id-goes-here".to_string()),
+    /// #   client_secret: tide_openidconnect::ClientSecret::new("app-secret-goes-here".to_string()),
+    /// #   redirect_url: tide_openidconnect::RedirectUrl::new("https://your.cool.site/callback".to_string()).unwrap(),
+    /// #   additional_audiences: vec![],
+    /// #   require_azp: false,
+    /// #   clock_skew_tolerance: std::time::Duration::from_secs(60),
+    /// #   idp_logout_url: None,
+    /// #   idp_logout_id_token_hint: false,
+    /// #   post_logout_redirect_url: None,
+    /// #   retain_id_token: false,
+    /// #   dynamic_client_registration: None,
+    /// #   backchannel_logout_path: None,
+    /// #   frontchannel_logout_path: None,
+    /// #   fetch_userinfo: false,
+    /// #   extra_authorization_params: Default::default(),
+    /// #   dpop: None,
+    /// #   access_denied_handler: None,
+    /// #   token_refresh_failed_handler: None,
+    /// #   provider_endpoints: None,
+    /// #   realm: None,
+    /// #   introspection: None,
+    /// #   claims_locales: None,
+    /// #   redirect_strategy: Default::default(),
+    /// #   csp_policy: None,
+    /// #   http_client: Default::default(),
+    /// #   http_timeouts: Default::default(),
+    /// #   allow_insecure_http: false,
+    /// #   verify_on_startup: true,
+    /// #   session_encryption_keys: vec![],
+    /// #   session_ttl: Default::default(),
+    /// #   issuer_verification: Default::default(),
+    /// #   token_endpoint_auth_method: None,
+    /// };
+    /// let middleware =
+    ///     tide_openidconnect::OpenIdConnectMiddleware::<GroupClaims>::new_with_additional_claims(
+    ///         &config,
+    ///     )
+    ///     .await;
+    /// # })
+    /// ```
+    pub async fn new_with_additional_claims(config: &Config) -> Self {
+        Self::try_new_with_additional_claims(config)
+            .await
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    /// Create a new instance that requires the ID token to include the
+    /// provider-specific claims in `AC`, returning an error (instead of
+    /// panicking) if the middleware could not be created.
+    ///
+    /// See [`try_new`](OpenIdConnectMiddleware::try_new) and
+    /// [`new_with_additional_claims`](Self::new_with_additional_claims)
+    /// for more information.
+    pub async fn try_new_with_additional_claims(config: &Config) -> Result<Self, OidcError> {
+        check_config_urls(config)?;
+        let http_client = HttpClient::new(&config.http_client, &config.http_timeouts)
+            .map_err(|error| OidcError::HttpClient(error.to_string()))?;
+
+        // Get the OpenID Connect provider metadata (which also fetches
+        // the provider's JSON Web Key Set), unless the provider's
+        // endpoints have been configured explicitly.
+        let provider_metadata = fetch_provider_metadata(
+            &http_client,
+            &config.issuer_url,
+            config.issuer_verification,
+            config.provider_endpoints.as_ref(),
+        )
+        .await?;
+        warn_if_no_signing_algs(&provider_metadata);
+
+        // Get our client credentials, either from the config, or by way
+        // of dynamic client registration.
+        let (client_id, client_secret) = match &config.dynamic_client_registration {
+            Some(registration) => {
+                let credentials = registration
+                    .credentials(&http_client, &provider_metadata, &config.redirect_url)
+                    .await
+                    .map_err(OidcError::Registration)?;
+                (credentials.client_id, credentials.client_secret)
+            }
+            None => (config.client_id.clone(), Some(config.client_secret.clone())),
+        };
+
+        Ok(Self::with_provider(
+            config,
+            http_client,
+            Some(provider_metadata),
+            client_id,
+            client_secret,
+            LazyDiscoveryConfig::default(),
+        ))
+    }
+
+    /// Create a new instance that requires the ID token to include the
+    /// provider-specific claims in `AC`, without contacting the
+    /// provider.
+    ///
+    /// See [`new_lazy`](OpenIdConnectMiddleware::new_lazy) and
+    /// [`new_with_additional_claims`](Self::new_with_additional_claims)
+    /// for more information.
+    pub fn new_lazy_with_additional_claims(
+        config: &Config,
+        lazy_discovery: LazyDiscoveryConfig,
+    ) -> Result<Self, OidcError> {
+        check_config_urls(config)?;
+        let http_client = HttpClient::new(&config.http_client, &config.http_timeouts)
+            .map_err(|error| OidcError::HttpClient(error.to_string()))?;
+
+        // Dynamic client registration requires the provider metadata,
+        // but the client id is needed before that has been loaded.
+        if config.dynamic_client_registration.is_some() {
+            return Err(OidcError::Registration(
+                "dynamic client registration requires the provider at startup; use try_new()"
+                    .to_string(),
+            ));
+        }
+
+        Ok(Self::with_provider(
+            config,
+            http_client,
+            None,
             config.client_id.clone(),
             Some(config.client_secret.clone()),
-        )
-        .set_redirect_uri(config.redirect_url.clone());
+            lazy_discovery,
+        ))
+    }
+
+    /// Initializes the middleware with the given provider metadata
+    /// (which is loaded by the first request if `None`) and client
+    /// credentials.
+    fn with_provider(
+        config: &Config,
+        http_client: HttpClient,
+        provider_metadata: Option<ProviderMetadata>,
+        client_id: ClientId,
+        client_secret: Option<ClientSecret>,
+        lazy_discovery: LazyDiscoveryConfig,
+    ) -> Self {
+        // Cache the provider's signing keys separately from the client,
+        // so that we can refresh them when the provider rotates its keys.
+        let has_provider_metadata = provider_metadata.is_some();
+        let jwks = Arc::new(JwksCache::new(
+            http_client.clone(),
+            provider_metadata
+                .as_ref()
+                .map(|metadata| metadata.jwks_uri().clone()),
+            provider_metadata
+                .as_ref()
+                .map(|metadata| metadata.jwks().clone())
+                .unwrap_or_else(|| CoreJsonWebKeySet::new(vec![])),
+        ));
+
+        // Cache the provider metadata, from which the OpenID Connect
+        // client is created for each request, so that the metadata can
+        // be refreshed while the middleware is running.
+        let discovery = Arc::new(ProviderDiscovery::new(
+            http_client.clone(),
+            config.issuer_url.clone(),
+            config.issuer_verification,
+            config.provider_endpoints.clone(),
+            provider_metadata,
+            lazy_discovery,
+        ));
+
+        // Opaque access tokens are introspected with their own client
+        // credentials, if enabled.
+        let introspector = config.introspection.as_ref().map(|introspection| {
+            Arc::new(TokenIntrospector::new(
+                http_client.clone(),
+                Arc::clone(&discovery),
+                introspection.clone(),
+                config.token_endpoint_auth_method,
+            ))
+        });
 
         // Initialize the middleware with our defaults. Note that we do not
         // have to include "openid" in the (default) scopes, because the
         // openidconnect-rs crate always adds that to the scopes list.
         let login_path = "/login".to_string();
+        let realm = config
+            .realm
+            .clone()
+            .unwrap_or_else(|| client_id.to_string());
         Self {
+            provider_id: None,
+            session_key_prefix: DEFAULT_SESSION_KEY_PREFIX.to_string(),
+            session_key: session_key(DEFAULT_SESSION_KEY_PREFIX, None),
+            session_encryption: SessionEncryption::new(&config.session_encryption_keys),
+            issuer_url: config.issuer_url.clone(),
+            issuer_verification: config.issuer_verification,
+            client_id,
+            client_secret,
+            client_auth: Arc::new(ClientAuth::default()),
+            token_endpoint_auth_method: config.token_endpoint_auth_method,
             login_path: login_path.clone(),
+            login_variants: vec![],
+            route_scopes: HashMap::new(),
+            silent_login_path: None,
+            silent_login_failure_path: "/".to_string(),
             scopes: vec![],
+            id_token_signing_algs: None,
+            at_hash_validation: true,
+            at_hash_required: false,
+            state_entropy_bytes: DEFAULT_ENTROPY_BYTES,
+            nonce_config: NonceConfig::default(),
+            log_nonces: false,
+            verifier_config: VerifierConfig::default(),
+            max_pending_auth: 1,
+            tolerate_duplicate_callback: false,
+            bearer_fallback: false,
+            response_mode: ResponseMode::default(),
+            persisted_claims: None,
             redirect_url: config.redirect_url.clone(),
+            redirect_url_fn: None,
+            login_hint_extractor: None,
+            extra_authorization_params: config.extra_authorization_params.allowed_params(),
+            claims_locales: config.claims_locales.clone().unwrap_or_default(),
+            additional_audiences: config.additional_audiences.clone(),
+            require_azp: config.require_azp,
+            clock_skew_tolerance: chrono::Duration::from_std(config.clock_skew_tolerance)
+                .unwrap_or_else(|_| chrono::Duration::zero()),
+            allowed_redirect_hosts: vec![],
+            pushed_authorization_requests: None,
+            request_object_signing: None,
+            http_client,
+            discovery,
+            discovery_cache: None,
+            verify_on_startup: config.verify_on_startup,
+            provider_verified: Arc::new(AtomicBool::new(has_provider_metadata)),
+            session_management: None,
             login_landing_path: "/".to_string(),
-            client,
-            redirect_strategy: Arc::new(HttpRedirect::new(login_path)),
+            jwks,
+            introspector,
+            dpop: config.dpop.clone(),
+            redirect_strategy: config.redirect_strategy.handler(&login_path, &realm),
+            realm,
+            redirect_strategy_kind: Some(config.redirect_strategy.clone()),
+            csp_policy: config.csp_policy.clone(),
+            insufficient_scope_response: Arc::new(insufficient_scope_response),
+            access_denied_response: Arc::new(access_denied_response),
+            access_denied_handler: config.access_denied_handler.clone(),
+            token_refresh_failed_handler: config.token_refresh_failed_handler.clone(),
+            authorization_policy: AuthorizationPolicy::default(),
             logout_path: "/logout".to_string(),
+            logout_mode: LogoutMode::default(),
+            local_logout_path: None,
             logout_destroys_session: true,
             idp_logout_url: config.idp_logout_url.clone(),
+            idp_logout_id_token_hint: config.idp_logout_id_token_hint,
+            post_logout_redirect_url: config.post_logout_redirect_url.clone(),
+            cookie_domain: None,
+            cookie_path: None,
+            retain_id_token: config.retain_id_token,
             logout_landing_path: "/".to_string(),
+            backchannel_logout_path: config.backchannel_logout_path.clone(),
+            frontchannel_logout_path: config.frontchannel_logout_path.clone(),
+            fetch_userinfo: config.fetch_userinfo,
+            session_ttl: config.session_ttl,
+            logout_handler: None,
+            state_store: None,
+            post_auth_hook: None,
+            pre_logout_hook: None,
+            security_event_sink: None,
+            logout_token_ids: Arc::default(),
+            token_exchange_cache: Arc::default(),
+            token_refreshes: Arc::default(),
+            additional_claims: PhantomData,
+            auth_event_levels: AuthEventLevels::default(),
+            shared: OnceLock::new(),
         }
     }
 
+    /// Sets the id used to distinguish this middleware from other
+    /// instances of the middleware in the same application, which is
+    /// required when the application allows users to sign in using one
+    /// of several Identity Providers.
+    ///
+    /// The middleware's session state is stored under a
+    /// [key](Self::with_session_key_prefix) that includes the provider
+    /// id, so that multiple middleware instances
+    /// do not clobber each other's session data. Each instance must
+    /// also be configured with its own login, logout, and redirect
+    /// paths.
+    ///
+    /// The provider id of the middleware that authenticated a request
+    /// is available through the
+    /// [`provider_id()`](crate::OpenIdConnectRequestExt::provider_id)
+    /// request extension. Note that requests that are not
+    /// authenticated by any of the middleware instances use the
+    /// [redirect strategy](Self::with_unauthenticated_redirect_strategy)
+    /// of the *first* middleware instance.
+    ///
+    /// Defaults to no provider id.
+    pub fn with_provider_id(mut self, provider_id: &str) -> Self {
+        self.provider_id = Some(provider_id.to_string());
+        self.session_key = session_key(&self.session_key_prefix, Some(provider_id));
+        self
+    }
+
+    /// Sets the prefix of the keys under which the middleware stores its
+    /// state in the Tide session, so that those keys do not collide with
+    /// the application's own session data.
+    ///
+    /// The middleware stores all of its state -- the pending logins and,
+    /// once the user has logged in, the authenticated user's tokens and
+    /// claims -- under a single key: `{prefix}oidc`, or
+    /// `{prefix}oidc.{provider_id}` if the middleware has a [provider
+    /// id](Self::with_provider_id). It does not use any other session
+    /// keys. Note that changing the prefix of a deployed application
+    /// logs out all of its users, whose state is then under the old key.
+    ///
+    /// Defaults to `tide.`, and so to the `tide.oidc` session key.
+    pub fn with_session_key_prefix(mut self, prefix: &str) -> Self {
+        self.session_key_prefix = prefix.to_string();
+        self.session_key = session_key(prefix, self.provider_id.as_deref());
+        self
+    }
+
     /// Sets the path to the "login" route that will be intercepted by the
     /// middleware in order to redirect the browser to the OpenID Connect
     /// authentication page.
@@ -171,13 +1605,29 @@ impl OpenIdConnectMiddleware {
     /// Defaults to `/login`
     pub fn with_login_path(mut self, login_path: &str) -> Self {
         self.login_path = login_path.to_string();
+        if let Some(kind) = &self.redirect_strategy_kind {
+            self.redirect_strategy = kind.handler(login_path, &self.realm);
+        }
         self
     }
 
     /// Adds one or more scopes to the OpenID Connect request.
     ///
+    /// The provider may grant fewer scopes than were requested (for
+    /// example, because the user did not consent to all of them), in
+    /// which case the middleware logs a warning. The scopes that were
+    /// actually granted are available to requests through the
+    /// [`scopes()`](crate::OpenIdConnectRequestExt::scopes) and
+    /// [`has_scope()`](crate::OpenIdConnectRequestExt::has_scope)
+    /// request extensions.
+    ///
     /// Defaults to `openid` (which is the minimum required scope).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `scopes` is empty, or if any of the scopes is empty.
     pub fn with_scopes(mut self, scopes: &[impl AsRef<str>]) -> Self {
+        assert_valid_scopes(scopes);
         self.scopes = scopes
             .iter()
             .map(|s| Scope::new(s.as_ref().to_owned()))
@@ -185,175 +1635,3603 @@ impl OpenIdConnectMiddleware {
         self
     }
 
-    /// Sets the path where the browser will be sent after a successful
-    /// login sequence.
+    /// Adds a login route, at `name` under the [login
+    /// path](Self::with_login_path) (so `/login/admin` for the `admin`
+    /// variant), that requests the given scopes instead of the
+    /// [default scopes](Self::with_scopes). This allows, for example,
+    /// an "elevated" login for administrative actions without
+    /// requesting those scopes from every user. The scopes that were
+    /// granted by the variant's login are then the session's
+    /// [`scopes()`](crate::OpenIdConnectRequestExt::scopes).
     ///
-    /// Defaults to `/`
-    pub fn with_login_landing_path(mut self, login_landing_path: &str) -> Self {
-        self.login_landing_path = login_landing_path.to_string();
+    /// Defaults to no login variants.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is empty or contains a `/`, or if `scopes` is
+    /// empty (or any of the scopes is empty).
+    pub fn with_login_variant(mut self, name: &str, scopes: &[impl AsRef<str>]) -> Self {
+        assert!(
+            !name.is_empty() && !name.contains('/'),
+            "login variant names must be non-empty and must not contain `/`"
+        );
+        assert_valid_scopes(scopes);
+        let scopes = scopes
+            .iter()
+            .map(|s| Scope::new(s.as_ref().to_owned()))
+            .collect();
+        self.login_variants.retain(|(existing, _)| existing != name);
+        self.login_variants.push((name.to_string(), scopes));
         self
     }
 
-    /// Sets the path to the "logout" route that will be intercepted by
-    /// the middleware in order to clear the sessions's authentication
-    /// state.
+    /// Requires scopes on specific paths (matched exactly, without the
+    /// query string), in addition to the [default scopes](Self::with_scopes)
+    /// that are requested at login. For example:
     ///
-    /// Defaults to `/logout`
-    pub fn with_logout_path(mut self, logout_path: &str) -> Self {
-        self.logout_path = logout_path.to_string();
+    /// ```no_run
+    /// # use std::collections::HashMap;
+    /// # use tide_openidconnect::{Config, OpenIdConnectMiddleware};
+    /// # async fn example(config: Config) {
+    /// let middleware = OpenIdConnectMiddleware::new(&config)
+    ///     .await
+    ///     .with_per_route_scopes(HashMap::from([
+    ///         ("/profile", vec!["openid".to_string(), "profile".to_string()]),
+    ///         ("/api/data", vec!["openid".to_string(), "data:read".to_string()]),
+    ///     ]));
+    /// # }
+    /// ```
+    ///
+    /// Logins that will return to one of these paths (by way of the
+    /// login path's `return_to` query parameter) request the path's
+    /// scopes along with the default scopes. Requests to one of these
+    /// paths from an authenticated session that was not granted all of
+    /// the path's scopes are redirected (with a `303 See Other`) to the
+    /// login path, which starts a new authorization request for the
+    /// additional scopes (along with the scopes that the session was
+    /// already granted) and then returns the browser to the same path.
+    /// Should the provider still not grant the scopes, the request is
+    /// not redirected again, but denied with the [insufficient scope
+    /// response](Self::with_insufficient_scope_response) (`403
+    /// Forbidden`, by default); as are requests with methods other than
+    /// `GET` and `HEAD`, which cannot be redirected to a login, and
+    /// requests authenticated by a [bearer
+    /// token](Self::with_bearer_fallback) that lacks the scopes (along
+    /// with an RFC 6750 `insufficient_scope` challenge).
+    ///
+    /// Defaults to no per-route scopes.
+    pub fn with_per_route_scopes(
+        mut self,
+        per_route_scopes: HashMap<&'static str, Vec<String>>,
+    ) -> Self {
+        self.route_scopes = per_route_scopes
+            .into_iter()
+            .map(|(path, scopes)| {
+                (
+                    path.to_string(),
+                    scopes.into_iter().map(Scope::new).collect(),
+                )
+            })
+            .collect();
         self
     }
 
-    /// Sets a flag indicating if the logout URL should destroy *all*
-    /// session state -- both the auth state *and* any app-level state --
-    /// or if logout should clear only the auth state and leave the remainder
-    /// of the state intact.
+    /// Sets the JWS algorithms that will be accepted when verifying the
+    /// signature on the ID token. Algorithms that the middleware does
+    /// not consider safe -- `none` and the symmetric `HS*` algorithms --
+    /// are ignored even if they are included in this list.
     ///
-    /// Applications should only retain session state after a logout if
-    /// doing so will *not* leave any (private) artifacts of the user's
-    /// data in the session after the logout process. The current page,
-    /// status of collapsed UI elements, etc. would be safe to retain,
-    /// whereas the user name, form content, etc. needs to be cleared
-    /// after the logout process (which can be done by, for example,
-    /// configuring the
-    /// [`logout_landing_path`](Self::with_logout_landing_path) to go to
-    /// a route that cleans up personally-identifying information after
-    /// the logout completes).
+    /// Defaults to the algorithms listed in the provider's
+    /// `id_token_signing_alg_values_supported` metadata (limited to
+    /// the algorithms that the middleware considers safe).
+    pub fn with_id_token_signing_algs(mut self, algs: &[CoreJwsSigningAlgorithm]) -> Self {
+        self.id_token_signing_algs = Some(allowed_id_token_signing_algs(algs));
+        self
+    }
+
+    /// Sets a flag indicating if the ID token's `at_hash` (access token
+    /// hash) claim, when present, is verified against the access token
+    /// returned alongside the ID token. The hash is computed using the
+    /// ID token's signing algorithm, and the login fails if the hashes
+    /// do not match, which protects against access token substitution.
+    ///
+    /// Disabling this validation should only be necessary for providers
+    /// that compute the `at_hash` claim incorrectly.
     ///
     /// Defaults to `true`
-    pub fn with_logout_destroys_session(mut self, logout_destroys_session: bool) -> Self {
-        self.logout_destroys_session = logout_destroys_session;
+    pub fn with_at_hash_validation(mut self, at_hash_validation: bool) -> Self {
+        self.at_hash_validation = at_hash_validation;
         self
     }
 
-    /// Sets the path where the browser will be sent after the logout
-    /// sequence.
+    /// Sets a flag indicating if the ID token *must* include an
+    /// `at_hash` (access token hash) claim. The `at_hash` claim is
+    /// optional in the Authorization Code flow, and so logins are
+    /// allowed without it unless this flag is set. Has no effect if
+    /// [`at_hash` validation](Self::with_at_hash_validation) has been
+    /// disabled.
     ///
-    /// Defaults to `/`
+    /// Defaults to `false`
+    pub fn with_at_hash_required(mut self, at_hash_required: bool) -> Self {
+        self.at_hash_required = at_hash_required;
+        self
+    }
+
+    /// Sets the number of random bytes in the `state` (CSRF token)
+    /// parameter of each authentication request. The bytes are
+    /// generated by a cryptographically secure random number generator
+    /// and base64url-encoded, so the `state` parameter is 4/3 as long
+    /// as the number of bytes.
+    ///
+    /// Defaults to `16` (128 bits)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is less than `16`.
+    pub fn with_state_entropy_bytes(mut self, bytes: u32) -> Self {
+        assert!(
+            bytes >= DEFAULT_ENTROPY_BYTES,
+            "state must contain at least {} random bytes",
+            DEFAULT_ENTROPY_BYTES
+        );
+        self.state_entropy_bytes = bytes;
+        self
+    }
+
+    /// Sets the number of random bytes in the `nonce` parameter of
+    /// each authentication request; see
+    /// [`with_state_entropy_bytes()`](Self::with_state_entropy_bytes).
+    /// Shorthand for [`with_nonce_config()`](Self::with_nonce_config)
+    /// with [`NonceConfig::new`].
+    ///
+    /// Defaults to `16` (128 bits)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is less than `16`.
+    pub fn with_nonce_entropy_bytes(self, bytes: u32) -> Self {
+        self.with_nonce_config(NonceConfig::new(bytes as usize))
+    }
+
+    /// Sets the length of the `nonce` parameter of each authentication
+    /// request, and the generator that produces it.
+    ///
+    /// Defaults to 16 random bytes from a
+    /// [`CsrngNonceGenerator`](crate::nonce::CsrngNonceGenerator).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configured `length` is less than `16`.
+    pub fn with_nonce_config(mut self, nonce_config: NonceConfig) -> Self {
+        assert!(
+            nonce_config.length >= MIN_NONCE_BYTES,
+            "nonce must contain at least {} random bytes",
+            MIN_NONCE_BYTES
+        );
+        self.nonce_config = nonce_config;
+        self
+    }
+
+    /// Includes the nonce of each login in the middleware's log events
+    /// (and, when an ID token's nonce does not match, the nonce in the
+    /// token), so that nonce mismatches can be diagnosed by correlating
+    /// the login with its callback. Nonces are otherwise logged as
+    /// `[redacted]`.
+    ///
+    /// Nonces protect logins against ID token replay, so this should
+    /// only be enabled while debugging, and never in production.
+    ///
+    /// Defaults to `false`.
+    pub fn with_nonce_logging(mut self, log_nonces: bool) -> Self {
+        self.log_nonces = log_nonces;
+        self
+    }
+
+    /// Disables some of the checks of ID token (and logout token)
+    /// verification; see [`VerifierConfig`].
+    ///
+    /// **Insecure: for tests only.** This is only available with the
+    /// `insecure-test-overrides` feature, which must never be enabled
+    /// in production builds.
+    ///
+    /// Defaults to [`VerifierConfig::default()`], which enables every
+    /// check.
+    #[cfg(feature = "insecure-test-overrides")]
+    pub fn with_verifier_config(mut self, verifier_config: VerifierConfig) -> Self {
+        tide::log::warn!(
+            "ID token verification has been relaxed for testing: {:?}",
+            verifier_config
+        );
+        self.verifier_config = verifier_config;
+        self
+    }
+
+    /// Sets the maximum number of login attempts that can be pending
+    /// (started, but not yet completed) in a single session. The state
+    /// of each pending login is stored in the session, and so this
+    /// limit prevents a client from growing the session by repeatedly
+    /// navigating to the login path; when the limit is exceeded, the
+    /// oldest pending login is evicted, and its callback is rejected.
+    ///
+    /// With the default of `1`, only the most recent login attempt is
+    /// kept, which means that starting a second login (in another
+    /// browser tab, for example) invalidates the first. Increase the
+    /// limit if users are expected to log in from several tabs at once.
+    ///
+    /// Defaults to `1`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_pending_auth` is `0`.
+    pub fn with_max_pending_auth(mut self, max_pending_auth: usize) -> Self {
+        assert!(
+            max_pending_auth > 0,
+            "at least one pending login must be allowed"
+        );
+        self.max_pending_auth = max_pending_auth;
+        self
+    }
+
+    /// Sets a flag indicating if a replayed callback (from a double
+    /// submit, or the browser's back button) for the login that
+    /// established the session is redirected to the page that the login
+    /// redirected to, instead of failing. Authorization codes can only
+    /// be used once, and so the replayed callback would otherwise show
+    /// an error page to a user who is, in fact, logged in.
+    ///
+    /// Only replays of the session's own login (with the same CSRF
+    /// state) that arrive within a few minutes of that login are
+    /// tolerated; other callbacks are still rejected, which is why this
+    /// flag does not mask genuine replay attacks. The replayed code is
+    /// never sent to the provider.
+    ///
+    /// Defaults to `false`
+    pub fn with_tolerate_duplicate_callback(mut self, tolerate_duplicate_callback: bool) -> Self {
+        self.tolerate_duplicate_callback = tolerate_duplicate_callback;
+        self
+    }
+
+    /// Sets a flag indicating if requests without an authenticated
+    /// session may authenticate with a JWT access token in the
+    /// `Authorization: Bearer` header instead, which allows the same
+    /// routes to serve both browsers and scripts. The token is verified
+    /// in the same way as by the [bearer
+    /// middleware](Self::bearer_middleware), and the request extension
+    /// is populated identically for both kinds of requests. The session
+    /// takes precedence if the request has both.
+    ///
+    /// Protected routes respond to requests that presented an invalid
+    /// bearer token with a `401 Unauthorized` and a `WWW-Authenticate:
+    /// Bearer error="invalid_token"` challenge, while requests without
+    /// any credentials still get the [redirect
+    /// strategy](Self::with_unauthenticated_redirect_strategy)'s response.
+    ///
+    /// Defaults to `false`
+    pub fn with_bearer_fallback(mut self, bearer_fallback: bool) -> Self {
+        self.bearer_fallback = bearer_fallback;
+        self
+    }
+
+    /// Sets the mode in which the provider returns the authorization
+    /// response to the callback URL. [`ResponseMode::FormPost`] has the
+    /// browser `POST` the response to the callback URL, instead of
+    /// adding it to the URL's query string. [`ResponseMode::Jwt`] and
+    /// [`ResponseMode::FormPostJwt`] enable the JWT Secured
+    /// Authorization Response Mode (JARM), in which the response is
+    /// signed by the provider; this is required by some high-security
+    /// profiles, such as FAPI.
+    ///
+    /// Defaults to [`ResponseMode::Query`]
+    pub fn with_response_mode(mut self, response_mode: ResponseMode) -> Self {
+        self.response_mode = response_mode;
+        self
+    }
+
+    /// Limits the ID token claims that are persisted in the session (and
+    /// made available through the
+    /// [`id_token_claims()`](crate::OpenIdConnectRequestExt::id_token_claims)
+    /// and [`claim()`](crate::OpenIdConnectRequestExt::claim) request
+    /// extensions) to the given claim names, which keeps the session
+    /// small when the provider includes large claim sets in the ID
+    /// token. The `sub` and `sid` claims are always persisted (the
+    /// latter is required for [Front-Channel
+    /// Logout](crate::frontchannel_logout)). Localized claims
+    /// (`name#en`, etc.) are persisted if their base name (`name`) is
+    /// in the list.
+    ///
+    /// Defaults to persisting all of the ID token claims.
+    pub fn with_persisted_claims(mut self, claims: &[impl AsRef<str>]) -> Self {
+        self.persisted_claims = Some(claims.iter().map(|c| c.as_ref().to_owned()).collect());
+        self
+    }
+
+    /// Sets the method that the middleware uses to authenticate itself
+    /// to the provider's token endpoint (for code exchanges and token
+    /// refreshes, and, if enabled, its Pushed Authorization Request
+    /// endpoint).
+    ///
+    /// Defaults to [`ClientAuth::ClientSecret`].
+    pub fn with_client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = Arc::new(client_auth);
+        self
+    }
+
+    /// Sets the method that the middleware uses to authenticate itself
+    /// to the provider's introspection endpoint, with the client id
+    /// from the [introspection configuration](Config::introspection).
+    /// Has no effect if introspection is not enabled.
+    ///
+    /// Defaults to [`ClientAuth::ClientSecret`] (with the
+    /// introspection configuration's client secret).
+    pub fn with_introspection_client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.introspector = self
+            .introspector
+            .map(|introspector| Arc::new(introspector.with_client_auth(client_auth)));
+        self
+    }
+
+    /// Replaces the HTTP client that the middleware uses for all
+    /// subsequent requests to the provider (token exchange, UserInfo,
+    /// Pushed Authorization Requests, and discovery document and JSON
+    /// Web Key Set refreshes). This allows the application to customize
+    /// TLS settings (including client certificates for mutual TLS),
+    /// timeouts, and connection pooling.
+    ///
+    /// Note that the initial discovery request (and, if enabled,
+    /// dynamic client registration) is performed by
+    /// [`new`](Self::new) with the client described by
+    /// [`Config::http_client`], unless the middleware was created with
+    /// [`new_lazy`](Self::new_lazy); inject the client as
+    /// [`HttpClientConfig::client`] instead if those requests must use
+    /// it too (to reach a provider whose certificate is issued by an
+    /// internal CA, for example).
+    ///
+    /// Defaults to a client created from [`Config::http_client`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # async_std::task::block_on(async {
+    /// # let config = tide_openidconnect::Config {
+    /// #   issuer_url: tide_openidconnect::IssuerUrl::new("https://your-tenant-name.us.auth0.com/".to_string()).unwrap(),
+    /// #   client_id: tide_openidconnect::ClientId::new("app-id-goes-here".to_string()),
+    /// #   client_secret: tide_openidconnect::ClientSecret::new("app-secret-goes-here".to_string()),
+    /// #   redirect_url: tide_openidconnect::RedirectUrl::new("https://your.cool.site/callback".to_string()).unwrap(),
+    /// #   additional_audiences: vec![],
+    /// #   require_azp: false,
+    /// #   clock_skew_tolerance: std::time::Duration::from_secs(60),
+    /// #   idp_logout_url: None,
+    /// #   idp_logout_id_token_hint: false,
+    /// #   post_logout_redirect_url: None,
+    /// #   retain_id_token: false,
+    /// #   dynamic_client_registration: None,
+    /// #   backchannel_logout_path: None,
+    /// #   frontchannel_logout_path: None,
+    /// #   fetch_userinfo: false,
+    /// #   extra_authorization_params: Default::default(),
+    /// #   dpop: None,
+    /// #   access_denied_handler: None,
+    /// #   token_refresh_failed_handler: None,
+    /// #   provider_endpoints: None,
+    /// #   realm: None,
+    /// #   introspection: None,
+    /// #   claims_locales: None,
+    /// #   redirect_strategy: Default::default(),
+    /// #   csp_policy: None,
+    /// #   http_client: Default::default(),
+    /// #   http_timeouts: Default::default(),
+    /// #   allow_insecure_http: false,
+    /// #   verify_on_startup: true,
+    /// #   session_encryption_keys: vec![],
+    /// #   session_ttl: Default::default(),
+    /// #   issuer_verification: Default::default(),
+    /// #   token_endpoint_auth_method: None,
+    /// # };
+    /// use isahc::config::{ClientCertificate, Configurable, PrivateKey, RedirectPolicy};
+    ///
+    /// let http_client = isahc::HttpClient::builder()
+    ///     .redirect_policy(RedirectPolicy::None)
+    ///     .ssl_client_certificate(ClientCertificate::pem_file(
+    ///         "client.pem",
+    ///         PrivateKey::pem_file("client.key", None),
+    ///     ))
+    ///     .build()
+    ///     .unwrap();
+    /// let middleware = tide_openidconnect::OpenIdConnectMiddleware::new(&config)
+    ///     .await
+    ///     .with_http_client(http_client.into());
+    /// # })
+    /// ```
+    ///
+    /// The [request timeout](Self::with_request_timeout) and
+    /// [retries](Self::with_retries) of the middleware also apply to
+    /// the injected client.
+    pub fn with_http_client(self, http_client: HttpClient) -> Self {
+        let http_client = self.http_client.with_settings_of(http_client);
+        self.replace_http_client(http_client)
+    }
+
+    /// Fails requests to the provider (token exchanges, provider
+    /// metadata refreshes, JSON Web Key Set fetches, etc.) that take
+    /// longer than `timeout`, overriding the request timeout of
+    /// [`Config::http_timeouts`].
+    ///
+    /// The initial provider discovery in [`new`](Self::new) happens
+    /// before the timeout is set, and so uses the configured timeout.
+    ///
+    /// Defaults to [`HttpTimeouts::request`].
+    pub fn with_request_timeout(self, timeout: std::time::Duration) -> Self {
+        let http_client = self.http_client.clone().with_timeout(Some(timeout));
+        self.replace_http_client(http_client)
+    }
+
+    /// Retries requests to the provider that fail with a transient
+    /// error -- a connection error, a [timeout](Self::with_request_timeout),
+    /// or a `5xx` response -- up to `retries` times, waiting 100ms
+    /// before the first retry and doubling the wait after each one.
+    ///
+    /// Only idempotent requests (discovery, the JSON Web Key Set and
+    /// UserInfo) are retried. Requests to the token endpoint are never
+    /// retried, since the provider may already have redeemed the
+    /// authorization code (or refresh token) of a request whose response
+    /// was lost, and neither are requests that are rejected by the
+    /// provider.
+    ///
+    /// Defaults to no retries.
+    pub fn with_retries(self, retries: u32) -> Self {
+        let http_client = self.http_client.clone().with_retries(retries);
+        self.replace_http_client(http_client)
+    }
+
+    /// Replaces the HTTP client of the middleware and its caches.
+    fn replace_http_client(mut self, http_client: HttpClient) -> Self {
+        self.jwks = Arc::new(self.jwks.with_http_client(http_client.clone()));
+        self.discovery = Arc::new(self.discovery.with_http_client(http_client.clone()));
+        let discovery = &self.discovery;
+        self.introspector = self.introspector.as_ref().map(|introspector| {
+            Arc::new(introspector.with_http_client(http_client.clone(), Arc::clone(discovery)))
+        });
+        self.http_client = http_client;
+
+        // The refresh task (if any) only holds weak references to the
+        // previous caches, and so exits on its own; start a new one.
+        if let Some(discovery_cache) = self.discovery_cache {
+            spawn_refresh_task(&self.discovery, &self.jwks, discovery_cache.ttl);
+        }
+        self
+    }
+
+    /// Enables [Pushed Authorization Requests](PushedAuthorizationRequests),
+    /// in which the login route POSTs the authorization request to the
+    /// provider and then redirects the browser with only the resulting
+    /// `request_uri`, instead of sending the request parameters through
+    /// the browser.
+    ///
+    /// Defaults to disabled.
+    pub fn with_pushed_authorization_requests(
+        mut self,
+        pushed_authorization_requests: PushedAuthorizationRequests,
+    ) -> Self {
+        self.pushed_authorization_requests = Some(pushed_authorization_requests);
+        self
+    }
+
+    /// Enables or disables [Pushed Authorization
+    /// Requests](Self::with_pushed_authorization_requests) when the
+    /// provider advertises a `pushed_authorization_request_endpoint`,
+    /// falling back to a regular authorization request otherwise (see
+    /// [`PushedAuthorizationRequests::Optional`]).
+    ///
+    /// Defaults to disabled.
+    pub fn with_par(mut self, enabled: bool) -> Self {
+        self.pushed_authorization_requests = if enabled {
+            Some(PushedAuthorizationRequests::Optional)
+        } else {
+            None
+        };
+        self
+    }
+
+    /// Signs the authorization request parameters, which are then passed
+    /// to the provider as a [request object](RequestObjectSigning) in the
+    /// `request` parameter. The parameters are also included in the
+    /// authorization URL itself, as OpenID Connect requires (and for
+    /// providers that ignore the request object), and in the [pushed
+    /// authorization request](Self::with_pushed_authorization_requests),
+    /// if enabled.
+    ///
+    /// Defaults to disabled.
+    pub fn with_request_object_signing(
+        mut self,
+        request_object_signing: RequestObjectSigning,
+    ) -> Self {
+        self.request_object_signing = Some(Arc::new(request_object_signing));
+        self
+    }
+
+    /// Enables caching of the provider's discovery document
+    /// (`/.well-known/openid-configuration`), which is then refreshed
+    /// in the background at the interval given by the
+    /// [cache configuration](DiscoveryCacheConfig) -- and optionally
+    /// after a request to one of the provider's endpoints fails -- so
+    /// that the middleware picks up changes to the provider's
+    /// configuration without having to be restarted.
+    ///
+    /// Each refresh replaces the cached metadata and signing keys as a
+    /// whole, so requests never see a partially-updated copy, and a
+    /// failed refresh is logged and keeps the last good copy. Unless
+    /// they were set with
+    /// [`with_id_token_signing_algs()`](Self::with_id_token_signing_algs),
+    /// the ID token signing algorithms follow the refreshed metadata.
+    /// See also [`refresh_metadata()`](Self::refresh_metadata).
+    ///
+    /// Defaults to fetching the discovery document only at startup.
+    pub fn with_discovery_cache(mut self, discovery_cache: DiscoveryCacheConfig) -> Self {
+        spawn_refresh_task(&self.discovery, &self.jwks, discovery_cache.ttl);
+        self.discovery_cache = Some(discovery_cache);
+        self
+    }
+
+    /// Enables OpenID Connect [Session
+    /// Management](crate::session_management), which stores the
+    /// provider's `session_state` in the session and serves a script
+    /// that re-authenticates the user when their session at the provider
+    /// changes.
+    ///
+    /// Defaults to session management being disabled.
+    pub fn with_session_management(mut self, session_management: SessionManagementConfig) -> Self {
+        self.session_management = Some(session_management);
+        self
+    }
+
+    /// Sets the path to the "silent login" route that will be
+    /// intercepted by the middleware in order to start a login that
+    /// does not interact with the user (by sending `prompt=none` to the
+    /// provider). This allows applications -- usually from a hidden
+    /// iframe or a background redirect -- to renew the user's session
+    /// without a visible login page, as long as the user still has a
+    /// session with the provider.
+    ///
+    /// A successful silent login sends the browser to the
+    /// [`login_landing_path`](Self::with_login_landing_path), just like
+    /// a regular login. If the provider is unable to log the user in
+    /// without interacting with them, the browser is instead sent to
+    /// the [`silent_login_failure_path`](Self::with_silent_login_failure_path),
+    /// at which point the application can fall back to a regular
+    /// (interactive) login.
+    ///
+    /// Defaults to no silent login path (silent logins are disabled).
+    pub fn with_silent_login_path(mut self, silent_login_path: &str) -> Self {
+        self.silent_login_path = Some(silent_login_path.to_string());
+        self
+    }
+
+    /// Sets the path where the browser will be sent when a [silent
+    /// login](Self::with_silent_login_path) fails because the provider
+    /// needs to interact with the user. The provider's error code
+    /// (`login_required`, `interaction_required`, `consent_required`,
+    /// or `account_selection_required`) is added to the path as the
+    /// `error` query parameter, after any query string that is already
+    /// part of the path.
+    ///
+    /// Defaults to `/`
+    pub fn with_silent_login_failure_path(mut self, silent_login_failure_path: &str) -> Self {
+        self.silent_login_failure_path = silent_login_failure_path.to_string();
+        self
+    }
+
+    /// Sets the path where the browser will be sent after a successful
+    /// login sequence. The path may include a query string and/or
+    /// fragment (for example, `/dashboard?welcome=1`), which are
+    /// preserved verbatim in the redirect.
+    ///
+    /// Defaults to `/`
+    pub fn with_login_landing_path(mut self, login_landing_path: &str) -> Self {
+        self.login_landing_path = login_landing_path.to_string();
+        self
+    }
+
+    /// Sets the path to the "logout" route that will be intercepted by
+    /// the middleware in order to clear the sessions's authentication
+    /// state.
+    ///
+    /// Defaults to `/logout`
+    pub fn with_logout_path(mut self, logout_path: &str) -> Self {
+        self.logout_path = logout_path.to_string();
+        self
+    }
+
+    /// Sets the [`LogoutMode`] of the logout route, which determines
+    /// whether or not the user is also logged out of the Identity
+    /// Provider.
+    ///
+    /// Defaults to [`LogoutMode::IdpInitiated`]
+    pub fn with_logout_mode(mut self, logout_mode: LogoutMode) -> Self {
+        self.logout_mode = logout_mode;
+        self
+    }
+
+    /// Sets the path to an additional "local logout" route that clears
+    /// the session's authentication state (in the same way as the
+    /// [logout route](Self::with_logout_path)) and then redirects the
+    /// browser to the logout landing path, *without* logging the user
+    /// out of the Identity Provider; see [`LogoutMode::LocalOnly`]. This
+    /// allows applications to offer both kinds of logout.
+    ///
+    /// Defaults to no local logout route.
+    pub fn with_local_logout_path(mut self, local_logout_path: &str) -> Self {
+        self.local_logout_path = Some(local_logout_path.to_string());
+        self
+    }
+
+    /// Sets a flag indicating if the logout URL should destroy *all*
+    /// session state -- both the auth state *and* any app-level state --
+    /// or if logout should clear only the auth state and leave the remainder
+    /// of the state intact.
+    ///
+    /// Applications should only retain session state after a logout if
+    /// doing so will *not* leave any (private) artifacts of the user's
+    /// data in the session after the logout process. The current page,
+    /// status of collapsed UI elements, etc. would be safe to retain,
+    /// whereas the user name, form content, etc. needs to be cleared
+    /// after the logout process (which can be done by, for example,
+    /// configuring the
+    /// [`logout_landing_path`](Self::with_logout_landing_path) to go to
+    /// a route that cleans up personally-identifying information after
+    /// the logout completes).
+    ///
+    /// Defaults to `true`
+    pub fn with_logout_destroys_session(mut self, logout_destroys_session: bool) -> Self {
+        self.logout_destroys_session = logout_destroys_session;
+        self
+    }
+
+    /// Sets the `Domain` attribute of the cookies that the middleware
+    /// sets of its own, so that they are shared between the subdomains
+    /// of an application that is served from several of them (an `app`
+    /// and an `api` subdomain, for example).
+    ///
+    /// The login itself only relies on the session cookie, whose domain
+    /// is configured on the session middleware instead; the only cookie
+    /// that the middleware sets is the [logout state
+    /// cookie](Config::post_logout_redirect_url), which should use the
+    /// same domain as the session cookie if the post-logout redirect URL
+    /// is on a different subdomain than the logout. The cookie's
+    /// `SameSite=Lax` policy is unaffected (subdomains of the same site
+    /// are not "cross-site"), and it remains `Secure` whenever the
+    /// post-logout redirect URL is an `https` URL.
+    ///
+    /// A broad domain sends the cookie to *every* subdomain, including
+    /// ones that host less-trusted content, which can then read or
+    /// overwrite it; only widen the domain when every subdomain is
+    /// equally trusted. Browsers also ignore cookies whose domain does
+    /// not include the host that sets them.
+    ///
+    /// Defaults to no domain (a host-only cookie).
+    pub fn with_cookie_domain(mut self, domain: &str) -> Self {
+        self.cookie_domain = Some(domain.to_string());
+        self
+    }
+
+    /// Sets the `Path` attribute of the cookies that the middleware sets
+    /// of its own (see [`with_cookie_domain()`](Self::with_cookie_domain)).
+    /// The path must include the path of the [post-logout redirect
+    /// URL](Config::post_logout_redirect_url), since the browser
+    /// otherwise never returns the logout state cookie.
+    ///
+    /// Defaults to the path of the post-logout redirect URL.
+    pub fn with_cookie_path(mut self, path: &str) -> Self {
+        self.cookie_path = Some(path.to_string());
+        self
+    }
+
+    /// Sets the path where the browser will be sent after the logout
+    /// sequence. The path may include a query string and/or fragment,
+    /// which are preserved verbatim in the redirect.
+    ///
+    /// Defaults to `/`
     pub fn with_logout_landing_path(mut self, logout_landing_path: &str) -> Self {
         self.logout_landing_path = logout_landing_path.to_string();
         self
     }
 
-    /// Sets the trait used to generate redirect responses to
-    /// unauthenticated requests.
+    /// Sets the handler that invalidates a user's sessions in response
+    /// to [Back-Channel Logout](crate::backchannel_logout) requests, and
+    /// which can also report sessions as
+    /// [logged out](LogoutHandler::is_logged_out). Only used if the
+    /// [back-channel logout path](Self::with_backchannel_logout_path)
+    /// has been configured.
+    pub fn with_logout_handler<H>(mut self, logout_handler: H) -> Self
+    where
+        H: LogoutHandler + 'static,
+    {
+        self.logout_handler = Some(Arc::new(logout_handler));
+        self
+    }
+
+    /// Sets the path at which the middleware accepts [Back-Channel
+    /// Logout](crate::backchannel_logout) requests from the provider,
+    /// which requires a [logout handler](Self::with_logout_handler);
+    /// see [`Config::backchannel_logout_path`].
+    ///
+    /// Defaults to the path in the [`Config`], if any.
+    pub fn with_backchannel_logout_path(mut self, backchannel_logout_path: &str) -> Self {
+        self.backchannel_logout_path = Some(backchannel_logout_path.to_string());
+        self
+    }
+
+    /// Sets the [store](crate::state_store) in which the state (CSRF
+    /// token, nonce, etc.) of pending logins is kept, instead of in the
+    /// session. The session then only records the CSRF tokens.
+    ///
+    /// Defaults to keeping the state in the session.
+    pub fn with_state_store(mut self, state_store: Arc<dyn OidcStateStore>) -> Self {
+        self.state_store = Some(state_store);
+        self
+    }
+
+    /// Sets a [hook](PostAuthHook) that runs after a user has
+    /// authenticated (and the ID token has been verified), but before
+    /// the session is marked as authenticated. If the hook returns an
+    /// error, the login fails and the session remains unauthenticated.
+    ///
+    /// Defaults to no hook.
+    pub fn with_post_auth_hook(mut self, post_auth_hook: Arc<dyn PostAuthHook>) -> Self {
+        self.post_auth_hook = Some(post_auth_hook);
+        self
+    }
+
+    /// Sets a [hook](PreLogoutHook) that runs before an authenticated
+    /// session is cleared by a logout (through the logout or local
+    /// logout paths), and before the [logout
+    /// handler](Self::with_logout_handler) is called for a back-channel
+    /// logout. Errors returned by the hook are logged, but the logout
+    /// proceeds regardless.
+    ///
+    /// Defaults to no hook.
+    pub fn with_pre_logout_hook(mut self, pre_logout_hook: Arc<dyn PreLogoutHook>) -> Self {
+        self.pre_logout_hook = Some(pre_logout_hook);
+        self
+    }
+
+    /// Sets the [sink](SecurityEventSink) to which token validation
+    /// failures (invalid signatures, and nonce and state mismatches)
+    /// are reported, for security event logging. The sink is shared
+    /// with the [bearer middleware](Self::bearer_middleware).
+    ///
+    /// Defaults to no sink.
+    pub fn with_security_event_sink(
+        mut self,
+        security_event_sink: Arc<dyn SecurityEventSink>,
+    ) -> Self {
+        self.security_event_sink = Some(security_event_sink);
+        self
+    }
+
+    /// Sets the function used to compute the [redirect
+    /// URL](Config::redirect_url) from the incoming login request, which
+    /// allows a single application to serve multiple domains and still
+    /// return the browser to the domain on which it started the login.
+    /// The computed URL is used for both the authorization request and
+    /// the subsequent code exchange.
+    ///
+    /// The computed URL's host must be in `allowed_hosts`, and its path
+    /// must match the path of the configured redirect URL (which is
+    /// where the middleware listens for the callback); otherwise, the
+    /// login is rejected. Every computed URL must also be registered
+    /// with the provider.
+    ///
+    /// Defaults to always using the configured redirect URL.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use tide_openidconnect::{self, RedirectUrl};
+    /// # async_std::task::block_on(async {
+    /// # let config = tide_openidconnect::Config {
+    /// #   issuer_url: tide_openidconnect::IssuerUrl::new("https://your-tenant-name.us.auth0.com/".to_string()).unwrap(),
+    /// #   client_id: tide_openidconnect::ClientId::new("app-id-goes-here".to_string()),
+    /// #   client_secret: tide_openidconnect::ClientSecret::new("app-secret-goes-here".to_string()),
+    /// #   redirect_url: tide_openidconnect::RedirectUrl::new("https://a.example.com/callback".to_string()).unwrap(),
+    /// #   additional_audiences: vec![],
+    /// #   require_azp: false,
+    /// #   clock_skew_tolerance: std::time::Duration::from_secs(60),
+    /// #   idp_logout_url: None,
+    /// #   idp_logout_id_token_hint: false,
+    /// #   post_logout_redirect_url: None,
+    /// #   retain_id_token: false,
+    /// #   dynamic_client_registration: None,
+    /// #   backchannel_logout_path: None,
+    /// #   frontchannel_logout_path: None,
+    /// #   fetch_userinfo: false,
+    /// #   extra_authorization_params: Default::default(),
+    /// #   dpop: None,
+    /// #   access_denied_handler: None,
+    /// #   token_refresh_failed_handler: None,
+    /// #   provider_endpoints: None,
+    /// #   realm: None,
+    /// #   introspection: None,
+    /// #   claims_locales: None,
+    /// #   redirect_strategy: Default::default(),
+    /// #   csp_policy: None,
+    /// #   http_client: Default::default(),
+    /// #   http_timeouts: Default::default(),
+    /// #   allow_insecure_http: false,
+    /// #   verify_on_startup: true,
+    /// #   session_encryption_keys: vec![],
+    /// #   session_ttl: Default::default(),
+    /// #   issuer_verification: Default::default(),
+    /// #   token_endpoint_auth_method: None,
+    /// # };
+    /// let middleware = tide_openidconnect::OpenIdConnectMiddleware::new(&config)
+    ///     .await
+    ///     .with_redirect_url_fn(&["a.example.com", "b.example.com"], |req| {
+    ///         let host = req.header("Host")?.as_str();
+    ///         RedirectUrl::new(format!("https://{}/callback", host)).ok()
+    ///     });
+    /// # })
+    /// ```
+    pub fn with_redirect_url_fn<F>(
+        mut self,
+        allowed_hosts: &[impl AsRef<str>],
+        redirect_url_fn: F,
+    ) -> Self
+    where
+        F: Fn(&tide::http::Request) -> Option<RedirectUrl> + Send + Sync + 'static,
+    {
+        self.allowed_redirect_hosts = allowed_hosts
+            .iter()
+            .map(|host| host.as_ref().to_owned())
+            .collect();
+        self.redirect_url_fn = Some(Arc::new(redirect_url_fn));
+        self
+    }
+
+    /// Sets the [`LoginHintExtractor`] that is used to get the
+    /// `login_hint` from login requests, such as
+    /// [`QueryParamLoginHint`](crate::login_hint::QueryParamLoginHint).
+    /// The login hint, if any, is added to the authorization request so
+    /// that the Identity Provider can pre-fill its login form.
+    ///
+    /// Defaults to no login hint extractor.
+    pub fn with_login_hint_extractor<E>(mut self, login_hint_extractor: E) -> Self
+    where
+        E: LoginHintExtractor + 'static,
+    {
+        self.login_hint_extractor = Some(Arc::new(login_hint_extractor));
+        self
+    }
+
+    /// Adds vendor-specific parameters to every authorization request,
+    /// in addition to the
+    /// [`extra_authorization_params`](Config::extra_authorization_params)
+    /// from the [`Config`]; for example, Auth0's `audience` parameter
+    /// (which selects the API for which the access token is issued), or
+    /// Keycloak's `kc_idp_hint`.
+    ///
+    /// ```no_run
+    /// # async fn example(config: tide_openidconnect::Config) {
+    /// let middleware = tide_openidconnect::OpenIdConnectMiddleware::new(&config)
+    ///     .await
+    ///     .with_extra_authorize_params(vec![("audience", "https://api.example.com")]);
+    /// # }
+    /// ```
+    ///
+    /// Parameters that are controlled by the middleware (`state`,
+    /// `nonce`, `redirect_uri`, `scope`, etc.) are ignored, with a
+    /// warning.
+    ///
+    /// Defaults to no additional parameters.
+    pub fn with_extra_authorize_params<K, V>(
+        mut self,
+        params: impl IntoIterator<Item = (K, V)>,
+    ) -> Self
+    where
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        let params = ExtraAuthorizationParams(
+            params
+                .into_iter()
+                .map(|(name, value)| (name.as_ref().to_string(), value.as_ref().to_string()))
+                .collect(),
+        );
+        self.extra_authorization_params
+            .extend(params.allowed_params());
+        self
+    }
+
+    /// Sets the trait used to generate redirect responses to
+    /// unauthenticated requests.
+    ///
+    /// Defaults to the strategy selected by
+    /// [`Config::redirect_strategy`].
+    pub fn with_unauthenticated_redirect_strategy<R>(mut self, redirect_strategy: R) -> Self
+    where
+        R: RedirectStrategy + 'static,
+    {
+        self.redirect_strategy = Arc::new(RedirectStrategyHandler(redirect_strategy));
+        self.redirect_strategy_kind = None;
+        self
+    }
+
+    /// Sets the handler that generates the responses to unauthenticated
+    /// requests. Unlike a [`RedirectStrategy`], the handler receives the
+    /// request and is asynchronous; closures that take a
+    /// [`tide::http::Request`] and return a future of a
+    /// `tide::Result<tide::Response>` can be used as handlers.
+    ///
+    /// Defaults to the strategy selected by
+    /// [`Config::redirect_strategy`].
+    pub fn with_unauthenticated_handler<H>(mut self, handler: H) -> Self
+    where
+        H: UnauthenticatedHandler + 'static,
+    {
+        self.redirect_strategy = Arc::new(handler);
+        self.redirect_strategy_kind = None;
+        self
+    }
+
+    /// Sets the function used to generate the response to
+    /// authenticated requests that were not granted all of the scopes
+    /// required by an
+    /// [`authenticated_with_scopes()`](crate::OpenIdConnectRouteExt::authenticated_with_scopes)
+    /// route (or by the [per-route scopes](Self::with_per_route_scopes)
+    /// of the requested path). The function receives the scopes that are missing. Not
+    /// used if the [`Config`] has an
+    /// [`access_denied_handler`](Config::access_denied_handler).
+    ///
+    /// Defaults to `403 Forbidden`, with a JSON body that includes the
+    /// missing scopes.
+    pub fn with_insufficient_scope_response<F>(mut self, insufficient_scope_response: F) -> Self
+    where
+        F: Fn(&[String]) -> tide::Response + Send + Sync + 'static,
+    {
+        self.insufficient_scope_response = Arc::new(insufficient_scope_response);
+        self
+    }
+
+    /// Sets the function used to generate the response to
+    /// authenticated requests that were denied by the predicate of an
+    /// [`authorized()`](crate::OpenIdConnectRouteExt::authorized) route.
+    /// Not used if the [`Config`] has an
+    /// [`access_denied_handler`](Config::access_denied_handler).
+    ///
+    /// Defaults to `403 Forbidden`, with a JSON body.
+    pub fn with_access_denied_response<F>(mut self, access_denied_response: F) -> Self
+    where
+        F: Fn() -> tide::Response + Send + Sync + 'static,
+    {
+        self.access_denied_response = Arc::new(move |_| access_denied_response());
+        self
+    }
+
+    /// Requires every request that this middleware authenticates to
+    /// have been granted the given scope, in addition to any scopes
+    /// required by the [route](crate::OpenIdConnectRouteExt). Requests
+    /// that lack the scope are denied with the [access denied
+    /// response](Self::with_access_denied_response) (or the
+    /// [`Config`]'s
+    /// [`access_denied_handler`](Config::access_denied_handler)), even
+    /// on public routes; unauthenticated requests are not affected.
+    /// Requests authenticated with a bearer token (by way of the
+    /// [bearer fallback](Self::with_bearer_fallback) or the [bearer
+    /// middleware](Self::bearer_middleware)) are checked as well. Can be
+    /// called multiple times, in which case all of the scopes are
+    /// required.
+    ///
+    /// Defaults to no required scopes.
+    pub fn with_required_scope(mut self, scope: &str) -> Self {
+        self.authorization_policy
+            .required_scopes
+            .push(scope.to_string());
+        self
+    }
+
+    /// Requires every request that this middleware authenticates to
+    /// have the given claim, with exactly the given value (for example,
+    /// `with_required_claim("email_verified", json!(true))`). Requests
+    /// that do not are denied in the same way as requests that lack a
+    /// [required scope](Self::with_required_scope). Note that only
+    /// [persisted](Self::with_persisted_claims) claims are available
+    /// to the check. Can be called multiple times, in which case all of
+    /// the claims are required.
+    ///
+    /// Defaults to no required claims.
+    pub fn with_required_claim(mut self, claim: &str, value: serde_json::Value) -> Self {
+        self.authorization_policy
+            .required_claims
+            .push((claim.to_string(), value));
+        self
+    }
+
+    /// Sets whether or not the response to requests that fail the
+    /// [required scopes](Self::with_required_scope) or
+    /// [claims](Self::with_required_claim) says which requirement was
+    /// not met. This is useful while debugging, but tells clients more
+    /// about the application's authorization policy than they need to
+    /// know. When disabled, the access denied handler receives
+    /// [`OidcError::AccessDenied`]; when enabled, it receives
+    /// [`OidcError::InsufficientScope`] or
+    /// [`OidcError::RequiredClaim`].
+    ///
+    /// Defaults to `false`.
+    pub fn with_authorization_failure_details(mut self, enabled: bool) -> Self {
+        self.authorization_policy.failure_details = enabled;
+        self
+    }
+
+    /// Sets the level at which the given [authentication
+    /// event](AuthEvent) is logged, or disables logging of the event if
+    /// `level` is `None`. Events are logged with structured key-value
+    /// pairs through the `log` facade (as re-exported by
+    /// [`tide::log`]).
+    ///
+    /// Defaults to the level documented for each [`AuthEvent`].
+    pub fn with_auth_event_log_level(mut self, event: AuthEvent, level: Option<Level>) -> Self {
+        self.auth_event_levels.set(event, level);
+        self
+    }
+
+    /// Returns a [`HealthCheck`] that reports whether or not the
+    /// middleware is able to authenticate requests, and which can be
+    /// used after the middleware has been added to the application
+    /// (for example, in a readiness probe). Must be called *after*
+    /// configuring the [discovery cache](Self::with_discovery_cache), if
+    /// any, since that determines when the provider metadata is
+    /// considered stale.
+    pub fn health_check(&self) -> HealthCheck {
+        HealthCheck::new(
+            self.discovery.clone(),
+            self.jwks.clone(),
+            self.discovery_cache.map(|cache| cache.ttl * 2),
+        )
+    }
+
+    /// Wraps the given listener (anything that can be passed to
+    /// [`listen()`](tide::Server::listen)) so that binding it first
+    /// fetches the provider's discovery document and JSON Web Key Set,
+    /// unless [`Config::verify_on_startup`] is `false`. `listen()` then
+    /// returns an [`io::Error`](std::io::Error) -- and no requests are
+    /// accepted -- if the provider cannot be reached, which is
+    /// especially useful for [lazily-created](Self::new_lazy)
+    /// middleware. Note that the startup check *only* runs if this
+    /// listener is passed to `listen()`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use tide_openidconnect::{Config, LazyDiscoveryConfig, OpenIdConnectMiddleware};
+    /// # async fn example(config: Config) -> std::io::Result<()> {
+    /// let middleware = OpenIdConnectMiddleware::new_lazy(&config, LazyDiscoveryConfig::default())
+    ///     .map_err(std::io::Error::other)?;
+    /// let listener = middleware.startup_listener("127.0.0.1:8000");
+    ///
+    /// let mut app = tide::new();
+    /// app.with(middleware);
+    /// app.listen(listener).await
+    /// # }
+    /// ```
+    pub fn startup_listener<L>(&self, listener: L) -> StartupCheckListener<L> {
+        StartupCheckListener::new(
+            listener,
+            self.verify_on_startup.then(|| self.health_check()),
+            Arc::clone(&self.provider_verified),
+        )
+    }
+
+    /// Returns `true` if the middleware has loaded the provider's
+    /// metadata and signing keys (and those are not stale); see
+    /// [`HealthCheck::is_ready`].
+    pub fn is_ready(&self) -> bool {
+        self.health_check().is_ready()
+    }
+
+    /// Checks that the provider is reachable (by re-fetching its
+    /// metadata and signing keys) and that the middleware is ready; see
+    /// [`HealthCheck::check`].
+    pub async fn check(&self) -> bool {
+        self.health_check().check().await
+    }
+
+    /// Re-fetches the provider's discovery document and signing keys
+    /// immediately, whether or not a [discovery
+    /// cache](Self::with_discovery_cache) has been configured. A failed
+    /// refresh keeps the last good copy. Once the middleware has been
+    /// added to the application, use
+    /// [`HealthCheck::refresh_metadata`] (for example, from an admin
+    /// endpoint) instead.
+    pub async fn refresh_metadata(&self) -> Result<(), OidcError> {
+        self.health_check().refresh_metadata().await
+    }
+
+    /// Creates an [`OpenIdConnectBearerMiddleware`] that authenticates
+    /// API requests with the provider's JWT access tokens, and which
+    /// shares the middleware's provider metadata, signing keys, client
+    /// credentials, [authorization policy](Self::with_required_scope) and
+    /// [responses](Self::with_insufficient_scope_response) to denied
+    /// requests. Must be called *after* configuring the
+    /// middleware (with [`with_http_client()`](Self::with_http_client),
+    /// for example), since the bearer middleware does not see later
+    /// changes.
+    ///
+    /// Access tokens must be intended for the client id or for one of
+    /// the [`additional_audiences`](Config::additional_audiences), and
+    /// must be signed with one of the [ID token signing
+    /// algorithms](Self::with_id_token_signing_algs).
+    pub fn bearer_middleware(&self) -> OpenIdConnectBearerMiddleware {
+        OpenIdConnectBearerMiddleware {
+            provider_id: self.provider_id.clone(),
+            issuer_url: self.issuer_url.clone(),
+            issuer_verification: self.issuer_verification,
+            audiences: std::iter::once(self.client_id.to_string())
+                .chain(self.additional_audiences.iter().cloned())
+                .collect(),
+            realm: self.realm.clone(),
+            signing_algs: self.id_token_signing_algs.clone(),
+            clock_skew_tolerance: self.clock_skew_tolerance,
+            discovery: Arc::clone(&self.discovery),
+            jwks: Arc::clone(&self.jwks),
+            introspector: self.introspector.clone(),
+            token_exchanger: Arc::new(self.token_exchanger()),
+            access_denied_handler: self.access_denied_handler(),
+            authorization_policy: self.authorization_policy.clone(),
+            security_event_sink: self.security_event_sink.clone(),
+            login_path: self.login_path.clone(),
+        }
+    }
+
+    /// Creates a [`ClientCredentialsClient`] that shares the
+    /// middleware's client credentials (including dynamically
+    /// registered credentials and the [client
+    /// authentication](Self::with_client_auth) method) and HTTP client,
+    /// which allows background jobs to obtain machine-to-machine tokens
+    /// without duplicating the middleware's configuration.
+    ///
+    /// Returns an error if the provider does not advertise a token
+    /// endpoint.
+    pub fn client_credentials_client(&self) -> Result<ClientCredentialsClient, OidcError> {
+        let token_url = self
+            .discovery
+            .metadata()?
+            .token_endpoint()
+            .cloned()
+            .ok_or_else(|| {
+                OidcError::Discovery(
+                    "OpenID Connect provider does not advertise a token_endpoint.".to_string(),
+                )
+            })?;
+        Ok(ClientCredentialsClient::new(
+            self.http_client.clone(),
+            self.client_id.clone(),
+            self.client_secret.clone(),
+            Arc::clone(&self.client_auth),
+            self.token_endpoint_auth_method()?,
+            token_url,
+            self.dpop.clone(),
+        ))
+    }
+
+    /// Creates a [`CibaClient`] that shares the middleware's client
+    /// credentials (including dynamically registered credentials and the
+    /// [client authentication](Self::with_client_auth) method) and HTTP
+    /// client, for authenticating users by way of
+    /// [CIBA](crate::ciba_flow).
+    ///
+    /// Returns an error if the provider does not advertise a backchannel
+    /// authentication endpoint (or a token endpoint).
+    pub fn ciba_client(&self) -> Result<CibaClient, OidcError> {
+        let metadata = self.discovery.metadata()?;
+        CibaClient::new(
+            self.http_client.clone(),
+            self.client_id.clone(),
+            self.client_secret.clone(),
+            Arc::clone(&self.client_auth),
+            self.token_endpoint_auth_method,
+            &metadata,
+            IdTokenVerifier {
+                issuer_url: self.issuer_url.clone(),
+                issuer_verification: self.issuer_verification,
+                jwks: Arc::clone(&self.jwks),
+                signing_algs: self.id_token_signing_algs(),
+                clock_skew_tolerance: self.clock_skew_tolerance,
+            },
+        )
+    }
+
+    /// Returns the name of the provider that is included in the
+    /// authentication events: the provider id, if any, otherwise the
+    /// issuer URL.
+    fn provider_name(&self) -> &str {
+        self.provider_id
+            .as_deref()
+            .unwrap_or_else(|| self.issuer_url.as_str())
+    }
+
+    /// Creates the OpenID Connect client from the current provider
+    /// metadata and the cached JSON Web Key Set (which is used to verify
+    /// signed UserInfo responses).
+    fn client(&self) -> Result<Client, OidcError> {
+        let metadata = self.discovery.metadata()?;
+        Ok(Client::new(
+            self.client_id.clone(),
+            self.basic_auth_secret().cloned(),
+            metadata.issuer().clone(),
+            metadata.authorization_endpoint().clone(),
+            metadata.token_endpoint().cloned(),
+            metadata.userinfo_endpoint().cloned(),
+            self.jwks.keys(),
+        )
+        .set_auth_type(
+            TokenEndpointAuthMethod::resolve(
+                self.token_endpoint_auth_method,
+                metadata.token_endpoint_auth_methods_supported(),
+            )
+            .auth_type(),
+        )
+        .set_redirect_uri(self.redirect_url.clone()))
+    }
+
+    /// Returns the configured [method](Config::token_endpoint_auth_method)
+    /// of sending the client secret, or else the one that the provider
+    /// advertises.
+    fn token_endpoint_auth_method(&self) -> Result<TokenEndpointAuthMethod, OidcError> {
+        Ok(TokenEndpointAuthMethod::resolve(
+            self.token_endpoint_auth_method,
+            self.discovery
+                .metadata()?
+                .token_endpoint_auth_methods_supported(),
+        ))
+    }
+
+    /// Returns the ID token signing algorithms that the middleware
+    /// accepts: the configured ones, or else the ones advertised by the
+    /// provider.
+    fn id_token_signing_algs(&self) -> Vec<CoreJwsSigningAlgorithm> {
+        self.id_token_signing_algs
+            .clone()
+            .unwrap_or_else(|| self.discovery.id_token_signing_algs())
+    }
+
+    /// Returns the client secret, unless the client authenticates by
+    /// some other means (such as a client assertion).
+    fn basic_auth_secret(&self) -> Option<&ClientSecret> {
+        match *self.client_auth {
+            ClientAuth::ClientSecret => self.client_secret.as_ref(),
+            ClientAuth::PrivateKeyJwt(_) => None,
+        }
+    }
+
+    /// Returns the nonce for inclusion in log events, which is redacted
+    /// unless [nonce logging](Self::with_nonce_logging) is enabled.
+    #[cfg(feature = "tracing")]
+    fn loggable_nonce<'a>(&self, nonce: &'a Nonce) -> &'a str {
+        if self.log_nonces {
+            nonce.secret()
+        } else {
+            "[redacted]"
+        }
+    }
+
+    /// Reports a security event to the configured sink, if any.
+    async fn emit_security_event(&self, event: SecurityEvent) {
+        if let Some(security_event_sink) = &self.security_event_sink {
+            security_event_sink.emit(event).await;
+        }
+    }
+
+    /// Refreshes the provider metadata after a request to one of the
+    /// provider's endpoints failed, if the middleware has been
+    /// configured to do so.
+    async fn refresh_discovery_after_failure(&self) {
+        if matches!(self.discovery_cache, Some(cache) if cache.refresh_on_failure) {
+            self.discovery.refresh_after_failure(&self.jwks).await;
+        }
+    }
+
+    /// Computes the redirect URL for this login request, if the
+    /// middleware has been configured with a [redirect URL
+    /// function](Self::with_redirect_url_fn). Returns `None` if the
+    /// configured redirect URL should be used instead.
+    fn request_redirect_url(&self, req: &tide::http::Request) -> tide::Result<Option<RedirectUrl>> {
+        let redirect_url_fn = match &self.redirect_url_fn {
+            Some(redirect_url_fn) => redirect_url_fn,
+            None => return Ok(None),
+        };
+
+        redirect_url_fn(req)
+            .filter(|redirect_url| {
+                let url = redirect_url.url();
+                url.path() == self.redirect_url.url().path()
+                    && url.host_str().is_some_and(|host| {
+                        self.allowed_redirect_hosts
+                            .iter()
+                            .any(|allowed_host| allowed_host.eq_ignore_ascii_case(host))
+                    })
+            })
+            .map(Some)
+            .ok_or_else(|| {
+                tide::log::warn!("Rejecting login request with an invalid redirect URL.");
+                tide::http::Error::from_str(StatusCode::BadRequest, "Invalid redirect URL.")
+            })
+    }
+
+    async fn generate_redirect<State>(
+        &self,
+        mut req: Request<State>,
+        silent: bool,
+        variant: Option<&str>,
+    ) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let authorize_url = self.authorize_url(req.as_mut(), silent, variant).await?;
+        let csp_policy = self.csp_policy.clone().unwrap_or_else(|| {
+            format!(
+                "default-src 'none'; connect-src {}",
+                authorize_url.origin().ascii_serialization()
+            )
+        });
+        let mut res: tide::Response = Redirect::new(&authorize_url).into();
+        res.insert_header("Content-Security-Policy", csp_policy);
+        Ok(res)
+    }
+
+    /// Builds the authorization URL of a new login, and adds the login
+    /// to the pending logins in the request's session (evicting the
+    /// oldest pending logins if there are too many of them).
+    async fn authorize_url(
+        &self,
+        req: &mut tide::http::Request,
+        silent: bool,
+        variant: Option<&str>,
+    ) -> tide::Result<Url> {
+        let redirect_url = self.request_redirect_url(req)?;
+        let metadata = self.discovery.metadata()?;
+        let client = self.client()?;
+        let state_bytes = self.state_entropy_bytes;
+        let nonce_generator = Arc::clone(&self.nonce_config.generator);
+        let mut request = client.authorize_url(
+            AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
+            move || CsrfToken::new_random_len(state_bytes),
+            move || Nonce::new(nonce_generator.generate()),
+        );
+        // The openidconnect-rs crate always adds the "openid" scope.
+        let return_to = safe_return_to(req.url());
+        let granted_scopes = self.step_up_granted_scopes(req, return_to.as_deref());
+        for s in self
+            .requested_scopes(variant, return_to.as_deref(), &granted_scopes)
+            .into_iter()
+            .skip(1)
+        {
+            request = request.add_scope(s);
+        }
+        if let Some(redirect_url) = &redirect_url {
+            request = request.set_redirect_uri(Cow::Borrowed(redirect_url));
+        }
+        if silent {
+            request = request.add_prompt(CoreAuthPrompt::None);
+        }
+        for claims_locale in &self.claims_locales {
+            request = request.add_claims_locale(LanguageTag::new(claims_locale.clone()));
+        }
+        if let Some(response_mode) = self.response_mode.param() {
+            request = request.add_extra_param("response_mode", response_mode);
+        }
+        for (name, value) in &self.extra_authorization_params {
+            request = request.add_extra_param(name.as_str(), value.as_str());
+        }
+        if let Some(login_hint) = self
+            .login_hint_extractor
+            .as_ref()
+            .and_then(|extractor| extractor.extract(req))
+        {
+            request = request.set_login_hint(LoginHint::new(login_hint));
+        }
+        let (mut authorize_url, csrf_token, nonce) = request.url();
+        if let Some(request_object_signing) = &self.request_object_signing {
+            let request_object = request_object_signing
+                .sign(&self.client_id, self.issuer_url.as_str(), &authorize_url)
+                .map_err(|error| {
+                    tide::log::error!("Unable to sign the request object: {}", error);
+                    tide::http::Error::from_str(StatusCode::InternalServerError, error)
+                })?;
+            authorize_url
+                .query_pairs_mut()
+                .append_pair("request", &request_object);
+        }
+
+        // Push the authorization request to the provider, if enabled
+        // (and supported by the provider).
+        let authorize_url = match (
+            self.pushed_authorization_requests,
+            &metadata
+                .additional_metadata()
+                .pushed_authorization_request_endpoint,
+        ) {
+            (Some(_), Some(endpoint)) => {
+                match push_authorization_request(
+                    &self.http_client,
+                    endpoint,
+                    metadata.authorization_endpoint(),
+                    &self.client_id,
+                    self.basic_auth_secret().map(|client_secret| {
+                        (
+                            client_secret,
+                            TokenEndpointAuthMethod::resolve(
+                                self.token_endpoint_auth_method,
+                                metadata.token_endpoint_auth_methods_supported(),
+                            ),
+                        )
+                    }),
+                    &self
+                        .client_auth
+                        .assertion_params(&self.client_id, self.issuer_url.as_str())
+                        .map_err(|error| {
+                            tide::http::Error::from_str(StatusCode::InternalServerError, error)
+                        })?,
+                    &authorize_url,
+                )
+                .await
+                {
+                    Ok(authorize_url) => authorize_url,
+                    Err(error) => {
+                        tide::log::warn!("Pushed Authorization Request failed: {}", error);
+                        self.refresh_discovery_after_failure().await;
+                        return Err(tide::http::Error::from_str(
+                            StatusCode::InternalServerError,
+                            error,
+                        ));
+                    }
+                }
+            }
+            (Some(PushedAuthorizationRequests::Required), None) => {
+                tide::log::error!(
+                    "Pushed Authorization Requests are required, but the OpenID Connect provider does not advertise a pushed_authorization_request_endpoint."
+                );
+                return Err(tide::http::Error::from_str(
+                    StatusCode::InternalServerError,
+                    "Pushed Authorization Requests are not supported by the provider.",
+                ));
+            }
+            _ => authorize_url,
+        };
+        event!(
+            debug,
+            state = %csrf_token.secret(),
+            nonce = %self.loggable_nonce(&nonce),
+            "Redirecting browser to the authorization endpoint."
+        );
+        log_auth_event!(self.auth_event_levels, AuthEvent::RedirectInitiated, self.provider_name(), {
+            silent: silent,
+        });
+
+        // Add this login attempt to the middleware's session state so
+        // that we can validate the login after the user completes the
+        // authentication flow, evicting the oldest pending logins if
+        // there are too many of them.
+        let pending_auth = PendingAuth {
+            csrf_token,
+            nonce,
+            redirect_url,
+            silent,
+            return_to,
+            variant: variant.map(str::to_string),
+            granted_scopes,
+        };
+        let session = req.ext_mut().get_mut::<Session>().expect(
+            "request session not initialized, did you enable tide::sessions::SessionMiddleware?",
+        );
+        let pre_auth = match &self.state_store {
+            Some(state_store) => {
+                let mut states = match self.session_state(session) {
+                    Some(MiddlewareSessionState::PreAuthStored { states }) => states,
+                    _ => vec![],
+                };
+                self.save_pending_auth(state_store.as_ref(), &pending_auth)
+                    .await?;
+                states.push(pending_auth.csrf_token);
+                let evicted = states.len().saturating_sub(self.max_pending_auth);
+                for state in states.drain(..evicted) {
+                    state_store.delete(state.secret()).await.map_err(|error| {
+                        tide::http::Error::new(StatusCode::InternalServerError, error)
+                    })?;
+                }
+                MiddlewareSessionState::PreAuthStored { states }
+            }
+            None => {
+                let mut pending = match self.session_state(session) {
+                    Some(MiddlewareSessionState::PreAuth { pending }) => pending,
+                    _ => vec![],
+                };
+                pending.push(pending_auth);
+                let evicted = pending.len().saturating_sub(self.max_pending_auth);
+                pending.drain(..evicted);
+                MiddlewareSessionState::PreAuth { pending }
+            }
+        };
+        self.set_session_state(session, &pre_auth)?;
+
+        Ok(authorize_url)
+    }
+
+    fn id_token_verifier(&self) -> CoreIdTokenVerifier<'_> {
+        let verifier = match &self.client_secret {
+            Some(client_secret) => CoreIdTokenVerifier::new_confidential_client(
+                self.client_id.clone(),
+                client_secret.clone(),
+                self.issuer_url.clone(),
+                self.jwks.keys(),
+            ),
+            None => CoreIdTokenVerifier::new_public_client(
+                self.client_id.clone(),
+                self.issuer_url.clone(),
+                self.jwks.keys(),
+            ),
+        }
+        .set_allowed_algs(self.id_token_signing_algs())
+        // The issuer is verified by `verify_id_token`, according to the
+        // configured issuer verification mode.
+        .require_issuer_match(false)
+        // openidconnect-rs rejects tokens that expired before the time
+        // returned by this function, so turning back the clock allows
+        // for the configured amount of clock skew.
+        .set_time_fn(move || {
+            let now = Utc::now();
+            now.checked_sub_signed(self.clock_skew_tolerance)
+                .unwrap_or(now)
+        })
+        .set_other_audience_verifier_fn(move |aud| {
+            self.additional_audiences
+                .iter()
+                .any(|additional_audience| additional_audience == aud.as_str())
+        });
+        if self.verifier_config.skip_signature_check {
+            verifier.insecure_disable_signature_check()
+        } else {
+            verifier
+        }
+    }
+
+    /// Verifies the ID token, refreshing the provider's JSON Web Key Set
+    /// (and then trying again) if the token was signed with a key that
+    /// is not in our cached key set. This is what allows us to survive
+    /// key rotations without restarting the application.
+    ///
+    /// Logout tokens are verified using the same process, which is why
+    /// this function is generic over the token's additional claims.
+    async fn verify_id_token<'a, TC, N>(
+        &self,
+        id_token: &'a openidconnect::IdToken<
+            TC,
+            CoreGenderClaim,
+            CoreJweContentEncryptionAlgorithm,
+            CoreJwsSigningAlgorithm,
+            CoreJsonWebKeyType,
+        >,
+        nonce_verifier: N,
+    ) -> Result<&'a openidconnect::IdTokenClaims<TC, CoreGenderClaim>, ClaimsVerificationError>
+    where
+        TC: AdditionalClaims + Sync,
+        N: NonceVerifier + Copy + Send,
+    {
+        let claims = if self.verifier_config.skip_nonce_check {
+            self.id_token_claims(id_token, |_: Option<&Nonce>| Ok(()))
+                .await
+        } else {
+            self.id_token_claims(id_token, nonce_verifier).await
+        }?;
+        if !self.verifier_config.skip_issuer_check
+            && !self
+                .issuer_verification
+                .matches(&self.issuer_url, claims.issuer().as_str())
+        {
+            return Err(ClaimsVerificationError::InvalidIssuer(format!(
+                "expected `{}` (found `{}`)",
+                self.issuer_url.as_str(),
+                claims.issuer().as_str()
+            )));
+        }
+        Ok(claims)
+    }
+
+    /// Returns the verified claims of the ID token, refreshing the JSON
+    /// Web Key Set if needed (see [`verify_id_token`](Self::verify_id_token)).
+    async fn id_token_claims<'a, TC, N>(
+        &self,
+        id_token: &'a openidconnect::IdToken<
+            TC,
+            CoreGenderClaim,
+            CoreJweContentEncryptionAlgorithm,
+            CoreJwsSigningAlgorithm,
+            CoreJsonWebKeyType,
+        >,
+        nonce_verifier: N,
+    ) -> Result<&'a openidconnect::IdTokenClaims<TC, CoreGenderClaim>, ClaimsVerificationError>
+    where
+        TC: AdditionalClaims + Sync,
+        N: NonceVerifier + Copy + Send,
+    {
+        match id_token.claims(&self.id_token_verifier(), nonce_verifier) {
+            Err(ClaimsVerificationError::SignatureVerification(
+                SignatureVerificationError::NoMatchingKey,
+            )) if self.jwks.refresh().await => {
+                id_token.claims(&self.id_token_verifier(), nonce_verifier)
+            }
+            result => result,
+        }
+    }
+
+    /// Verifies the ID token's `azp` (authorized party) claim, which
+    /// must be present if the token has multiple audiences (or if the
+    /// middleware has been configured to always require it), and which
+    /// must always be our client id. Note that the openidconnect-rs
+    /// crate does not verify this claim.
+    fn verify_authorized_party(&self, claims: &IdTokenClaims) -> Result<(), String> {
+        match claims.authorized_party() {
+            Some(authorized_party) if *authorized_party != self.client_id => Err(format!(
+                "Authorized party `{}` does not match our client id.",
+                authorized_party.as_str()
+            )),
+            Some(_) => Ok(()),
+            None if self.require_azp || claims.audiences().len() > 1 => {
+                Err("ID token does not include an authorized party (azp) claim.".to_string())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Verifies the ID token's `nbf` (not before) claim, if any, allowing
+    /// for the configured amount of clock skew. Note that the
+    /// openidconnect-rs crate does not verify this claim.
+    /// Creates a token exchanger with the middleware's client
+    /// credentials.
+    fn token_exchanger(&self) -> TokenExchanger {
+        TokenExchanger {
+            http_client: self.http_client.clone(),
+            discovery: Arc::clone(&self.discovery),
+            client_id: self.client_id.clone(),
+            client_secret: self.client_secret.clone(),
+            client_auth: Arc::clone(&self.client_auth),
+            token_endpoint_auth_method: self.token_endpoint_auth_method,
+            dpop: self.dpop.clone(),
+            cache: Arc::clone(&self.token_exchange_cache),
+        }
+    }
+
+    /// Returns the authentication data of a request that belongs to a
+    /// session with the given (authenticated) state.
+    fn authenticated_state(
+        &self,
+        session_state: MiddlewareSessionState,
+    ) -> Option<OpenIdConnectRequestExtData> {
+        let refresher = self
+            .needs_refresh(&session_state)
+            .then(|| self.shared() as Arc<dyn SessionRefresher>);
+        match session_state {
+            MiddlewareSessionState::PostAuth {
+                subject,
+                access_token,
+                scopes,
+                issued_at,
+                expires_at,
+                claims,
+                userinfo,
+                id_token,
+                ..
+            } => Some(OpenIdConnectRequestExtData::Authenticated {
+                auth_info: LazyAuthInfo::new(SessionAuthInfo {
+                    provider_id: self.provider_id.clone(),
+                    issuer: self.issuer_url.clone(),
+                    client_id: self.client_id.to_string(),
+                    subject: subject.to_string(),
+                    access_token: access_token.secret().to_string(),
+                    scopes: scopes.iter().map(|s| s.to_string()).collect(),
+                    issued_at,
+                    expires_at,
+                }),
+                claims,
+                userinfo,
+                id_token: id_token.filter(|_| self.retain_id_token),
+                access_denied_handler: self.access_denied_handler(),
+                token_exchanger: Arc::new(self.token_exchanger()),
+                session_key: Some(self.session_key.clone()),
+                refresher,
+                realm: self.realm.clone(),
+                redirect_strategy: self.redirect_strategy.clone(),
+                login_path: self.login_path.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the snapshot of the middleware that request handlers and
+    /// route guards reach through the request's extensions.
+    fn shared(&self) -> Arc<Self> {
+        Arc::clone(self.shared.get_or_init(|| Arc::new(self.clone())))
+    }
+
+    /// Returns the handler for requests whose session could not be
+    /// refreshed.
+    fn token_refresh_failed_handler(&self) -> Arc<dyn TokenRefreshFailedHandler> {
+        match &self.token_refresh_failed_handler {
+            Some(handler) => Arc::clone(handler),
+            None => Arc::new(RestartLogin {
+                redirect_strategy: self.redirect_strategy.clone(),
+                destroy_session: self.logout_destroys_session,
+            }),
+        }
+    }
+
+    /// Returns the handler for authenticated requests that are denied
+    /// access to a route.
+    fn access_denied_handler(&self) -> Arc<dyn AccessDeniedHandler> {
+        match &self.access_denied_handler {
+            Some(handler) => Arc::clone(handler),
+            None => Arc::new(AccessDeniedResponses {
+                insufficient_scope: Arc::clone(&self.insufficient_scope_response),
+                access_denied: Arc::clone(&self.access_denied_response),
+            }),
+        }
+    }
+
+    fn verify_not_before(&self, claims: &IdTokenClaims) -> Result<(), String> {
+        match claims
+            .additional_claims()
+            .claims
+            .get("nbf")
+            .and_then(|nbf| nbf.as_i64())
+        {
+            Some(nbf)
+                if Utc::now()
+                    .checked_add_signed(self.clock_skew_tolerance)
+                    .is_some_and(|now| nbf > now.timestamp()) =>
+            {
+                Err(format!("ID token is not valid until {}.", nbf))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Verifies that the ID token's `at_hash` claim (if any) matches the
+    /// access token that was returned alongside the ID token, which
+    /// prevents an attacker from substituting a different access token.
+    fn verify_access_token_hash(
+        &self,
+        id_token: &IdToken,
+        claims: &IdTokenClaims,
+        access_token: &AccessToken,
+    ) -> Result<(), String> {
+        if !self.at_hash_validation {
+            return Ok(());
+        }
+
+        match claims.access_token_hash() {
+            Some(expected_hash) => {
+                let signing_alg = id_token.signing_alg().map_err(|e| e.to_string())?;
+                let actual_hash = AccessTokenHash::from_token(access_token, &signing_alg)
+                    .map_err(|e| e.to_string())?;
+                if &actual_hash == expected_hash {
+                    Ok(())
+                } else {
+                    Err("Access token hash does not match the access token.".to_string())
+                }
+            }
+            None if self.at_hash_required => {
+                Err("ID token does not include an access token hash.".to_string())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Fetches the user's claims from the provider's UserInfo endpoint,
+    /// verifying that they belong to the same subject as the ID token.
+    /// Signed (JWT) responses are verified using the provider's JSON
+    /// Web Key Set.
+    async fn fetch_userinfo(
+        &self,
+        client: &Client,
+        access_token: &AccessToken,
+        subject: &SubjectIdentifier,
+    ) -> tide::Result<Claims> {
+        let request = client
+            .user_info(access_token.clone(), Some(subject.clone()))
+            .map_err(|error| {
+                tide::log::error!(
+                    "OpenID Connect provider does not advertise a userinfo_endpoint."
+                );
+                tide::http::Error::new(StatusCode::InternalServerError, error)
+            })?;
+        let accept_language = accept_language(&self.claims_locales);
+        let userinfo: UserInfoClaims<OtherClaims, CoreGenderClaim> = instrument!(
+            request.request_async(|mut request| {
+                if let Some(accept_language) = accept_language {
+                    request
+                        .headers
+                        .insert(http::header::ACCEPT_LANGUAGE, accept_language);
+                }
+                self.http_client
+                    .request_with_dpop(self.dpop.as_ref(), request)
+            }),
+            "oidc.userinfo",
+            issuer = %self.issuer_url.as_str(),
+            client_id = %self.client_id.as_str(),
+        )
+        .await
+        .map_err(|error| {
+            event!(warn, error = %error, "UserInfo request failed.");
+            let status = match &error {
+                UserInfoError::ClaimsVerification(_) => StatusCode::Unauthorized,
+                UserInfoError::Request(error) if error.is_timeout() => {
+                    tide::log::warn!(
+                        "UserInfo request to {} timed out.",
+                        self.discovery
+                            .metadata()
+                            .ok()
+                            .and_then(|metadata| metadata.userinfo_endpoint().cloned())
+                            .map(|url| url.to_string())
+                            .unwrap_or_default()
+                    );
+                    StatusCode::GatewayTimeout
+                }
+                _ => StatusCode::InternalServerError,
+            };
+            tide::http::Error::new(status, error)
+        })?;
+
+        match serde_json::to_value(userinfo)
+            .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?
+        {
+            serde_json::Value::Object(claims) => Ok(claims),
+            _ => Ok(Claims::new()),
+        }
+    }
+
+    /// Returns the scopes that are included in the authorization
+    /// request of the given [login variant](Self::with_login_variant)
+    /// (or of the default login), along with the [per-route
+    /// scopes](Self::with_per_route_scopes) of the path that the login
+    /// returns to (if any) and the scopes that the session had already
+    /// been granted (for step-up logins), which always include `openid`.
+    fn requested_scopes(
+        &self,
+        variant: Option<&str>,
+        return_to: Option<&str>,
+        granted_scopes: &[Scope],
+    ) -> Vec<Scope> {
+        let mut scopes = vec![Scope::new("openid".to_string())];
+        let route_scopes = return_to.map_or(&[][..], |return_to| {
+            self.route_scopes_at(return_to_path(return_to))
+        });
+        for scope in self
+            .login_variant_scopes(variant)
+            .iter()
+            .chain(granted_scopes)
+            .chain(route_scopes)
+        {
+            if !scopes.contains(scope) {
+                scopes.push(scope.clone());
+            }
+        }
+        scopes
+    }
+
+    /// Returns the scopes that the session has been granted if the
+    /// login is a step-up for the [per-route
+    /// scopes](Self::with_per_route_scopes) of the path that it returns
+    /// to, so that the new session does not lose them (a session that
+    /// logged in with a [login variant](Self::with_login_variant), for
+    /// example).
+    fn step_up_granted_scopes(
+        &self,
+        req: &tide::http::Request,
+        return_to: Option<&str>,
+    ) -> Vec<Scope> {
+        if return_to
+            .is_none_or(|return_to| self.route_scopes_at(return_to_path(return_to)).is_empty())
+        {
+            return vec![];
+        }
+        match req
+            .ext()
+            .get::<Session>()
+            .and_then(|session| self.session_state(session))
+        {
+            Some(MiddlewareSessionState::PostAuth { scopes, .. }) => scopes,
+            _ => vec![],
+        }
+    }
+
+    /// Returns the [per-route scopes](Self::with_per_route_scopes) of
+    /// the given path.
+    fn route_scopes_at(&self, path: &str) -> &[Scope] {
+        self.route_scopes.get(path).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the configured scopes of the given [login
+    /// variant](Self::with_login_variant), or the default scopes if
+    /// there is no such variant.
+    fn login_variant_scopes(&self, variant: Option<&str>) -> &[Scope] {
+        variant
+            .and_then(|variant| self.login_variants.iter().find(|(name, _)| name == variant))
+            .map_or(&self.scopes, |(_, scopes)| scopes)
+    }
+
+    /// Returns the name of the [login variant](Self::with_login_variant)
+    /// whose route is at `path`, if any.
+    fn login_variant_at(&self, path: &str) -> Option<&str> {
+        let name = path
+            .strip_prefix(self.login_path.trim_end_matches('/'))?
+            .strip_prefix('/')?;
+        self.login_variants
+            .iter()
+            .find(|(variant, _)| variant == name)
+            .map(|(variant, _)| variant.as_str())
+    }
+
+    /// Converts the (verified) ID token claims into the form in which
+    /// they are persisted in the session, removing any claims that are
+    /// not in the allowlist.
+    fn persisted_claims(&self, claims: &IdTokenClaims) -> Result<Claims, serde_json::Error> {
+        let mut claims = match serde_json::to_value(claims)? {
+            serde_json::Value::Object(claims) => claims,
+            _ => Claims::new(),
+        };
+        if let Some(persisted_claims) = &self.persisted_claims {
+            claims.retain(|name, _| {
+                let base_name = name.split('#').next().unwrap_or(name);
+                base_name == "sub"
+                    || base_name == "sid"
+                    || persisted_claims.iter().any(|c| c == base_name)
+            });
+        }
+        Ok(claims)
+    }
+
+    /// Extracts the authorization response from the callback request,
+    /// verifying the response JWT if the middleware uses one of the
+    /// JWT response modes.
+    async fn authorization_response<State>(
+        &self,
+        req: &mut Request<State>,
+    ) -> tide::Result<AuthorizationResponse>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let response = match self.response_mode {
+            ResponseMode::Query => return req.query(),
+            ResponseMode::FormPost => return req.body_form().await,
+            ResponseMode::Jwt => req.query::<JwtAuthorizationResponse>()?.response,
+            ResponseMode::FormPostJwt => {
+                req.body_form::<JwtAuthorizationResponse>().await?.response
+            }
+        };
+
+        // JARM responses are signed with RS256 unless the provider says
+        // otherwise, and we only accept the algorithms that we accept
+        // for ID tokens.
+        let metadata = self.discovery.metadata()?;
+        let allowed_algs = allowed_id_token_signing_algs(
+            metadata
+                .additional_metadata()
+                .authorization_signing_alg_values_supported
+                .as_deref()
+                .unwrap_or(&[CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256]),
+        );
+        let verify = || {
+            verify_jwt_response(
+                &response,
+                &self.jwks.keys(),
+                &allowed_algs,
+                &self.issuer_url,
+                self.issuer_verification,
+                &self.client_id,
+                self.clock_skew_tolerance,
+            )
+        };
+        let result = match verify() {
+            // The provider may have rotated its keys; try again with the
+            // new key set.
+            Err(JwtError::UnknownKey) if self.jwks.refresh().await => verify(),
+            result => result,
+        };
+        match result {
+            Ok(response) => Ok(response),
+            Err(error) => {
+                tide::log::warn!("Rejected JWT authorization response: {}", error);
+                if error.is_signature_error() {
+                    self.emit_security_event(SecurityEvent::InvalidTokenSignature {
+                        sub: unverified_subject(&response),
+                        ip: remote_ip(req.as_ref()),
+                    })
+                    .await;
+                }
+                Err(tide::http::Error::from_str(
+                    StatusCode::Unauthorized,
+                    "Invalid authorization response.",
+                ))
+            }
+        }
+    }
+
+    async fn handle_callback<State>(&self, mut req: Request<State>) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        log_auth_event!(
+            self.auth_event_levels,
+            AuthEvent::CallbackReceived,
+            self.provider_name(),
+            {}
+        );
+
+        // Get the middleware state from the session. If this fails then
+        // A) the browser got to the callback URL without actually going
+        // through the auth process, or B) more likely, the session
+        // middleware is configured with Strict cookies instead of Lax
+        // cookies. We cannot tell at this level which error occurred,
+        // so we just reject the request and log the error.
+        if let Some(
+            pre_auth @ (MiddlewareSessionState::PreAuth { .. }
+            | MiddlewareSessionState::PreAuthStored { .. }),
+        ) = self.session_state(req.session())
+        {
+            // Extract the OpenID callback information and verify the CSRF
+            // state against the pending login attempts.
+            let callback_data = self.authorization_response(&mut req).await?;
+            event!(
+                debug,
+                state = %callback_data.state,
+                pending = pre_auth.pending_len(),
+                "Received authorization callback."
+            );
+            let (
+                PendingAuth {
+                    nonce,
+                    redirect_url,
+                    silent,
+                    return_to,
+                    variant,
+                    granted_scopes,
+                    ..
+                },
+                pending,
+            ) = match self
+                .take_pending_auth(pre_auth, &callback_data.state)
+                .await?
+            {
+                Some(taken) => taken,
+                None => {
+                    event!(warn, state = %callback_data.state, "Invalid CSRF state.");
+                    self.emit_security_event(SecurityEvent::StateMismatch {
+                        ip: remote_ip(req.as_ref()),
+                    })
+                    .await;
+                    return Err(tide::http::Error::from_str(
+                        StatusCode::Unauthorized,
+                        "Invalid CSRF state.",
+                    ));
+                }
+            };
+            event!(debug, nonce = %self.loggable_nonce(&nonce), "CSRF state verified.");
+            let session_state = callback_data.session_state;
+            let state = callback_data.state;
+
+            // The provider returns an error instead of a code if the
+            // login failed, which is expected for silent logins when the
+            // user needs to interact with the provider. In that case we
+            // clear the (now-used) login state and let the application
+            // decide what to do next.
+            let code = match (callback_data.code, callback_data.error) {
+                (Some(code), _) => code,
+                (None, Some(error)) if silent && SILENT_LOGIN_ERRORS.contains(&error.as_str()) => {
+                    event!(debug, error = %error, "Silent login requires user interaction.");
+                    if pending.pending_len() == 0 {
+                        req.session_mut().remove(&self.session_key);
+                    } else {
+                        self.set_session_state(req.session_mut(), &pending)?;
+                    }
+                    return Ok(Redirect::new(append_query_param(
+                        &self.silent_login_failure_path,
+                        "error",
+                        &error,
+                    ))
+                    .into());
+                }
+                (None, error) => {
+                    let error = error.unwrap_or_else(|| "missing authorization code".to_string());
+                    event!(warn, error = %error, "Authorization failed.");
+                    return Err(tide::http::Error::from_str(
+                        StatusCode::BadRequest,
+                        format!("Authorization failed: {}", error),
+                    ));
+                }
+            };
+
+            // Exchange the code for a token, using the same redirect URL
+            // that we used in the authorization request.
+            let client = self.client()?;
+            let mut token_request = client.exchange_code(code);
+            if let Some(redirect_url) = &redirect_url {
+                token_request = token_request.set_redirect_uri(Cow::Borrowed(redirect_url));
+            }
+            let token_endpoint = self
+                .discovery
+                .metadata()?
+                .token_endpoint()
+                .map(|url| url.to_string())
+                .unwrap_or_default();
+            for (name, value) in self
+                .client_auth
+                .assertion_params(&self.client_id, &token_endpoint)
+                .map_err(|error| {
+                    tide::http::Error::from_str(StatusCode::InternalServerError, error)
+                })?
+            {
+                token_request = token_request.add_extra_param(name, value);
+            }
+            let token_request_started = Instant::now();
+            let token_response = match instrument!(
+                token_request.request_async(|request| {
+                    self.http_client
+                        .request_with_dpop(self.dpop.as_ref(), request)
+                }),
+                "oidc.token_exchange",
+                issuer = %self.issuer_url.as_str(),
+                client_id = %self.client_id.as_str(),
+            )
+            .await
+            {
+                Ok(token_response) => {
+                    log_auth_event!(
+                        self.auth_event_levels,
+                        AuthEvent::TokenExchangeSucceeded,
+                        self.provider_name(),
+                        { duration_ms: elapsed_ms(token_request_started) }
+                    );
+                    token_response
+                }
+                Err(error) => {
+                    event!(warn, error = %error, "Token exchange failed.");
+                    log_auth_event!(
+                        self.auth_event_levels,
+                        AuthEvent::TokenExchangeFailed,
+                        self.provider_name(),
+                        {
+                            duration_ms: elapsed_ms(token_request_started),
+                            error: error.to_string().as_str(),
+                        }
+                    );
+                    self.refresh_discovery_after_failure().await;
+
+                    // The provider rejecting the code (with an OAuth 2.0
+                    // error response) is the client's problem -- unless
+                    // it rejected our credentials, which is ours --
+                    // while anything else is the provider's. Neither the
+                    // provider's response nor the code are included in
+                    // the response.
+                    return Err(match error {
+                        RequestTokenError::ServerResponse(response) => match response.error() {
+                            CoreErrorResponseType::InvalidClient
+                            | CoreErrorResponseType::UnauthorizedClient => {
+                                tide::http::Error::from_str(
+                                    StatusCode::InternalServerError,
+                                    "The provider rejected the client's credentials.",
+                                )
+                            }
+                            _ => tide::http::Error::from_str(
+                                StatusCode::BadRequest,
+                                "The authorization code was rejected by the provider.",
+                            ),
+                        },
+                        RequestTokenError::Request(error) if error.is_timeout() => {
+                            tide::log::warn!("Token request to {} timed out.", token_endpoint);
+                            tide::http::Error::from_str(
+                                StatusCode::GatewayTimeout,
+                                "The provider's token endpoint timed out.",
+                            )
+                        }
+                        _ => tide::http::Error::from_str(
+                            StatusCode::BadGateway,
+                            "The provider's token endpoint is unavailable.",
+                        ),
+                    });
+                }
+            };
+
+            // Get the claims and verify the nonce.
+            let id_token = token_response.extra_fields().id_token().ok_or_else(|| {
+                tide::http::Error::from_str(
+                    StatusCode::InternalServerError,
+                    "OpenID Connect server did not return an ID token.",
+                )
+            })?;
+            let claims = match self.verify_id_token(id_token, &nonce).await {
+                Ok(claims) => claims,
+                Err(error) => {
+                    event!(warn, nonce = %self.loggable_nonce(&nonce), error = %error, "ID token verification failed.");
+                    let ip = remote_ip(req.as_ref());
+                    match error {
+                        ClaimsVerificationError::InvalidNonce(_) => {
+                            if self.log_nonces {
+                                event!(
+                                    debug,
+                                    nonce = %nonce.secret(),
+                                    token_nonce = %unverified_nonce(&id_token.to_string()).unwrap_or_default(),
+                                    "ID token nonce does not match the login's nonce."
+                                );
+                            }
+                            self.emit_security_event(SecurityEvent::NonceMismatch { ip })
+                                .await;
+                        }
+                        ClaimsVerificationError::NoSignature
+                        | ClaimsVerificationError::SignatureVerification(_) => {
+                            self.emit_security_event(SecurityEvent::InvalidTokenSignature {
+                                sub: unverified_subject(&id_token.to_string()),
+                                ip,
+                            })
+                            .await;
+                        }
+                        _ => {}
+                    }
+                    return Err(tide::http::Error::new(StatusCode::Unauthorized, error));
+                }
+            };
+            self.verify_authorized_party(claims).map_err(|error| {
+                event!(warn, nonce = %self.loggable_nonce(&nonce), error = %error, "Authorized party verification failed.");
+                tide::http::Error::from_str(StatusCode::Unauthorized, error)
+            })?;
+            self.verify_not_before(claims).map_err(|error| {
+                event!(warn, nonce = %self.loggable_nonce(&nonce), error = %error, "ID token is not yet valid.");
+                tide::http::Error::from_str(StatusCode::Unauthorized, error)
+            })?;
+            serde_json::from_value::<AC>(serde_json::Value::Object(
+                claims.additional_claims().claims.clone(),
+            ))
+            .map_err(|error| {
+                event!(warn, nonce = %self.loggable_nonce(&nonce), error = %error, "ID token is missing additional claims.");
+                tide::http::Error::new(StatusCode::Unauthorized, error)
+            })?;
+            self.verify_access_token_hash(id_token, claims, token_response.access_token())
+                .map_err(|error| {
+                    event!(warn, nonce = %self.loggable_nonce(&nonce), error = %error, "Access token hash verification failed.");
+                    tide::http::Error::from_str(StatusCode::Unauthorized, error)
+                })?;
+            event!(
+                debug,
+                nonce = %self.loggable_nonce(&nonce),
+                subject = %claims.subject().as_str(),
+                "ID token verified; session is now authenticated."
+            );
+
+            // Fetch the user's claims from the UserInfo endpoint, if
+            // enabled.
+            let userinfo = if self.fetch_userinfo {
+                Some(
+                    self.fetch_userinfo(&client, token_response.access_token(), claims.subject())
+                        .await?,
+                )
+            } else {
+                None
+            };
+
+            // Get the scopes that were granted by the user; per RFC 6749,
+            // the provider only includes the `scope` field in the token
+            // response if the granted scopes differ from the requested
+            // scopes. Warn if the user did not grant all of the requested
+            // scopes, since the application will probably need to request
+            // (incremental) consent for those scopes later.
+            let requested_scopes =
+                self.requested_scopes(variant.as_deref(), return_to.as_deref(), &granted_scopes);
+            let granted_scopes = token_response
+                .scopes()
+                .cloned()
+                .unwrap_or_else(|| requested_scopes.clone());
+            let missing_scopes: Vec<&str> = requested_scopes
+                .iter()
+                .filter(|scope| !granted_scopes.contains(scope))
+                .map(|scope| scope.as_str())
+                .collect();
+            if !missing_scopes.is_empty() {
+                tide::log::warn!(
+                    "OpenID Connect provider did not grant all of the requested scopes; missing scopes: {}",
+                    missing_scopes.join(" ")
+                );
+            }
+
+            // Let the application run its own logic (or reject the
+            // user) before the session is authenticated.
+            if let Some(post_auth_hook) = &self.post_auth_hook {
+                let id_token_claims = serde_json::to_value(claims).map_err(|error| {
+                    tide::http::Error::new(StatusCode::InternalServerError, error)
+                })?;
+                if let Err(error) = post_auth_hook
+                    .on_authenticated(req.as_mut(), &id_token_claims)
+                    .await
+                {
+                    event!(warn, subject = %claims.subject().as_str(), error = %error, "Post-authentication hook failed.");
+                    let status = match error {
+                        OidcError::Rejected(_) => StatusCode::Forbidden,
+                        _ => StatusCode::InternalServerError,
+                    };
+                    return Err(tide::http::Error::new(status, error));
+                }
+            }
+
+            // Add the user id (and claims) to the session state in order
+            // to mark this session as authenticated.
+            let persisted_claims = self
+                .persisted_claims(claims)
+                .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?;
+            let expires_at = token_response
+                .expires_in()
+                .and_then(|expires_in| chrono::Duration::from_std(expires_in).ok())
+                .map(|expires_in| Utc::now() + expires_in);
+            let session_expires_at = match self.session_ttl {
+                SessionTtl::FromToken => expires_at,
+                SessionTtl::Fixed(ttl) => chrono::Duration::from_std(ttl)
+                    .ok()
+                    .and_then(|ttl| Utc::now().checked_add_signed(ttl)),
+            };
+            self.set_session_state(
+                req.session_mut(),
+                &MiddlewareSessionState::PostAuth {
+                    subject: claims.subject().clone(),
+                    access_token: token_response.access_token().clone(),
+                    scopes: granted_scopes,
+                    issued_at: claims.issue_time(),
+                    expires_at,
+                    claims: persisted_claims,
+                    userinfo,
+                    id_token: (self.idp_logout_id_token_hint || self.retain_id_token)
+                        .then(|| id_token.to_string()),
+                    session_expires_at,
+                    session_state: session_state.filter(|_| self.session_management.is_some()),
+                    completed_login: self.tolerate_duplicate_callback.then(|| {
+                        Box::new(CompletedLogin {
+                            state,
+                            completed_at: Utc::now(),
+                            return_to: return_to.clone(),
+                        })
+                    }),
+                    refresh_token: token_response.refresh_token().cloned(),
+                    step_up_path: return_to
+                        .as_deref()
+                        .map(return_to_path)
+                        .filter(|path| !self.route_scopes_at(path).is_empty())
+                        .map(str::to_string),
+                },
+            )?;
+            log_auth_event!(self.auth_event_levels, AuthEvent::SessionWritten, self.provider_name(), {
+                sub: claims.subject().as_str(),
+            });
+
+            // The user has logged in; redirect them to the page that they
+            // were trying to access, or to the main site.
+            Ok(Redirect::new(return_to.as_deref().unwrap_or(&self.login_landing_path)).into())
+        } else if let Some(location) = self.duplicate_callback_location(&mut req).await? {
+            Ok(Redirect::new(location).into())
+        } else {
+            tide::log::warn!(
+                "Missing OpenID Connect state in session; make sure SessionMiddleware is configured with {}.",
+                self.response_mode.session_cookie_advice()
+            );
+            Err(tide::http::Error::from_str(
+                StatusCode::InternalServerError,
+                "Missing authorization state.",
+            ))
+        }
+    }
+
+    /// Returns the location to which a replayed callback should be
+    /// redirected, if duplicate callbacks are tolerated and the
+    /// callback belongs to the (recently completed) login that
+    /// established the session.
+    async fn duplicate_callback_location<State>(
+        &self,
+        req: &mut Request<State>,
+    ) -> tide::Result<Option<String>>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        if !self.tolerate_duplicate_callback {
+            return Ok(None);
+        }
+        let completed_login = match self.session_state(req.session()) {
+            Some(MiddlewareSessionState::PostAuth {
+                completed_login: Some(completed_login),
+                ..
+            }) if completed_login.completed_at + DUPLICATE_CALLBACK_WINDOW > Utc::now() => {
+                completed_login
+            }
+            _ => return Ok(None),
+        };
+        let callback_data = self.authorization_response(req).await?;
+        if callback_data.state != completed_login.state {
+            return Ok(None);
+        }
+        event!(debug, state = %callback_data.state, "Ignoring duplicate callback for a completed login.");
+        Ok(Some(
+            completed_login
+                .return_to
+                .unwrap_or_else(|| self.login_landing_path.clone()),
+        ))
+    }
+
+    async fn handle_backchannel_logout<State>(&self, mut req: Request<State>) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let logout_handler = self.logout_handler.as_ref().ok_or_else(|| {
+            tide::log::error!(
+                "Back-channel logout path is configured, but no logout handler has been provided."
+            );
+            tide::http::Error::from_str(
+                StatusCode::NotImplemented,
+                "Back-channel logout is not supported.",
+            )
+        })?;
+
+        #[derive(Deserialize)]
+        struct BackchannelLogoutRequest {
+            logout_token: String,
+        }
+        let logout = match req.body_form::<BackchannelLogoutRequest>().await {
+            Ok(logout_request) => self.verify_logout_token(&logout_request.logout_token).await,
+            Err(error) => Err(error.to_string()),
+        };
+
+        // Per the spec, invalid logout requests get a `400 Bad Request`
+        // with an OAuth 2.0-style error response.
+        let target = match logout {
+            Ok(logout) => logout,
+            Err(error) => {
+                tide::log::warn!("Rejecting back-channel logout request: {}", error);
+                return Ok(tide::Response::builder(StatusCode::BadRequest)
+                    .header("Cache-Control", "no-store")
+                    .body(serde_json::json!({
+                        "error": "invalid_request",
+                        "error_description": error,
+                    }))
+                    .build());
+            }
+        };
+
+        // The pre-logout hook needs a subject, which logout tokens that
+        // only identify the provider session do not include.
+        match &target {
+            LogoutTarget::Subject(subject)
+            | LogoutTarget::Session {
+                sub: Some(subject), ..
+            } => self.run_pre_logout_hook(subject).await,
+            LogoutTarget::Session { sub: None, .. } => {}
+        }
+        match &target {
+            LogoutTarget::Session { sub, sid } => {
+                logout_handler
+                    .logout_by_session_id(sub.as_deref(), sid)
+                    .await?
+            }
+            LogoutTarget::Subject(subject) => logout_handler.logout_by_subject(subject).await?,
+        }
+        Ok(tide::Response::builder(StatusCode::Ok)
+            .header("Cache-Control", "no-store")
+            .build())
+    }
+
+    /// Returns `true` if the [logout handler](Self::with_logout_handler)
+    /// reports that the (authenticated) session has been logged out by
+    /// a back-channel logout.
+    async fn logged_out_by_provider(
+        &self,
+        session_state: &MiddlewareSessionState,
+    ) -> tide::Result<bool> {
+        let (logout_handler, subject, claims, issued_at) =
+            match (&self.logout_handler, session_state) {
+                (
+                    Some(logout_handler),
+                    MiddlewareSessionState::PostAuth {
+                        subject,
+                        claims,
+                        issued_at,
+                        ..
+                    },
+                ) => (logout_handler, subject, claims, issued_at),
+                _ => return Ok(false),
+            };
+        let sid = claims.get("sid").and_then(|sid| sid.as_str());
+        logout_handler
+            .is_logged_out(subject.as_str(), sid, *issued_at)
+            .await
+            .map_err(|error| {
+                tide::log::error!("Unable to check for back-channel logouts: {}", error);
+                error
+            })
+    }
+
+    /// Returns `true` if the session's access token has expired, but can
+    /// be refreshed. Sessions with a [fixed TTL](SessionTtl::Fixed) are
+    /// only refreshed until the session itself expires.
+    fn needs_refresh(&self, session_state: &MiddlewareSessionState) -> bool {
+        let now = Utc::now();
+        match session_state {
+            MiddlewareSessionState::PostAuth {
+                expires_at: Some(expires_at),
+                session_expires_at,
+                refresh_token: Some(_),
+                ..
+            } if *expires_at <= now => match self.session_ttl {
+                SessionTtl::FromToken => true,
+                SessionTtl::Fixed(_) => session_expires_at.is_none_or(|at| now < at),
+            },
+            _ => false,
+        }
+    }
+
+    /// Exchanges the session's refresh token for a new access token,
+    /// returning the updated session state. The provider may rotate the
+    /// refresh token; the current one is kept if it does not. Requests
+    /// that present the same refresh token at the same time share a
+    /// single refresh.
+    async fn refresh_session(
+        &self,
+        mut session_state: MiddlewareSessionState,
+    ) -> Result<MiddlewareSessionState, OidcError> {
+        let (access_token, scopes, expires_at, session_expires_at, refresh_token) =
+            match &mut session_state {
+                MiddlewareSessionState::PostAuth {
+                    access_token,
+                    scopes,
+                    expires_at,
+                    session_expires_at,
+                    refresh_token: Some(refresh_token),
+                    ..
+                } => (
+                    access_token,
+                    scopes,
+                    expires_at,
+                    session_expires_at,
+                    refresh_token,
+                ),
+                _ => return Ok(session_state),
+            };
+
+        let slot = self.token_refreshes.slot(refresh_token);
+        let mut refreshed = slot.lock().await;
+        let tokens = match &*refreshed {
+            Some((_, tokens)) => {
+                event!(
+                    debug,
+                    "Using the tokens of a concurrent refresh of the session."
+                );
+                tokens.clone()
+            }
+            None => match self.exchange_refresh_token(refresh_token).await {
+                Ok(tokens) => {
+                    *refreshed = Some((Instant::now(), tokens.clone()));
+                    tokens
+                }
+                Err(error) => {
+                    self.token_refreshes.forget(refresh_token);
+                    return Err(error);
+                }
+            },
+        };
+        drop(refreshed);
+
+        *access_token = tokens.access_token;
+        if let Some(granted_scopes) = tokens.scopes {
+            *scopes = granted_scopes;
+        }
+        *expires_at = tokens.expires_at;
+        if self.session_ttl == SessionTtl::FromToken {
+            *session_expires_at = *expires_at;
+        }
+        if let Some(rotated) = tokens.refresh_token {
+            *refresh_token = rotated;
+        }
+        Ok(session_state)
+    }
+
+    /// Exchanges a refresh token for new tokens.
+    async fn exchange_refresh_token(
+        &self,
+        refresh_token: &RefreshToken,
+    ) -> Result<RefreshedTokens, OidcError> {
+        let client = self.client()?;
+        let mut token_request = client.exchange_refresh_token(refresh_token);
+        let token_endpoint = self
+            .discovery
+            .metadata()?
+            .token_endpoint()
+            .map(|url| url.to_string())
+            .unwrap_or_default();
+        for (name, value) in self
+            .client_auth
+            .assertion_params(&self.client_id, &token_endpoint)
+            .map_err(OidcError::TokenRequest)?
+        {
+            token_request = token_request.add_extra_param(name, value);
+        }
+        let token_response = instrument!(
+            token_request.request_async(|request| {
+                self.http_client
+                    .request_with_dpop(self.dpop.as_ref(), request)
+            }),
+            "oidc.token_refresh",
+            issuer = %self.issuer_url.as_str(),
+            client_id = %self.client_id.as_str(),
+        )
+        .await
+        .map_err(|error| OidcError::TokenRequest(error.to_string()))?;
+
+        event!(debug, "Refreshed the session's access token.");
+        Ok(RefreshedTokens {
+            access_token: token_response.access_token().clone(),
+            scopes: token_response.scopes().cloned(),
+            expires_at: token_response
+                .expires_in()
+                .and_then(|expires_in| chrono::Duration::from_std(expires_in).ok())
+                .map(|expires_in| Utc::now() + expires_in),
+            refresh_token: token_response.refresh_token().cloned(),
+        })
+    }
+
+    /// Runs the pre-logout hook (if any) for the given subject; the
+    /// logout proceeds even if the hook fails.
+    async fn run_pre_logout_hook(&self, subject: &str) {
+        if let Some(pre_logout_hook) = &self.pre_logout_hook {
+            if let Err(error) = pre_logout_hook.before_logout(subject).await {
+                tide::log::warn!(
+                    "Pre-logout hook failed for {}; logging out anyway: {}",
+                    subject,
+                    error
+                );
+            }
+        }
+    }
+
+    /// Clears the session's authentication state and redirects the
+    /// browser according to the given logout mode.
+    async fn handle_logout<State>(
+        &self,
+        req: &mut Request<State>,
+        logout_mode: LogoutMode,
+    ) -> tide::Response
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        // Get the ID token (if any) before clearing the session, so
+        // that we can pass it to the identity provider.
+        let id_token = match self.session_state(req.session()) {
+            Some(MiddlewareSessionState::PostAuth {
+                subject, id_token, ..
+            }) => {
+                self.run_pre_logout_hook(subject.as_str()).await;
+                id_token
+            }
+            _ => None,
+        };
+
+        // Destroy the session as part of the logout, or clear only
+        // the app state, depending on how the middleware has been
+        // configured.
+        self.clear_session(req);
+
+        // Redirect the user now that their authentication state has
+        // been cleared; we send them either to the identity provider's
+        // logout URL (if provided), or to the app's logout landing
+        // path if the app is not configured to log the user out of
+        // the identity provider (or if this is a local-only logout).
+        match (&self.idp_logout_url, logout_mode) {
+            (Some(idp_logout_url), LogoutMode::IdpInitiated) => {
+                let mut idp_logout_url = match id_token.filter(|_| self.idp_logout_id_token_hint) {
+                    Some(id_token) => {
+                        append_query_param(idp_logout_url, "id_token_hint", &id_token)
+                    }
+                    None => idp_logout_url.clone(),
+                };
+
+                // Ask the provider to return the browser to us (with a
+                // state that we can verify) once it has logged the user
+                // out, if we handle that return.
+                let logout_state = match &self.post_logout_redirect_url {
+                    Some(post_logout_redirect_url) => {
+                        let logout_state = CsrfToken::new_random();
+                        idp_logout_url = append_query_param(
+                            &append_query_param(
+                                &idp_logout_url,
+                                "post_logout_redirect_uri",
+                                post_logout_redirect_url.as_str(),
+                            ),
+                            "state",
+                            logout_state.secret(),
+                        );
+                        Some(logout_state)
+                    }
+                    None => None,
+                };
+
+                let mut res: tide::Response = Redirect::new(idp_logout_url).into();
+                if let Some(logout_state) = logout_state {
+                    res.insert_cookie(self.logout_state_cookie(logout_state.secret().clone()));
+                }
+                res
+            }
+            _ => Redirect::new(&self.logout_landing_path).into(),
+        }
+    }
+
+    /// Returns the name of the [logout state
+    /// cookie](Self::logout_state_cookie).
+    fn logout_state_cookie_name(&self) -> String {
+        format!("{}.logout", self.session_key)
+    }
+
+    /// Returns the cookie in which the `state` of an RP-initiated
+    /// logout is kept until the provider returns the browser to the
+    /// [post-logout redirect URL](Config::post_logout_redirect_url).
     ///
-    /// Defaults to [`HttpRedirect`](crate::redirect_strategy::HttpRedirect)
-    pub fn with_unauthenticated_redirect_strategy<R>(mut self, redirect_strategy: R) -> Self
+    /// The state cannot be kept in the session, which the logout may
+    /// destroy. The cookie is scoped to the [configured
+    /// domain](Self::with_cookie_domain) and
+    /// [path](Self::with_cookie_path), which defaults to the path of the
+    /// post-logout redirect URL (the only request that needs it), and is
+    /// `Secure` whenever that URL is an `https` URL. The same attributes
+    /// are used to remove the cookie again, so that browsers match it.
+    fn logout_state_cookie(&self, logout_state: String) -> Cookie<'static> {
+        let (default_path, secure) = match &self.post_logout_redirect_url {
+            Some(url) => (url.url().path().to_string(), url.url().scheme() == "https"),
+            None => ("/".to_string(), false),
+        };
+        let mut cookie = Cookie::build(self.logout_state_cookie_name(), logout_state)
+            .path(self.cookie_path.clone().unwrap_or(default_path))
+            .http_only(true)
+            .secure(secure)
+            .same_site(SameSite::Lax)
+            .finish();
+        if let Some(domain) = &self.cookie_domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+
+    /// Returns whether the browser has started an RP-initiated logout
+    /// (with this middleware) that has not yet returned to the
+    /// [post-logout redirect URL](Config::post_logout_redirect_url).
+    pub(crate) fn has_pending_logout<State>(&self, req: &Request<State>) -> bool
     where
-        R: RedirectStrategy + 'static,
+        State: Clone + Send + Sync + 'static,
     {
-        self.redirect_strategy = Arc::new(redirect_strategy);
-        self
+        req.cookie(&self.logout_state_cookie_name()).is_some()
     }
 
-    async fn generate_redirect<State>(&self, mut req: Request<State>) -> tide::Result
+    /// Completes an RP-initiated logout when the provider returns the
+    /// browser to the [post-logout redirect
+    /// URL](Config::post_logout_redirect_url), verifying the logout
+    /// `state` before redirecting the browser to the logout landing
+    /// path.
+    fn handle_post_logout<State>(&self, req: Request<State>) -> tide::Result
     where
         State: Clone + Send + Sync + 'static,
     {
-        let mut request = self.client.authorize_url(
-            AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
-            CsrfToken::new_random,
-            Nonce::new_random,
-        );
-        for s in &self.scopes {
-            request = request.add_scope(s.clone());
-        }
-        let (authorize_url, csrf_token, nonce) = request.url();
-
-        // Initialize the middleware's session state so that we can
-        // validate the login after the user completes the authentication
-        // flow.
-        req.session_mut()
-            .insert(
-                SESSION_KEY,
-                MiddlewareSessionState::PreAuth(csrf_token, nonce),
+        #[derive(Deserialize)]
+        struct PostLogoutRequest {
+            state: Option<String>,
+        }
+        let post_logout_request: PostLogoutRequest = req.query()?;
+
+        // The state is optional (not every provider returns it), but
+        // must match the logout if either the logout or the return
+        // includes one.
+        let cookie = req.cookie(&self.logout_state_cookie_name());
+        let expected_state = cookie.as_ref().map(|cookie| cookie.value());
+        if post_logout_request.state.as_deref() != expected_state {
+            tide::log::warn!("Rejecting post-logout return with invalid state.");
+            return Err(tide::http::Error::from_str(
+                StatusCode::BadRequest,
+                "Invalid logout state.",
+            ));
+        }
+
+        let mut res: tide::Response = Redirect::new(&self.logout_landing_path).into();
+        if cookie.is_some() {
+            res.remove_cookie(self.logout_state_cookie(String::new()));
+        }
+        Ok(res)
+    }
+
+    /// Returns the session management script for the current session.
+    fn handle_session_check<State>(&self, req: &Request<State>) -> tide::Response
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let metadata = self.discovery.metadata().ok();
+        let check_session_iframe = metadata
+            .as_ref()
+            .and_then(|metadata| metadata.additional_metadata().check_session_iframe.as_ref());
+        let session_state = match self.session_state(req.session()) {
+            Some(MiddlewareSessionState::PostAuth { session_state, .. }) => session_state,
+            _ => None,
+        };
+        let poll_interval = self
+            .session_management
+            .as_ref()
+            .map(|config| config.poll_interval)
+            .unwrap_or_default();
+        script_response(match (check_session_iframe, &session_state) {
+            (Some(check_session_iframe), Some(session_state)) => Some(SessionCheck {
+                check_session_iframe: check_session_iframe.as_str(),
+                client_id: self.client_id.as_str(),
+                session_state,
+                reauthenticate_path: self
+                    .silent_login_path
+                    .as_deref()
+                    .unwrap_or(&self.login_path),
+                poll_interval,
+            }),
+            _ => None,
+        })
+    }
+
+    /// Logs out the current session if it belongs to the provider
+    /// session identified in the front-channel logout request.
+    fn handle_frontchannel_logout<State>(&self, mut req: Request<State>) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        let logout_request: FrontchannelLogoutRequest = req.query()?;
+        if matches!(&logout_request.iss, Some(iss) if !self.issuer_verification.matches(&self.issuer_url, iss))
+        {
+            tide::log::warn!("Rejecting front-channel logout request from unknown issuer.");
+            return Err(tide::http::Error::from_str(
+                StatusCode::BadRequest,
+                "Invalid issuer.",
+            ));
+        }
+
+        // Only log out the session if it is the one that the provider
+        // is logging out (or if the provider did not say which session
+        // it is logging out).
+        if let Some(MiddlewareSessionState::PostAuth { claims, .. }) =
+            self.session_state(req.session())
+        {
+            let session_sid = claims.get("sid").and_then(|sid| sid.as_str());
+            if logout_request.sid.is_none() || logout_request.sid.as_deref() == session_sid {
+                self.clear_session(&mut req);
+            }
+        }
+
+        Ok(pixel_response())
+    }
+
+    /// Saves a pending login to the state store.
+    async fn save_pending_auth(
+        &self,
+        state_store: &dyn OidcStateStore,
+        pending_auth: &PendingAuth,
+    ) -> tide::Result<()> {
+        let state = pending_auth.csrf_token.secret();
+        let data = serde_json::to_string(&StoredAuth {
+            redirect_url: pending_auth.redirect_url.clone(),
+            silent: pending_auth.silent,
+            return_to: pending_auth.return_to.clone(),
+            variant: pending_auth.variant.clone(),
+            granted_scopes: pending_auth.granted_scopes.clone(),
+        })?;
+        state_store
+            .save_state(state, &data, PENDING_AUTH_TTL)
+            .await
+            .and(
+                state_store
+                    .save_nonce(state, pending_auth.nonce.secret(), PENDING_AUTH_TTL)
+                    .await,
             )
-            .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?;
+            .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))
+    }
 
-        Ok(Redirect::new(&authorize_url).into())
+    /// Removes the login attempt with the given CSRF state from the
+    /// pending logins (and from the state store, if the login was kept
+    /// there), returning the login attempt and the remaining pending
+    /// logins. Returns `None` if there is no such login attempt.
+    async fn take_pending_auth(
+        &self,
+        pre_auth: MiddlewareSessionState,
+        state: &str,
+    ) -> tide::Result<Option<(PendingAuth, MiddlewareSessionState)>> {
+        match (pre_auth, &self.state_store) {
+            (MiddlewareSessionState::PreAuth { mut pending }, _) => Ok(pending
+                .iter()
+                .position(|p| p.csrf_token.secret() == state)
+                .map(|index| {
+                    let pending_auth = pending.remove(index);
+                    (pending_auth, MiddlewareSessionState::PreAuth { pending })
+                })),
+            (MiddlewareSessionState::PreAuthStored { mut states }, Some(state_store)) => {
+                let index = match states.iter().position(|s| s.secret() == state) {
+                    Some(index) => index,
+                    None => return Ok(None),
+                };
+                let csrf_token = states.remove(index);
+                let to_server_error =
+                    |error| tide::http::Error::new(StatusCode::InternalServerError, error);
+                let (data, nonce) = state_store.take(state).await.map_err(to_server_error)?;
+
+                // The login may have expired (or been deleted) in the
+                // meantime.
+                let (stored, nonce) = match (data, nonce) {
+                    (Some(data), Some(nonce)) => {
+                        (serde_json::from_str::<StoredAuth>(&data)?, nonce)
+                    }
+                    _ => return Ok(None),
+                };
+                Ok(Some((
+                    PendingAuth {
+                        csrf_token,
+                        nonce: Nonce::new(nonce),
+                        redirect_url: stored.redirect_url,
+                        silent: stored.silent,
+                        return_to: stored.return_to,
+                        variant: stored.variant,
+                        granted_scopes: stored.granted_scopes,
+                    },
+                    MiddlewareSessionState::PreAuthStored { states },
+                )))
+            }
+            // The state store has been removed since the login started.
+            _ => Ok(None),
+        }
     }
 
-    async fn handle_callback<State>(&self, mut req: Request<State>) -> tide::Result
+    /// Returns `true` if the session has a pending login with the given
+    /// CSRF state (or any pending login, if the state is not known), or
+    /// has just completed that login.
+    pub(crate) fn has_pending_login(&self, session: &Session, state: Option<&str>) -> bool {
+        let matches = |csrf_token: &CsrfToken| state.is_none_or(|s| csrf_token.secret() == s);
+        match self.session_state(session) {
+            Some(MiddlewareSessionState::PreAuth { pending }) => {
+                pending.iter().any(|p| matches(&p.csrf_token))
+            }
+            Some(MiddlewareSessionState::PreAuthStored { states }) => states.iter().any(matches),
+            Some(MiddlewareSessionState::PostAuth {
+                completed_login: Some(completed_login),
+                ..
+            }) => state == Some(completed_login.state.as_str()),
+            _ => false,
+        }
+    }
+
+    /// Returns `true` if the session has been authenticated by this
+    /// middleware.
+    pub(crate) fn has_authenticated_session(&self, session: &Session) -> bool {
+        matches!(
+            self.session_state(session),
+            Some(MiddlewareSessionState::PostAuth { .. })
+        )
+    }
+
+    /// Gets the middleware's state from the session, decrypting it if
+    /// [session encryption](Config::session_encryption_keys) has been
+    /// enabled. Returns `None` if the state is missing or could not be
+    /// decrypted.
+    fn session_state(&self, session: &Session) -> Option<MiddlewareSessionState> {
+        let session_encryption = match &self.session_encryption {
+            Some(session_encryption) => session_encryption,
+            None => return session.get(&self.session_key),
+        };
+
+        let payload: String = session.get(&self.session_key)?;
+        match session_encryption.decrypt(self.session_key.as_bytes(), &payload) {
+            Some(plaintext) => serde_json::from_slice(&plaintext).ok(),
+            None => {
+                tide::log::warn!(
+                    "Unable to decrypt OpenID Connect session state; treating the session as unauthenticated."
+                );
+                None
+            }
+        }
+    }
+
+    /// Stores the middleware's state in the session, encrypting it if
+    /// [session encryption](Config::session_encryption_keys) has been
+    /// enabled.
+    fn set_session_state(
+        &self,
+        session: &mut Session,
+        state: &MiddlewareSessionState,
+    ) -> tide::Result<()> {
+        match &self.session_encryption {
+            Some(session_encryption) => {
+                let plaintext = serde_json::to_vec(state).map_err(|error| {
+                    tide::http::Error::new(StatusCode::InternalServerError, error)
+                })?;
+                let payload = session_encryption
+                    .encrypt(self.session_key.as_bytes(), &plaintext)
+                    .map_err(|error| {
+                        tide::http::Error::from_str(StatusCode::InternalServerError, error)
+                    })?;
+                session.insert(&self.session_key, payload)
+            }
+            None => session.insert(&self.session_key, state),
+        }
+        .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))
+    }
+
+    /// Destroys the session, or clears only the auth state, depending
+    /// on how the middleware has been configured.
+    fn clear_session<State>(&self, req: &mut Request<State>)
     where
         State: Clone + Send + Sync + 'static,
     {
-        // Get the middleware state from the session. If this fails then
-        // A) the browser got to the callback URL without actually going
-        // through the auth process, or B) more likely, the session
-        // middleware is configured with Strict cookies instead of Lax
-        // cookies. We cannot tell at this level which error occurred,
-        // so we just reject the request and log the error.
-        if let Some(MiddlewareSessionState::PreAuth(csrf_token, nonce)) =
-            req.session().get(SESSION_KEY)
+        if self.logout_destroys_session {
+            req.session_mut().destroy();
+        } else {
+            req.session_mut().remove(&self.session_key);
+        }
+    }
+
+    /// Verifies the logout token, returning the sessions that must be
+    /// invalidated. Logout tokens are not ID tokens (they need not
+    /// include a subject), and so are verified as JWTs, with the same
+    /// keys, algorithms, issuer and audiences as ID tokens.
+    async fn verify_logout_token(&self, logout_token: &str) -> Result<LogoutTarget, String> {
+        let allowed_algs = self.id_token_signing_algs();
+        let verify = || {
+            if self.verifier_config.skip_signature_check {
+                unverified_claims(logout_token)
+            } else {
+                verify_signature(logout_token, &self.jwks.keys(), &allowed_algs)
+            }
+        };
+        let claims = match verify() {
+            Err(JwtError::UnknownKey) if self.jwks.refresh().await => verify(),
+            result => result,
+        }
+        .map_err(|error| error.to_string())?;
+
+        let audiences: Vec<&str> = std::iter::once(self.client_id.as_str())
+            .chain(self.additional_audiences.iter().map(String::as_str))
+            .collect();
+        verify_registered_claims(
+            &claims,
+            (!self.verifier_config.skip_issuer_check)
+                .then_some((&self.issuer_url, self.issuer_verification)),
+            &audiences,
+            self.clock_skew_tolerance,
+        )
+        .map_err(|error| error.to_string())?;
+
+        let claims: LogoutTokenClaims =
+            serde_json::from_value(serde_json::Value::Object(claims))
+                .map_err(|error| format!("Invalid logout token claims: {}", error))?;
+        // Logout tokens must *not* include a nonce.
+        if claims.has_nonce() && !self.verifier_config.skip_nonce_check {
+            return Err("Logout token must not contain a nonce.".to_string());
+        }
+        self.logout_token_ids.verify(claims)
+    }
+}
+
+/// Adds a query parameter to a landing path (or URL), preserving any
+/// query string and fragment that are already part of the path.
+fn append_query_param(path: &str, name: &str, value: &str) -> String {
+    let (path, fragment) = match path.find('#') {
+        Some(index) => path.split_at(index),
+        None => (path, ""),
+    };
+    let separator = match path.find('?') {
+        Some(index) if index + 1 < path.len() && !path.ends_with('&') => "&",
+        Some(_) => "",
+        None => "?",
+    };
+    let param: String = openidconnect::url::form_urlencoded::Serializer::new(String::new())
+        .append_pair(name, value)
+        .finish();
+    format!("{}{}{}{}", path, separator, param, fragment)
+}
+
+/// Returns the `Accept-Language` header value for the given languages
+/// (most preferred first), with decreasing quality values.
+fn accept_language(locales: &[String]) -> Option<http::HeaderValue> {
+    let value = locales
+        .iter()
+        .enumerate()
+        .map(|(index, locale)| match index {
+            0 => locale.clone(),
+            _ => format!("{};q={:.1}", locale, (10 - index.min(9)) as f32 / 10.0),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    if value.is_empty() {
+        return None;
+    }
+    match http::HeaderValue::from_str(&value) {
+        Ok(value) => Some(value),
+        Err(_) => {
+            tide::log::warn!("Ignoring invalid claims locales: {}", value);
+            None
+        }
+    }
+}
+
+/// Returns the `return_to` query parameter of a login request if it is a
+/// local path, which prevents the parameter from being used to redirect
+/// the browser to another site after logging in.
+fn safe_return_to(url: &Url) -> Option<String> {
+    url.query_pairs()
+        .find(|(name, _)| name == "return_to")
+        .map(|(_, value)| value.into_owned())
+        .filter(|return_to| {
+            return_to.starts_with('/')
+                && !return_to.starts_with("//")
+                && !return_to.starts_with("/\\")
+                && !return_to.chars().any(char::is_control)
+        })
+}
+
+/// Returns the path of a (validated) `return_to` target, without its
+/// query string and fragment.
+fn return_to_path(return_to: &str) -> &str {
+    return_to.split(['?', '#']).next().unwrap_or_default()
+}
+
+/// Default response to requests that lack a required scope.
+fn insufficient_scope_response(missing_scopes: &[String]) -> tide::Response {
+    tide::Response::builder(StatusCode::Forbidden)
+        .body(serde_json::json!({
+            "error": "insufficient_scope",
+            "error_description": OidcError::InsufficientScope(missing_scopes.to_vec()).to_string(),
+            "scope": missing_scopes.join(" "),
+        }))
+        .build()
+}
+
+/// Default response to requests that are denied by a claims predicate
+/// (or the authorization policy), which names the missing claim if
+/// there is one.
+fn access_denied_response(reason: &OidcError) -> tide::Response {
+    let mut body = serde_json::json!({
+        "error": "access_denied",
+        "error_description": reason.to_string(),
+    });
+    if let OidcError::RequiredClaim(claim) = reason {
+        body["claim"] = serde_json::json!(claim);
+    }
+    tide::Response::builder(StatusCode::Forbidden)
+        .body(body)
+        .build()
+}
+
+#[tide::utils::async_trait]
+impl<AC> LoginUrlBuilder for OpenIdConnectMiddleware<AC>
+where
+    AC: AdditionalClaims,
+{
+    async fn build_login_url(&self, req: &mut tide::http::Request) -> tide::Result<String> {
+        Ok(self.authorize_url(req, false, None).await?.to_string())
+    }
+}
+
+#[tide::utils::async_trait]
+impl<AC> SessionRefresher for OpenIdConnectMiddleware<AC>
+where
+    AC: AdditionalClaims,
+{
+    async fn refresh(&self, req: &mut tide::http::Request) -> tide::Result<Option<tide::Response>> {
+        let session_state = match req
+            .ext()
+            .get::<Session>()
+            .and_then(|s| self.session_state(s))
         {
-            // Extract the OpenID callback information and verify the CSRF
-            // state.
-            #[derive(Deserialize)]
-            struct OpenIdCallback {
-                code: AuthorizationCode,
-                state: String,
-            }
-            let callback_data: OpenIdCallback = req.query()?;
-            if &callback_data.state != csrf_token.secret() {
-                return Err(tide::http::Error::from_str(
-                    StatusCode::Unauthorized,
-                    "Invalid CSRF state.",
+            Some(session_state) if self.needs_refresh(&session_state) => session_state,
+            _ => return Ok(None),
+        };
+        let session = req
+            .ext_mut()
+            .get_mut::<Session>()
+            .expect("Sessions are checked by the middleware.");
+        match self.refresh_session(session_state).await {
+            Ok(session_state) => {
+                self.set_session_state(session, &session_state)?;
+                if let Some(auth_state) = self.authenticated_state(session_state) {
+                    req.ext_mut().insert(auth_state);
+                }
+                Ok(None)
+            }
+            Err(error) => {
+                event!(warn, error = %error, "Unable to refresh the session's tokens.");
+                session.remove(&self.session_key);
+                req.ext_mut().insert(RejectedCredentials::new(
+                    "The session could not be refreshed.",
                 ));
+                req.ext_mut()
+                    .insert(OpenIdConnectRequestExtData::Unauthenticated {
+                        redirect_strategy: self.redirect_strategy.clone(),
+                        login_path: self.login_path.clone(),
+                    });
+                self.token_refresh_failed_handler()
+                    .handle(req, error)
+                    .await
+                    .map(Some)
             }
+        }
+    }
+}
 
-            // Exchange the code for a token.
-            let token_response = self
-                .client
-                .exchange_code(callback_data.code)
-                .request_async(http_client)
-                .await
-                .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?;
+/// Panics unless at least one scope is requested, and none of the
+/// scopes is empty (which would corrupt the `scope` parameter).
+fn assert_valid_scopes(scopes: &[impl AsRef<str>]) {
+    assert!(
+        !scopes.is_empty() && scopes.iter().all(|scope| !scope.as_ref().trim().is_empty()),
+        "at least one scope must be requested, and scopes must not be empty"
+    );
+}
 
-            // Get the claims and verify the nonce.
-            let claims = token_response
-                .extra_fields()
-                .id_token()
-                .ok_or_else(|| {
-                    tide::http::Error::from_str(
-                        StatusCode::InternalServerError,
-                        "OpenID Connect server did not return an ID token.",
-                    )
-                })?
-                .claims(&self.client.id_token_verifier(), &nonce)
-                .map_err(|error| tide::http::Error::new(StatusCode::Unauthorized, error))?;
-
-            // Add the user id to the session state in order to mark this
-            // session as authenticated.
-            req.session_mut()
-                .insert(
-                    SESSION_KEY,
-                    MiddlewareSessionState::PostAuth(
-                        claims.subject().clone(),
-                        token_response.access_token().clone(),
-                        token_response.scopes().unwrap_or(&self.scopes).clone(),
-                    ),
-                )
-                .map_err(|error| tide::http::Error::new(StatusCode::InternalServerError, error))?;
+pub(crate) fn allowed_id_token_signing_algs(
+    algs: &[CoreJwsSigningAlgorithm],
+) -> Vec<CoreJwsSigningAlgorithm> {
+    algs.iter()
+        .filter(|alg| ALLOWED_ID_TOKEN_SIGNING_ALGS.contains(alg))
+        .cloned()
+        .collect()
+}
 
-            // The user has logged in; redirect them to the main site.
-            Ok(Redirect::new(&self.login_landing_path).into())
+impl<AC> OpenIdConnectMiddleware<AC>
+where
+    AC: AdditionalClaims,
+{
+    /// Returns the middleware route (login, callback, logout, etc.) at
+    /// which the request is handled by the middleware itself, if any.
+    pub(crate) fn route<State>(&self, req: &Request<State>) -> Option<MiddlewareRoute<'_>> {
+        let method = req.method();
+        let path = req.url().path();
+        if method == Method::Get && path == self.login_path {
+            Some(MiddlewareRoute::Login {
+                silent: false,
+                variant: None,
+            })
+        } else if method == Method::Get && self.silent_login_path.as_deref() == Some(path) {
+            Some(MiddlewareRoute::Login {
+                silent: true,
+                variant: None,
+            })
+        } else if let Some(variant) = self
+            .login_variant_at(path)
+            .filter(|_| method == Method::Get)
+        {
+            Some(MiddlewareRoute::Login {
+                silent: false,
+                variant: Some(variant),
+            })
+        } else if method == self.response_mode.callback_method()
+            && path == self.redirect_url.url().path()
+        {
+            Some(MiddlewareRoute::Callback)
+        } else if method == Method::Post && self.backchannel_logout_path.as_deref() == Some(path) {
+            Some(MiddlewareRoute::BackchannelLogout)
+        } else if method == Method::Get && self.frontchannel_logout_path.as_deref() == Some(path) {
+            Some(MiddlewareRoute::FrontchannelLogout)
+        } else if method == Method::Get
+            && self
+                .session_management
+                .as_ref()
+                .is_some_and(|config| config.script_path == path)
+        {
+            Some(MiddlewareRoute::SessionCheck)
+        } else if method == Method::Get && path == self.logout_path {
+            Some(MiddlewareRoute::Logout(self.logout_mode))
+        } else if method == Method::Get && self.local_logout_path.as_deref() == Some(path) {
+            Some(MiddlewareRoute::Logout(LogoutMode::LocalOnly))
+        } else if method == Method::Get
+            && self
+                .post_logout_redirect_url
+                .as_ref()
+                .is_some_and(|url| url.url().path() == path)
+        {
+            Some(MiddlewareRoute::PostLogout)
         } else {
+            None
+        }
+    }
+
+    /// Handles a request at one of the middleware's own
+    /// [routes](Self::route).
+    pub(crate) async fn handle_route<State>(
+        &self,
+        route: MiddlewareRoute<'_>,
+        mut req: Request<State>,
+    ) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        match route {
+            MiddlewareRoute::Login { silent, variant } => {
+                instrument!(
+                    self.generate_redirect(req, silent, variant),
+                    "oidc.login",
+                    issuer = %self.issuer_url.as_str(),
+                    client_id = %self.client_id.as_str(),
+                )
+                .await
+            }
+            MiddlewareRoute::Callback => {
+                instrument!(
+                    self.handle_callback(req),
+                    "oidc.callback",
+                    issuer = %self.issuer_url.as_str(),
+                    client_id = %self.client_id.as_str(),
+                )
+                .await
+            }
+            MiddlewareRoute::BackchannelLogout => self.handle_backchannel_logout(req).await,
+            MiddlewareRoute::FrontchannelLogout => self.handle_frontchannel_logout(req),
+            MiddlewareRoute::SessionCheck => Ok(self.handle_session_check(&req)),
+            MiddlewareRoute::Logout(logout_mode) => {
+                Ok(self.handle_logout(&mut req, logout_mode).await)
+            }
+            MiddlewareRoute::PostLogout => self.handle_post_logout(req),
+        }
+    }
+
+    /// Returns a `503 Service Unavailable` response if the provider
+    /// metadata has not been loaded (and cannot be loaded now), which
+    /// only happens to a lazily-created middleware.
+    pub(crate) async fn unavailable_response(&self) -> Option<tide::Response> {
+        // A lazily-created middleware that was supposed to verify the
+        // provider at startup (but was never given the chance) does so
+        // now, by loading the metadata.
+        if self.verify_on_startup && !self.provider_verified.swap(true, Ordering::Relaxed) {
             tide::log::warn!(
-                    "Missing OpenID Connect state in session; make sure SessionMiddleware is configured with SameSite::Lax (but do *not* mutate server-side state on GET requests if you make that change!)."
+                "verify_on_startup is enabled, but the application was not started with startup_listener(); the OpenID Connect provider is verified by the first request instead."
+            );
+        }
+        self.discovery.ensure_loaded(&self.jwks).await.err()?;
+        Some(provider_unavailable_response())
+    }
+
+    /// Augments a request that is passed on to the application with its
+    /// authentication status, returning the response if the request is
+    /// denied by the authorization policy.
+    pub(crate) async fn authenticate<State>(
+        &self,
+        req: &mut Request<State>,
+    ) -> tide::Result<Option<tide::Response>>
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        // Get the middleware's session state (which will *not* be
+        // present if the browser has not yet gone through the auth
+        // process), then augment the request with the authentication
+        // status. Note that we must not replace the authentication
+        // status set by another instance of the middleware (for a
+        // different provider) unless we have actually authenticated
+        // the request.
+        let session_state = match self.session_state(req.session()) {
+            // Expired sessions are no longer authenticated, unless their
+            // access token can still be refreshed (which is left to the
+            // routes that require authentication).
+            Some(
+                session_state @ MiddlewareSessionState::PostAuth {
+                    session_expires_at: Some(session_expires_at),
+                    ..
+                },
+            ) if session_expires_at <= Utc::now() && !self.needs_refresh(&session_state) => {
+                event!(debug, "Authenticated session has expired.");
+                req.session_mut().remove(&self.session_key);
+                req.set_ext(RejectedCredentials::new("The session has expired."));
+                None
+            }
+            // As are sessions that the provider has logged out.
+            Some(session_state) if self.logged_out_by_provider(&session_state).await? => {
+                event!(
+                    debug,
+                    "Authenticated session has been logged out by the provider."
                 );
-            Err(tide::http::Error::from_str(
-                StatusCode::InternalServerError,
-                "Missing authorization state.",
-            ))
+                req.session_mut().remove(&self.session_key);
+                req.set_ext(RejectedCredentials::new("The session has been logged out."));
+                None
+            }
+            session_state => session_state,
+        };
+        let route_scopes = self.route_scopes_at(req.url().path());
+        let step_up_attempted = matches!(
+            &session_state,
+            Some(MiddlewareSessionState::PostAuth {
+                step_up_path: Some(step_up_path),
+                ..
+            }) if step_up_path == req.url().path()
+        );
+        let authenticated_here = match session_state.and_then(|s| self.authenticated_state(s)) {
+            Some(auth_state) => {
+                req.set_ext(auth_state);
+
+                // Routes with their own scopes require authentication,
+                // and so get a fresh access token (and its scopes).
+                if !route_scopes.is_empty() {
+                    if let Some(res) = refresh_expired_session(req).await? {
+                        return Ok(Some(res));
+                    }
+                }
+                true
+            }
+            _ if req.ext::<OpenIdConnectRequestExtData>().is_some() => false,
+            _ => {
+                // Fall back to the request's bearer token (if any and
+                // if enabled) when there is no authenticated session.
+                let auth_state = match bearer_token(req).filter(|_| self.bearer_fallback) {
+                    Some(token) => {
+                        self.bearer_middleware()
+                            .fallback_auth_state(token, remote_ip(req.as_ref()))
+                            .await
+                    }
+                    None => OpenIdConnectRequestExtData::Unauthenticated {
+                        redirect_strategy: self.redirect_strategy.clone(),
+                        login_path: self.login_path.clone(),
+                    },
+                };
+                req.set_ext(auth_state);
+                true
+            }
+        };
+
+        // Enforce the authorization policy on the requests that
+        // this middleware authenticated.
+        if authenticated_here {
+            if let Some(reason) = self.authorization_policy.failure(req.auth_state()) {
+                event!(
+                    debug,
+                    "Authenticated request failed the authorization policy: {}",
+                    reason
+                );
+                return self
+                    .access_denied_handler()
+                    .handle(req.as_ref(), reason)
+                    .await
+                    .map(Some);
+            }
+        }
+
+        // Sessions that lack the scopes of the requested route log in
+        // again, requesting those scopes as well. That only works for
+        // navigations, and only once: bearer tokens, requests that
+        // cannot be redirected, and sessions whose login did not obtain
+        // the scopes are denied instead.
+        let (missing_route_scopes, from_session) = match req.ext::<OpenIdConnectRequestExtData>() {
+            Some(OpenIdConnectRequestExtData::Authenticated {
+                auth_info,
+                session_key,
+                ..
+            }) if authenticated_here => (
+                route_scopes
+                    .iter()
+                    .filter(|scope| {
+                        !auth_info
+                            .session
+                            .scopes
+                            .iter()
+                            .any(|granted| granted == scope.as_str())
+                    })
+                    .map(|scope| scope.to_string())
+                    .collect::<Vec<_>>(),
+                session_key.is_some(),
+            ),
+            _ => (vec![], false),
+        };
+        if !missing_route_scopes.is_empty() {
+            if from_session
+                && !step_up_attempted
+                && matches!(req.method(), Method::Get | Method::Head)
+            {
+                return Ok(Some(self.route_scopes_redirect(req.url())));
+            }
+            event!(
+                debug,
+                path = %req.url().path(),
+                "Request lacks the scopes of the requested route: {}",
+                missing_route_scopes.join(" ")
+            );
+
+            // Tell API clients which scopes the route requires, unless
+            // the application has done so itself.
+            let required_scopes: Vec<String> =
+                route_scopes.iter().map(|scope| scope.to_string()).collect();
+            let mut res = self
+                .access_denied_handler()
+                .handle(
+                    req.as_ref(),
+                    OidcError::InsufficientScope(missing_route_scopes),
+                )
+                .await?;
+            if res.status() == StatusCode::Forbidden && res.header(WWW_AUTHENTICATE).is_none() {
+                res.insert_header(
+                    WWW_AUTHENTICATE,
+                    bearer_challenge(
+                        Some(&self.realm),
+                        Some(ChallengeError::InsufficientScope(&required_scopes)),
+                    ),
+                );
+            }
+            return Ok(Some(res));
+        }
+
+        Ok(None)
+    }
+
+    /// Redirects a request for a route with [per-route
+    /// scopes](Self::with_per_route_scopes) to the login path, which
+    /// then returns the browser to the same route.
+    fn route_scopes_redirect(&self, url: &Url) -> tide::Response {
+        let mut return_to = url.path().to_string();
+        if let Some(query) = url.query() {
+            return_to.push('?');
+            return_to.push_str(query);
+        }
+        let location = format!(
+            "{}?{}",
+            self.login_path,
+            openidconnect::url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("return_to", &return_to)
+                .finish()
+        );
+        event!(
+            debug,
+            path = %url.path(),
+            "Session lacks the scopes of the requested route; redirecting browser to {}.",
+            location
+        );
+        Redirect::see_other(location).into()
+    }
+
+    /// Passes an (authenticated) request on to the application.
+    pub(crate) async fn run_next<State>(
+        &self,
+        mut req: Request<State>,
+        next: Next<'_, State>,
+    ) -> tide::Result
+    where
+        State: Clone + Send + Sync + 'static,
+    {
+        // Allow handlers to build login URLs of their own.
+        let login_url_builder: Arc<dyn LoginUrlBuilder> = self.shared();
+        req.set_ext(LoginUrlBuilderHandle(login_url_builder));
+
+        // Call the downstream middleware, turning any `LoginRequired`
+        // errors returned by handlers into redirects.
+        let mut res = next.run(req).await;
+        if let Some(LoginRequired { location }) = res.downcast_error::<LoginRequired>() {
+            let location = location.clone();
+            res.insert_header(tide::http::headers::LOCATION, location);
         }
+        Ok(res)
+    }
+}
+
+/// Returns the `503 Service Unavailable` response to requests that
+/// cannot be handled until the provider metadata has been loaded.
+pub(crate) fn provider_unavailable_response() -> tide::Response {
+    tide::Response::builder(StatusCode::ServiceUnavailable)
+        .body(OidcError::ProviderUnavailable.to_string())
+        .build()
+}
+
+/// Returns an error if the request has no session, which the middleware
+/// requires.
+pub(crate) fn require_session<State>(req: &Request<State>) -> tide::Result<()> {
+    if req.ext::<Session>().is_none() {
+        tide::log::error!("{}", OidcError::MissingSessionMiddleware);
+        return Err(tide::Error::new(
+            StatusCode::InternalServerError,
+            OidcError::MissingSessionMiddleware,
+        ));
     }
+    Ok(())
 }
 
 #[tide::utils::async_trait]
-impl<State> Middleware<State> for OpenIdConnectMiddleware
+impl<State, AC> Middleware<State> for OpenIdConnectMiddleware<AC>
 where
     State: Clone + Send + Sync + 'static,
+    AC: AdditionalClaims,
 {
     async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
         // Is this URL one of the URLs that we need to intercept as part
@@ -363,50 +5241,33 @@ where
         // browser to the login URL. And if they are authenticated, then
         // just proceed to the handler (after populating the request extension
         // fields).
-        if req.method() == Method::Get && req.url().path() == self.login_path {
-            self.generate_redirect(req).await
-        } else if req.method() == Method::Get && req.url().path() == self.redirect_url.url().path()
-        {
-            self.handle_callback(req).await
-        } else if req.method() == Method::Get && req.url().path() == self.logout_path {
-            // Destroy the session as part of the logout, or clear only
-            // the app state, depending on how the middleware has been
-            // configured.
-            if self.logout_destroys_session {
-                req.session_mut().destroy();
-            } else {
-                req.session_mut().remove(SESSION_KEY);
-            }
+        //
+        // All of that requires a session, which is easily forgotten
+        // (or installed after this middleware), so fail loudly (but
+        // without panicking) if there is none.
+        require_session(&req)?;
 
-            // Redirect the user now that their authentication state has
-            // been cleared; we send them either to the identity provider's
-            // logout URL (if provided), or to the app's logout landing
-            // path if the app is not configured to log the user out of
-            // the identity provider.
-            if let Some(idp_logout_url) = &self.idp_logout_url {
-                Ok(Redirect::new(idp_logout_url).into())
-            } else {
-                Ok(Redirect::new(&self.logout_landing_path).into())
+        // Nor can anything be authenticated without the provider
+        // metadata, which a lazily-created middleware only has once the
+        // provider has been reachable. Public routes are still served,
+        // though, so that a provider outage does not take down the
+        // whole application.
+        if let Some(res) = self.unavailable_response().await {
+            if self.route(&req).is_some() {
+                return Ok(res);
             }
-        } else {
-            // Get the middleware's session state (which will *not* be
-            // present if the browser has not yet gone through the auth
-            // process), then augment the request with the authentication
-            // status.
-            match req.session().get(SESSION_KEY) {
-                Some(MiddlewareSessionState::PostAuth(subject, access_token, scopes)) => req
-                    .set_ext(OpenIdConnectRequestExtData::Authenticated {
-                        user_id: subject.to_string(),
-                        access_token: access_token.secret().to_string(),
-                        scopes: scopes.iter().map(|s| s.to_string()).collect(),
-                    }),
-                _ => req.set_ext(OpenIdConnectRequestExtData::Unauthenticated {
-                    redirect_strategy: self.redirect_strategy.clone(),
-                }),
-            };
+            if req.ext::<OpenIdConnectRequestExtData>().is_none() {
+                req.set_ext(OpenIdConnectRequestExtData::ProviderUnavailable);
+            }
+            return self.run_next(req, next).await;
+        }
 
-            // Call the downstream middleware.
-            Ok(next.run(req).await)
+        match self.route(&req) {
+            Some(route) => self.handle_route(route, req).await,
+            None => match self.authenticate(&mut req).await? {
+                Some(res) => Ok(res),
+                None => self.run_next(req, next).await,
+            },
         }
     }
 }
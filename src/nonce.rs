@@ -0,0 +1,85 @@
+//! Nonce generation.
+//!
+//! Every authentication request includes a `nonce`, which the provider
+//! copies into the ID token and which the middleware then checks in
+//! order to prevent replay attacks; see [OpenID Connect Core 1.0,
+//! Section 15.5.2](https://openid.net/specs/openid-connect-core-1_0.html#NonceNotes).
+//! By default the nonce contains 16 random bytes from a
+//! cryptographically secure random number generator, which can be
+//! changed with
+//! [`with_nonce_config()`](crate::OpenIdConnectMiddleware::with_nonce_config).
+
+use std::sync::Arc;
+
+use rand::RngCore;
+
+/// Minimum (and default) number of random bytes in each nonce.
+pub(crate) const MIN_NONCE_BYTES: usize = 16;
+
+/// Generates the `nonce` parameter of each authentication request.
+pub trait NonceGenerator: Send + Sync {
+    /// Returns a new nonce, which must be unguessable and must never be
+    /// reused.
+    fn generate(&self) -> String;
+}
+
+/// Generates nonces from `length` bytes of [`rand::thread_rng()`]
+/// output, base64url-encoded (without padding).
+#[derive(Debug, Clone, Copy)]
+pub struct CsrngNonceGenerator {
+    length: usize,
+}
+
+impl CsrngNonceGenerator {
+    /// Creates a generator whose nonces contain `length` random bytes.
+    pub fn new(length: usize) -> Self {
+        Self { length }
+    }
+}
+
+impl NonceGenerator for CsrngNonceGenerator {
+    fn generate(&self) -> String {
+        let mut bytes = vec![0u8; self.length];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+    }
+}
+
+/// Nonce configuration: the number of random bytes in each nonce, and
+/// the generator that produces them.
+#[derive(Clone)]
+pub struct NonceConfig {
+    /// Number of random bytes in each nonce (before encoding), which
+    /// must be at least `16`.
+    pub length: usize,
+
+    /// Generator that produces each nonce, which must include (at
+    /// least) `length` bytes of entropy.
+    pub generator: Arc<dyn NonceGenerator>,
+}
+
+impl NonceConfig {
+    /// Creates a configuration whose nonces contain `length` random
+    /// bytes from a [`CsrngNonceGenerator`].
+    pub fn new(length: usize) -> Self {
+        Self {
+            length,
+            generator: Arc::new(CsrngNonceGenerator::new(length)),
+        }
+    }
+}
+
+impl Default for NonceConfig {
+    /// Uses 16 random bytes from a [`CsrngNonceGenerator`].
+    fn default() -> Self {
+        Self::new(MIN_NONCE_BYTES)
+    }
+}
+
+impl std::fmt::Debug for NonceConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NonceConfig")
+            .field("length", &self.length)
+            .finish()
+    }
+}
@@ -0,0 +1,94 @@
+//! Pushed Authorization Requests ([RFC 9126]).
+//!
+//! [RFC 9126]: https://datatracker.ietf.org/doc/html/rfc9126
+
+use crate::client_auth::TokenEndpointAuthMethod;
+use crate::isahc::HttpClient;
+use http::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use openidconnect::{
+    url::{form_urlencoded, Url},
+    AuthUrl, ClientId, ClientSecret, HttpRequest,
+};
+use serde::Deserialize;
+
+/// Determines how the middleware uses the provider's Pushed
+/// Authorization Request endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushedAuthorizationRequests {
+    /// Push the authorization request if the provider advertises a
+    /// `pushed_authorization_request_endpoint`, otherwise fall back to
+    /// the regular (front-channel) authorization request.
+    Optional,
+
+    /// Always push the authorization request; logins will fail if the
+    /// provider does not advertise a
+    /// `pushed_authorization_request_endpoint`.
+    Required,
+}
+
+#[derive(Deserialize)]
+struct PushedAuthorizationResponse {
+    request_uri: String,
+}
+
+/// Pushes the parameters in `authorize_url` to the provider's Pushed
+/// Authorization Request endpoint, and then returns the URL to which
+/// the browser should be redirected in order to continue the flow.
+pub(crate) async fn push_authorization_request(
+    http_client: &HttpClient,
+    endpoint: &Url,
+    authorization_endpoint: &AuthUrl,
+    client_id: &ClientId,
+    client_secret: Option<(&ClientSecret, TokenEndpointAuthMethod)>,
+    client_assertion: &[(&str, String)],
+    authorize_url: &Url,
+) -> Result<Url, String> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/x-www-form-urlencoded"),
+    );
+
+    // Authenticate with the client secret (sent with the given method),
+    // unless the client authenticates with a client assertion instead.
+    let mut credentials = vec![];
+    if let Some((client_secret, auth_method)) = client_secret {
+        auth_method.add_credentials(client_id, client_secret, &mut headers, &mut credentials)?;
+    }
+
+    let body = form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(authorize_url.query_pairs())
+        // The authorization request already includes the client id.
+        .extend_pairs(
+            credentials
+                .into_iter()
+                .filter(|(name, _)| *name != "client_id"),
+        )
+        .extend_pairs(client_assertion)
+        .finish();
+
+    let response = http_client
+        .request(HttpRequest {
+            url: endpoint.clone(),
+            method: http::Method::POST,
+            headers,
+            body: body.into_bytes(),
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status_code.is_success() {
+        return Err(format!(
+            "Pushed Authorization Request failed with HTTP status {}.",
+            response.status_code
+        ));
+    }
+
+    let response: PushedAuthorizationResponse =
+        serde_json::from_slice(&response.body).map_err(|e| e.to_string())?;
+
+    let mut url = authorization_endpoint.url().clone();
+    url.query_pairs_mut()
+        .append_pair("client_id", client_id.as_str())
+        .append_pair("request_uri", &response.request_uri);
+    Ok(url)
+}
@@ -0,0 +1,217 @@
+use crate::instrument::instrument;
+use crate::isahc::HttpClient;
+use crate::issuer::IssuerVerification;
+use openidconnect::http::{
+    header::{HeaderValue, ACCEPT},
+    Method, StatusCode,
+};
+use openidconnect::{
+    core::{
+        CoreAuthDisplay, CoreClaimName, CoreClaimType, CoreClientAuthMethod, CoreGrantType,
+        CoreJsonWebKey, CoreJsonWebKeySet, CoreJsonWebKeyType, CoreJsonWebKeyUse,
+        CoreJweContentEncryptionAlgorithm, CoreJweKeyManagementAlgorithm, CoreJwsSigningAlgorithm,
+        CoreResponseMode, CoreResponseType, CoreSubjectIdentifierType,
+    },
+    url::Url,
+    AuthUrl, HttpRequest, IssuerUrl, JsonWebKeySetUrl, ResponseTypes, TokenUrl, UserInfoUrl,
+};
+use serde::{Deserialize, Serialize};
+
+/// Provider metadata fields that are not part of the core OpenID
+/// Connect Discovery specification, but which are used by the
+/// middleware.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct AdditionalProviderMetadata {
+    /// Pushed Authorization Request endpoint ([RFC 9126]).
+    ///
+    /// [RFC 9126]: https://datatracker.ietf.org/doc/html/rfc9126
+    pub(crate) pushed_authorization_request_endpoint: Option<Url>,
+
+    /// Algorithms with which the provider signs JWT authorization
+    /// responses ([JARM]).
+    ///
+    /// [JARM]: https://openid.net/specs/oauth-v2-jarm.html
+    pub(crate) authorization_signing_alg_values_supported: Option<Vec<CoreJwsSigningAlgorithm>>,
+
+    /// Page that checks the user's session at the provider ([Session
+    /// Management]).
+    ///
+    /// [Session Management]: https://openid.net/specs/openid-connect-session-1_0.html
+    pub(crate) check_session_iframe: Option<Url>,
+
+    /// Token introspection endpoint ([RFC 7662]), as advertised in the
+    /// authorization server metadata ([RFC 8414]).
+    ///
+    /// [RFC 7662]: https://datatracker.ietf.org/doc/html/rfc7662
+    /// [RFC 8414]: https://datatracker.ietf.org/doc/html/rfc8414
+    pub(crate) introspection_endpoint: Option<Url>,
+
+    /// Backchannel authentication endpoint ([CIBA]).
+    ///
+    /// [CIBA]: https://openid.net/specs/openid-client-initiated-backchannel-authentication-core-1_0.html
+    pub(crate) backchannel_authentication_endpoint: Option<Url>,
+}
+
+impl openidconnect::AdditionalProviderMetadata for AdditionalProviderMetadata {}
+
+/// OpenID Connect provider metadata, including our additional fields.
+pub(crate) type ProviderMetadata = openidconnect::ProviderMetadata<
+    AdditionalProviderMetadata,
+    CoreAuthDisplay,
+    CoreClientAuthMethod,
+    CoreClaimName,
+    CoreClaimType,
+    CoreGrantType,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJweKeyManagementAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreResponseMode,
+    CoreResponseType,
+    CoreSubjectIdentifierType,
+>;
+
+/// Provider endpoints for Identity Providers whose discovery document
+/// (`/.well-known/openid-configuration`) is unavailable or wrong (in
+/// air-gapped environments whose endpoints are on internal hostnames,
+/// for example); see
+/// [`Config::provider_endpoints`](crate::Config::provider_endpoints).
+/// The middleware does not fetch the discovery document when these
+/// endpoints are configured, but still only accepts ID tokens that were
+/// issued by the configured [`issuer_url`](crate::Config::issuer_url).
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProviderEndpoints {
+    /// URL of the provider's authorization endpoint, to which the
+    /// browser is sent in order to log in.
+    pub authorization_endpoint: AuthUrl,
+
+    /// URL of the provider's token endpoint.
+    pub token_endpoint: TokenUrl,
+
+    /// URL of the provider's JSON Web Key Set, from which the signing
+    /// keys are fetched at startup (unless [`jwks`](Self::jwks) is
+    /// provided) and whenever the provider rotates its keys.
+    pub jwks_uri: JsonWebKeySetUrl,
+
+    /// Optional URL of the provider's UserInfo endpoint, which is only
+    /// required if the middleware [fetches the UserInfo
+    /// claims](crate::Config::fetch_userinfo).
+    #[serde(default)]
+    pub userinfo_endpoint: Option<UserInfoUrl>,
+
+    /// Optional signing keys, which are used instead of fetching the
+    /// keys from the [`jwks_uri`](Self::jwks_uri) at startup.
+    #[serde(default)]
+    pub jwks: Option<CoreJsonWebKeySet>,
+
+    /// Algorithms with which the provider signs its ID tokens.
+    ///
+    /// Defaults to `RS256`.
+    #[serde(default = "default_id_token_signing_algs")]
+    pub id_token_signing_algs: Vec<CoreJwsSigningAlgorithm>,
+}
+
+fn default_id_token_signing_algs() -> Vec<CoreJwsSigningAlgorithm> {
+    vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256]
+}
+
+impl ProviderEndpoints {
+    /// Builds the provider metadata for the given issuer from the
+    /// configured endpoints, fetching the signing keys if necessary.
+    pub(crate) async fn provider_metadata(
+        &self,
+        http_client: &HttpClient,
+        issuer_url: &IssuerUrl,
+    ) -> Result<ProviderMetadata, String> {
+        let jwks = match &self.jwks {
+            Some(jwks) => jwks.clone(),
+            None => instrument!(
+                CoreJsonWebKeySet::fetch_async(&self.jwks_uri, |request| {
+                    http_client.request(request)
+                }),
+                "oidc.jwks_fetch",
+                url = %self.jwks_uri.as_str(),
+            )
+            .await
+            .map_err(|error| error.to_string())?,
+        };
+
+        Ok(ProviderMetadata::new(
+            issuer_url.clone(),
+            self.authorization_endpoint.clone(),
+            self.jwks_uri.clone(),
+            vec![ResponseTypes::new(vec![CoreResponseType::Code])],
+            vec![CoreSubjectIdentifierType::Public],
+            self.id_token_signing_algs.clone(),
+            AdditionalProviderMetadata {
+                pushed_authorization_request_endpoint: None,
+                authorization_signing_alg_values_supported: None,
+                check_session_iframe: None,
+                introspection_endpoint: None,
+                backchannel_authentication_endpoint: None,
+            },
+        )
+        .set_token_endpoint(Some(self.token_endpoint.clone()))
+        .set_userinfo_endpoint(self.userinfo_endpoint.clone())
+        .set_jwks(jwks))
+    }
+}
+
+/// Fetches the provider's discovery document (and the JSON Web Key Set
+/// that it references), which must be that of the given issuer.
+///
+/// This does what `ProviderMetadata::discover_async` does, except that
+/// the document's `issuer` is compared with the configured issuer
+/// according to the [`IssuerVerification`] mode, instead of always
+/// requiring an exact match.
+pub(crate) async fn discover_provider_metadata(
+    http_client: &HttpClient,
+    issuer_url: &IssuerUrl,
+    issuer_verification: IssuerVerification,
+) -> Result<ProviderMetadata, String> {
+    // The discovery URL is the same whether or not the issuer ends
+    // with a slash.
+    let discovery_url = issuer_url
+        .join(".well-known/openid-configuration")
+        .map_err(|error| error.to_string())?;
+    let response = http_client
+        .request(HttpRequest {
+            url: discovery_url.clone(),
+            method: Method::GET,
+            headers: vec![(ACCEPT, HeaderValue::from_static("application/json"))]
+                .into_iter()
+                .collect(),
+            body: vec![],
+        })
+        .await
+        .map_err(|error| error.to_string())?;
+    if response.status_code != StatusCode::OK {
+        return Err(format!(
+            "HTTP status code {} at {}",
+            response.status_code, discovery_url
+        ));
+    }
+
+    let metadata: ProviderMetadata = serde_json::from_slice(&response.body)
+        .map_err(|error| format!("Failed to parse discovery document: {}", error))?;
+    if !issuer_verification.matches(issuer_url, metadata.issuer().as_str()) {
+        return Err(format!(
+            "Unexpected issuer URI `{}` (expected `{}`)",
+            metadata.issuer().as_str(),
+            issuer_url.as_str()
+        ));
+    }
+
+    let jwks = instrument!(
+        CoreJsonWebKeySet::fetch_async(metadata.jwks_uri(), |request| {
+            http_client.request(request)
+        }),
+        "oidc.jwks_fetch",
+        url = %metadata.jwks_uri().as_str(),
+    )
+    .await
+    .map_err(|error| error.to_string())?;
+    Ok(metadata.set_jwks(jwks))
+}
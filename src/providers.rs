@@ -0,0 +1,255 @@
+//! Multiple Identity Providers behind a single middleware.
+
+use openidconnect::{AdditionalClaims, EmptyAdditionalClaims};
+use serde::Deserialize;
+use tide::{http::Method, Body, Middleware, Next, Request, StatusCode};
+
+use crate::jwt::unverified_claims;
+use crate::middleware::{require_session, MiddlewareRoute};
+use crate::request_ext::OpenIdConnectRequestExtData;
+use crate::OpenIdConnectMiddleware;
+
+/// Lets users sign in with one of several Identity Providers, each of
+/// which is configured as its own [`OpenIdConnectMiddleware`] (with its
+/// own client credentials, scopes, signing keys, etc.), but which share
+/// the login path and (usually) the redirect URL.
+///
+/// Each provider is given a [provider
+/// id](OpenIdConnectMiddleware::with_provider_id), and its login path
+/// is set to `{login_path}/{provider_id}`. The login path itself
+/// accepts a `provider` query parameter (`/login?provider=google`), and
+/// otherwise logs in with the first provider. Callbacks are handled by
+/// the provider that started the login, as identified by the callback's
+/// `state`, and the logout path logs the user out of the provider that
+/// authenticated them. The
+/// [`provider_id()`](crate::OpenIdConnectRequestExt::provider_id)
+/// request extension returns the id of that provider.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use tide_openidconnect::{Config, OpenIdConnectMiddleware, OpenIdConnectProviders};
+/// # async fn example(azure_config: Config, google_config: Config) {
+/// let mut app = tide::new();
+/// app.with(
+///     OpenIdConnectProviders::new()
+///         .with_provider("azure", OpenIdConnectMiddleware::new(&azure_config).await)
+///         .with_provider("google", OpenIdConnectMiddleware::new(&google_config).await),
+/// );
+/// # }
+/// ```
+pub struct OpenIdConnectProviders<AC = EmptyAdditionalClaims>
+where
+    AC: AdditionalClaims,
+{
+    login_path: String,
+    providers: Vec<(String, OpenIdConnectMiddleware<AC>)>,
+}
+
+impl<AC> std::fmt::Debug for OpenIdConnectProviders<AC>
+where
+    AC: AdditionalClaims,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OpenIdConnectProviders")
+            .field("login_path", &self.login_path)
+            .field(
+                "providers",
+                &self.providers.iter().map(|(id, _)| id).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl<AC> Default for OpenIdConnectProviders<AC>
+where
+    AC: AdditionalClaims,
+{
+    fn default() -> Self {
+        Self {
+            login_path: "/login".to_string(),
+            providers: vec![],
+        }
+    }
+}
+
+impl<AC> OpenIdConnectProviders<AC>
+where
+    AC: AdditionalClaims,
+{
+    /// Creates a middleware without any providers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a provider, replacing the provider with the same id (if
+    /// any). The provider's middleware is given the provider id, and
+    /// its login path is set to `{login_path}/{provider_id}`.
+    pub fn with_provider(
+        mut self,
+        provider_id: &str,
+        middleware: OpenIdConnectMiddleware<AC>,
+    ) -> Self {
+        let middleware = middleware
+            .with_provider_id(provider_id)
+            .with_login_path(&provider_login_path(&self.login_path, provider_id));
+        self.providers.retain(|(id, _)| id != provider_id);
+        self.providers.push((provider_id.to_string(), middleware));
+        self
+    }
+
+    /// Sets the path to the "login" route, which is also the prefix of
+    /// each provider's own login path.
+    ///
+    /// Defaults to `/login`
+    pub fn with_login_path(mut self, login_path: &str) -> Self {
+        self.login_path = login_path.to_string();
+        self.providers = std::mem::take(&mut self.providers)
+            .into_iter()
+            .map(|(id, middleware)| {
+                let middleware = middleware.with_login_path(&provider_login_path(login_path, &id));
+                (id, middleware)
+            })
+            .collect();
+        self
+    }
+
+    /// Returns the provider that was selected with the `provider`
+    /// query parameter of the login path, or the first provider.
+    fn login_provider<State>(
+        &self,
+        req: &Request<State>,
+    ) -> tide::Result<&OpenIdConnectMiddleware<AC>> {
+        #[derive(Deserialize)]
+        struct LoginQuery {
+            provider: Option<String>,
+        }
+        let query: LoginQuery = req.query()?;
+        let provider = match query.provider {
+            Some(provider_id) => self.providers.iter().find(|(id, _)| *id == provider_id),
+            None => self.providers.first(),
+        };
+        provider
+            .map(|(_, middleware)| middleware)
+            .ok_or_else(|| tide::http::Error::from_str(StatusCode::BadRequest, "Unknown provider."))
+    }
+}
+
+/// Returns the login path of a provider.
+fn provider_login_path(login_path: &str, provider_id: &str) -> String {
+    format!("{}/{}", login_path.trim_end_matches('/'), provider_id)
+}
+
+/// Returns the `state` of an authorization callback, which is in the
+/// query string or, for `form_post` responses, in the (restored) body.
+/// JWT-secured responses carry their state inside the `response` JWT,
+/// which is decoded *without* verifying it: the state only selects the
+/// provider, which then verifies the response itself.
+async fn callback_state<State>(req: &mut Request<State>) -> tide::Result<Option<String>> {
+    if req.method() == Method::Get {
+        let query = req.url().query().unwrap_or_default().as_bytes().to_vec();
+        return Ok(response_state(&query));
+    }
+
+    let body = req.take_body();
+    let mime = body.mime().clone();
+    let bytes = body.into_bytes().await?;
+    let state = response_state(&bytes);
+    let mut body = Body::from_bytes(bytes);
+    body.set_mime(mime);
+    req.set_body(body);
+    Ok(state)
+}
+
+/// Returns the `state` parameter of form-encoded authorization response
+/// parameters, or the (unverified) `state` claim of their `response`
+/// JWT.
+fn response_state(params: &[u8]) -> Option<String> {
+    let param = |name: &str| {
+        openidconnect::url::form_urlencoded::parse(params)
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    param("state").or_else(|| {
+        let claims = unverified_claims(&param("response")?).ok()?;
+        claims.get("state")?.as_str().map(str::to_string)
+    })
+}
+
+#[tide::utils::async_trait]
+impl<State, AC> Middleware<State> for OpenIdConnectProviders<AC>
+where
+    State: Clone + Send + Sync + 'static,
+    AC: AdditionalClaims,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        require_session(&req)?;
+        let first = match self.providers.first() {
+            Some((_, first)) => first,
+            None => return Ok(next.run(req).await),
+        };
+
+        // The shared login path selects the provider. Otherwise the
+        // routes are those of the providers; routes that are shared by
+        // the providers (the callback and the logout paths, usually) are
+        // handled by the provider whose login the request is completing
+        // (or ending).
+        let selected = if req.method() == Method::Get && req.url().path() == self.login_path {
+            let route = MiddlewareRoute::Login {
+                silent: false,
+                variant: None,
+            };
+            Some((self.login_provider(&req)?, route))
+        } else {
+            let routes: Vec<_> = self
+                .providers
+                .iter()
+                .filter_map(|(_, middleware)| {
+                    middleware.route(&req).map(|route| (middleware, route))
+                })
+                .collect();
+            let state = if routes
+                .iter()
+                .any(|(_, route)| *route == MiddlewareRoute::Callback)
+            {
+                callback_state(&mut req).await?
+            } else {
+                None
+            };
+            let session = req.session();
+            routes
+                .iter()
+                .find(|(middleware, route)| match route {
+                    MiddlewareRoute::Callback => {
+                        middleware.has_pending_login(session, state.as_deref())
+                    }
+                    MiddlewareRoute::Logout(_) => middleware.has_authenticated_session(session),
+                    MiddlewareRoute::PostLogout => middleware.has_pending_logout(&req),
+                    _ => false,
+                })
+                .or_else(|| routes.first())
+                .copied()
+        };
+        if let Some((provider, route)) = selected {
+            if let Some(res) = provider.unavailable_response().await {
+                return Ok(res);
+            }
+            return provider.handle_route(route, req).await;
+        }
+
+        // Every provider gets to authenticate the request, skipping the
+        // (lazily-created) providers that are not available.
+        for (_, middleware) in &self.providers {
+            if middleware.unavailable_response().await.is_some() {
+                continue;
+            }
+            if let Some(res) = middleware.authenticate(&mut req).await? {
+                return Ok(res);
+            }
+        }
+        if req.ext::<OpenIdConnectRequestExtData>().is_none() {
+            req.set_ext(OpenIdConnectRequestExtData::ProviderUnavailable);
+        }
+        first.run_next(req, next).await
+    }
+}
@@ -11,13 +11,52 @@
 //! - [`ClientSideRefresh`] allows you to use *client-side* code to
 //!   redirect the browser and can be used to avoid CORS issues, but may
 //!   add additional latency and browser window flashing.
+//! - [`Unauthorized401`] does not redirect at all, and instead returns
+//!   a `401 Unauthorized` response with a small JSON body that includes
+//!   the login URL. This strategy is meant for API routes that are
+//!   called by single-page applications using `XMLHttpRequest` or
+//!   `fetch`, which can then navigate the browser to the login URL
+//!   themselves.
+//!
+//! The strategy can be selected in the [`Config`](crate::Config) (see
+//! [`RedirectStrategyKind`]), or set to any [`RedirectStrategy`]
+//! implementation with
+//! [`with_unauthenticated_redirect_strategy()`](crate::OpenIdConnectMiddleware::with_unauthenticated_redirect_strategy).
+//!
+//! Applications that need to vary the response by request (by the
+//! `Accept` header, path prefix or tenant, for example) can instead
+//! implement [`UnauthenticatedHandler`], which receives the request and
+//! is asynchronous. The trait is implemented by all of the built-in
+//! strategies, and by closures that take the request and return a
+//! future:
+//!
+//! ```no_run
+//! use tide_openidconnect::redirect_strategy::RedirectStrategyKind;
+//! use tide::{Redirect, Response, StatusCode};
+//!
+//! let redirect_strategy = RedirectStrategyKind::custom(|req: tide::http::Request| async move {
+//!     if req.url().path().starts_with("/api/") {
+//!         Ok(Response::new(StatusCode::Unauthorized))
+//!     } else {
+//!         Ok(Redirect::new("/login").into())
+//!     }
+//! });
+//! ```
+
+use serde::Deserialize;
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::challenge::{bearer_challenge, ChallengeError, RejectedCredentials};
+use crate::error::OidcError;
+use crate::middleware::{AccessDeniedFn, InsufficientScopeFn};
 
 use tide::{
     http::{
-        headers::{HeaderName, HeaderValues, ToHeaderValues},
+        headers::{HeaderName, HeaderValues, ToHeaderValues, WWW_AUTHENTICATE},
         mime,
     },
-    Redirect, Response,
+    Redirect, Response, StatusCode,
 };
 
 /// Redirect the browser to another location.
@@ -27,6 +66,121 @@ pub trait RedirectStrategy: Send + Sync {
     fn redirect(&self) -> Response;
 }
 
+/// Generates the response to unauthenticated requests for [protected
+/// routes](crate::OpenIdConnectRouteExt).
+#[tide::utils::async_trait]
+pub trait UnauthenticatedHandler: Send + Sync {
+    /// Returns the response to the given unauthenticated request.
+    async fn unauthenticated(&self, req: &tide::http::Request) -> tide::Result<Response>;
+}
+
+/// Closures receive a copy of the request (without its body).
+#[tide::utils::async_trait]
+impl<F, Fut> UnauthenticatedHandler for F
+where
+    F: Fn(tide::http::Request) -> Fut + Send + Sync,
+    Fut: Future<Output = tide::Result<Response>> + Send + 'static,
+{
+    async fn unauthenticated(&self, req: &tide::http::Request) -> tide::Result<Response> {
+        self(req.clone()).await
+    }
+}
+
+/// Generates the response to authenticated requests that were denied
+/// access to a [protected route](crate::OpenIdConnectRouteExt), either
+/// because they were not granted a required scope
+/// ([`OidcError::InsufficientScope`]) or because the route's claims
+/// predicate denied them ([`OidcError::AccessDenied`]), or because they
+/// failed the middleware's [authorization
+/// policy](crate::OpenIdConnectMiddleware::with_required_claim); see
+/// [`Config::access_denied_handler`](crate::Config::access_denied_handler).
+///
+/// Handlers typically redirect browsers to a friendly "you don't have
+/// access" page. Note that these requests are never sent back through
+/// the login process, since logging in again would not change the
+/// outcome.
+#[tide::utils::async_trait]
+pub trait AccessDeniedHandler: Send + Sync {
+    /// Returns the response to the given request, which was denied for
+    /// the given reason.
+    async fn handle(&self, req: &tide::http::Request, reason: OidcError) -> tide::Result<Response>;
+}
+
+/// Closures receive a copy of the request (without its body).
+#[tide::utils::async_trait]
+impl<F, Fut> AccessDeniedHandler for F
+where
+    F: Fn(tide::http::Request, OidcError) -> Fut + Send + Sync,
+    Fut: Future<Output = tide::Result<Response>> + Send + 'static,
+{
+    async fn handle(&self, req: &tide::http::Request, reason: OidcError) -> tide::Result<Response> {
+        self(req.clone(), reason).await
+    }
+}
+
+impl std::fmt::Debug for dyn AccessDeniedHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AccessDeniedHandler")
+    }
+}
+
+/// Adapts the middleware's [insufficient
+/// scope](crate::OpenIdConnectMiddleware::with_insufficient_scope_response)
+/// and [access
+/// denied](crate::OpenIdConnectMiddleware::with_access_denied_response)
+/// responses to the [`AccessDeniedHandler`] trait.
+pub(crate) struct AccessDeniedResponses {
+    pub(crate) insufficient_scope: Arc<InsufficientScopeFn>,
+    pub(crate) access_denied: Arc<AccessDeniedFn>,
+}
+
+#[tide::utils::async_trait]
+impl AccessDeniedHandler for AccessDeniedResponses {
+    async fn handle(
+        &self,
+        _req: &tide::http::Request,
+        reason: OidcError,
+    ) -> tide::Result<Response> {
+        Ok(match reason {
+            OidcError::InsufficientScope(missing_scopes) => {
+                (self.insufficient_scope)(&missing_scopes)
+            }
+            reason => (self.access_denied)(&reason),
+        })
+    }
+}
+
+/// Adapts a [`RedirectStrategy`] to the [`UnauthenticatedHandler`]
+/// trait.
+pub(crate) struct RedirectStrategyHandler<R>(pub(crate) R);
+
+#[tide::utils::async_trait]
+impl<R: RedirectStrategy> UnauthenticatedHandler for RedirectStrategyHandler<R> {
+    async fn unauthenticated(&self, _req: &tide::http::Request) -> tide::Result<Response> {
+        Ok(self.0.redirect())
+    }
+}
+
+/// Implements [`UnauthenticatedHandler`] for built-in strategies, which
+/// do not depend on the request.
+macro_rules! impl_unauthenticated_handler {
+    ($($strategy:ty),*) => {
+        $(
+            #[tide::utils::async_trait]
+            impl UnauthenticatedHandler for $strategy {
+                async fn unauthenticated(
+                    &self,
+                    _req: &tide::http::Request,
+                ) -> tide::Result<Response> {
+                    Ok(self.redirect())
+                }
+            }
+        )*
+    };
+}
+
+impl_unauthenticated_handler!(HttpRedirect, ClientSideRefresh);
+
 /// HTTP-level redirect: `302 Found` with a `Location` header.
 #[derive(Debug)]
 pub struct HttpRedirect {
@@ -108,3 +262,115 @@ impl RedirectStrategy for ClientSideRefresh {
         res.build()
     }
 }
+
+/// API-friendly "redirect": `401 Unauthorized` with a `WWW-Authenticate`
+/// header and a JSON body containing the login URL:
+///
+/// ```json
+/// {"error":"unauthenticated","login_url":"/login"}
+/// ```
+///
+/// The `WWW-Authenticate` header is a `Bearer` challenge, which includes
+/// an `invalid_token` error if the request's session has expired.
+#[derive(Debug)]
+pub struct Unauthorized401 {
+    login_url: String,
+    realm: Option<String>,
+}
+
+impl Unauthorized401 {
+    /// Create a new instance, with the login URL that will be included
+    /// in the response body.
+    pub fn new(login_url: impl AsRef<str>) -> Self {
+        Self {
+            login_url: login_url.as_ref().to_string(),
+            realm: None,
+        }
+    }
+
+    /// Sets the `realm` of the `WWW-Authenticate` challenge.
+    ///
+    /// Defaults to no realm.
+    pub fn with_realm(mut self, realm: impl AsRef<str>) -> Self {
+        self.realm = Some(realm.as_ref().to_string());
+        self
+    }
+
+    fn response(&self, error: Option<ChallengeError<'_>>) -> Response {
+        Response::builder(StatusCode::Unauthorized)
+            .header(
+                WWW_AUTHENTICATE,
+                bearer_challenge(self.realm.as_deref(), error),
+            )
+            .body(serde_json::json!({
+                "error": "unauthenticated",
+                "login_url": self.login_url,
+            }))
+            .build()
+    }
+}
+
+impl RedirectStrategy for Unauthorized401 {
+    fn redirect(&self) -> Response {
+        self.response(None)
+    }
+}
+
+#[tide::utils::async_trait]
+impl UnauthenticatedHandler for Unauthorized401 {
+    async fn unauthenticated(&self, req: &tide::http::Request) -> tide::Result<Response> {
+        Ok(self.response(
+            req.ext()
+                .get::<RejectedCredentials>()
+                .map(|rejected| ChallengeError::InvalidToken(&rejected.description)),
+        ))
+    }
+}
+
+/// Redirect strategy selected in the [`Config`](crate::Config); the
+/// built-in strategies are created with the middleware's
+/// [login path](crate::OpenIdConnectMiddleware::with_login_path).
+#[derive(Clone, Default, Deserialize)]
+pub enum RedirectStrategyKind {
+    /// [`HttpRedirect`] to the login path.
+    #[default]
+    HttpRedirect,
+
+    /// [`Unauthorized401`] response with the login path (and the
+    /// [realm](crate::Config::realm) of the middleware).
+    Unauthorized401,
+
+    /// Application-provided handler; see [`custom()`](Self::custom).
+    /// This variant cannot be deserialized, and so must be set in code.
+    #[serde(skip)]
+    Custom(Arc<dyn UnauthenticatedHandler>),
+}
+
+impl std::fmt::Debug for RedirectStrategyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HttpRedirect => f.write_str("HttpRedirect"),
+            Self::Unauthorized401 => f.write_str("Unauthorized401"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl RedirectStrategyKind {
+    /// Uses the given handler (or closure) for unauthenticated
+    /// requests.
+    pub fn custom<H>(handler: H) -> Self
+    where
+        H: UnauthenticatedHandler + 'static,
+    {
+        Self::Custom(Arc::new(handler))
+    }
+
+    pub(crate) fn handler(&self, login_path: &str, realm: &str) -> Arc<dyn UnauthenticatedHandler> {
+        match self {
+            Self::HttpRedirect => Arc::new(HttpRedirect::new(login_path)),
+            Self::Unauthorized401 => Arc::new(Unauthorized401::new(login_path).with_realm(realm)),
+            Self::Custom(handler) => Arc::clone(handler),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Dynamic Client Registration.
+//!
+//! Some Identity Providers require that clients register themselves
+//! dynamically (per [RFC 7591]) before they can start the authorization
+//! flow. Setting the
+//! [`dynamic_client_registration`](crate::Config::dynamic_client_registration)
+//! config option tells the middleware to register itself with the
+//! provider's `registration_endpoint` the first time that it starts,
+//! and then to persist the resulting credentials to a
+//! [`CredentialStore`] so that subsequent startups reuse the same
+//! client registration.
+//!
+//! [`FileCredentialStore`] persists the credentials to a JSON file;
+//! applications can implement [`CredentialStore`] themselves in order to
+//! store the credentials elsewhere (a secrets manager, a database, etc.).
+//!
+//! [RFC 7591]: https://datatracker.ietf.org/doc/html/rfc7591
+
+use std::{io, path::PathBuf, sync::Arc};
+
+use crate::isahc::HttpClient;
+use crate::provider_metadata::ProviderMetadata;
+use openidconnect::{
+    core::CoreClientRegistrationRequest, registration::EmptyAdditionalClientMetadata, AccessToken,
+    ClientId, ClientName, ClientSecret, LocalizedClaim, RedirectUrl,
+};
+use serde::{Deserialize, Serialize};
+
+/// Client credentials issued by the Identity Provider as the result of
+/// a dynamic client registration.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ClientCredentials {
+    /// Client ID assigned by the Identity Provider.
+    pub client_id: ClientId,
+
+    /// Client Secret assigned by the Identity Provider, if any.
+    pub client_secret: Option<ClientSecret>,
+}
+
+/// Persistent storage for dynamically-registered client credentials.
+#[tide::utils::async_trait]
+pub trait CredentialStore: Send + Sync {
+    /// Loads the previously-saved credentials, or returns `None` if
+    /// the client has not yet been registered.
+    async fn load(&self) -> io::Result<Option<ClientCredentials>>;
+
+    /// Saves the credentials returned by the Identity Provider.
+    async fn save(&self, credentials: &ClientCredentials) -> io::Result<()>;
+}
+
+/// Stores the client credentials in a JSON file.
+///
+/// Note that the file contains the client secret, and so must be
+/// protected accordingly.
+#[derive(Debug)]
+pub struct FileCredentialStore {
+    path: PathBuf,
+}
+
+impl FileCredentialStore {
+    /// Create a new instance, with the path to the file in which the
+    /// credentials will be stored.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[tide::utils::async_trait]
+impl CredentialStore for FileCredentialStore {
+    async fn load(&self) -> io::Result<Option<ClientCredentials>> {
+        match std::fs::read(&self.path) {
+            Ok(contents) => serde_json::from_slice(&contents)
+                .map(Some)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    async fn save(&self, credentials: &ClientCredentials) -> io::Result<()> {
+        let contents = serde_json::to_vec_pretty(credentials)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        std::fs::write(&self.path, contents)
+    }
+}
+
+/// Dynamic Client Registration configuration.
+#[derive(Clone)]
+pub struct DynamicClientRegistration {
+    store: Arc<dyn CredentialStore>,
+    client_name: Option<String>,
+    initial_access_token: Option<String>,
+}
+
+impl std::fmt::Debug for DynamicClientRegistration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DynamicClientRegistration")
+            .field("client_name", &self.client_name)
+            .finish()
+    }
+}
+
+impl DynamicClientRegistration {
+    /// Create a new instance, with the store used to persist the
+    /// credentials returned by the Identity Provider.
+    pub fn new(store: impl CredentialStore + 'static) -> Self {
+        Self {
+            store: Arc::new(store),
+            client_name: None,
+            initial_access_token: None,
+        }
+    }
+
+    /// Sets the human-readable client name that will be included in
+    /// the registration request.
+    pub fn with_client_name(mut self, client_name: impl AsRef<str>) -> Self {
+        self.client_name = Some(client_name.as_ref().to_string());
+        self
+    }
+
+    /// Sets the Initial Access Token that some providers require in
+    /// order to authorize the registration request.
+    pub fn with_initial_access_token(mut self, initial_access_token: impl AsRef<str>) -> Self {
+        self.initial_access_token = Some(initial_access_token.as_ref().to_string());
+        self
+    }
+
+    /// Returns the stored credentials, registering the client with the
+    /// Identity Provider (and storing the resulting credentials) if the
+    /// client has not yet been registered.
+    pub(crate) async fn credentials(
+        &self,
+        http_client: &HttpClient,
+        provider_metadata: &ProviderMetadata,
+        redirect_url: &RedirectUrl,
+    ) -> Result<ClientCredentials, String> {
+        if let Some(credentials) = self.store.load().await.map_err(|e| e.to_string())? {
+            return Ok(credentials);
+        }
+
+        let registration_endpoint = provider_metadata.registration_endpoint().ok_or_else(|| {
+            "OpenID Connect provider does not support dynamic client registration.".to_string()
+        })?;
+
+        let mut request = CoreClientRegistrationRequest::new(
+            vec![redirect_url.clone()],
+            EmptyAdditionalClientMetadata {},
+        )
+        .set_initial_access_token(self.initial_access_token.clone().map(AccessToken::new));
+        if let Some(client_name) = &self.client_name {
+            let mut localized_client_name = LocalizedClaim::new();
+            localized_client_name.insert(None, ClientName::new(client_name.clone()));
+            request = request.set_client_name(Some(localized_client_name));
+        }
+
+        let response = request
+            .register_async(registration_endpoint, |request| {
+                http_client.request(request)
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        let credentials = ClientCredentials {
+            client_id: response.client_id().clone(),
+            client_secret: response.client_secret().cloned(),
+        };
+
+        self.store
+            .save(&credentials)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(credentials)
+    }
+}
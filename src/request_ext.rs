@@ -1,14 +1,141 @@
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
-use crate::redirect_strategy::RedirectStrategy;
+use crate::bearer_forwarding::UpstreamClient;
+use crate::dpop::DpopConfig;
+use crate::error::OidcError;
+use crate::middleware::Claims;
+use crate::redirect_strategy::{AccessDeniedHandler, UnauthenticatedHandler};
+use crate::token_exchange::{ExchangeRequest, ExchangedToken, TokenExchanger};
+use chrono::{DateTime, Utc};
+use openidconnect::{
+    core::CoreGenderClaim, AdditionalClaims, IssuerUrl, StandardClaims, SubjectIdentifier,
+};
+use serde::de::DeserializeOwned;
+use tide::http::{Method, Url};
 use tide::Request;
 
+/// Authentication information for an authenticated request, as
+/// returned by [`auth_info()`](OpenIdConnectRequestExt::auth_info).
+#[derive(Debug, Clone)]
+pub struct AuthInfo {
+    /// [Provider id](crate::OpenIdConnectMiddleware::with_provider_id)
+    /// of the middleware that authenticated the request, if any.
+    pub provider_id: Option<String>,
+
+    /// Issuer URL of the Identity Provider that authenticated the
+    /// request (the verified ID token's `iss` claim, which always
+    /// matches the middleware's [`issuer_url`](crate::Config::issuer_url)).
+    pub issuer: IssuerUrl,
+
+    /// Client id with which the middleware authenticated the request.
+    pub client_id: String,
+
+    /// Identity Provider-specific user id (the ID token's `sub` claim).
+    pub subject: String,
+
+    /// Identity Provider-specific access token.
+    pub access_token: String,
+
+    /// Scopes authorized by/granted to the user.
+    pub scopes: Vec<String>,
+
+    /// Time at which the ID token was issued, which is (approximately)
+    /// the time at which the user logged in.
+    pub issued_at: DateTime<Utc>,
+
+    /// Time at which the access token expires, or `None` if the
+    /// provider did not say when the access token expires.
+    pub expires_at: Option<DateTime<Utc>>,
+
+    /// Standard claims (`email`, `preferred_username`, etc.) from the
+    /// ID token. Note that claims that were not
+    /// [persisted](crate::OpenIdConnectMiddleware::with_persisted_claims)
+    /// will be missing.
+    pub claims: StandardClaims<CoreGenderClaim>,
+}
+
+/// Authentication information from the session state, which does not
+/// include the standard claims (since deserializing those is relatively
+/// expensive).
+#[derive(Debug)]
+pub(crate) struct SessionAuthInfo {
+    pub(crate) provider_id: Option<String>,
+    pub(crate) issuer: IssuerUrl,
+    pub(crate) client_id: String,
+    pub(crate) subject: String,
+    pub(crate) access_token: String,
+    pub(crate) scopes: Vec<String>,
+    pub(crate) issued_at: DateTime<Utc>,
+    pub(crate) expires_at: Option<DateTime<Utc>>,
+}
+
+/// Authentication information that is only assembled the first time a
+/// handler asks for all of it, so that public pages that merely check
+/// whether the request is authenticated (or who the user is) do not pay
+/// for it.
+#[derive(Debug)]
+pub(crate) struct LazyAuthInfo {
+    pub(crate) session: Box<SessionAuthInfo>,
+    auth_info: OnceLock<Box<AuthInfo>>,
+}
+
+impl LazyAuthInfo {
+    pub(crate) fn new(session: SessionAuthInfo) -> Self {
+        Self {
+            session: Box::new(session),
+            auth_info: OnceLock::new(),
+        }
+    }
+
+    /// Gets the authentication information, deserializing the standard
+    /// claims from the given (persisted) claims if necessary.
+    fn get(&self, claims: &Claims) -> &AuthInfo {
+        self.auth_info.get_or_init(|| {
+            let session = &self.session;
+            // The persisted claims always include the subject, and so
+            // should always deserialize into the standard claims.
+            let standard_claims = serde_json::from_value(serde_json::Value::Object(claims.clone()))
+                .unwrap_or_else(|_| {
+                    StandardClaims::new(SubjectIdentifier::new(session.subject.clone()))
+                });
+            Box::new(AuthInfo {
+                provider_id: session.provider_id.clone(),
+                issuer: session.issuer.clone(),
+                client_id: session.client_id.clone(),
+                subject: session.subject.clone(),
+                access_token: session.access_token.clone(),
+                scopes: session.scopes.clone(),
+                issued_at: session.issued_at,
+                expires_at: session.expires_at,
+                claims: standard_claims,
+            })
+        })
+    }
+}
+
 /// Provides access to request-level authentication data.
+///
+/// The middleware populates the authentication data on *every* request
+/// that belongs to an authenticated session, and not just on the routes
+/// that [require authentication](crate::OpenIdConnectRouteExt), so
+/// public pages can personalize their content for logged-in users by
+/// checking [`is_authenticated()`](Self::is_authenticated). Doing so is
+/// cheap: the full [`AuthInfo`] (including the standard claims) is only
+/// assembled if a handler asks for it, and sessions whose access token
+/// has expired are only refreshed by the routes that require
+/// authentication (until then, the expired token is returned).
+#[tide::utils::async_trait]
 pub trait OpenIdConnectRequestExt {
     /// Returns `true` if the request is authenticated, `false`
     /// otherwise.
     fn is_authenticated(&self) -> bool;
 
+    /// Gets all of the authentication information (the subject,
+    /// scopes, token lifetime, and standard claims) for the
+    /// authenticated user, or `None` if the session has not been
+    /// authenticated.
+    fn auth_info(&self) -> Option<AuthInfo>;
+
     /// Gets the Identity Provider-specific access token for the
     /// authenticated user, or `None` if the session has not been
     /// authenticated.
@@ -18,59 +145,499 @@ pub trait OpenIdConnectRequestExt {
     /// `None` if the session has not been authenticated.
     fn scopes(&self) -> Option<Vec<String>>;
 
+    /// Returns `true` if the request is authenticated *and* the given
+    /// scope was granted to the user, `false` otherwise. Note that the
+    /// provider may grant fewer scopes than were
+    /// [requested](crate::OpenIdConnectMiddleware::with_scopes).
+    fn has_scope(&self, scope: &str) -> bool;
+
     /// Gets the Identity Provider-specific user id of the authenticated
     /// user, or `None` if the session has not been authenticated.
     fn user_id(&self) -> Option<String>;
+
+    /// Borrows the Identity Provider-specific user id of the
+    /// authenticated user, or returns `None` if the session has not
+    /// been authenticated. Unlike [`user_id()`](Self::user_id), the
+    /// subject is not copied, which matters to endpoints that check the
+    /// user on every (frequent) request.
+    fn authenticated_subject(&self) -> Option<&str>;
+
+    /// Gets the [provider id](crate::OpenIdConnectMiddleware::with_provider_id)
+    /// of the middleware that authenticated the request, or `None` if
+    /// the session has not been authenticated (or if that middleware
+    /// was not configured with a provider id).
+    fn provider_id(&self) -> Option<String>;
+
+    /// Gets the issuer URL of the Identity Provider that authenticated
+    /// the request, or `None` if the session has not been
+    /// authenticated. Useful when users can sign in with [multiple
+    /// providers](crate::OpenIdConnectMiddleware::with_provider_id).
+    fn issuer(&self) -> Option<IssuerUrl>;
+
+    /// Gets the client id of the middleware that authenticated the
+    /// request, or `None` if the session has not been authenticated.
+    fn client_id(&self) -> Option<String>;
+
+    /// Gets the standard claims (`email`, `preferred_username`, etc.)
+    /// from the authenticated user's ID token, or `None` if the session
+    /// has not been authenticated.
+    ///
+    /// The claims are captured when the ID token is verified during the
+    /// login process, and so do not change until the user logs in again.
+    /// Note that claims that were not
+    /// [persisted](crate::OpenIdConnectMiddleware::with_persisted_claims)
+    /// will be missing from the returned claims.
+    fn id_token_claims(&self) -> Option<StandardClaims<CoreGenderClaim>>;
+
+    /// Gets an individual claim from the authenticated user's ID token
+    /// -- including claims that are not part of the standard claims --
+    /// deserialized into the given type. Returns `None` if the session
+    /// has not been authenticated, or if the claim is missing or could
+    /// not be deserialized into that type.
+    fn claim<T: DeserializeOwned>(&self, name: &str) -> Option<T>;
+
+    /// Gets the provider-specific claims from the authenticated user's
+    /// ID token, or `None` if the session has not been authenticated (or
+    /// if the claims could not be deserialized into the given type).
+    /// `AC` is usually the type with which the middleware was
+    /// [created](crate::OpenIdConnectMiddleware::new_with_additional_claims).
+    ///
+    /// Note that claims that were not
+    /// [persisted](crate::OpenIdConnectMiddleware::with_persisted_claims)
+    /// will be missing when deserializing the claims.
+    fn additional_claims<AC: AdditionalClaims>(&self) -> Option<AC>;
+
+    /// Gets the claims returned by the provider's UserInfo endpoint,
+    /// deserialized into the given type (for example,
+    /// `StandardClaims<CoreGenderClaim>`, or an application-specific
+    /// type that includes the provider's custom attributes). Returns
+    /// `None` if the session has not been authenticated, if the
+    /// middleware has not been configured to
+    /// [fetch the UserInfo claims](crate::Config::fetch_userinfo), or if
+    /// the claims could not be deserialized into that type.
+    ///
+    /// The claims are fetched during the login process, and so do not
+    /// change until the user logs in again.
+    fn userinfo<T: DeserializeOwned>(&self) -> Option<T>;
+
+    /// Gets the authenticated user's ID token, exactly as it was
+    /// issued (and signed) by the provider, which allows the token to
+    /// be forwarded to downstream services that verify it themselves.
+    /// Returns `None` if the session has not been authenticated, or if
+    /// the middleware has not been configured to [retain the ID
+    /// token](crate::Config::retain_id_token).
+    fn id_token_raw(&self) -> Option<String>;
+
+    /// Exchanges the authenticated user's access token for a token
+    /// that targets another service (the request's `audience`), by way
+    /// of [OAuth 2.0 Token Exchange](https://datatracker.ietf.org/doc/html/rfc8693).
+    /// The middleware authenticates to the provider's token endpoint
+    /// with its own client credentials.
+    ///
+    /// Exchanged tokens are cached per session (and request) until they
+    /// expire, so handlers can call this method on every request.
+    /// Returns [`OidcError::NotAuthenticated`] if the session has not
+    /// been authenticated.
+    async fn exchange_token(&self, request: ExchangeRequest) -> Result<ExchangedToken, OidcError>;
+
+    /// Creates a [DPoP](crate::dpop) proof for a request with the given
+    /// method to the given URL (a downstream API, for example) that
+    /// carries the authenticated user's access token, which must then be
+    /// sent with the `DPoP` authorization scheme and the proof in the
+    /// `DPoP` header. Each proof may only be used for a single request.
+    ///
+    /// Returns `Ok(None)` if DPoP is not [enabled](crate::Config::dpop)
+    /// (in which case the access token is an ordinary bearer token) or
+    /// the request was authenticated with a bearer token, or
+    /// [`OidcError::NotAuthenticated`] if the session has not been
+    /// authenticated.
+    fn dpop_proof(&self, method: Method, url: &Url) -> Result<Option<String>, OidcError>;
+
+    /// Gets the application's [`surf::Client`], set up to send the
+    /// authenticated user's access token to upstream services, or
+    /// `None` if the session has not been authenticated (or if the
+    /// [`BearerForwardingMiddleware`](crate::BearerForwardingMiddleware)
+    /// has not been added to the app).
+    fn upstream_client(&self) -> Option<surf::Client>;
+
+    /// Removes the middleware's authentication state from the session,
+    /// so that the user must log in again, without sending the browser
+    /// through the [logout](crate::OpenIdConnectMiddleware::with_logout_path)
+    /// process. The rest of the session (the application's own state)
+    /// is left intact; call `session_mut().destroy()` as well in order
+    /// to destroy the entire session. The remainder of the request is
+    /// treated as unauthenticated. Does nothing if the request is not
+    /// authenticated.
+    ///
+    /// Note that this only invalidates the *current* session. In order
+    /// to invalidate all of a user's sessions (an admin-triggered "sign
+    /// out everywhere", for example), the application must record the
+    /// session ids of each [subject](Self::user_id) in its session
+    /// store and destroy those sessions itself -- which is also what a
+    /// back-channel [`LogoutHandler`](crate::backchannel_logout::LogoutHandler)
+    /// must do, and so the two can share an implementation.
+    fn invalidate_session(&mut self);
+
+    /// Builds the URL of the provider's authorization endpoint for a new
+    /// login, exactly as the middleware's [login
+    /// path](crate::OpenIdConnectMiddleware::with_login_path) would
+    /// redirect to it, which allows applications to render their own
+    /// login page (with a "Sign in with SSO" link that points directly
+    /// at the provider, for example). Typically called on unauthenticated
+    /// requests, but works on every request.
+    ///
+    /// The login's CSRF state and nonce are stored in the session, so
+    /// that the callback validates once the user completes the login.
+    /// Note that each call therefore starts a new pending login, which
+    /// counts against the [maximum number of pending
+    /// logins](crate::OpenIdConnectMiddleware::with_max_pending_auth):
+    /// calling this method more often than that (on a single page, or
+    /// across several pages before the user follows a link) evicts the
+    /// oldest URLs, whose logins then fail.
+    async fn build_login_url(&mut self) -> tide::Result<String>;
 }
 
+#[tide::utils::async_trait]
 impl<State> OpenIdConnectRequestExt for Request<State>
 where
     State: Send + Sync + 'static,
 {
     fn is_authenticated(&self) -> bool {
-        matches!(
-            self.auth_state(),
-            OpenIdConnectRequestExtData::Authenticated { .. }
-        )
+        self.session_auth_info().is_some()
+    }
+
+    fn auth_info(&self) -> Option<AuthInfo> {
+        self.auth_info_ref().cloned()
     }
 
     fn access_token(&self) -> Option<String> {
+        self.session_auth_info()
+            .map(|auth_info| auth_info.access_token.clone())
+    }
+
+    fn scopes(&self) -> Option<Vec<String>> {
+        self.session_auth_info()
+            .map(|auth_info| auth_info.scopes.clone())
+    }
+
+    fn has_scope(&self, scope: &str) -> bool {
+        self.session_auth_info()
+            .is_some_and(|auth_info| auth_info.scopes.iter().any(|s| s == scope))
+    }
+
+    fn user_id(&self) -> Option<String> {
+        self.session_auth_info()
+            .map(|auth_info| auth_info.subject.clone())
+    }
+
+    fn authenticated_subject(&self) -> Option<&str> {
+        self.session_auth_info()
+            .map(|auth_info| auth_info.subject.as_str())
+    }
+
+    fn provider_id(&self) -> Option<String> {
+        self.session_auth_info()
+            .and_then(|auth_info| auth_info.provider_id.clone())
+    }
+
+    fn issuer(&self) -> Option<IssuerUrl> {
+        self.session_auth_info()
+            .map(|auth_info| auth_info.issuer.clone())
+    }
+
+    fn client_id(&self) -> Option<String> {
+        self.session_auth_info()
+            .map(|auth_info| auth_info.client_id.clone())
+    }
+
+    fn id_token_claims(&self) -> Option<StandardClaims<CoreGenderClaim>> {
+        self.auth_info_ref()
+            .map(|auth_info| auth_info.claims.clone())
+    }
+
+    fn claim<T: DeserializeOwned>(&self, name: &str) -> Option<T> {
+        match self.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated { claims, .. } => claims
+                .get(name)
+                .and_then(|claim| serde_json::from_value(claim.clone()).ok()),
+            _ => None,
+        }
+    }
+
+    fn additional_claims<AC: AdditionalClaims>(&self) -> Option<AC> {
         match self.auth_state() {
-            OpenIdConnectRequestExtData::Authenticated { access_token, .. } => {
-                Some(access_token.clone())
+            OpenIdConnectRequestExtData::Authenticated { claims, .. } => {
+                serde_json::from_value(serde_json::Value::Object(claims.clone())).ok()
             }
             _ => None,
         }
     }
 
-    fn scopes(&self) -> Option<Vec<String>> {
+    fn userinfo<T: DeserializeOwned>(&self) -> Option<T> {
         match self.auth_state() {
-            OpenIdConnectRequestExtData::Authenticated { scopes, .. } => Some(scopes.clone()),
+            OpenIdConnectRequestExtData::Authenticated {
+                userinfo: Some(userinfo),
+                ..
+            } => serde_json::from_value(serde_json::Value::Object(userinfo.clone())).ok(),
             _ => None,
         }
     }
 
-    fn user_id(&self) -> Option<String> {
+    fn id_token_raw(&self) -> Option<String> {
         match self.auth_state() {
-            OpenIdConnectRequestExtData::Authenticated { user_id, .. } => Some(user_id.clone()),
+            OpenIdConnectRequestExtData::Authenticated { id_token, .. } => id_token.clone(),
             _ => None,
         }
     }
+
+    async fn exchange_token(&self, request: ExchangeRequest) -> Result<ExchangedToken, OidcError> {
+        match self.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated {
+                auth_info,
+                token_exchanger,
+                session_key,
+                ..
+            } => {
+                // Exchanged tokens are cached per session, or per bearer
+                // token for requests that do not have a session.
+                let access_token = &auth_info.session.access_token;
+                let cache_key = match session_key {
+                    Some(_) => self.session().id(),
+                    None => access_token,
+                };
+                token_exchanger
+                    .exchange(cache_key, access_token, request)
+                    .await
+            }
+            _ => Err(OidcError::NotAuthenticated),
+        }
+    }
+
+    fn dpop_proof(&self, method: Method, url: &Url) -> Result<Option<String>, OidcError> {
+        match self.auth_state() {
+            // Bearer tokens are presented by the client, which holds
+            // the key to which they are bound (if any), and so only the
+            // access tokens that the middleware obtained itself get a
+            // proof.
+            OpenIdConnectRequestExtData::Authenticated {
+                auth_info,
+                token_exchanger,
+                session_key: Some(_),
+                ..
+            } => match &token_exchanger.dpop {
+                Some(dpop) => dpop
+                    .proof(method.as_ref(), url, Some(&auth_info.session.access_token))
+                    .map(Some)
+                    .map_err(OidcError::Dpop),
+                None => Ok(None),
+            },
+            OpenIdConnectRequestExtData::Authenticated { .. } => Ok(None),
+            _ => Err(OidcError::NotAuthenticated),
+        }
+    }
+
+    fn upstream_client(&self) -> Option<surf::Client> {
+        self.ext::<UpstreamClient>()
+            .map(|UpstreamClient(client)| client.clone())
+    }
+
+    fn invalidate_session(&mut self) {
+        let unauthenticated = match self.ext::<OpenIdConnectRequestExtData>() {
+            Some(OpenIdConnectRequestExtData::Authenticated {
+                session_key,
+                redirect_strategy,
+                login_path,
+                ..
+            }) => {
+                let session_key = session_key.clone();
+                let unauthenticated = OpenIdConnectRequestExtData::Unauthenticated {
+                    redirect_strategy: Arc::clone(redirect_strategy),
+                    login_path: login_path.clone(),
+                };
+                if let Some(session_key) = session_key {
+                    self.session_mut().remove(&session_key);
+                }
+                unauthenticated
+            }
+            _ => return,
+        };
+        tide::log::debug!("Invalidated the authenticated session.");
+        self.set_ext(unauthenticated);
+    }
+
+    async fn build_login_url(&mut self) -> tide::Result<String> {
+        let LoginUrlBuilderHandle(builder) = self
+            .ext::<LoginUrlBuilderHandle>()
+            .cloned()
+            .expect("You must install OpenIdConnectMiddleware to build login URLs.");
+        builder.build_login_url(self.as_mut()).await
+    }
+}
+
+/// Builds login URLs on behalf of request handlers; implemented by the
+/// middleware.
+#[tide::utils::async_trait]
+pub(crate) trait LoginUrlBuilder: Send + Sync {
+    /// Builds the authorization URL of a new login, and adds the login
+    /// to the pending logins in the request's session.
+    async fn build_login_url(&self, req: &mut tide::http::Request) -> tide::Result<String>;
+}
+
+/// Request extension through which handlers reach the middleware's
+/// [`LoginUrlBuilder`].
+#[derive(Clone)]
+pub(crate) struct LoginUrlBuilderHandle(pub(crate) Arc<dyn LoginUrlBuilder>);
+
+/// Refreshes the expired access token of an authenticated session on
+/// behalf of the route guards; implemented by the middleware.
+#[tide::utils::async_trait]
+pub(crate) trait SessionRefresher: Send + Sync {
+    /// Refreshes the session's tokens, updating the session and the
+    /// request's authentication data, or returns the response to send
+    /// instead if the tokens could not be refreshed.
+    async fn refresh(&self, req: &mut tide::http::Request) -> tide::Result<Option<tide::Response>>;
+}
+
+/// Refreshes the request's session if its access token has expired,
+/// returning the response to send instead if it could not be
+/// refreshed. The middleware leaves refreshing to the routes that
+/// require authentication (or forward the access token), so that
+/// public routes never wait for the provider.
+pub(crate) async fn refresh_expired_session<State>(
+    req: &mut Request<State>,
+) -> tide::Result<Option<tide::Response>>
+where
+    State: Send + Sync + 'static,
+{
+    let refresher = match req.ext::<OpenIdConnectRequestExtData>() {
+        Some(OpenIdConnectRequestExtData::Authenticated {
+            refresher: Some(refresher),
+            ..
+        }) => Arc::clone(refresher),
+        _ => return Ok(None),
+    };
+    refresher.refresh(req.as_mut()).await
 }
 
 pub(crate) enum OpenIdConnectRequestExtData {
     Unauthenticated {
-        redirect_strategy: Arc<dyn RedirectStrategy>,
+        redirect_strategy: Arc<dyn UnauthenticatedHandler>,
+        login_path: String,
     },
     Authenticated {
-        access_token: String,
-        scopes: Vec<String>,
-        user_id: String,
+        auth_info: LazyAuthInfo,
+        claims: Claims,
+        userinfo: Option<Claims>,
+        id_token: Option<String>,
+        access_denied_handler: Arc<dyn AccessDeniedHandler>,
+        token_exchanger: Arc<TokenExchanger>,
+        /// Session key of the middleware's session state, or `None` if
+        /// the request was authenticated with a bearer token.
+        session_key: Option<String>,
+        /// Refreshes the session's access token, which has expired, or
+        /// `None` if the token has not expired (or cannot be refreshed).
+        refresher: Option<Arc<dyn SessionRefresher>>,
+        /// Realm of the `WWW-Authenticate` challenges in the responses
+        /// to the request.
+        realm: String,
+        redirect_strategy: Arc<dyn UnauthenticatedHandler>,
+        login_path: String,
     },
+    /// The request could not be authenticated, because the provider
+    /// metadata of a lazily-created middleware has not been loaded yet
+    /// (and the provider is unreachable). Public routes are still
+    /// served, while routes that require authentication respond with
+    /// `503 Service Unavailable`.
+    ProviderUnavailable,
+}
+
+/// Outcome of the checks performed by a route guard. Requests are
+/// either allowed, or denied because we don't know who the user is
+/// (which logging in resolves), or because we know who the user is and
+/// the answer is no (which logging in again would not change, and so
+/// must never send the user back through the login process).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AuthDecision {
+    /// The request may proceed.
+    Allow,
+
+    /// The request is not authenticated.
+    Unauthenticated,
+
+    /// The request is authenticated, but was not granted the given
+    /// (required) scopes.
+    InsufficientScope(Vec<String>),
+
+    /// The request is authenticated, but the user may not access the
+    /// route.
+    Forbidden,
+}
+
+impl OpenIdConnectRequestExtData {
+    /// Decides whether the request has been granted the required scopes
+    /// and is approved by the given claims predicate.
+    pub(crate) fn decide<F>(&self, required_scopes: &[String], predicate: F) -> AuthDecision
+    where
+        F: FnOnce(&Claims) -> bool,
+    {
+        match self {
+            Self::Unauthenticated { .. } | Self::ProviderUnavailable => {
+                AuthDecision::Unauthenticated
+            }
+            Self::Authenticated {
+                auth_info, claims, ..
+            } => {
+                let missing_scopes: Vec<String> = required_scopes
+                    .iter()
+                    .filter(|scope| !auth_info.session.scopes.contains(scope))
+                    .cloned()
+                    .collect();
+                if !missing_scopes.is_empty() {
+                    AuthDecision::InsufficientScope(missing_scopes)
+                } else if !predicate(claims) {
+                    AuthDecision::Forbidden
+                } else {
+                    AuthDecision::Allow
+                }
+            }
+        }
+    }
 }
 
 pub(crate) trait OpenIdConnectRequestExtInternal {
     fn auth_state(&self) -> &OpenIdConnectRequestExtData;
+
+    fn session_auth_info(&self) -> Option<&SessionAuthInfo> {
+        match self.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated { auth_info, .. } => {
+                Some(auth_info.session.as_ref())
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the DPoP configuration to which the access token is
+    /// bound, if the middleware obtained the token itself (rather than
+    /// it being presented as a bearer token) with DPoP enabled.
+    fn dpop_config(&self) -> Option<&DpopConfig> {
+        match self.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated {
+                token_exchanger,
+                session_key: Some(_),
+                ..
+            } => token_exchanger.dpop.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn auth_info_ref(&self) -> Option<&AuthInfo> {
+        match self.auth_state() {
+            OpenIdConnectRequestExtData::Authenticated {
+                auth_info, claims, ..
+            } => Some(auth_info.get(claims)),
+            _ => None,
+        }
+    }
 }
 
 impl<State> OpenIdConnectRequestExtInternal for Request<State>
@@ -0,0 +1,86 @@
+//! Signed authorization request objects ([RFC 9101]), which pass the
+//! authorization request parameters to the provider as a JWT in the
+//! `request` parameter.
+//!
+//! [RFC 9101]: https://datatracker.ietf.org/doc/html/rfc9101
+
+use chrono::Utc;
+use openidconnect::{
+    core::{CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey},
+    url::Url,
+    ClientId, JsonWebKey, PrivateSigningKey,
+};
+use serde_json::json;
+
+/// Lifetime of the request objects generated by the middleware; each
+/// request object is only used for a single authorization request.
+const REQUEST_OBJECT_LIFETIME: chrono::Duration = chrono::Duration::minutes(5);
+
+/// Signs the authorization request parameters as a request object, which
+/// is added to the authorization request as the `request` parameter;
+/// see [`with_request_object_signing()`].
+///
+/// [`with_request_object_signing()`]: crate::OpenIdConnectMiddleware::with_request_object_signing
+pub struct RequestObjectSigning {
+    /// Key used to sign the request objects. The provider must have
+    /// been configured with the corresponding public key; the key id,
+    /// if any, is included in the JWT header.
+    pub signing_key: Box<CoreRsaPrivateSigningKey>,
+
+    /// Algorithm with which the request objects are signed, which must
+    /// be one of the RSA algorithms (`RS256`, `PS256`, etc.).
+    pub alg: CoreJwsSigningAlgorithm,
+}
+
+impl std::fmt::Debug for RequestObjectSigning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestObjectSigning")
+            .field("signing_key", &"[redacted]")
+            .field("alg", &self.alg)
+            .finish()
+    }
+}
+
+impl RequestObjectSigning {
+    /// Creates a request object that contains all of the parameters of
+    /// the given authorization URL, and which is intended for the given
+    /// audience (the provider's issuer URL).
+    pub(crate) fn sign(
+        &self,
+        client_id: &ClientId,
+        audience: &str,
+        authorize_url: &Url,
+    ) -> Result<String, String> {
+        let alg = serde_json::to_value(&self.alg).map_err(|e| e.to_string())?;
+        let mut header = json!({ "alg": alg, "typ": "oauth-authz-req+jwt" });
+        if let Some(kid) = self.signing_key.as_verification_key().key_id() {
+            header["kid"] = json!(kid.as_str());
+        }
+
+        let now = Utc::now();
+        let mut claims = json!({
+            "iss": client_id.as_str(),
+            "aud": audience,
+            "iat": now.timestamp(),
+            "exp": (now + REQUEST_OBJECT_LIFETIME).timestamp(),
+        });
+        for (name, value) in authorize_url.query_pairs() {
+            claims[name.as_ref()] = json!(value);
+        }
+
+        let signing_input = format!(
+            "{}.{}",
+            base64::encode_config(header.to_string(), base64::URL_SAFE_NO_PAD),
+            base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD),
+        );
+        let signature = self
+            .signing_key
+            .sign(&self.alg, signing_input.as_bytes())
+            .map_err(|e| e.to_string())?;
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+}
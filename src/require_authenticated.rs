@@ -0,0 +1,161 @@
+//! Authentication checks that redirect unauthenticated requests to the
+//! login path with a `303 See Other`, and which can be used either as a
+//! middleware or from within a handler.
+
+use crate::error::OidcError;
+use crate::request_ext::{refresh_expired_session, AuthDecision, OpenIdConnectRequestExtData};
+use openidconnect::url::form_urlencoded;
+use tide::{Middleware, Next, Redirect, Request, StatusCode};
+
+/// Error returned by [`RequireAuthenticated::check()`] for
+/// unauthenticated requests. [`OpenIdConnectMiddleware`] turns this
+/// error into a `303 See Other` redirect to the error's
+/// [`location`](Self::location), and so handlers can simply return the
+/// error with `?`.
+///
+/// [`OpenIdConnectMiddleware`]: crate::OpenIdConnectMiddleware
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("Login required; redirecting to {location}")]
+pub struct LoginRequired {
+    /// Login path, including the `return_to` query parameter.
+    pub location: String,
+}
+
+/// Requires that the request is authenticated; see
+/// [`require_authenticated()`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequireAuthenticated;
+
+/// Returns a check that redirects unauthenticated requests to the
+/// middleware's [login path](crate::OpenIdConnectMiddleware::with_login_path)
+/// with a `303 See Other`, including the path (and query string) of the
+/// request as the `return_to` query parameter so that the browser is
+/// sent back to the same page after logging in.
+///
+/// The check can be used as a middleware:
+///
+/// ```no_run
+/// # let mut app = tide::new();
+/// app.at("/account")
+///     .with(tide_openidconnect::require_authenticated())
+///     .get(|_req: tide::Request<()>| async { Ok("Protected GET") });
+/// ```
+///
+/// Or from within a handler:
+///
+/// ```no_run
+/// # let mut app = tide::new();
+/// app.at("/account").get(|req: tide::Request<()>| async move {
+///     tide_openidconnect::require_authenticated().check(&req)?;
+///     Ok("Protected GET")
+/// });
+/// ```
+///
+/// As a middleware, the check first refreshes sessions whose access
+/// token has expired. Handlers that call
+/// [`check()`](RequireAuthenticated::check) themselves cannot do so,
+/// and see such sessions as authenticated.
+///
+/// Unlike the [`OpenIdConnectRequestExt`](crate::OpenIdConnectRequestExt)
+/// functions, this check does not panic if the
+/// [`OpenIdConnectMiddleware`](crate::OpenIdConnectMiddleware) is
+/// missing; it returns a `500 Internal Server Error` instead, as it does
+/// if the check is applied to the login path itself (which would
+/// otherwise cause a redirect loop). Requests that cannot be
+/// authenticated because the provider of a lazily-created middleware is
+/// unavailable get a `503 Service Unavailable`.
+pub fn require_authenticated() -> RequireAuthenticated {
+    RequireAuthenticated
+}
+
+impl RequireAuthenticated {
+    /// Returns `Ok(())` if the request is authenticated, or a
+    /// [`LoginRequired`] error (with a `303 See Other` status) if it is
+    /// not.
+    pub fn check<State>(&self, req: &Request<State>) -> tide::Result<()> {
+        let auth_state = match req.ext::<OpenIdConnectRequestExtData>() {
+            Some(auth_state) => auth_state,
+            None => {
+                tide::log::error!(
+                    "require_authenticated() was used without installing OpenIdConnectMiddleware."
+                );
+                return Err(tide::Error::from_str(
+                    StatusCode::InternalServerError,
+                    "Authentication is not configured.",
+                ));
+            }
+        };
+        let login_path = match (auth_state.decide(&[], |_| true), auth_state) {
+            (AuthDecision::Allow, _) => return Ok(()),
+            (_, OpenIdConnectRequestExtData::Unauthenticated { login_path, .. }) => login_path,
+            // Nor can anyone log in while the provider is unavailable.
+            (_, OpenIdConnectRequestExtData::ProviderUnavailable) => {
+                return Err(tide::Error::from_str(
+                    StatusCode::ServiceUnavailable,
+                    OidcError::ProviderUnavailable.to_string(),
+                ))
+            }
+            // Authenticated requests are always allowed by this check,
+            // but must never be sent back to the login page if that
+            // changes.
+            (_, OpenIdConnectRequestExtData::Authenticated { .. }) => {
+                return Err(tide::Error::from_str(
+                    StatusCode::Forbidden,
+                    "Access denied.",
+                ))
+            }
+        };
+        if req.url().path() == login_path {
+            tide::log::error!(
+                "require_authenticated() must not protect the login path ({}).",
+                login_path
+            );
+            return Err(tide::Error::from_str(
+                StatusCode::InternalServerError,
+                "Authentication is misconfigured.",
+            ));
+        }
+
+        let mut return_to = req.url().path().to_string();
+        if let Some(query) = req.url().query() {
+            return_to.push('?');
+            return_to.push_str(query);
+        }
+        let location = format!(
+            "{}?{}",
+            login_path,
+            form_urlencoded::Serializer::new(String::new())
+                .append_pair("return_to", &return_to)
+                .finish()
+        );
+        tide::log::debug!(
+            "Unauthenticated request; redirecting browser to {}.",
+            location
+        );
+        Err(tide::Error::new(
+            StatusCode::SeeOther,
+            LoginRequired { location },
+        ))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State> Middleware<State> for RequireAuthenticated
+where
+    State: Clone + Send + Sync + 'static,
+{
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if let Some(res) = refresh_expired_session(&mut req).await? {
+            return Ok(res);
+        }
+        match self.check(&req) {
+            Ok(()) => Ok(next.run(req).await),
+            Err(error) => match error.downcast_ref::<LoginRequired>() {
+                Some(LoginRequired { location }) => {
+                    Ok(Redirect::see_other(location.as_str()).into())
+                }
+                None => Err(error),
+            },
+        }
+    }
+}
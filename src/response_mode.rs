@@ -0,0 +1,126 @@
+//! Authorization response modes, including the JWT Secured
+//! Authorization Response Mode ([JARM]), in which the provider signs
+//! the authorization response so that the middleware can verify that
+//! the `code` and `state` were issued by the provider (and for this
+//! client) before using them.
+//!
+//! [JARM]: https://openid.net/specs/oauth-v2-jarm.html
+
+use crate::issuer::IssuerVerification;
+use crate::jwt::{verify_jwt, JwtError};
+use openidconnect::core::{CoreJsonWebKeySet, CoreJwsSigningAlgorithm};
+use openidconnect::{AuthorizationCode, ClientId, IssuerUrl};
+use serde::Deserialize;
+use tide::http::Method;
+
+/// Determines how the provider returns the authorization response (the
+/// `code` and `state`, or an `error`) to the middleware's callback URL;
+/// see
+/// [`with_response_mode()`](crate::OpenIdConnectMiddleware::with_response_mode).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResponseMode {
+    /// The response parameters are added to the query string of the
+    /// callback URL, which is the default response mode of the
+    /// authorization code flow.
+    #[default]
+    Query,
+
+    /// The response parameters are the fields of a form that the
+    /// browser `POST`s to the callback URL (`response_mode=form_post`),
+    /// which keeps them out of the URL, and thus out of the browser
+    /// history, server logs, and `Referer` headers.
+    ///
+    /// As with any `POST` callback, the response is only accepted if
+    /// its `state` matches a login that was started by the same
+    /// session. Browsers do not include `SameSite=Lax` cookies in
+    /// cross-site `POST` requests, and so the session cookie must be
+    /// configured with `SameSite::None` when using this response mode.
+    FormPost,
+
+    /// The response parameters are returned as a signed JWT in the
+    /// `response` query parameter (`response_mode=jwt`). The middleware
+    /// verifies the JWT's signature (using the provider's JSON Web Key
+    /// Set), issuer, audience and expiration time before processing the
+    /// response.
+    Jwt,
+
+    /// The response parameters are returned as a signed JWT in the
+    /// `response` field of a form that the browser `POST`s to the
+    /// callback URL (`response_mode=form_post.jwt`). The JWT is verified
+    /// in the same way as with [`Jwt`](Self::Jwt).
+    ///
+    /// Note that browsers do not include `SameSite=Lax` cookies in
+    /// cross-site `POST` requests, and so the session cookie must be
+    /// configured with `SameSite::None` when using this response mode.
+    FormPostJwt,
+}
+
+impl ResponseMode {
+    /// Returns the value of the `response_mode` authorization request
+    /// parameter, or `None` if the parameter is not needed.
+    pub(crate) fn param(self) -> Option<&'static str> {
+        match self {
+            Self::Query => None,
+            Self::FormPost => Some("form_post"),
+            Self::Jwt => Some("jwt"),
+            Self::FormPostJwt => Some("form_post.jwt"),
+        }
+    }
+
+    /// Returns the method with which the browser requests the callback
+    /// URL.
+    pub(crate) fn callback_method(self) -> Method {
+        match self {
+            Self::Query | Self::Jwt => Method::Get,
+            Self::FormPost | Self::FormPostJwt => Method::Post,
+        }
+    }
+
+    /// Describes the session cookie configuration with which browsers
+    /// include the session cookie in the (cross-site) callback request.
+    pub(crate) fn session_cookie_advice(self) -> &'static str {
+        match self.callback_method() {
+            Method::Post => "SameSite::None and a Secure cookie (browsers only send SameSite=None cookies over HTTPS)",
+            _ => "SameSite::Lax (but do *not* mutate server-side state on GET requests if you make that change!)",
+        }
+    }
+}
+
+/// Authorization response parameters.
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuthorizationResponse {
+    pub(crate) code: Option<AuthorizationCode>,
+    pub(crate) error: Option<String>,
+    pub(crate) state: String,
+    pub(crate) session_state: Option<String>,
+}
+
+/// Authorization response that has been encoded as a JWT.
+#[derive(Debug, Deserialize)]
+pub(crate) struct JwtAuthorizationResponse {
+    pub(crate) response: String,
+}
+
+/// Verifies a JWT authorization response and returns the response
+/// parameters that it contains.
+pub(crate) fn verify_jwt_response(
+    response: &str,
+    keys: &CoreJsonWebKeySet,
+    allowed_algs: &[CoreJwsSigningAlgorithm],
+    issuer_url: &IssuerUrl,
+    issuer_verification: IssuerVerification,
+    client_id: &ClientId,
+    clock_skew_tolerance: chrono::Duration,
+) -> Result<AuthorizationResponse, JwtError> {
+    let claims = verify_jwt(
+        response,
+        keys,
+        allowed_algs,
+        issuer_url,
+        issuer_verification,
+        &[client_id.as_str()],
+        clock_skew_tolerance,
+    )?;
+    serde_json::from_value(serde_json::Value::Object(claims))
+        .map_err(|_| JwtError::Invalid("malformed authorization response"))
+}
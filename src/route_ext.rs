@@ -1,5 +1,12 @@
-use crate::request_ext::{OpenIdConnectRequestExtData, OpenIdConnectRequestExtInternal};
-use tide::{Middleware, Next, Request, Route};
+use crate::challenge::{bearer_challenge, ChallengeError};
+use crate::error::OidcError;
+use crate::middleware::{provider_unavailable_response, Claims};
+use crate::request_ext::{
+    refresh_expired_session, AuthDecision, OpenIdConnectRequestExtData,
+    OpenIdConnectRequestExtInternal,
+};
+use tide::http::headers::WWW_AUTHENTICATE;
+use tide::{Middleware, Next, Request, Route, StatusCode};
 
 /// Authorization extensions to Tide [Route](tide::Route) handles.
 ///
@@ -52,6 +59,23 @@ use tide::{Middleware, Next, Request, Route};
 ///     .authenticated()
 ///     .post(|req: Request| async { Ok("Protected POST") });
 ///
+/// app.at("/admin")
+///     .authenticated_with_scopes(&["admin:write"])
+///     .post(|req: Request| async { Ok("Protected and scoped POST") });
+///
+/// app.at("/platform")
+///     .authorized(|claims| {
+///         claims
+///             .get("groups")
+///             .and_then(|groups| groups.as_array())
+///             .is_some_and(|groups| groups.iter().any(|g| g == "platform-admins"))
+///     })
+///     .get(|req: Request| async { Ok("Protected and authorized GET") });
+///
+/// app.at("/api/data")
+///     .authenticated_or(|_req| tide::Response::new(tide::StatusCode::Unauthorized))
+///     .get(|req: Request| async { Ok("Protected API GET") });
+///
 /// # })
 /// ```
 pub trait OpenIdConnectRouteExt {
@@ -59,35 +83,185 @@ pub trait OpenIdConnectRouteExt {
     /// route, redirecting the browser to the login page if the request
     /// is not authenticated.
     fn authenticated(&mut self) -> &mut Self;
+
+    /// Requires authentication *and* the given scopes on the subsequent
+    /// portions of this route. Unauthenticated requests are handled in
+    /// the same way as [`authenticated()`](Self::authenticated), whereas
+    /// authenticated requests that were not granted all of the scopes
+    /// receive the [insufficient scope
+    /// response](crate::OpenIdConnectMiddleware::with_insufficient_scope_response)
+    /// (`403 Forbidden` by default) instead of being sent back through
+    /// the login process.
+    fn authenticated_with_scopes(&mut self, scopes: &[impl AsRef<str>]) -> &mut Self;
+
+    /// Requires authentication on the subsequent portions of this
+    /// route *and* that the given predicate approves the authenticated
+    /// user's ID token [claims](crate::Claims), which include any
+    /// custom or additional claims (but only those claims that were
+    /// [persisted](crate::OpenIdConnectMiddleware::with_persisted_claims)).
+    ///
+    /// Unauthenticated requests are handled in the same way as
+    /// [`authenticated()`](Self::authenticated), and so there is no
+    /// need to also apply `authenticated()` to the route (although
+    /// doing so is harmless, since the two checks are independent).
+    /// Authenticated requests that are denied by the predicate receive
+    /// the [access denied
+    /// response](crate::OpenIdConnectMiddleware::with_access_denied_response)
+    /// (`403 Forbidden` by default) instead of being sent back through
+    /// the login process. Multiple `authorized()` predicates can be
+    /// applied to a route, in which case all of them must approve the
+    /// request.
+    fn authorized<F>(&mut self, predicate: F) -> &mut Self
+    where
+        F: Fn(&Claims) -> bool + Send + Sync + 'static;
+
+    /// Requires authentication on the subsequent portions of this
+    /// route, responding to unauthenticated requests with the response
+    /// generated by the given function *instead of* the middleware's
+    /// [redirect
+    /// strategy](crate::OpenIdConnectMiddleware::with_unauthenticated_redirect_strategy).
+    /// The function receives the request, and so can vary the response
+    /// by (for example) the request's `Accept` header. This is useful
+    /// for API routes, which should return `401 Unauthorized` rather
+    /// than redirecting to the login page.
+    fn authenticated_or<F>(&mut self, unauthenticated_response: F) -> &mut Self
+    where
+        F: Fn(&tide::http::Request) -> tide::Response + Send + Sync + 'static;
 }
 
 impl<'a, State: Clone + Send + Sync + 'static> OpenIdConnectRouteExt for Route<'a, State> {
     fn authenticated(&mut self) -> &mut Self {
-        self.with(MustAuthenticateMiddleware {})
+        self.with(MustAuthenticateMiddleware::default())
+    }
+
+    fn authenticated_with_scopes(&mut self, scopes: &[impl AsRef<str>]) -> &mut Self {
+        self.with(MustAuthenticateMiddleware {
+            required_scopes: scopes.iter().map(|s| s.as_ref().to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    fn authorized<F>(&mut self, predicate: F) -> &mut Self
+    where
+        F: Fn(&Claims) -> bool + Send + Sync + 'static,
+    {
+        self.with(MustAuthenticateMiddleware {
+            predicate: Some(Box::new(predicate)),
+            ..Default::default()
+        })
+    }
+
+    fn authenticated_or<F>(&mut self, unauthenticated_response: F) -> &mut Self
+    where
+        F: Fn(&tide::http::Request) -> tide::Response + Send + Sync + 'static,
+    {
+        self.with(MustAuthenticateMiddleware {
+            unauthenticated_response: Some(Box::new(unauthenticated_response)),
+            ..Default::default()
+        })
     }
 }
 
-struct MustAuthenticateMiddleware;
+/// Decides whether or not the authenticated user may access a route.
+type ClaimsPredicate = dyn Fn(&Claims) -> bool + Send + Sync;
+
+/// Creates the response to an unauthenticated request.
+type UnauthenticatedFn = dyn Fn(&tide::http::Request) -> tide::Response + Send + Sync;
+
+#[derive(Default)]
+struct MustAuthenticateMiddleware {
+    required_scopes: Vec<String>,
+    predicate: Option<Box<ClaimsPredicate>>,
+    unauthenticated_response: Option<Box<UnauthenticatedFn>>,
+}
 
 #[tide::utils::async_trait]
 impl<State> Middleware<State> for MustAuthenticateMiddleware
 where
     State: Clone + Send + Sync + 'static,
 {
-    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
-        // Is the request authenticated? If so, forward the request to
-        // the next item in the middleware chain. Otherwise, redirect
-        // the browser to the login page.
-        match req.auth_state() {
-            OpenIdConnectRequestExtData::Authenticated { .. } => {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        // Sessions whose access token has expired are refreshed first.
+        if let Some(res) = refresh_expired_session(&mut req).await? {
+            return Ok(res);
+        }
+
+        // Is the request authenticated (and authorized)? If so, forward
+        // the request to the next item in the middleware chain.
+        // Otherwise, redirect the browser to the login page if we don't
+        // know who the user is, or deny the request if we do.
+        let decision = req.auth_state().decide(&self.required_scopes, |claims| {
+            self.predicate
+                .as_ref()
+                .map_or(true, |predicate| predicate(claims))
+        });
+        match (decision, req.auth_state()) {
+            (AuthDecision::Allow, _) => {
                 tide::log::debug!(
                     "Authenticated request; forwarding request to next item in middleware chain."
                 );
                 Ok(next.run(req).await)
             }
-            OpenIdConnectRequestExtData::Unauthenticated { redirect_strategy } => {
+            (
+                AuthDecision::InsufficientScope(missing_scopes),
+                OpenIdConnectRequestExtData::Authenticated {
+                    access_denied_handler,
+                    realm,
+                    ..
+                },
+            ) => {
+                tide::log::debug!(
+                    "Authenticated request is missing required scopes: {}",
+                    missing_scopes.join(" ")
+                );
+
+                // Tell API clients which scopes the route requires,
+                // unless the application has done so itself.
+                let mut res = access_denied_handler
+                    .handle(req.as_ref(), OidcError::InsufficientScope(missing_scopes))
+                    .await?;
+                if res.status() == StatusCode::Forbidden && res.header(WWW_AUTHENTICATE).is_none() {
+                    res.insert_header(
+                        WWW_AUTHENTICATE,
+                        bearer_challenge(
+                            Some(realm),
+                            Some(ChallengeError::InsufficientScope(&self.required_scopes)),
+                        ),
+                    );
+                }
+                Ok(res)
+            }
+            (
+                _,
+                OpenIdConnectRequestExtData::Authenticated {
+                    access_denied_handler,
+                    ..
+                },
+            ) => {
+                tide::log::debug!(
+                    "Authenticated request was denied by the route's claims predicate."
+                );
+                access_denied_handler
+                    .handle(req.as_ref(), OidcError::AccessDenied)
+                    .await
+            }
+            (
+                _,
+                OpenIdConnectRequestExtData::Unauthenticated {
+                    redirect_strategy, ..
+                },
+            ) => {
+                if let Some(unauthenticated_response) = &self.unauthenticated_response {
+                    tide::log::debug!("Unauthenticated request; using the route's response.");
+                    return Ok(unauthenticated_response(req.as_ref()));
+                }
+
                 tide::log::debug!("Unauthenticated request; redirecting browser to login page.");
-                Ok(redirect_strategy.redirect())
+                redirect_strategy.unauthenticated(req.as_ref()).await
+            }
+            (_, OpenIdConnectRequestExtData::ProviderUnavailable) => {
+                tide::log::debug!("Provider is unavailable; cannot authenticate request.");
+                Ok(provider_unavailable_response())
             }
         }
     }
@@ -0,0 +1,124 @@
+//! Security event notifications.
+//!
+//! Some compliance frameworks require that failed token validations be
+//! recorded in a security event log (a SIEM, for example). The
+//! middleware reports those failures to the configured
+//! [`SecurityEventSink`]; see
+//! [`with_security_event_sink()`](crate::OpenIdConnectMiddleware::with_security_event_sink).
+//! As with the other log events, tokens are never included.
+
+use std::net::{IpAddr, SocketAddr};
+
+/// Token validation failure that may indicate an attack.
+///
+/// Every event includes the IP address of the client, if known, which
+/// is taken from the `Forwarded` (or `X-Forwarded-For`) header if
+/// present, and from the peer address of the connection otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SecurityEvent {
+    /// The signature of a token (an ID token, a JWT authorization
+    /// response, or a bearer access token) could not be verified.
+    /// Includes the subject that the token *claims* to be for, if any,
+    /// which has not been verified.
+    InvalidTokenSignature {
+        /// Unverified `sub` claim of the token.
+        sub: Option<String>,
+        /// IP address of the client.
+        ip: Option<IpAddr>,
+    },
+
+    /// The `nonce` of an ID token did not match the nonce of the login
+    /// attempt, which may indicate a replayed ID token.
+    NonceMismatch {
+        /// IP address of the client.
+        ip: Option<IpAddr>,
+    },
+
+    /// The `state` of an authorization callback did not match any of
+    /// the session's pending login attempts, which may indicate a
+    /// cross-site request forgery attempt (or a replayed callback).
+    StateMismatch {
+        /// IP address of the client.
+        ip: Option<IpAddr>,
+    },
+}
+
+impl SecurityEvent {
+    /// Returns the snake_case name of the event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::InvalidTokenSignature { .. } => "invalid_token_signature",
+            Self::NonceMismatch { .. } => "nonce_mismatch",
+            Self::StateMismatch { .. } => "state_mismatch",
+        }
+    }
+
+    /// Returns the IP address of the client, if known.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::InvalidTokenSignature { ip, .. }
+            | Self::NonceMismatch { ip }
+            | Self::StateMismatch { ip } => *ip,
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            Self::InvalidTokenSignature { .. } => "Token signature verification failed.",
+            Self::NonceMismatch { .. } => "ID token nonce does not match the login attempt.",
+            Self::StateMismatch { .. } => "Callback state does not match any login attempt.",
+        }
+    }
+}
+
+/// Receives the [security events](SecurityEvent) reported by the
+/// middleware; see
+/// [`with_security_event_sink()`](crate::OpenIdConnectMiddleware::with_security_event_sink).
+#[tide::utils::async_trait]
+pub trait SecurityEventSink: Send + Sync {
+    /// Called with each security event. The request that triggered the
+    /// event is rejected regardless of what the sink does, so sinks
+    /// that forward events to a remote service should not hold up the
+    /// response for long.
+    async fn emit(&self, event: SecurityEvent);
+}
+
+/// Logs each security event at `Warn`, with `event`, `ip` and (for
+/// [`SecurityEvent::InvalidTokenSignature`]) `sub` keys that
+/// structured loggers can index.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoggingSecurityEventSink;
+
+#[tide::utils::async_trait]
+impl SecurityEventSink for LoggingSecurityEventSink {
+    async fn emit(&self, event: SecurityEvent) {
+        let ip = event.ip().map(|ip| ip.to_string()).unwrap_or_default();
+        match &event {
+            SecurityEvent::InvalidTokenSignature { sub, .. } => {
+                tide::log::warn!("{}", event.message(), {
+                    event: event.name(),
+                    ip: ip.as_str(),
+                    sub: sub.as_deref().unwrap_or_default(),
+                });
+            }
+            _ => {
+                tide::log::warn!("{}", event.message(), {
+                    event: event.name(),
+                    ip: ip.as_str(),
+                });
+            }
+        }
+    }
+}
+
+/// Returns the IP address of the client that made the request, if
+/// known.
+pub(crate) fn remote_ip(req: &tide::http::Request) -> Option<IpAddr> {
+    let remote = req.remote()?;
+    remote
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| remote.trim_matches(|c| c == '[' || c == ']').parse())
+        .ok()
+}
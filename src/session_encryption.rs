@@ -0,0 +1,124 @@
+//! Encryption of the middleware's session state.
+
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes256Gcm;
+use serde::{de, Deserialize, Deserializer};
+use std::convert::TryInto;
+
+/// Length (in bytes) of the AES-GCM nonce that is prepended to the
+/// ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// 256-bit key used to encrypt the middleware's session state; see
+/// [`session_encryption_keys`](crate::Config::session_encryption_keys).
+///
+/// Keys are deserialized from (standard, padded) base64 strings, which
+/// can be generated with, for example, `openssl rand -base64 32`.
+#[derive(Clone, Copy)]
+pub struct SessionEncryptionKey([u8; 32]);
+
+impl SessionEncryptionKey {
+    /// Create a new key from its raw bytes.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// Create a new key from its base64 encoding, returning `None` if
+    /// the string is not valid base64 or does not encode exactly 32
+    /// bytes.
+    pub fn from_base64(key: impl AsRef<str>) -> Option<Self> {
+        let key = base64::decode(key.as_ref()).ok()?;
+        let key: [u8; 32] = key.as_slice().try_into().ok()?;
+        Some(Self(key))
+    }
+}
+
+impl std::fmt::Debug for SessionEncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionEncryptionKey([redacted])")
+    }
+}
+
+impl<'de> Deserialize<'de> for SessionEncryptionKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let key = String::deserialize(deserializer)?;
+        Self::from_base64(key).ok_or_else(|| {
+            de::Error::custom("session encryption key must be 32 base64-encoded bytes")
+        })
+    }
+}
+
+/// Encrypts (and authenticates) the middleware's session state with
+/// AES-256-GCM. The first key is used for encryption; all of the keys
+/// are tried during decryption, which allows keys to be rotated without
+/// logging everyone out.
+#[derive(Clone, Debug)]
+pub(crate) struct SessionEncryption {
+    keys: Vec<SessionEncryptionKey>,
+}
+
+impl SessionEncryption {
+    /// Creates the encryption layer, or returns `None` (meaning that
+    /// the session state is stored in plaintext) if no keys have been
+    /// configured.
+    pub(crate) fn new(keys: &[SessionEncryptionKey]) -> Option<Self> {
+        if keys.is_empty() {
+            None
+        } else {
+            Some(Self {
+                keys: keys.to_vec(),
+            })
+        }
+    }
+
+    /// Encrypts the plaintext, binding the ciphertext to the associated
+    /// data (the session key), and returns the base64-encoded nonce and
+    /// ciphertext.
+    pub(crate) fn encrypt(&self, aad: &[u8], plaintext: &[u8]) -> Result<String, String> {
+        let key = self
+            .keys
+            .first()
+            .ok_or_else(|| "No session encryption key.".to_string())?;
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let ciphertext = Aes256Gcm::new(&key.0.into())
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| "Unable to encrypt session state.".to_string())?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend(ciphertext);
+        Ok(base64::encode(payload))
+    }
+
+    /// Decrypts the base64-encoded nonce and ciphertext, returning
+    /// `None` if the payload is malformed, was encrypted with an unknown
+    /// key, or has been tampered with.
+    pub(crate) fn decrypt(&self, aad: &[u8], payload: &str) -> Option<Vec<u8>> {
+        let payload = base64::decode(payload).ok()?;
+        if payload.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().ok()?;
+
+        self.keys.iter().find_map(|key| {
+            Aes256Gcm::new(&key.0.into())
+                .decrypt(
+                    &nonce.into(),
+                    Payload {
+                        msg: ciphertext,
+                        aad,
+                    },
+                )
+                .ok()
+        })
+    }
+}
@@ -0,0 +1,136 @@
+//! OpenID Connect [Session Management].
+//!
+//! Identity Providers that support session management advertise a
+//! `check_session_iframe` in their discovery document. The application
+//! loads that page in a hidden `<iframe>` and periodically asks it
+//! (using `postMessage`) whether the user's session at the provider has
+//! changed -- because the user logged out of the provider, for example,
+//! or logged in as somebody else. When the session has changed, the
+//! application re-authenticates the user.
+//!
+//! Enabling session management with
+//! [`with_session_management()`](crate::OpenIdConnectMiddleware::with_session_management)
+//! tells the middleware to store the `session_state` from the
+//! authentication response in the session, and to serve a small script
+//! at the configured [`script_path`](SessionManagementConfig::script_path)
+//! that performs the checks. Pages that should watch the provider
+//! session include the script:
+//!
+//! ```html
+//! <script src="/oidc/session.js" defer></script>
+//! ```
+//!
+//! When the provider reports that the session has changed, the script
+//! navigates the browser to the middleware's [silent login
+//! path](crate::OpenIdConnectMiddleware::with_silent_login_path) (or,
+//! if silent logins are disabled, to the login path). The script is
+//! empty (`204 No Content`) if the request is not authenticated, or if
+//! the provider does not support session management.
+//!
+//! Note that modern browsers partition (or block) third-party cookies,
+//! which prevents the provider's `check_session_iframe` from reading its
+//! own session cookie in many cases; front-channel or back-channel
+//! logout are more reliable alternatives.
+//!
+//! [Session Management]: https://openid.net/specs/openid-connect-session-1_0.html
+
+use std::time::Duration;
+
+use tide::{http::mime, Response, StatusCode};
+
+/// Session Management configuration; see the [module
+/// documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionManagementConfig {
+    /// Path at which the middleware serves the session check script.
+    pub script_path: String,
+
+    /// Interval at which the script asks the provider whether the
+    /// session has changed.
+    pub poll_interval: Duration,
+}
+
+impl Default for SessionManagementConfig {
+    /// Serves the script at `/oidc/session.js` and checks the session
+    /// every five seconds.
+    fn default() -> Self {
+        Self {
+            script_path: "/oidc/session.js".to_string(),
+            poll_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Values that are embedded in the session check script.
+pub(crate) struct SessionCheck<'a> {
+    pub(crate) check_session_iframe: &'a str,
+    pub(crate) client_id: &'a str,
+    pub(crate) session_state: &'a str,
+    pub(crate) reauthenticate_path: &'a str,
+    pub(crate) poll_interval: Duration,
+}
+
+/// Encodes a string as a JavaScript string literal.
+fn js_string(value: &str) -> String {
+    serde_json::Value::from(value)
+        .to_string()
+        .replace('<', "\\u003c")
+}
+
+/// Returns the session check script, which must not be cached by the
+/// browser (since it contains the current session state).
+pub(crate) fn script_response(check: Option<SessionCheck<'_>>) -> Response {
+    let check = match check {
+        Some(check) => check,
+        None => {
+            return Response::builder(StatusCode::NoContent)
+                .header("Cache-Control", "no-cache, no-store")
+                .build()
+        }
+    };
+
+    let op_origin = openidconnect::url::Url::parse(check.check_session_iframe)
+        .map(|url| url.origin().ascii_serialization())
+        .unwrap_or_default();
+    let script = format!(
+        r#"(function () {{
+  var opOrigin = {op_origin};
+  var message = {message};
+  var reauthenticatePath = {reauthenticate_path};
+  var iframe = document.createElement("iframe");
+  var timer;
+  iframe.src = {check_session_iframe};
+  iframe.title = "Session check";
+  iframe.style.display = "none";
+  iframe.onload = function () {{
+    var check = function () {{
+      iframe.contentWindow.postMessage(message, opOrigin);
+    }};
+    check();
+    timer = setInterval(check, {poll_interval});
+  }};
+  window.addEventListener("message", function (event) {{
+    if (event.origin !== opOrigin || event.source !== iframe.contentWindow) {{
+      return;
+    }}
+    if (event.data === "changed") {{
+      clearInterval(timer);
+      window.location.assign(reauthenticatePath);
+    }}
+  }});
+  (document.body || document.documentElement).appendChild(iframe);
+}})();
+"#,
+        op_origin = js_string(&op_origin),
+        message = js_string(&format!("{} {}", check.client_id, check.session_state)),
+        reauthenticate_path = js_string(check.reauthenticate_path),
+        check_session_iframe = js_string(check.check_session_iframe),
+        poll_interval = check.poll_interval.as_millis(),
+    );
+
+    Response::builder(StatusCode::Ok)
+        .header("Cache-Control", "no-cache, no-store")
+        .content_type(mime::JAVASCRIPT)
+        .body(script)
+        .build()
+}
@@ -0,0 +1,106 @@
+//! Verification of the connection to the provider when the application
+//! starts listening for requests.
+
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use async_std::io;
+use tide::listener::{ListenInfo, Listener, ToListener};
+
+use crate::health::HealthCheck;
+use crate::instrument::event;
+
+/// Listener that fetches (and parses) the provider's discovery document
+/// and JSON Web Key Set before the wrapped listener is bound, so that
+/// [`listen()`](tide::Server::listen) fails with an [`io::Error`] --
+/// instead of the application failing at request time -- if the
+/// provider is unreachable or misconfigured. Obtained from
+/// [`OpenIdConnectMiddleware::startup_listener`](crate::OpenIdConnectMiddleware::startup_listener).
+///
+/// The check is skipped if [`Config::verify_on_startup`](crate::Config::verify_on_startup)
+/// is `false`. It only ever runs when this listener is passed to
+/// `listen()`; see that option for what happens otherwise.
+#[derive(Debug)]
+pub struct StartupCheckListener<L> {
+    listener: L,
+    health_check: Option<HealthCheck>,
+    /// Shared with the middleware, which no longer has to verify the
+    /// provider once the listener has done so.
+    verified: Arc<AtomicBool>,
+}
+
+impl<L> StartupCheckListener<L> {
+    /// Wraps the listener; the provider is only contacted if a health
+    /// check is provided.
+    pub(crate) fn new(
+        listener: L,
+        health_check: Option<HealthCheck>,
+        verified: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            listener,
+            health_check,
+            verified,
+        }
+    }
+}
+
+impl<L> Display for StartupCheckListener<L>
+where
+    L: Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.listener.fmt(f)
+    }
+}
+
+impl<State, L> ToListener<State> for StartupCheckListener<L>
+where
+    State: Clone + Send + Sync + 'static,
+    L: ToListener<State>,
+{
+    type Listener = StartupCheckListener<L::Listener>;
+
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(StartupCheckListener {
+            listener: self.listener.to_listener()?,
+            health_check: self.health_check,
+            verified: self.verified,
+        })
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State, L> Listener<State> for StartupCheckListener<L>
+where
+    State: Clone + Send + Sync + 'static,
+    L: Listener<State>,
+{
+    async fn bind(&mut self, app: tide::Server<State>) -> io::Result<()> {
+        if let Some(health_check) = &self.health_check {
+            health_check.refresh_metadata().await.map_err(|error| {
+                io::Error::other(format!("OpenID Connect provider check failed: {}", error))
+            })?;
+            if !health_check.is_ready() {
+                return Err(io::Error::other(
+                    "OpenID Connect provider check failed: the provider's JSON Web Key Set has no keys",
+                ));
+            }
+            event!(
+                info,
+                "Verified connectivity to the OpenID Connect provider."
+            );
+            self.verified.store(true, Ordering::Relaxed);
+        }
+        self.listener.bind(app).await
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        self.listener.accept().await
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        self.listener.info()
+    }
+}
@@ -0,0 +1,272 @@
+//! Storage for the state of pending logins.
+//!
+//! By default, the middleware stores the CSRF state, nonce, and redirect
+//! information of each pending login in the Tide session, which can
+//! exceed the size limit of cookie-based sessions. Configuring an
+//! [`OidcStateStore`] with
+//! [`with_state_store()`](crate::OpenIdConnectMiddleware::with_state_store)
+//! moves that data into the store instead, keyed by the (random) CSRF
+//! state. The session then only records the CSRF states of the browser's
+//! pending logins, which is what binds each login to the browser that
+//! started it (and so prevents login CSRF attacks).
+//!
+//! [`MemoryStateStore`] keeps the data in memory, which only works if
+//! every request is handled by the same process. Applications that run
+//! multiple instances need a shared store instead: enable the `redis`
+//! feature to use `RedisStateStore`, or implement [`OidcStateStore`] on
+//! top of another shared store.
+//!
+//! Completing a login consumes its state with [`OidcStateStore::take()`],
+//! which must load and delete the state in a single atomic step: a
+//! callback that is replayed (or raced) with the same CSRF state must not
+//! be able to complete the login a second time.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Persistent storage for the state of pending logins. All of the
+/// methods are keyed by the login's CSRF state.
+#[tide::utils::async_trait]
+pub trait OidcStateStore: Send + Sync {
+    /// Saves the (opaque, serialized) data of a pending login, which may
+    /// be discarded after the given amount of time.
+    async fn save_state(&self, state: &str, data: &str, ttl: Duration) -> io::Result<()>;
+
+    /// Loads the data of a pending login, or returns `None` if the login
+    /// is unknown (or has expired).
+    async fn load_state(&self, state: &str) -> io::Result<Option<String>>;
+
+    /// Saves the nonce of a pending login, which may be discarded after
+    /// the given amount of time.
+    async fn save_nonce(&self, state: &str, nonce: &str, ttl: Duration) -> io::Result<()>;
+
+    /// Loads the nonce of a pending login, or returns `None` if the
+    /// login is unknown (or has expired).
+    async fn load_nonce(&self, state: &str) -> io::Result<Option<String>>;
+
+    /// Deletes the data and nonce of a pending login, which happens when
+    /// the login is evicted by a newer login.
+    async fn delete(&self, state: &str) -> io::Result<()>;
+
+    /// Atomically loads and deletes the data and nonce of a pending
+    /// login, which happens once the login completes. Of any number of
+    /// concurrent calls for the same state, at most one may return the
+    /// data and nonce; the others return `None` for both.
+    async fn take(&self, state: &str) -> io::Result<(Option<String>, Option<String>)>;
+}
+
+#[derive(Debug, Default)]
+struct StoredLogin {
+    data: Option<String>,
+    nonce: Option<String>,
+    expires_at: Option<Instant>,
+}
+
+/// Stores the state of pending logins in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStateStore {
+    logins: Mutex<HashMap<String, StoredLogin>>,
+}
+
+impl MemoryStateStore {
+    /// Create a new, empty instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn update(&self, state: &str, ttl: Duration, f: impl FnOnce(&mut StoredLogin)) {
+        let now = Instant::now();
+        let mut logins = self
+            .logins
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        // Forget logins that were abandoned before they could complete.
+        logins.retain(|_, login| login.expires_at.is_none_or(|expires_at| expires_at > now));
+
+        let login = logins.entry(state.to_string()).or_default();
+        f(login);
+        login.expires_at = now.checked_add(ttl);
+    }
+
+    fn get(&self, state: &str, f: impl FnOnce(&StoredLogin) -> Option<String>) -> Option<String> {
+        let logins = self
+            .logins
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        logins
+            .get(state)
+            .filter(|login| {
+                login
+                    .expires_at
+                    .is_none_or(|expires_at| expires_at > Instant::now())
+            })
+            .and_then(f)
+    }
+}
+
+#[tide::utils::async_trait]
+impl OidcStateStore for MemoryStateStore {
+    async fn save_state(&self, state: &str, data: &str, ttl: Duration) -> io::Result<()> {
+        self.update(state, ttl, |login| login.data = Some(data.to_string()));
+        Ok(())
+    }
+
+    async fn load_state(&self, state: &str) -> io::Result<Option<String>> {
+        Ok(self.get(state, |login| login.data.clone()))
+    }
+
+    async fn save_nonce(&self, state: &str, nonce: &str, ttl: Duration) -> io::Result<()> {
+        self.update(state, ttl, |login| login.nonce = Some(nonce.to_string()));
+        Ok(())
+    }
+
+    async fn load_nonce(&self, state: &str) -> io::Result<Option<String>> {
+        Ok(self.get(state, |login| login.nonce.clone()))
+    }
+
+    async fn delete(&self, state: &str) -> io::Result<()> {
+        self.logins
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(state);
+        Ok(())
+    }
+
+    async fn take(&self, state: &str) -> io::Result<(Option<String>, Option<String>)> {
+        let login = self
+            .logins
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(state)
+            .filter(|login| {
+                login
+                    .expires_at
+                    .is_none_or(|expires_at| expires_at > Instant::now())
+            });
+        Ok(login.map_or((None, None), |login| (login.data, login.nonce)))
+    }
+}
+
+#[cfg(feature = "redis")]
+pub use self::redis_store::RedisStateStore;
+
+#[cfg(feature = "redis")]
+mod redis_store {
+    use std::io;
+    use std::time::Duration;
+
+    use redis::aio::MultiplexedConnection;
+    use redis::AsyncCommands;
+
+    use super::OidcStateStore;
+
+    /// Stores the state of pending logins in Redis, so that every
+    /// instance of the application can complete logins started by the
+    /// others. Each login is stored as two keys (the data and the nonce)
+    /// that expire on their own; completing a login reads and deletes
+    /// both keys in a single `MULTI`/`EXEC` transaction.
+    ///
+    /// Only available with the `redis` feature.
+    #[derive(Clone)]
+    pub struct RedisStateStore {
+        connection: MultiplexedConnection,
+        key_prefix: String,
+    }
+
+    impl std::fmt::Debug for RedisStateStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("RedisStateStore")
+                .field("connection", &"[multiplexed]")
+                .field("key_prefix", &self.key_prefix)
+                .finish()
+        }
+    }
+
+    impl RedisStateStore {
+        /// Connects to Redis with the given client. Keys are prefixed
+        /// with `tide-openidconnect:` unless changed with
+        /// [`with_key_prefix()`](Self::with_key_prefix).
+        pub async fn new(client: redis::Client) -> redis::RedisResult<Self> {
+            Ok(Self {
+                connection: client.get_multiplexed_async_std_connection().await?,
+                key_prefix: "tide-openidconnect:".to_string(),
+            })
+        }
+
+        /// Sets the prefix of the keys of this store, which allows
+        /// several applications to share a Redis database.
+        pub fn with_key_prefix(mut self, key_prefix: &str) -> Self {
+            self.key_prefix = key_prefix.to_string();
+            self
+        }
+
+        fn state_key(&self, state: &str) -> String {
+            format!("{}state:{}", self.key_prefix, state)
+        }
+
+        fn nonce_key(&self, state: &str) -> String {
+            format!("{}nonce:{}", self.key_prefix, state)
+        }
+
+        async fn set(&self, key: String, value: &str, ttl: Duration) -> io::Result<()> {
+            // `SET ... EX 0` is an error, so round the expiration up.
+            let seconds = ttl.as_secs().max(1) as usize;
+            self.connection
+                .clone()
+                .set_ex(key, value, seconds)
+                .await
+                .map_err(to_io_error)
+        }
+
+        async fn get(&self, key: String) -> io::Result<Option<String>> {
+            self.connection.clone().get(key).await.map_err(to_io_error)
+        }
+    }
+
+    fn to_io_error(error: redis::RedisError) -> io::Error {
+        io::Error::other(error)
+    }
+
+    #[tide::utils::async_trait]
+    impl OidcStateStore for RedisStateStore {
+        async fn save_state(&self, state: &str, data: &str, ttl: Duration) -> io::Result<()> {
+            self.set(self.state_key(state), data, ttl).await
+        }
+
+        async fn load_state(&self, state: &str) -> io::Result<Option<String>> {
+            self.get(self.state_key(state)).await
+        }
+
+        async fn save_nonce(&self, state: &str, nonce: &str, ttl: Duration) -> io::Result<()> {
+            self.set(self.nonce_key(state), nonce, ttl).await
+        }
+
+        async fn load_nonce(&self, state: &str) -> io::Result<Option<String>> {
+            self.get(self.nonce_key(state)).await
+        }
+
+        async fn delete(&self, state: &str) -> io::Result<()> {
+            self.connection
+                .clone()
+                .del(&[self.state_key(state), self.nonce_key(state)])
+                .await
+                .map_err(to_io_error)
+        }
+
+        async fn take(&self, state: &str) -> io::Result<(Option<String>, Option<String>)> {
+            let (state_key, nonce_key) = (self.state_key(state), self.nonce_key(state));
+            redis::pipe()
+                .atomic()
+                .get(&state_key)
+                .get(&nonce_key)
+                .del(&[&state_key, &nonce_key])
+                .ignore()
+                .query_async(&mut self.connection.clone())
+                .await
+                .map_err(to_io_error)
+        }
+    }
+}
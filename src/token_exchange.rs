@@ -0,0 +1,209 @@
+//! OAuth 2.0 Token Exchange ([RFC 8693]), which allows handlers to swap
+//! the user's access token for a token that targets a downstream API.
+//!
+//! [RFC 8693]: https://datatracker.ietf.org/doc/html/rfc8693
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::client_auth::{ClientAuth, TokenEndpointAuthMethod};
+use crate::discovery::ProviderDiscovery;
+use crate::dpop::DpopConfig;
+use crate::error::OidcError;
+use crate::instrument::{event, instrument};
+use crate::isahc::HttpClient;
+use chrono::{DateTime, Utc};
+use http::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use openidconnect::{url::form_urlencoded, ClientId, ClientSecret, HttpRequest};
+use serde::Deserialize;
+
+/// `grant_type` for token exchange requests.
+const TOKEN_EXCHANGE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+
+/// `subject_token_type` of the user's access token.
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+
+/// Token exchange request; see
+/// [`exchange_token()`](crate::OpenIdConnectRequestExt::exchange_token).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ExchangeRequest {
+    /// Logical name of the service (API) at which the new token will be
+    /// used, such as `inventory-api`.
+    pub audience: String,
+
+    /// Scopes requested for the new token; the provider decides which
+    /// scopes to grant if this list is empty.
+    pub scopes: Vec<String>,
+}
+
+/// Token returned by a token exchange.
+#[derive(Debug, Clone)]
+pub struct ExchangedToken {
+    /// Access token for the requested audience.
+    pub access_token: String,
+
+    /// Time at which the access token expires, or `None` if the
+    /// provider did not say when the access token expires.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+struct TokenExchangeResponse {
+    access_token: String,
+    expires_in: Option<i64>,
+}
+
+/// Exchanged tokens, indexed by session id (or, for requests that were
+/// authenticated with a bearer token, by that token) and exchange
+/// request.
+pub(crate) type TokenExchangeCache = Mutex<HashMap<(String, ExchangeRequest), ExchangedToken>>;
+
+/// Performs token exchanges on behalf of a single (authenticated)
+/// request, using the client credentials of the middleware that
+/// authenticated the request.
+#[derive(Debug)]
+pub(crate) struct TokenExchanger {
+    pub(crate) http_client: HttpClient,
+    pub(crate) discovery: Arc<ProviderDiscovery>,
+    pub(crate) client_id: ClientId,
+    pub(crate) client_secret: Option<ClientSecret>,
+    pub(crate) client_auth: Arc<ClientAuth>,
+    pub(crate) token_endpoint_auth_method: Option<TokenEndpointAuthMethod>,
+    pub(crate) dpop: Option<DpopConfig>,
+    pub(crate) cache: Arc<TokenExchangeCache>,
+}
+
+impl TokenExchanger {
+    /// Exchanges the subject token (the user's access token) for a
+    /// token for the requested audience, returning a cached token if
+    /// the same session (identified by the cache key) has already
+    /// obtained a token for the same request and that token has not yet
+    /// expired.
+    pub(crate) async fn exchange(
+        &self,
+        cache_key: &str,
+        subject_token: &str,
+        request: ExchangeRequest,
+    ) -> Result<ExchangedToken, OidcError> {
+        let key = (cache_key.to_string(), request);
+        if let Some(token) = self
+            .cache
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(&key)
+            .filter(|token| token.expires_at.is_some_and(|exp| exp > Utc::now()))
+        {
+            event!(debug, audience = %key.1.audience, "Using cached exchanged token.");
+            return Ok(token.clone());
+        }
+
+        let token = instrument!(
+            self.request_token(subject_token, &key.1),
+            "oidc.token_exchange",
+            client_id = %self.client_id.as_str(),
+            audience = %key.1.audience,
+        )
+        .await?;
+
+        // Only tokens with an expiration time are cached (otherwise we
+        // would never know when to evict them); expired tokens are
+        // evicted whenever a new token is added to the cache.
+        if token.expires_at.is_some() {
+            let mut cache = self
+                .cache
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let now = Utc::now();
+            cache.retain(|_, token| token.expires_at.is_some_and(|exp| exp > now));
+            cache.insert(key, token.clone());
+        }
+        Ok(token)
+    }
+
+    async fn request_token(
+        &self,
+        subject_token: &str,
+        request: &ExchangeRequest,
+    ) -> Result<ExchangedToken, OidcError> {
+        let metadata = self.discovery.metadata()?;
+        let token_endpoint = metadata
+            .token_endpoint()
+            .map(|url| url.url().clone())
+            .ok_or_else(|| {
+                OidcError::TokenRequest("provider does not have a token endpoint".to_string())
+            })?;
+        let auth_method = TokenEndpointAuthMethod::resolve(
+            self.token_endpoint_auth_method,
+            metadata.token_endpoint_auth_methods_supported(),
+        );
+
+        let mut params = vec![
+            ("grant_type", TOKEN_EXCHANGE_GRANT_TYPE.to_string()),
+            ("subject_token", subject_token.to_string()),
+            ("subject_token_type", ACCESS_TOKEN_TYPE.to_string()),
+            ("audience", request.audience.clone()),
+        ];
+        if !request.scopes.is_empty() {
+            params.push(("scope", request.scopes.join(" ")));
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-www-form-urlencoded"),
+        );
+        match self.client_auth.as_ref() {
+            ClientAuth::ClientSecret => {
+                if let Some(client_secret) = &self.client_secret {
+                    auth_method
+                        .add_credentials(&self.client_id, client_secret, &mut headers, &mut params)
+                        .map_err(OidcError::TokenRequest)?;
+                } else {
+                    params.push(("client_id", self.client_id.to_string()));
+                }
+            }
+            client_auth => {
+                params.push(("client_id", self.client_id.to_string()));
+                params.extend(
+                    client_auth
+                        .assertion_params(&self.client_id, token_endpoint.as_str())
+                        .map_err(OidcError::TokenRequest)?,
+                );
+            }
+        }
+
+        let body = form_urlencoded::Serializer::new(String::new())
+            .extend_pairs(params)
+            .finish();
+        let response = self
+            .http_client
+            .request_with_dpop(
+                self.dpop.as_ref(),
+                HttpRequest {
+                    url: token_endpoint,
+                    method: http::Method::POST,
+                    headers,
+                    body: body.into_bytes(),
+                },
+            )
+            .await
+            .map_err(|e| OidcError::TokenRequest(e.to_string()))?;
+        if !response.status_code.is_success() {
+            return Err(OidcError::TokenRequest(format!(
+                "token exchange failed with HTTP status {}: {}",
+                response.status_code,
+                String::from_utf8_lossy(&response.body)
+            )));
+        }
+
+        let response: TokenExchangeResponse = serde_json::from_slice(&response.body)
+            .map_err(|e| OidcError::TokenRequest(e.to_string()))?;
+        Ok(ExchangedToken {
+            access_token: response.access_token,
+            expires_at: response
+                .expires_in
+                .map(|expires_in| Utc::now() + chrono::Duration::seconds(expires_in)),
+        })
+    }
+}
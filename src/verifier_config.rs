@@ -0,0 +1,34 @@
+//! Test-only relaxations of ID token verification, which are only
+//! available with the `insecure-test-overrides` feature.
+//!
+//! **Never enable this feature in production builds.** Each of the
+//! overrides disables a check that protects the application against
+//! forged or replayed tokens. They exist so that integration tests
+//! (against an emulated provider, for example) can focus on one part of
+//! the login flow without having to satisfy every other check.
+
+// The configuration is always part of the middleware (with every check
+// enabled), but can only be changed with the feature.
+#![cfg_attr(not(feature = "insecure-test-overrides"), allow(unreachable_pub))]
+
+/// Checks of the ID token (and logout token) verification that can be
+/// disabled with
+/// [`with_verifier_config()`](crate::OpenIdConnectMiddleware::with_verifier_config).
+/// Every check is enabled by default.
+///
+/// **Insecure: for tests only.** A middleware with any of these checks
+/// disabled accepts tokens that it would otherwise reject.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerifierConfig {
+    /// Accepts tokens from any issuer, regardless of the configured
+    /// [issuer verification](crate::Config::issuer_verification).
+    pub skip_issuer_check: bool,
+
+    /// Accepts ID tokens whose `nonce` does not match the login, and
+    /// logout tokens that include a `nonce`.
+    pub skip_nonce_check: bool,
+
+    /// Accepts tokens without verifying their signature, and thus
+    /// regardless of their signing key (`kid`) and algorithm.
+    pub skip_signature_check: bool,
+}
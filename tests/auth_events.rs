@@ -0,0 +1,200 @@
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use crate::common::{assert_redirect, create_test_server, get_config};
+use http_types::StatusCode;
+use log::kv::{Key, Value, VisitSource};
+use log::{Level, Log, Metadata, Record};
+use std::collections::HashMap;
+use std::sync::{Mutex, Once};
+use tide_openidconnect::{AuthEvent, OpenIdConnectMiddleware, RedirectUrl};
+use tide_testing::TideTestingExt;
+
+pub mod common;
+
+/// A logged authentication event: the level and the key-value pairs.
+type LoggedEvent = (Level, HashMap<String, String>);
+
+/// Captures every record that has an `event` key; tests run
+/// concurrently, so each test uses its own provider id in order to find
+/// its own events.
+struct EventLogger {
+    events: Mutex<Vec<LoggedEvent>>,
+}
+
+impl Log for EventLogger {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        struct Collect(HashMap<String, String>);
+        impl<'kvs> VisitSource<'kvs> for Collect {
+            fn visit_pair(
+                &mut self,
+                key: Key<'kvs>,
+                value: Value<'kvs>,
+            ) -> Result<(), log::kv::Error> {
+                self.0.insert(key.to_string(), value.to_string());
+                Ok(())
+            }
+        }
+
+        let mut pairs = Collect(HashMap::new());
+        record.key_values().visit(&mut pairs).unwrap();
+        if pairs.0.contains_key("event") {
+            self.events.lock().unwrap().push((record.level(), pairs.0));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: EventLogger = EventLogger {
+    events: Mutex::new(Vec::new()),
+};
+
+/// Returns the events that were logged for the given provider id.
+fn events_for(provider: &str) -> Vec<LoggedEvent> {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+
+    LOGGER
+        .events
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, pairs)| pairs.get("provider").map(String::as_str) == Some(provider))
+        .cloned()
+        .collect()
+}
+
+fn find_event<'a>(events: &'a [LoggedEvent], name: &str) -> Option<&'a LoggedEvent> {
+    events
+        .iter()
+        .find(|(_, pairs)| pairs.get("event").map(String::as_str) == Some(name))
+}
+
+#[async_std::test]
+async fn login_logs_auth_events() -> http_types::Result<()> {
+    events_for("events");
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_provider_id("events"),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let events = events_for("events");
+            let (level, pairs) = find_event(&events, "redirect_initiated").unwrap();
+            assert_eq!(*level, Level::Debug);
+            assert_eq!(pairs["silent"], "false");
+
+            let (level, _) = find_event(&events, "callback_received").unwrap();
+            assert_eq!(*level, Level::Debug);
+
+            let (level, pairs) = find_event(&events, "token_exchange_succeeded").unwrap();
+            assert_eq!(*level, Level::Debug);
+            assert!(pairs["duration_ms"].parse::<u64>().is_ok());
+
+            let (level, pairs) = find_event(&events, "session_written").unwrap();
+            assert_eq!(*level, Level::Info);
+            assert_eq!(pairs["sub"], "id");
+
+            // Tokens are never logged.
+            assert!(events
+                .iter()
+                .all(|(_, pairs)| pairs.values().all(|value| !value.contains("atoken"))));
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn failed_token_exchanges_are_logged() -> http_types::Result<()> {
+    events_for("failures");
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_provider_id("failures"),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Complete the login with a code that the provider did not
+            // issue, which causes the token exchange to fail.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let res = client
+                .get(format!(
+                    "/callback?code=UNKNOWN&state={}",
+                    authorize_url.state.unwrap()
+                ))
+                .await?;
+            assert_eq!(res.status(), StatusCode::BadGateway);
+
+            let events = events_for("failures");
+            let (level, pairs) = find_event(&events, "token_exchange_failed").unwrap();
+            assert_eq!(*level, Level::Warn);
+            assert!(pairs["duration_ms"].parse::<u64>().is_ok());
+            assert!(!pairs["error"].is_empty());
+            assert!(find_event(&events, "session_written").is_none());
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn auth_event_log_levels_can_be_changed() -> http_types::Result<()> {
+    events_for("levels");
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_provider_id("levels")
+                    .with_auth_event_log_level(
+                        AuthEvent::RedirectInitiated,
+                        Some(tide::log::Level::Trace),
+                    )
+                    .with_auth_event_log_level(AuthEvent::SessionWritten, None),
+            );
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let events = events_for("levels");
+            let (level, _) = find_event(&events, "redirect_initiated").unwrap();
+            assert_eq!(*level, Level::Trace);
+            assert!(find_event(&events, "callback_received").is_some());
+            assert!(find_event(&events, "session_written").is_none());
+
+            Ok(())
+        })
+        .await
+}
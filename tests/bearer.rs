@@ -0,0 +1,684 @@
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::{
+    client_assertion_ec_signing_key_pem, OpenIdConnectEmulator, TokenTimes,
+};
+use crate::common::{
+    assert_redirect, assert_response, create_test_server, get_config, RecordingSecurityEventSink,
+};
+use chrono::Duration;
+use http_types::StatusCode;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tide::Request;
+use tide_testing::TideTestingExt;
+
+use tide_openidconnect::security_events::SecurityEvent;
+use tide_openidconnect::{
+    ClientAuth, ClientId, ClientSecret, IntrospectionConfig, OpenIdConnectBearerMiddleware,
+    OpenIdConnectMiddleware, OpenIdConnectRequestExt, OpenIdConnectRouteExt, PrivateKeyJwt,
+    RedirectUrl,
+};
+
+pub mod common;
+
+fn add_api_routes(app: &mut tide::Server<()>) {
+    app.at("/api/me")
+        .authenticated()
+        .get(|req: Request<()>| async move {
+            Ok(format!(
+                "sub={} scopes={:?} client_id={} iss={}",
+                req.user_id().unwrap(),
+                req.scopes().unwrap(),
+                req.auth_info().unwrap().client_id,
+                req.claim::<String>("iss").unwrap() == req.auth_info().unwrap().issuer.as_str(),
+            ))
+        });
+    app.at("/api/admin")
+        .authenticated_with_scopes(&["admin"])
+        .get(|_req: Request<()>| async { Ok("admin") });
+}
+
+fn assert_invalid_token(res: &surf::Response) {
+    assert_eq!(res.status(), StatusCode::Unauthorized);
+    assert!(res
+        .header("WWW-Authenticate")
+        .unwrap()
+        .get(0)
+        .unwrap()
+        .as_str()
+        .starts_with(r#"Bearer realm="CLIENT-ID", error="invalid_token", error_description=""#));
+}
+
+#[async_std::test]
+async fn bearer_tokens_authenticate_api_requests() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectBearerMiddleware::new(&get_config(&emu.issuer_url())).await);
+            add_api_routes(&mut app);
+            let client = app.client();
+
+            // Requests without a token are challenged (rather than
+            // redirected to the login page).
+            let res = client.get("/api/me").await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert_eq!(
+                res.header("WWW-Authenticate").unwrap().get(0).unwrap(),
+                r#"Bearer realm="CLIENT-ID""#
+            );
+
+            let token = emu.create_jwt_access_token(
+                "id",
+                "openid read",
+                "CLIENT-ID",
+                TokenTimes::default(),
+            );
+            let mut res = client
+                .get("/api/me")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_response(
+                &mut res,
+                r#"sub=id scopes=["openid", "read"] client_id=CLIENT-ID iss=true"#,
+            )
+            .await;
+
+            // Public routes see the authenticated user, too.
+            let mut res = client
+                .get("/")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_response(
+                &mut res,
+                &format!(
+                    "authed visits=1 access_token={} scopes=[\"openid\", \"read\"] userid=id",
+                    token
+                ),
+            )
+            .await;
+
+            // Scopes are checked as for browser sessions.
+            let res = client
+                .get("/api/admin")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+            assert_eq!(
+                res.header("WWW-Authenticate").unwrap().get(0).unwrap(),
+                r#"Bearer realm="CLIENT-ID", error="insufficient_scope", scope="admin""#
+            );
+
+            let token = emu.create_jwt_access_token(
+                "id",
+                "openid admin",
+                "CLIENT-ID",
+                TokenTimes::default(),
+            );
+            let mut res = client
+                .get("/api/admin")
+                .header("Authorization", format!("bearer {}", token))
+                .await?;
+            assert_response(&mut res, "admin").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn invalid_bearer_tokens_are_rejected() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectBearerMiddleware::new(&get_config(&emu.issuer_url())).await);
+            add_api_routes(&mut app);
+            let client = app.client();
+
+            let valid_claims =
+                emu.jwt_access_token_claims("id", "openid", "CLIENT-ID", TokenTimes::default());
+            let valid_token = emu.sign_jwt_access_token(&valid_claims);
+            let (signing_input, _) = valid_token.rsplit_once('.').unwrap();
+
+            let mut wrong_issuer = valid_claims.clone();
+            wrong_issuer["iss"] = json!("https://attacker.example.com");
+            let mut id_token_like = valid_claims.clone();
+            id_token_like["nonce"] = json!("NONCE");
+
+            let invalid_tokens = vec![
+                (
+                    "expired",
+                    emu.create_jwt_access_token(
+                        "id",
+                        "openid",
+                        "CLIENT-ID",
+                        TokenTimes::new(Duration::minutes(-5)),
+                    ),
+                ),
+                (
+                    "not yet valid",
+                    emu.create_jwt_access_token(
+                        "id",
+                        "openid",
+                        "CLIENT-ID",
+                        TokenTimes::default().with_not_before(Duration::minutes(5)),
+                    ),
+                ),
+                (
+                    "wrong audience",
+                    emu.create_jwt_access_token(
+                        "id",
+                        "openid",
+                        "OTHER-CLIENT",
+                        TokenTimes::default(),
+                    ),
+                ),
+                ("wrong issuer", emu.sign_jwt_access_token(&wrong_issuer)),
+                ("ID token", emu.sign_jwt_access_token(&id_token_like)),
+                ("ID token without a nonce", emu.sign_id_token(&valid_claims)),
+                ("bad signature", format!("{}.AAAA", signing_input)),
+                ("malformed", "not-a-jwt".to_string()),
+            ];
+            for (reason, token) in invalid_tokens {
+                let res = client
+                    .get("/api/me")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .await?;
+                assert_eq!(res.status(), StatusCode::Unauthorized, "{}", reason);
+                assert_invalid_token(&res);
+            }
+
+            // The challenge says why the token was rejected.
+            let res = client
+                .get("/api/me")
+                .header(
+                    "Authorization",
+                    format!(
+                        "Bearer {}",
+                        emu.create_jwt_access_token(
+                            "id",
+                            "openid",
+                            "CLIENT-ID",
+                            TokenTimes::new(Duration::minutes(-5)),
+                        )
+                    ),
+                )
+                .await?;
+            assert_eq!(
+                res.header("WWW-Authenticate").unwrap().get(0).unwrap(),
+                r#"Bearer realm="CLIENT-ID", error="invalid_token", error_description="JWT has expired""#
+            );
+            let res = client
+                .get("/api/me")
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", emu.sign_id_token(&valid_claims)),
+                )
+                .await?;
+            assert_eq!(
+                res.header("WWW-Authenticate").unwrap().get(0).unwrap(),
+                r#"Bearer realm="CLIENT-ID", error="invalid_token", error_description="JWT is not typed as an access token""#
+            );
+
+            // Invalid tokens are rejected on public routes, too, instead
+            // of silently being treated as unauthenticated.
+            let res = client
+                .get("/")
+                .header("Authorization", "Bearer not-a-jwt")
+                .await?;
+            assert_invalid_token(&res);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn invalid_bearer_token_signatures_are_reported() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let sink = Arc::new(RecordingSecurityEventSink::default());
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_security_event_sink(sink.clone())
+                    .bearer_middleware(),
+            );
+            add_api_routes(&mut app);
+            let client = app.client();
+
+            // Tokens that are rejected for other reasons are not
+            // reported...
+            let expired = emu.create_jwt_access_token(
+                "id",
+                "openid",
+                "CLIENT-ID",
+                TokenTimes::new(Duration::minutes(-5)),
+            );
+            let res = client
+                .get("/api/me")
+                .header("Authorization", format!("Bearer {}", expired))
+                .await?;
+            assert_invalid_token(&res);
+            assert!(sink.events().is_empty());
+
+            // ...but forged ones are, with the subject that they claim.
+            let (signing_input, _) = expired.rsplit_once('.').unwrap();
+            let res = client
+                .get("/api/me")
+                .header("Authorization", format!("Bearer {}.AAAA", signing_input))
+                .header("X-Forwarded-For", "203.0.113.7")
+                .await?;
+            assert_invalid_token(&res);
+            assert_eq!(
+                sink.events(),
+                vec![SecurityEvent::InvalidTokenSignature {
+                    sub: Some("id".to_string()),
+                    ip: Some("203.0.113.7".parse()?),
+                }]
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn bearer_middleware_enforces_the_authorization_policy() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let middleware = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                .await
+                .with_required_scope("api");
+            let bearer = middleware.bearer_middleware();
+            let mut app = create_test_server();
+            app.with(middleware);
+            app.with(bearer);
+            add_api_routes(&mut app);
+            let client = app.client();
+
+            // Tokens without the required scope are denied, even on
+            // public routes...
+            let token = emu.create_jwt_access_token(
+                "api-user",
+                "openid",
+                "CLIENT-ID",
+                TokenTimes::default(),
+            );
+            for path in ["/api/me", "/"] {
+                let res = client
+                    .get(path)
+                    .header("Authorization", format!("Bearer {}", token))
+                    .await?;
+                assert_eq!(res.status(), StatusCode::Forbidden, "{}", path);
+            }
+
+            // ...while tokens with the scope are not.
+            let token = emu.create_jwt_access_token(
+                "api-user",
+                "openid api",
+                "CLIENT-ID",
+                TokenTimes::default(),
+            );
+            let mut res = client
+                .get("/api/me")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_response(
+                &mut res,
+                r#"sub=api-user scopes=["openid", "api"] client_id=CLIENT-ID iss=true"#,
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn bearer_middleware_shares_keys_with_interactive_middleware() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let middleware = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+            let jwks_requests = emu.jwks_requests();
+            let bearer = middleware.bearer_middleware();
+            assert_eq!(emu.jwks_requests(), jwks_requests);
+
+            let mut app = create_test_server();
+            app.with(middleware);
+            app.with(bearer);
+            add_api_routes(&mut app);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Browser requests are still sent to the login page, and can
+            // log in as usual.
+            let res = client.get("/api/me").await?;
+            assert_redirect(&res, "/login");
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            // API clients can use bearer tokens, which are verified with
+            // the keys that the interactive middleware has already
+            // fetched.
+            let token = emu.create_jwt_access_token(
+                "api-user",
+                "openid",
+                "CLIENT-ID",
+                TokenTimes::default(),
+            );
+            let mut res = app
+                .client()
+                .get("/api/me")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_response(
+                &mut res,
+                r#"sub=api-user scopes=["openid"] client_id=CLIENT-ID iss=true"#,
+            )
+            .await;
+            assert_eq!(emu.jwks_requests(), jwks_requests);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn routes_can_accept_sessions_or_bearer_tokens() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_bearer_fallback(true),
+            );
+            add_api_routes(&mut app);
+            let token = emu.create_jwt_access_token(
+                "api-user",
+                "openid",
+                "CLIENT-ID",
+                TokenTimes::default(),
+            );
+
+            // No session, no token: browsers are sent to the login page.
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+            let res = client.get("/api/me").await?;
+            assert_redirect(&res, "/login");
+
+            // No session, invalid token: the token is rejected.
+            let res = app
+                .client()
+                .get("/api/me")
+                .header("Authorization", "Bearer not-a-jwt")
+                .await?;
+            assert_invalid_token(&res);
+
+            // No session, valid token: the token authenticates the
+            // request.
+            let mut res = app
+                .client()
+                .get("/api/me")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_response(
+                &mut res,
+                r#"sub=api-user scopes=["openid"] client_id=CLIENT-ID iss=true"#,
+            )
+            .await;
+
+            // Session: the session authenticates the request, and takes
+            // precedence over any token.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/api/me").await?;
+            assert_response(
+                &mut res,
+                r#"sub=id scopes=["openid"] client_id=CLIENT-ID iss=true"#,
+            )
+            .await;
+            let mut res = client
+                .get("/api/me")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_response(
+                &mut res,
+                r#"sub=id scopes=["openid"] client_id=CLIENT-ID iss=true"#,
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn per_route_scopes_apply_to_bearer_tokens() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_bearer_fallback(true)
+                    .with_per_route_scopes(HashMap::from([(
+                        "/api/data",
+                        vec!["openid".to_string(), "data:read".to_string()],
+                    )])),
+            );
+            app.at("/api/data")
+                .get(|_req: Request<()>| async { Ok("data") });
+
+            // Tokens without the route's scopes are denied (rather than
+            // sent through a login), and told which scopes are needed.
+            let token = emu.create_jwt_access_token(
+                "api-user",
+                "openid",
+                "CLIENT-ID",
+                TokenTimes::default(),
+            );
+            let res = app
+                .client()
+                .get("/api/data")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+            assert_eq!(
+                res.header("WWW-Authenticate").unwrap().get(0).unwrap(),
+                r#"Bearer realm="CLIENT-ID", error="insufficient_scope", scope="openid data:read""#
+            );
+
+            let token = emu.create_jwt_access_token(
+                "api-user",
+                "openid data:read",
+                "CLIENT-ID",
+                TokenTimes::default(),
+            );
+            let mut res = app
+                .client()
+                .get("/api/data")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_response(&mut res, "data").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn opaque_tokens_are_introspected() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut config = get_config(&emu.issuer_url());
+            config.introspection = Some(IntrospectionConfig {
+                client_id: ClientId::new("RESOURCE-SERVER".to_string()),
+                client_secret: ClientSecret::new("RESOURCE-SECRET".to_string()),
+                endpoint: None,
+                cache_ttl: std::time::Duration::from_secs(300),
+            });
+            let mut app = create_test_server();
+            app.with(OpenIdConnectBearerMiddleware::new(&config).await);
+            add_api_routes(&mut app);
+            let client = app.client();
+
+            // Active tokens are authenticated, and the introspection
+            // result is cached.
+            emu.add_opaque_token("opaque", "openid read", "api-user", Duration::hours(1))
+                .await;
+            for _ in 0..2 {
+                let mut res = client
+                    .get("/api/me")
+                    .header("Authorization", "Bearer opaque")
+                    .await?;
+                assert_response(
+                    &mut res,
+                    r#"sub=api-user scopes=["openid", "read"] client_id=CLIENT-ID iss=true"#,
+                )
+                .await;
+            }
+            assert_eq!(emu.introspections(), 1);
+
+            // Inactive tokens are rejected.
+            let res = client
+                .get("/api/me")
+                .header("Authorization", "Bearer unknown")
+                .await?;
+            assert_invalid_token(&res);
+            assert_eq!(emu.introspections(), 2);
+
+            // Results are only cached until the token expires.
+            emu.add_opaque_token("short-lived", "openid", "api-user", Duration::seconds(2))
+                .await;
+            let res = client
+                .get("/api/me")
+                .header("Authorization", "Bearer short-lived")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+            async_std::task::sleep(std::time::Duration::from_millis(2100)).await;
+            let res = client
+                .get("/api/me")
+                .header("Authorization", "Bearer short-lived")
+                .await?;
+            assert_invalid_token(&res);
+            assert_eq!(emu.introspections(), 4);
+
+            // JWT access tokens are still verified locally.
+            let token = emu.create_jwt_access_token(
+                "jwt-user",
+                "openid",
+                "CLIENT-ID",
+                TokenTimes::default(),
+            );
+            let mut res = client
+                .get("/api/me")
+                .header("Authorization", format!("Bearer {}", token))
+                .await?;
+            assert_response(
+                &mut res,
+                r#"sub=jwt-user scopes=["openid"] client_id=CLIENT-ID iss=true"#,
+            )
+            .await;
+            assert_eq!(emu.introspections(), 4);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn introspection_can_use_private_key_jwt() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .with_client_assertion_verification()
+        .run_with_emulator(|emu| async move {
+            let mut config = get_config(&emu.issuer_url());
+            config.introspection = Some(IntrospectionConfig {
+                client_id: ClientId::new("RESOURCE-SERVER".to_string()),
+                client_secret: ClientSecret::new("unused".to_string()),
+                endpoint: None,
+                cache_ttl: std::time::Duration::from_secs(300),
+            });
+            let middleware = OpenIdConnectMiddleware::new(&config)
+                .await
+                .with_introspection_client_auth(ClientAuth::PrivateKeyJwt(
+                    PrivateKeyJwt::from_pem(client_assertion_ec_signing_key_pem(), None)?,
+                ));
+            let mut app = create_test_server();
+            app.with(middleware.bearer_middleware());
+            add_api_routes(&mut app);
+            let client = app.client();
+
+            emu.add_opaque_token("opaque", "openid read", "api-user", Duration::hours(1))
+                .await;
+            let mut res = client
+                .get("/api/me")
+                .header("Authorization", "Bearer opaque")
+                .await?;
+            assert_response(
+                &mut res,
+                r#"sub=api-user scopes=["openid", "read"] client_id=CLIENT-ID iss=true"#,
+            )
+            .await;
+            assert_eq!(emu.introspections(), 1);
+            assert_eq!(emu.client_assertions(), 1);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn introspection_uses_client_authentication_method() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .with_token_endpoint_auth_method("client_secret_post")
+        .run_with_emulator(|emu| async move {
+            let mut config = get_config(&emu.issuer_url());
+            config.introspection = Some(IntrospectionConfig {
+                client_id: ClientId::new("RESOURCE-SERVER".to_string()),
+                client_secret: ClientSecret::new("RESOURCE-SECRET".to_string()),
+                endpoint: None,
+                cache_ttl: std::time::Duration::from_secs(300),
+            });
+            let mut app = create_test_server();
+            app.with(OpenIdConnectBearerMiddleware::new(&config).await);
+            add_api_routes(&mut app);
+            let client = app.client();
+
+            emu.add_opaque_token("opaque", "openid read", "api-user", Duration::hours(1))
+                .await;
+            let mut res = client
+                .get("/api/me")
+                .header("Authorization", "Bearer opaque")
+                .await?;
+            assert_response(
+                &mut res,
+                r#"sub=api-user scopes=["openid", "read"] client_id=CLIENT-ID iss=true"#,
+            )
+            .await;
+            assert_eq!(emu.introspections(), 1);
+
+            Ok(())
+        })
+        .await
+}
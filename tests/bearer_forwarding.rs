@@ -0,0 +1,194 @@
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::{client_assertion_signing_key, OpenIdConnectEmulator};
+use crate::common::{assert_redirect, assert_response, get_config};
+use http_types::StatusCode;
+use std::sync::Arc;
+use tide::sessions::{MemoryStore, SessionMiddleware};
+use tide::Request;
+use tide_testing::TideTestingExt;
+
+use tide_openidconnect::{
+    BearerForwardingMiddleware, Config, DpopConfig, OpenIdConnectMiddleware,
+    OpenIdConnectRequestExt, RedirectUrl,
+};
+
+pub mod common;
+
+#[derive(Clone)]
+struct AppState {
+    upstream: surf::Client,
+}
+
+impl AsRef<surf::Client> for AppState {
+    fn as_ref(&self) -> &surf::Client {
+        &self.upstream
+    }
+}
+
+/// Creates an upstream service that echoes the `Authorization` header
+/// of its requests (and whether they include a `DPoP` proof).
+fn create_upstream() -> surf::Client {
+    let mut upstream = tide::new();
+    upstream.at("/echo").get(|req: Request<()>| async move {
+        let authorization = req
+            .header("Authorization")
+            .map(|authorization| authorization.as_str().to_string())
+            .unwrap_or_else(|| "none".to_string());
+        Ok(match req.header("DPoP") {
+            Some(_) => format!("{} (with proof)", authorization),
+            None => authorization,
+        })
+    });
+    upstream.client()
+}
+
+/// Creates an app that proxies `/proxy?url=...` requests to the
+/// upstream service, forwarding the access token.
+async fn create_bff_app(
+    emu: &OpenIdConnectEmulator,
+    forwarding: BearerForwardingMiddleware,
+) -> tide::Server<AppState> {
+    create_bff_app_with_config(&get_config(&emu.issuer_url()), forwarding).await
+}
+
+/// Creates the same app as [`create_bff_app()`], with the given
+/// middleware configuration.
+async fn create_bff_app_with_config(
+    config: &Config,
+    forwarding: BearerForwardingMiddleware,
+) -> tide::Server<AppState> {
+    let mut app = tide::with_state(AppState {
+        upstream: create_upstream(),
+    });
+    app.with(
+        SessionMiddleware::new(MemoryStore::new(), b"secrets must be >= 32 bytes long")
+            .with_same_site_policy(tide::http::cookies::SameSite::Lax),
+    );
+    app.with(OpenIdConnectMiddleware::new(config).await);
+    app.with(forwarding);
+    app.at("/proxy").get(|req: Request<AppState>| async move {
+        let url = req
+            .url()
+            .query_pairs()
+            .find(|(name, _)| name == "url")
+            .map(|(_, url)| url.into_owned())
+            .unwrap_or_default();
+        Ok(match req.upstream_client() {
+            Some(client) => client.get(url).recv_string().await?,
+            None => "no client".to_string(),
+        })
+    });
+    app
+}
+
+#[async_std::test]
+async fn access_token_is_forwarded_to_https_upstreams() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let app = create_bff_app(
+                emu,
+                BearerForwardingMiddleware::new().with_allowed_host("upstream.example"),
+            )
+            .await;
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Unauthenticated requests do not get a client.
+            let mut res = client
+                .get("/proxy?url=https://upstream.example/echo")
+                .await?;
+            assert_response(&mut res, "no client").await;
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client
+                .get("/proxy?url=https://upstream.example/echo")
+                .await?;
+            assert_response(&mut res, "Bearer atoken").await;
+
+            // The token is not sent over plain HTTP, nor to other hosts.
+            for url in [
+                "http://upstream.example/echo",
+                "https://other.example/echo",
+                "https://upstream.example.evil.example/echo",
+            ] {
+                let mut res = client.get(format!("/proxy?url={}", url)).await?;
+                assert_eq!(res.status(), StatusCode::InternalServerError);
+                assert!(!res.body_string().await?.contains("atoken"));
+            }
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn access_token_can_be_forwarded_to_insecure_upstreams() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let app = create_bff_app(
+                emu,
+                BearerForwardingMiddleware::new()
+                    .with_allowed_host("upstream.example")
+                    .with_insecure_upstreams(true),
+            )
+            .await;
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client
+                .get("/proxy?url=http://upstream.example/echo")
+                .await?;
+            assert_response(&mut res, "Bearer atoken").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn dpop_bound_access_token_is_forwarded_with_a_proof() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .with_dpop_proof_verification()
+        .run_with_emulator(|emu| async move {
+            let mut config = get_config(&emu.issuer_url());
+            config.dpop = Some(DpopConfig {
+                private_key: Arc::new(client_assertion_signing_key()),
+            });
+            let app = create_bff_app_with_config(
+                &config,
+                BearerForwardingMiddleware::new().with_allowed_host("upstream.example"),
+            )
+            .await;
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client
+                .get("/proxy?url=https://upstream.example/echo")
+                .await?;
+            assert_response(&mut res, "DPoP atoken (with proof)").await;
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,174 @@
+use crate::common::get_config;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use async_std::prelude::FutureExt;
+use std::time::Duration;
+use tide_openidconnect::ciba_flow::{poll_for_token, CibaAuthentication, CibaClient};
+use tide_openidconnect::{OAuth2TokenResponse, OidcError, OpenIdConnectMiddleware, RedirectUrl};
+
+pub mod common;
+
+fn redirect_url() -> RedirectUrl {
+    RedirectUrl::new("http://localhost/callback".to_string()).unwrap()
+}
+
+fn authentication(login_hint: &str) -> CibaAuthentication {
+    CibaAuthentication {
+        scopes: vec!["payments".to_string()],
+        login_hint: Some(login_hint.to_string()),
+        binding_message: Some("Approve transfer #4711".to_string()),
+        ..Default::default()
+    }
+}
+
+#[async_std::test]
+async fn tokens_are_issued_once_the_user_approves() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(redirect_url())
+        .run_with_emulator(|emu| async move {
+            let client = CibaClient::from_config(&get_config(&emu.issuer_url()))
+                .await
+                .unwrap();
+            let request = client.authenticate(&authentication("alice")).await.unwrap();
+
+            // The token endpoint is polled until the user approves the
+            // request on their device.
+            let (token_response, _) = poll_for_token(&request)
+                .join(async {
+                    while emu.ciba_polls() < 2 {
+                        async_std::task::sleep(Duration::from_millis(10)).await;
+                    }
+                    emu.complete_ciba_requests("alice", true).await;
+                })
+                .await;
+            let token_response = token_response.unwrap();
+            assert_eq!(
+                token_response.access_token().secret(),
+                "ciba-token-for-alice"
+            );
+            assert_eq!(
+                token_response
+                    .scopes()
+                    .map(|scopes| scopes.iter().map(|s| s.to_string()).collect::<Vec<_>>()),
+                Some(vec!["openid".to_string(), "payments".to_string()])
+            );
+            assert!(emu.ciba_polls() > 2);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn denied_requests_are_reported() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(redirect_url())
+        .run_with_emulator(|emu| async move {
+            let client = CibaClient::from_config(&get_config(&emu.issuer_url()))
+                .await
+                .unwrap();
+            let request = client.authenticate(&authentication("bob")).await.unwrap();
+            emu.complete_ciba_requests("bob", false).await;
+
+            match poll_for_token(&request).await {
+                Err(OidcError::TokenRequest(error)) => assert_eq!(error, "access_denied"),
+                result => panic!("Unexpected result: {:?}", result),
+            }
+            assert_eq!(emu.ciba_polls(), 1);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn authentication_requests_require_a_hint() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(redirect_url())
+        .run_with_emulator(|emu| async move {
+            let client = CibaClient::from_config(&get_config(&emu.issuer_url()))
+                .await
+                .unwrap();
+
+            match client.authenticate(&CibaAuthentication::default()).await {
+                Err(OidcError::BackchannelAuthentication(error)) => {
+                    assert_eq!(error, "invalid_request")
+                }
+                result => panic!("Unexpected result: {:?}", result),
+            }
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn client_can_be_created_from_middleware() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(redirect_url())
+        .run_with_emulator(|emu| async move {
+            let middleware = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+            let client = middleware.ciba_client().unwrap();
+            let request = client.authenticate(&authentication("carol")).await.unwrap();
+            emu.complete_ciba_requests("carol", true).await;
+
+            let token_response = poll_for_token(&request).await.unwrap();
+            assert_eq!(
+                token_response.access_token().secret(),
+                "ciba-token-for-carol"
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn forged_id_tokens_are_rejected() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(redirect_url())
+        .run_with_emulator(|emu| async move {
+            let client = CibaClient::from_config(&get_config(&emu.issuer_url()))
+                .await
+                .unwrap();
+            let request = client
+                .authenticate(&authentication("mallory"))
+                .await
+                .unwrap();
+            emu.complete_ciba_requests("mallory", true).await;
+            let claims = emu.ciba_id_token_claims("mallory", request.auth_req_id());
+
+            // The claims of a genuine ID token are replaced, which breaks
+            // its signature...
+            let genuine = emu.sign_id_token(&claims);
+            let mut forged_claims = claims.clone();
+            forged_claims["sub"] = serde_json::json!("alice");
+            let forged_payload =
+                base64::encode_config(forged_claims.to_string(), base64::URL_SAFE_NO_PAD);
+            let segments: Vec<_> = genuine.split('.').collect();
+            let tampered = format!("{}.{}.{}", segments[0], forged_payload, segments[2]);
+
+            // ...whereas validly signed ID tokens are rejected if they were
+            // issued for another client or another authentication request.
+            let mut other_audience = claims.clone();
+            other_audience["aud"] = serde_json::json!("OTHER-CLIENT");
+            let mut other_request = claims.clone();
+            other_request["urn:openid:params:jwt:claim:auth_req_id"] =
+                serde_json::json!("other-request");
+
+            for id_token in [
+                tampered,
+                emu.sign_id_token(&other_audience),
+                emu.sign_id_token(&other_request),
+            ] {
+                emu.set_ciba_id_token(id_token).await;
+                match poll_for_token(&request).await {
+                    Err(OidcError::TokenRequest(error)) => {
+                        assert!(error.starts_with("invalid ID token"), "{}", error)
+                    }
+                    result => panic!("Unexpected result: {:?}", result),
+                }
+            }
+
+            // The genuine ID token is accepted.
+            emu.set_ciba_id_token(genuine).await;
+            assert!(poll_for_token(&request).await.is_ok());
+
+            Ok(())
+        })
+        .await
+}
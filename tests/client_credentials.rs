@@ -0,0 +1,159 @@
+use crate::common::oidc_emulator::{client_assertion_signing_key, OpenIdConnectEmulator};
+use async_std::prelude::FutureExt;
+use tide_openidconnect::client_credentials::{
+    ClientCredentialsClient, ClientCredentialsConfig, ClientCredentialsTokenSource, TokenSource,
+};
+use tide_openidconnect::{
+    ClientAuth, ClientSecret, IssuerUrl, OidcError, OpenIdConnectMiddleware, PrivateKeyJwt,
+    RedirectUrl,
+};
+
+pub mod common;
+
+fn get_config(issuer_url: &IssuerUrl) -> ClientCredentialsConfig {
+    let config = common::get_config(issuer_url);
+    ClientCredentialsConfig {
+        issuer_url: config.issuer_url,
+        client_id: config.client_id,
+        client_secret: config.client_secret,
+        scopes: vec!["api".to_string()],
+        http_client: Default::default(),
+        http_timeouts: Default::default(),
+        issuer_verification: Default::default(),
+    }
+}
+
+#[async_std::test]
+async fn token_source_caches_tokens() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let token_source = ClientCredentialsTokenSource::new(&get_config(&emu.issuer_url()))
+                .await
+                .unwrap();
+            assert_eq!(emu.client_credentials_grants(), 0);
+
+            let token = token_source.token().await.unwrap();
+            assert_eq!(token.secret(), "client-token-1");
+
+            // The token is valid for an hour, and so is reused.
+            let token = token_source.token().await.unwrap();
+            assert_eq!(token.secret(), "client-token-1");
+            assert_eq!(emu.client_credentials_grants(), 1);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn token_source_refreshes_expiring_tokens() -> http_types::Result<()> {
+    // Tokens that expire within a minute are already considered to be
+    // expired, and so are replaced on every call.
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .with_client_credentials_expires_in(60)
+        .run_with_emulator(|emu| async move {
+            let token_source = ClientCredentialsTokenSource::new(&get_config(&emu.issuer_url()))
+                .await
+                .unwrap();
+
+            let token = token_source.token().await.unwrap();
+            assert_eq!(token.secret(), "client-token-1");
+            let token = token_source.token().await.unwrap();
+            assert_eq!(token.secret(), "client-token-2");
+            assert_eq!(emu.client_credentials_grants(), 2);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn token_source_reports_token_errors() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let config = ClientCredentialsConfig {
+                client_secret: ClientSecret::new("WRONG-SECRET".to_string()),
+                ..get_config(&emu.issuer_url())
+            };
+            let token_source = ClientCredentialsTokenSource::new(&config).await.unwrap();
+
+            let result = token_source.token().await;
+            assert!(matches!(result, Err(OidcError::TokenRequest(_))));
+            assert_eq!(emu.client_credentials_grants(), 0);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn client_caches_tokens_per_scope_set() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let client =
+                ClientCredentialsClient::from_config(&common::get_config(&emu.issuer_url()))
+                    .await
+                    .unwrap();
+
+            let token = client.get_token(&["read", "write"]).await.unwrap();
+            assert_eq!(token.secret(), "client-token-1");
+
+            // The order of the scopes does not matter...
+            let token = client.get_token(&["write", "read"]).await.unwrap();
+            assert_eq!(token.secret(), "client-token-1");
+
+            // ...but each set of scopes has its own token.
+            let token = client.get_token(&["read"]).await.unwrap();
+            assert_eq!(token.secret(), "client-token-2");
+            assert_eq!(emu.client_credentials_grants(), 2);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn client_deduplicates_concurrent_token_requests() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let client =
+                ClientCredentialsClient::from_config(&common::get_config(&emu.issuer_url()))
+                    .await
+                    .unwrap();
+
+            let ((first, second), third) = client
+                .get_token(&["api"])
+                .join(client.get_token(&["api"]))
+                .join(client.get_token(&["api"]))
+                .await;
+            assert_eq!(first.unwrap().secret(), "client-token-1");
+            assert_eq!(second.unwrap().secret(), "client-token-1");
+            assert_eq!(third.unwrap().secret(), "client-token-1");
+            assert_eq!(emu.client_credentials_grants(), 1);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn client_can_be_created_from_middleware() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .with_client_assertion_verification()
+        .run_with_emulator(|emu| async move {
+            let middleware = OpenIdConnectMiddleware::new(&common::get_config(&emu.issuer_url()))
+                .await
+                .with_client_auth(ClientAuth::PrivateKeyJwt(PrivateKeyJwt::from_rsa_key(
+                    client_assertion_signing_key(),
+                )));
+            let client = middleware.client_credentials_client().unwrap();
+
+            // The client uses the middleware's client authentication.
+            let token = client.get_token(&["api"]).await.unwrap();
+            assert_eq!(token.secret(), "client-token-1");
+            assert_eq!(emu.client_assertions(), 1);
+
+            Ok(())
+        })
+        .await
+}
@@ -12,10 +12,12 @@ pub struct ParsedAuthorizeUrl {
     pub state: Option<String>,
     pub nonce: Option<String>,
     pub redirect_uri: String,
+    pub prompt: Option<String>,
+    pub login_hint: Option<String>,
 }
 
-impl ParsedAuthorizeUrl {
-    pub fn default() -> Self {
+impl Default for ParsedAuthorizeUrl {
+    fn default() -> Self {
         Self {
             host: "localhost".to_owned(),
             path: "/authorization".to_owned(),
@@ -25,9 +27,13 @@ impl ParsedAuthorizeUrl {
             state: None,
             nonce: None,
             redirect_uri: "http://localhost/callback".to_string(),
+            prompt: None,
+            login_hint: None,
         }
     }
+}
 
+impl ParsedAuthorizeUrl {
     pub fn from_response(res: &surf::Response) -> Self {
         Self::from_url(res.header(LOCATION).unwrap().get(0).unwrap().as_str())
     }
@@ -45,6 +51,8 @@ impl ParsedAuthorizeUrl {
             state: Some(query.get("state").unwrap().to_owned()),
             nonce: Some(query.get("nonce").unwrap().to_owned()),
             redirect_uri: query.get("redirect_uri").unwrap().to_owned(),
+            prompt: query.get("prompt").cloned(),
+            login_hint: query.get("login_hint").cloned(),
         }
     }
 
@@ -52,6 +60,20 @@ impl ParsedAuthorizeUrl {
         Self { nonce, ..self }
     }
 
+    pub fn with_login_hint(self, login_hint: impl AsRef<str>) -> Self {
+        Self {
+            login_hint: Some(login_hint.as_ref().to_owned()),
+            ..self
+        }
+    }
+
+    pub fn with_prompt(self, prompt: impl AsRef<str>) -> Self {
+        Self {
+            prompt: Some(prompt.as_ref().to_owned()),
+            ..self
+        }
+    }
+
     pub fn with_scopes(self, scopes: impl AsRef<str>) -> Self {
         Self {
             scopes: scopes.as_ref().to_owned(),
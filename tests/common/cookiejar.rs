@@ -1,16 +1,17 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use async_lock::Mutex;
 use tide::http::headers::{COOKIE, SET_COOKIE};
 
 pub struct SessionCookieJarMiddleware {
-    session_cookie: Arc<Mutex<Option<tide::http::Cookie<'static>>>>,
+    cookies: Arc<Mutex<HashMap<String, tide::http::Cookie<'static>>>>,
 }
 
 impl Default for SessionCookieJarMiddleware {
     fn default() -> Self {
         Self {
-            session_cookie: Arc::new(Mutex::new(None)),
+            cookies: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 }
@@ -23,34 +24,41 @@ impl surf::middleware::Middleware for SessionCookieJarMiddleware {
         client: surf::Client,
         next: surf::middleware::Next<'_>,
     ) -> surf::Result<surf::Response> {
-        // Add the session cookie, if we have one, to the request.
-        if let Some(cookie) = &*self.session_cookie.lock().await {
-            tide::log::trace!("Adding session cookie to request.");
-            req.set_header(COOKIE, cookie.to_string());
+        // Add the cookies (usually just the session cookie), if we have
+        // any, to the request.
+        {
+            let cookies = self.cookies.lock().await;
+            if !cookies.is_empty() {
+                tide::log::trace!("Adding cookies to request.");
+                let header = cookies
+                    .values()
+                    .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                req.set_header(COOKIE, header);
+            }
         }
 
         // Continue the request and collect the response.
         let res = next.run(req, client).await?;
 
-        // Did we get a session cookie back? If so, either replace our
-        // current session cookie, or clear the existing session cookie
-        // if the new one has already expired (which is how servers
-        // ask the browser to delete a cookie).
+        // Did we get any cookies back? If so, either replace our current
+        // cookie of that name, or clear the existing cookie if the new
+        // one has already expired (which is how servers ask the browser
+        // to delete a cookie).
         if let Some(values) = res.header(SET_COOKIE) {
-            if let Some(value) = values.get(0) {
-                let mut session_cookie_guard = self.session_cookie.lock().await;
-
+            let mut cookies = self.cookies.lock().await;
+            for value in values {
                 let cookie = tide::http::Cookie::parse(value.to_string()).unwrap();
                 if cookie
                     .expires()
-                    .unwrap()
-                    .ge(&time::OffsetDateTime::now_utc())
+                    .is_none_or(|expires| expires.ge(&time::OffsetDateTime::now_utc()))
                 {
-                    tide::log::trace!("Received new/updated session cookie from server.");
-                    *session_cookie_guard = Some(cookie);
+                    tide::log::trace!("Received new/updated cookie from server.");
+                    cookies.insert(cookie.name().to_string(), cookie);
                 } else {
-                    tide::log::trace!("Server removed session cookie.");
-                    *session_cookie_guard = None;
+                    tide::log::trace!("Server removed cookie.");
+                    cookies.remove(cookie.name());
                 }
             }
         }
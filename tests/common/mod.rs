@@ -1,11 +1,15 @@
 use http_types::{headers::LOCATION, StatusCode};
 use tide::sessions::{MemoryStore, SessionMiddleware};
 
+use std::sync::Mutex;
+
+use tide_openidconnect::security_events::{SecurityEvent, SecurityEventSink};
 use tide_openidconnect::{ClientId, ClientSecret, IssuerUrl, OpenIdConnectRequestExt, RedirectUrl};
 
 pub mod authorizeurl;
 pub mod cookiejar;
 pub mod oidc_emulator;
+pub mod proxy;
 
 const SECRET: [u8; 32] = *b"secrets must be >= 32 bytes long";
 
@@ -15,7 +19,35 @@ pub fn get_config(issuer_url: &IssuerUrl) -> tide_openidconnect::Config {
         client_id: ClientId::new("CLIENT-ID".to_string()),
         client_secret: ClientSecret::new("CLIENT-SECRET".to_string()),
         redirect_url: RedirectUrl::new("http://localhost/callback".to_string()).unwrap(),
+        additional_audiences: vec![],
+        require_azp: false,
+        clock_skew_tolerance: std::time::Duration::from_secs(60),
         idp_logout_url: None,
+        idp_logout_id_token_hint: false,
+        post_logout_redirect_url: None,
+        retain_id_token: false,
+        dynamic_client_registration: None,
+        backchannel_logout_path: None,
+        frontchannel_logout_path: None,
+        fetch_userinfo: false,
+        extra_authorization_params: Default::default(),
+        dpop: None,
+        access_denied_handler: None,
+        token_refresh_failed_handler: None,
+        provider_endpoints: None,
+        realm: None,
+        introspection: None,
+        claims_locales: None,
+        redirect_strategy: Default::default(),
+        csp_policy: None,
+        http_client: Default::default(),
+        http_timeouts: Default::default(),
+        allow_insecure_http: false,
+        verify_on_startup: true,
+        session_encryption_keys: vec![],
+        session_ttl: Default::default(),
+        issuer_verification: Default::default(),
+        token_endpoint_auth_method: None,
     }
 }
 
@@ -111,3 +143,21 @@ pub fn assert_redirect(res: &surf::Response, expected_target: impl AsRef<str>) {
         );
     }
 }
+
+/// Security event sink that records the events, so that tests can
+/// check which events were emitted.
+#[derive(Default)]
+pub struct RecordingSecurityEventSink(Mutex<Vec<SecurityEvent>>);
+
+impl RecordingSecurityEventSink {
+    pub fn events(&self) -> Vec<SecurityEvent> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[tide::utils::async_trait]
+impl SecurityEventSink for RecordingSecurityEventSink {
+    async fn emit(&self, event: SecurityEvent) {
+        self.0.lock().unwrap().push(event);
+    }
+}
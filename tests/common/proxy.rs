@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use async_lock::Mutex;
+use async_std::sync::Arc;
+use openidconnect::url::Url;
+use portpicker::pick_unused_port;
+use tide::Request;
+
+/// Minimal forwarding HTTP proxy, which records the requests that pass
+/// through it.
+pub struct HttpProxy {
+    /// TCP Port on which the proxy accepts HTTP requests.
+    port: u16,
+
+    /// Number of requests that have been forwarded by the proxy.
+    requests: Arc<AtomicUsize>,
+
+    /// `Proxy-Authorization` header from the most recent request.
+    proxy_authorization: Arc<Mutex<Option<String>>>,
+
+    /// Headers of the most recent request.
+    headers: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+#[derive(Clone)]
+struct State {
+    requests: Arc<AtomicUsize>,
+    proxy_authorization: Arc<Mutex<Option<String>>>,
+    headers: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl Default for HttpProxy {
+    fn default() -> Self {
+        Self {
+            port: pick_unused_port().expect("No ports free"),
+            requests: Arc::new(AtomicUsize::new(0)),
+            proxy_authorization: Arc::new(Mutex::new(None)),
+            headers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl HttpProxy {
+    pub fn url(&self) -> Url {
+        Url::parse(&format!("http://localhost:{}", self.port)).unwrap()
+    }
+
+    /// Returns the number of requests that the proxy has forwarded.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    /// Returns the `Proxy-Authorization` header from the most recent
+    /// request.
+    pub async fn proxy_authorization(&self) -> Option<String> {
+        self.proxy_authorization.lock().await.clone()
+    }
+
+    /// Returns the given header from the most recent request.
+    pub async fn header(&self, name: &str) -> Option<String> {
+        self.headers
+            .lock()
+            .await
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone())
+    }
+
+    pub async fn run(&self) -> http_types::Result<()> {
+        let mut app = tide::with_state(State {
+            requests: Arc::clone(&self.requests),
+            proxy_authorization: Arc::clone(&self.proxy_authorization),
+            headers: Arc::clone(&self.headers),
+        });
+
+        // Proxied requests use the absolute URL of the target, which
+        // we forward (minus the proxy headers) to the target server.
+        async fn forward(mut req: Request<State>) -> tide::Result {
+            req.state().requests.fetch_add(1, Ordering::SeqCst);
+            *req.state().proxy_authorization.lock().await = req
+                .header("Proxy-Authorization")
+                .map(|h| h.as_str().to_string());
+            *req.state().headers.lock().await = req
+                .iter()
+                .map(|(name, values)| (name.as_str().to_string(), values.as_str().to_string()))
+                .collect();
+
+            let mut upstream_req = surf::Request::new(req.method(), req.url().clone());
+            for (name, values) in req.iter() {
+                if !name.as_str().starts_with("proxy-") && name.as_str() != "host" {
+                    upstream_req.insert_header(name, values);
+                }
+            }
+            upstream_req.set_body(req.body_bytes().await?);
+            let mut upstream_res = surf::client().send(upstream_req).await?;
+
+            let mut res = tide::Response::new(upstream_res.status());
+            res.set_body(upstream_res.body_bytes().await?);
+            for (name, values) in upstream_res.iter() {
+                if name.as_str() != "content-length" && name.as_str() != "transfer-encoding" {
+                    res.insert_header(name, values);
+                }
+            }
+            Ok(res)
+        }
+        app.at("/").all(forward);
+        app.at("*").all(forward);
+
+        app.listen(format!("tcp://localhost:{}", self.port)).await?;
+        Ok(())
+    }
+}
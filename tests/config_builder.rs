@@ -0,0 +1,224 @@
+use tide_openidconnect::registration::{DynamicClientRegistration, FileCredentialStore};
+use tide_openidconnect::{Config, ConfigBuilder, ConfigError, IssuerVerification};
+
+fn builder() -> ConfigBuilder {
+    Config::builder()
+        .issuer_url("https://idp.example/")
+        .client_id("CLIENT-ID")
+        .client_secret("CLIENT-SECRET")
+        .redirect_url("https://app.example/callback")
+}
+
+fn build_error(builder: ConfigBuilder) -> ConfigError {
+    match builder.build() {
+        Ok(config) => panic!("Unexpected config: {:?}", config),
+        Err(error) => error,
+    }
+}
+
+#[test]
+fn builder_applies_defaults() {
+    let config = builder()
+        .issuer_verification(IssuerVerification::Strict)
+        .fetch_userinfo(true)
+        .build()
+        .unwrap();
+    assert_eq!(config.issuer_url.as_str(), "https://idp.example/");
+    assert_eq!(config.issuer_verification, IssuerVerification::Strict);
+    assert_eq!(config.client_id.as_str(), "CLIENT-ID");
+    assert_eq!(config.client_secret.secret(), "CLIENT-SECRET");
+    assert_eq!(config.redirect_url.as_str(), "https://app.example/callback");
+    assert!(config.fetch_userinfo);
+    assert_eq!(
+        config.clock_skew_tolerance,
+        std::time::Duration::from_secs(60)
+    );
+    assert!(config.verify_on_startup);
+    assert!(config.idp_logout_url.is_none());
+}
+
+#[test]
+fn required_fields_must_be_set() {
+    let error = build_error(
+        Config::builder()
+            .client_id("CLIENT-ID")
+            .client_secret("CLIENT-SECRET")
+            .redirect_url("https://app.example/callback"),
+    );
+    assert!(matches!(error, ConfigError::Missing("issuer_url")));
+    assert_eq!(error.to_string(), "`issuer_url` is required");
+
+    let error = build_error(builder().client_id(" "));
+    assert!(matches!(error, ConfigError::Missing("client_id")));
+
+    let error = build_error(
+        Config::builder()
+            .issuer_url("https://idp.example/")
+            .client_id("CLIENT-ID")
+            .redirect_url("https://app.example/callback"),
+    );
+    assert!(matches!(error, ConfigError::Missing("client_secret")));
+
+    let error = build_error(
+        Config::builder()
+            .issuer_url("https://idp.example/")
+            .client_id("CLIENT-ID")
+            .client_secret("CLIENT-SECRET"),
+    );
+    assert!(matches!(error, ConfigError::Missing("redirect_url")));
+}
+
+#[test]
+fn client_credentials_are_optional_with_dynamic_registration() {
+    let store = FileCredentialStore::new(std::env::temp_dir().join("unused-credentials.json"));
+    let config = Config::builder()
+        .issuer_url("https://idp.example/")
+        .redirect_url("https://app.example/callback")
+        .dynamic_client_registration(DynamicClientRegistration::new(store))
+        .build()
+        .unwrap();
+    assert!(config.dynamic_client_registration.is_some());
+}
+
+#[test]
+fn issuer_url_must_be_valid() {
+    for issuer_url in [
+        "idp.example",
+        "https://idp.example/#fragment",
+        "https://idp.example/?tenant=1",
+    ] {
+        match build_error(builder().issuer_url(issuer_url)) {
+            ConfigError::InvalidUrl { field, .. } => assert_eq!(field, "issuer_url"),
+            error => panic!("Unexpected error for {}: {:?}", issuer_url, error),
+        }
+    }
+}
+
+#[test]
+fn redirect_urls_can_be_relative_to_the_app() {
+    let config = builder()
+        .app_url("https://app.example/base/")
+        .redirect_url("callback")
+        .idp_logout_url("https://idp.example/logout")
+        .post_logout_redirect_url("logout/return")
+        .build()
+        .unwrap();
+    assert_eq!(
+        config.redirect_url.as_str(),
+        "https://app.example/base/callback"
+    );
+    assert_eq!(
+        config.post_logout_redirect_url.unwrap().as_str(),
+        "https://app.example/base/logout/return"
+    );
+
+    // Relative URLs require the app URL...
+    match build_error(builder().redirect_url("/callback")) {
+        ConfigError::RelativeUrl { field, url } => {
+            assert_eq!(field, "redirect_url");
+            assert_eq!(url, "/callback");
+        }
+        error => panic!("Unexpected error: {:?}", error),
+    }
+
+    // ...and redirect URLs must be under the app URL.
+    for redirect_url in ["/callback", "https://other.example/base/callback"] {
+        let error = build_error(
+            builder()
+                .app_url("https://app.example/base/")
+                .redirect_url(redirect_url),
+        );
+        assert!(
+            matches!(
+                error,
+                ConfigError::OutsideApp {
+                    field: "redirect_url",
+                    ..
+                }
+            ),
+            "{:?}",
+            error
+        );
+        assert!(error
+            .to_string()
+            .ends_with("is not under the application's URL `https://app.example/base/`"));
+    }
+}
+
+#[test]
+fn app_url_paths_are_compared_by_segment() {
+    for app_url in ["https://app.example/app", "https://app.example/app/"] {
+        let config = builder()
+            .app_url(app_url)
+            .redirect_url("https://app.example/app/callback")
+            .build()
+            .unwrap();
+        assert_eq!(
+            config.redirect_url.as_str(),
+            "https://app.example/app/callback"
+        );
+
+        let error = build_error(
+            builder()
+                .app_url(app_url)
+                .redirect_url("https://app.example/application/callback"),
+        );
+        assert!(
+            matches!(
+                error,
+                ConfigError::OutsideApp {
+                    field: "redirect_url",
+                    ..
+                }
+            ),
+            "{:?}",
+            error
+        );
+    }
+}
+
+#[test]
+fn logout_paths_must_be_absolute() {
+    let error = build_error(builder().backchannel_logout_path("logout/backchannel"));
+    assert!(matches!(
+        error,
+        ConfigError::InvalidPath {
+            field: "backchannel_logout_path",
+            ..
+        }
+    ));
+
+    let error = build_error(builder().frontchannel_logout_path("logout/frontchannel"));
+    assert!(matches!(
+        error,
+        ConfigError::InvalidPath {
+            field: "frontchannel_logout_path",
+            ..
+        }
+    ));
+}
+
+#[test]
+fn conflicting_options_are_rejected() {
+    for builder in [
+        builder().idp_logout_id_token_hint(true),
+        builder().post_logout_redirect_url("https://app.example/logout/return"),
+    ] {
+        let error = build_error(builder);
+        assert!(matches!(error, ConfigError::Conflict(_)), "{:?}", error);
+        assert!(error.to_string().ends_with("requires an `idp_logout_url`"));
+    }
+}
+
+#[test]
+fn insecure_urls_are_rejected() {
+    let error = build_error(builder().redirect_url("http://app.example/callback"));
+    assert!(matches!(error, ConfigError::InsecureUrl(_)), "{:?}", error);
+    assert!(error.to_string().starts_with("redirect_url `http://"));
+
+    assert!(builder()
+        .redirect_url("http://app.example/callback")
+        .allow_insecure_http(true)
+        .build()
+        .is_ok());
+}
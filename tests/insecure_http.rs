@@ -0,0 +1,201 @@
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use crate::common::{assert_redirect, assert_response, create_test_server, get_config};
+use tide_openidconnect::client_credentials::ClientCredentialsClient;
+use tide_openidconnect::{
+    AuthUrl, Config, IssuerUrl, JsonWebKeySetUrl, LazyDiscoveryConfig, OidcError,
+    OpenIdConnectMiddleware, ProviderEndpoints, RedirectUrl, TokenUrl, UserInfoUrl,
+};
+use tide_testing::TideTestingExt;
+
+pub mod common;
+
+fn config(issuer_url: &str, redirect_url: &str) -> Config {
+    let mut config = get_config(&IssuerUrl::new(issuer_url.to_string()).unwrap());
+    config.redirect_url = RedirectUrl::new(redirect_url.to_string()).unwrap();
+    config
+}
+
+fn provider_endpoints(base_url: &str) -> ProviderEndpoints {
+    ProviderEndpoints {
+        authorization_endpoint: AuthUrl::new(format!("{}/authorize", base_url)).unwrap(),
+        token_endpoint: TokenUrl::new(format!("{}/token", base_url)).unwrap(),
+        jwks_uri: JsonWebKeySetUrl::new(format!("{}/jwks", base_url)).unwrap(),
+        userinfo_endpoint: Some(UserInfoUrl::new(format!("{}/userinfo", base_url)).unwrap()),
+        jwks: None,
+        id_token_signing_algs: vec![],
+    }
+}
+
+/// Creates a (lazy) middleware, which never contacts the provider.
+fn create_middleware(config: &Config) -> Result<OpenIdConnectMiddleware, OidcError> {
+    OpenIdConnectMiddleware::new_lazy(config, LazyDiscoveryConfig::default())
+}
+
+fn assert_insecure_url(result: Result<OpenIdConnectMiddleware, OidcError>, field: &str) {
+    match result {
+        Err(OidcError::InsecureUrl(message)) => {
+            assert!(
+                message.starts_with(&format!("{} `http://", field)),
+                "{}",
+                message
+            );
+            assert!(
+                message.contains("Config::allow_insecure_http"),
+                "{}",
+                message
+            );
+        }
+        result => panic!("Unexpected result: {:?}", result.map(|_| ())),
+    }
+}
+
+#[test]
+fn https_urls_are_accepted() {
+    let mut config = config("https://idp.example/", "https://app.example/callback");
+    config.idp_logout_url = Some("https://idp.example/logout".to_string());
+    config.provider_endpoints = Some(provider_endpoints("https://idp.example"));
+    assert!(create_middleware(&config).is_ok());
+}
+
+#[test]
+fn http_issuer_url_is_rejected() {
+    let config = config("http://idp.example/", "https://app.example/callback");
+    assert_insecure_url(create_middleware(&config), "issuer_url");
+}
+
+#[test]
+fn http_redirect_url_is_rejected() {
+    let config = config("https://idp.example/", "http://app.example/callback");
+    assert_insecure_url(create_middleware(&config), "redirect_url");
+}
+
+#[test]
+fn http_idp_logout_url_is_rejected() {
+    let mut config = config("https://idp.example/", "https://app.example/callback");
+    config.idp_logout_url = Some("http://idp.example/logout".to_string());
+    assert_insecure_url(create_middleware(&config), "idp_logout_url");
+}
+
+#[test]
+fn http_post_logout_redirect_url_is_rejected() {
+    let mut config = config("https://idp.example/", "https://app.example/callback");
+    config.post_logout_redirect_url =
+        Some(RedirectUrl::new("http://app.example/logout/return".to_string()).unwrap());
+    assert_insecure_url(create_middleware(&config), "post_logout_redirect_url");
+}
+
+#[test]
+fn http_provider_endpoints_are_rejected() {
+    let secure = provider_endpoints("https://idp.example");
+    let insecure = provider_endpoints("http://idp.example");
+    let cases = [
+        (
+            "provider_endpoints.authorization_endpoint",
+            ProviderEndpoints {
+                authorization_endpoint: insecure.authorization_endpoint.clone(),
+                ..secure.clone()
+            },
+        ),
+        (
+            "provider_endpoints.token_endpoint",
+            ProviderEndpoints {
+                token_endpoint: insecure.token_endpoint.clone(),
+                ..secure.clone()
+            },
+        ),
+        (
+            "provider_endpoints.jwks_uri",
+            ProviderEndpoints {
+                jwks_uri: insecure.jwks_uri.clone(),
+                ..secure.clone()
+            },
+        ),
+        (
+            "provider_endpoints.userinfo_endpoint",
+            ProviderEndpoints {
+                userinfo_endpoint: insecure.userinfo_endpoint.clone(),
+                ..secure.clone()
+            },
+        ),
+    ];
+
+    for (field, endpoints) in cases {
+        let mut config = config("https://idp.example/", "https://app.example/callback");
+        config.provider_endpoints = Some(endpoints);
+        assert_insecure_url(create_middleware(&config), field);
+    }
+}
+
+#[test]
+fn loopback_http_urls_are_accepted() {
+    for host in [
+        "localhost",
+        "app.localhost",
+        "127.0.0.1",
+        "127.0.0.2",
+        "[::1]",
+    ] {
+        let mut config = config(
+            &format!("http://{}:8080/realms/main", host),
+            &format!("http://{}:8000/callback", host),
+        );
+        config.idp_logout_url = Some(format!("http://{}:8080/logout", host));
+        config.provider_endpoints = Some(provider_endpoints(&format!("http://{}:8080", host)));
+        assert!(create_middleware(&config).is_ok(), "{}", host);
+    }
+}
+
+#[test]
+fn insecure_http_can_be_allowed() {
+    let mut config = config("http://idp.example/", "http://app.example/callback");
+    config.idp_logout_url = Some("http://idp.example/logout".to_string());
+    config.provider_endpoints = Some(provider_endpoints("http://idp.example"));
+    config.allow_insecure_http = true;
+    assert!(create_middleware(&config).is_ok());
+}
+
+#[async_std::test]
+async fn other_clients_reject_insecure_urls() {
+    let config = config("http://idp.example/", "https://app.example/callback");
+    match ClientCredentialsClient::from_config(&config).await {
+        Err(OidcError::InsecureUrl(message)) => assert!(message.starts_with("issuer_url")),
+        result => panic!("Unexpected result: {:?}", result.map(|_| ())),
+    }
+}
+
+#[async_std::test]
+async fn login_with_insecure_redirect_url_requires_opt_in() -> http_types::Result<()> {
+    let redirect_url = RedirectUrl::new("http://app.example/callback".to_string()).unwrap();
+    OpenIdConnectEmulator::new(redirect_url.clone())
+        .run_with_emulator(|emu| async move {
+            let mut config = get_config(&emu.issuer_url());
+            config.redirect_url = redirect_url.clone();
+            let result = OpenIdConnectMiddleware::try_new(&config).await;
+            assert_insecure_url(result, "redirect_url");
+
+            config.allow_insecure_http = true;
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&config).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/").await?;
+            assert_response(
+                &mut res,
+                "authed visits=1 access_token=atoken scopes=[\"openid\"] userid=id",
+            )
+            .await;
+
+            Ok(())
+        })
+        .await
+}
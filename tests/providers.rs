@@ -0,0 +1,195 @@
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use crate::common::{assert_redirect, assert_response, create_test_server, get_config};
+use http_types::StatusCode;
+use tide_openidconnect::{
+    OpenIdConnectMiddleware, OpenIdConnectProviders, OpenIdConnectRequestExt, RedirectUrl,
+    ResponseMode,
+};
+use tide_testing::TideTestingExt;
+
+pub mod common;
+
+fn redirect_url() -> RedirectUrl {
+    RedirectUrl::new("http://localhost/callback".to_string()).unwrap()
+}
+
+/// Creates an app whose users can sign in with either the "azure" or
+/// the "google" emulator, both of which redirect to the same callback
+/// URL.
+async fn create_providers_app(
+    azure_emu: &OpenIdConnectEmulator,
+    google_emu: &OpenIdConnectEmulator,
+) -> tide::Server<()> {
+    create_providers_app_with_response_mode(azure_emu, google_emu, ResponseMode::Query).await
+}
+
+/// Creates the same app as [`create_providers_app()`], with both
+/// providers using the given response mode.
+async fn create_providers_app_with_response_mode(
+    azure_emu: &OpenIdConnectEmulator,
+    google_emu: &OpenIdConnectEmulator,
+    response_mode: ResponseMode,
+) -> tide::Server<()> {
+    let mut app = create_test_server();
+    app.with(
+        OpenIdConnectProviders::new()
+            .with_provider(
+                "azure",
+                OpenIdConnectMiddleware::new(&get_config(&azure_emu.issuer_url()))
+                    .await
+                    .with_response_mode(response_mode),
+            )
+            .with_provider(
+                "google",
+                OpenIdConnectMiddleware::new(&get_config(&google_emu.issuer_url()))
+                    .await
+                    .with_response_mode(response_mode),
+            ),
+    );
+    app.at("/provider")
+        .get(|req: tide::Request<()>| async move {
+            Ok(format!("{:?} {:?}", req.provider_id(), req.user_id()))
+        });
+    app
+}
+
+#[async_std::test]
+async fn login_path_selects_the_provider() -> http_types::Result<()> {
+    let azure_emu = OpenIdConnectEmulator::new(redirect_url());
+    let google_emu = OpenIdConnectEmulator::new(redirect_url());
+    azure_emu
+        .run_with_emulator(|azure_emu| async move {
+            google_emu
+                .run_with_emulator(|google_emu| async move {
+                    let app = create_providers_app(azure_emu, google_emu).await;
+                    let client = app.client().with(SessionCookieJarMiddleware::default());
+
+                    // The provider can be given as a query parameter...
+                    let res = client.get("/login?provider=google").await?;
+                    let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                    let callback_url = google_emu
+                        .add_token("gtoken", "openid", "guser", &authorize_url)
+                        .await;
+                    let res = client.get(callback_url).await?;
+                    assert_redirect(&res, "/");
+
+                    let mut res = client.get("/provider").await?;
+                    assert_response(&mut res, "Some(\"google\") Some(\"guser\")").await;
+
+                    let res = client.get("/logout").await?;
+                    assert_redirect(&res, "/");
+                    let mut res = client.get("/provider").await?;
+                    assert_response(&mut res, "None None").await;
+
+                    // ...or in the path.
+                    let res = client.get("/login/azure").await?;
+                    let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                    let callback_url = azure_emu
+                        .add_token("atoken", "openid", "auser", &authorize_url)
+                        .await;
+                    let res = client.get(callback_url).await?;
+                    assert_redirect(&res, "/");
+
+                    let mut res = client.get("/provider").await?;
+                    assert_response(&mut res, "Some(\"azure\") Some(\"auser\")").await;
+
+                    // Unknown providers are rejected.
+                    let res = client.get("/login?provider=okta").await?;
+                    assert_eq!(res.status(), StatusCode::BadRequest);
+
+                    Ok(())
+                })
+                .await
+        })
+        .await
+}
+
+#[async_std::test]
+async fn callbacks_are_handled_by_the_provider_that_started_the_login() -> http_types::Result<()> {
+    let azure_emu = OpenIdConnectEmulator::new(redirect_url());
+    let google_emu = OpenIdConnectEmulator::new(redirect_url());
+    azure_emu
+        .run_with_emulator(|azure_emu| async move {
+            google_emu
+                .run_with_emulator(|google_emu| async move {
+                    let app = create_providers_app(azure_emu, google_emu).await;
+                    let client = app.client().with(SessionCookieJarMiddleware::default());
+
+                    // The user starts logging in with both providers.
+                    let res = client.get("/login/azure").await?;
+                    let azure_authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                    let res = client.get("/login/google").await?;
+                    let google_authorize_url = ParsedAuthorizeUrl::from_response(&res);
+
+                    // A code issued by one provider cannot be redeemed with the state
+                    // of the other provider's login.
+                    let callback_url = azure_emu
+                        .add_token("atoken", "openid", "auser", &google_authorize_url)
+                        .await;
+                    let res = client.get(callback_url).await?;
+                    assert_eq!(res.status(), StatusCode::BadGateway);
+                    let mut res = client.get("/provider").await?;
+                    assert_response(&mut res, "None None").await;
+
+                    // Callbacks with unknown states are still rejected.
+                    let res = client.get("/callback?code=UNKNOWN&state=UNKNOWN").await?;
+                    assert_eq!(res.status(), StatusCode::Unauthorized);
+
+                    let callback_url = azure_emu
+                        .add_token("atoken", "openid", "auser", &azure_authorize_url)
+                        .await;
+                    let res = client.get(callback_url).await?;
+                    assert_redirect(&res, "/");
+                    let mut res = client.get("/provider").await?;
+                    assert_response(&mut res, "Some(\"azure\") Some(\"auser\")").await;
+
+                    Ok(())
+                })
+                .await
+        })
+        .await
+}
+
+#[async_std::test]
+async fn jwt_callbacks_are_handled_by_the_provider_that_started_the_login() -> http_types::Result<()>
+{
+    let azure_emu = OpenIdConnectEmulator::new(redirect_url());
+    let google_emu = OpenIdConnectEmulator::new(redirect_url());
+    azure_emu
+        .run_with_emulator(|azure_emu| async move {
+            google_emu
+                .run_with_emulator(|google_emu| async move {
+                    let app = create_providers_app_with_response_mode(
+                        azure_emu,
+                        google_emu,
+                        ResponseMode::Jwt,
+                    )
+                    .await;
+                    let client = app.client().with(SessionCookieJarMiddleware::default());
+
+                    // The user starts logging in with both providers. The state is
+                    // inside the signed response, and selects the second provider
+                    // rather than the first.
+                    client.get("/login/azure").await?;
+                    let res = client.get("/login/google").await?;
+                    let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+                    let callback_url = google_emu
+                        .add_token("gtoken", "openid", "guser", &authorize_url)
+                        .await;
+                    let response = google_emu
+                        .sign_jwt_response(&google_emu.jwt_response_claims(&callback_url));
+                    let res = client
+                        .get(format!("/callback?response={}", response))
+                        .await?;
+                    assert_redirect(&res, "/");
+                    let mut res = client.get("/provider").await?;
+                    assert_response(&mut res, "Some(\"google\") Some(\"guser\")").await;
+
+                    Ok(())
+                })
+                .await
+        })
+        .await
+}
@@ -2,12 +2,18 @@ use crate::common::authorizeurl::ParsedAuthorizeUrl;
 use crate::common::cookiejar::SessionCookieJarMiddleware;
 use crate::common::oidc_emulator::OpenIdConnectEmulator;
 use crate::common::{assert_redirect, assert_response, create_test_server, get_config};
+use http_types::headers::LOCATION;
 use http_types::StatusCode;
+use std::sync::Arc;
 use tide::Request;
 use tide_testing::TideTestingExt;
 
+use tide_openidconnect::redirect_strategy::{
+    ClientSideRefresh, RedirectStrategyKind, UnauthenticatedHandler,
+};
 use tide_openidconnect::{
-    OpenIdConnectMiddleware, OpenIdConnectRequestExt, OpenIdConnectRouteExt, RedirectUrl,
+    require_authenticated, Claims, OidcError, OpenIdConnectMiddleware, OpenIdConnectRequestExt,
+    OpenIdConnectRouteExt, RedirectUrl, SessionTtl,
 };
 
 pub mod common;
@@ -97,3 +103,806 @@ async fn authentication_protects_subsequent_verbs() -> http_types::Result<()> {
         })
         .await
 }
+
+/// Creates a server with an `admin:write`-scoped route, logs in (if
+/// `granted_scopes` is provided) and then requests the scoped route.
+async fn get_scoped_route(
+    emu: &OpenIdConnectEmulator,
+    middleware: OpenIdConnectMiddleware,
+    granted_scopes: Option<&str>,
+) -> http_types::Result<surf::Response> {
+    let mut app = create_test_server();
+    app.with(middleware.with_scopes(&["admin:write"]));
+    app.at("/admin")
+        .authenticated_with_scopes(&["admin:write"])
+        .get(|req: Request<()>| async move {
+            assert!(req.has_scope("admin:write"));
+            Ok("admin")
+        });
+    let client = app.client().with(SessionCookieJarMiddleware::default());
+
+    if let Some(granted_scopes) = granted_scopes {
+        let res = client.get("/login").await?;
+        let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+        let callback_url = emu
+            .add_token("atoken", granted_scopes, "id", &authorize_url)
+            .await;
+        let res = client.get(callback_url).await?;
+        assert_redirect(&res, "/");
+    }
+
+    client.get("/admin").await
+}
+
+#[async_std::test]
+async fn scoped_routes_require_login() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let middleware = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+            let res = get_scoped_route(emu, middleware, None).await?;
+            assert_redirect(&res, "/login");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn scoped_routes_reject_missing_scopes() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            // The user only granted the `openid` scope, and so is
+            // forbidden from accessing the route (rather than being
+            // redirected to the login page again).
+            let middleware = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+            let mut res = get_scoped_route(emu, middleware, Some("openid")).await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+            let body: serde_json::Value = res.body_json().await?;
+            assert_eq!(body["error"], "insufficient_scope");
+            assert_eq!(body["scope"], "admin:write");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn scoped_routes_allow_granted_scopes() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let middleware = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await;
+            let mut res = get_scoped_route(emu, middleware, Some("openid admin:write")).await?;
+            assert_response(&mut res, "admin").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn insufficient_scope_response_can_be_changed() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let middleware = OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                .await
+                .with_insufficient_scope_response(|missing_scopes| {
+                    tide::Response::builder(StatusCode::NotFound)
+                        .body(missing_scopes.join(" "))
+                        .build()
+                });
+            let mut res = get_scoped_route(emu, middleware, Some("openid")).await?;
+            assert_eq!(res.status(), StatusCode::NotFound);
+            assert_eq!(res.body_string().await?, "admin:write");
+
+            Ok(())
+        })
+        .await
+}
+
+/// Returns `true` if the claims include the given group.
+fn in_group(claims: &Claims, group: &str) -> bool {
+    claims
+        .get("groups")
+        .and_then(|groups| groups.as_array())
+        .is_some_and(|groups| groups.iter().any(|g| g == group))
+}
+
+#[async_std::test]
+async fn authorized_routes_check_claims() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+
+            // The emulator's ID tokens put the user in the `users` and
+            // `admins` groups (which are custom claims). The first
+            // route applies both `authenticated()` and `authorized()`,
+            // the others rely on `authorized()` alone.
+            app.at("/admins")
+                .authenticated()
+                .authorized(|claims| in_group(claims, "admins"))
+                .get(|_req: Request<()>| async { Ok("admins") });
+            app.at("/platform-admins")
+                .authorized(|claims| in_group(claims, "platform-admins"))
+                .get(|_req: Request<()>| async { Ok("platform-admins") });
+            app.at("/both")
+                .authorized(|claims| in_group(claims, "users"))
+                .authorized(|claims| in_group(claims, "platform-admins"))
+                .get(|_req: Request<()>| async { Ok("both") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Unauthenticated requests are sent to the login page,
+            // regardless of the predicate.
+            assert_redirect(&client.get("/admins").await?, "/login");
+            assert_redirect(&client.get("/platform-admins").await?, "/login");
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Authenticated requests are forbidden (rather than being
+            // redirected to the login page) if any predicate denies them.
+            let mut res = client.get("/admins").await?;
+            assert_response(&mut res, "admins").await;
+            let res = client.get("/platform-admins").await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+            let res = client.get("/both").await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn access_denied_response_can_be_changed() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_access_denied_response(|| tide::Redirect::new("/no-access").into()),
+            );
+            app.at("/platform-admins")
+                .authorized(|claims| in_group(claims, "platform-admins"))
+                .get(|_req: Request<()>| async { Ok("platform-admins") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let res = client.get("/platform-admins").await?;
+            assert_redirect(&res, "/no-access");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn access_denied_handler_receives_the_reason() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut config = get_config(&emu.issuer_url());
+            config.access_denied_handler = Some(Arc::new(
+                |_req: tide::http::Request, reason: OidcError| async move {
+                    let location = match reason {
+                        OidcError::InsufficientScope(missing_scopes) => {
+                            format!("/no-access?scopes={}", missing_scopes.join("+"))
+                        }
+                        _ => "/no-access".to_string(),
+                    };
+                    Ok(tide::Redirect::new(location).into())
+                },
+            ));
+
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&config).await);
+            app.at("/admin")
+                .authenticated_with_scopes(&["admin:write"])
+                .get(|_req: Request<()>| async { Ok("admin") });
+            app.at("/platform-admins")
+                .authorized(|claims| in_group(claims, "platform-admins"))
+                .get(|_req: Request<()>| async { Ok("platform-admins") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let res = client.get("/admin").await?;
+            assert_redirect(&res, "/no-access?scopes=admin:write");
+            let res = client.get("/platform-admins").await?;
+            assert_redirect(&res, "/no-access");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn forbidden_requests_are_not_sent_to_login() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_unauthenticated_redirect_strategy(
+                        ClientSideRefresh::from_path("/login").with_header("X-Login", "/login"),
+                    ),
+            );
+
+            // Both routes customize the unauthenticated response, which
+            // must not be used for authenticated users that are denied.
+            app.at("/platform-admins")
+                .authenticated_or(|_req| tide::Redirect::new("/login").into())
+                .authorized(|claims| in_group(claims, "platform-admins"))
+                .get(|_req: Request<()>| async { Ok("platform-admins") });
+            app.at("/scoped")
+                .authenticated_with_scopes(&["admin"])
+                .get(|_req: Request<()>| async { Ok("scoped") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            assert_redirect(&client.get("/platform-admins").await?, "/login");
+            let res = client.get("/scoped").await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert!(res.header("X-Login").is_some());
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            for path in ["/platform-admins", "/scoped"] {
+                let res = client.get(path).await?;
+                assert_eq!(res.status(), StatusCode::Forbidden, "{}", path);
+                assert!(res.header(LOCATION).is_none(), "{}", path);
+                assert!(res.header("X-Login").is_none(), "{}", path);
+            }
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn unauthenticated_response_can_be_overridden_per_route() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+
+            // A regular route, an API route that varies its response by
+            // the `Accept` header, and a route that hides its existence.
+            app.at("/page")
+                .authenticated()
+                .get(|_req: Request<()>| async { Ok("page") });
+            app.at("/api/data")
+                .authenticated_or(|req| {
+                    let wants_json = req
+                        .header("Accept")
+                        .is_some_and(|accept| accept.as_str().contains("application/json"));
+                    if wants_json {
+                        tide::Response::builder(StatusCode::Unauthorized)
+                            .body(
+                                tide::Body::from_json(&serde_json::json!({
+                                    "error": "unauthenticated"
+                                }))
+                                .unwrap(),
+                            )
+                            .build()
+                    } else {
+                        tide::Response::new(StatusCode::Unauthorized)
+                    }
+                })
+                .get(|_req: Request<()>| async { Ok("data") });
+            app.at("/webhook")
+                .authenticated_or(|_req| tide::Response::new(StatusCode::NotFound))
+                .post(|_req: Request<()>| async { Ok("webhook") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/page").await?;
+            assert_redirect(&res, "/login");
+            let mut res = client
+                .get("/api/data")
+                .header("Accept", "application/json")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert_eq!(
+                res.body_json::<serde_json::Value>().await?,
+                serde_json::json!({ "error": "unauthenticated" })
+            );
+            let mut res = client.get("/api/data").await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert_eq!(res.body_string().await?, "");
+            let res = client.post("/webhook").await?;
+            assert_eq!(res.status(), StatusCode::NotFound);
+
+            // Authenticated requests reach all of the routes.
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            let mut res = client.get("/page").await?;
+            assert_response(&mut res, "page").await;
+            let mut res = client.get("/api/data").await?;
+            assert_response(&mut res, "data").await;
+            let mut res = client.post("/webhook").await?;
+            assert_response(&mut res, "webhook").await;
+
+            Ok(())
+        })
+        .await
+}
+
+fn assert_see_other(res: &surf::Response, expected_target: &str) {
+    assert_eq!(res.status(), StatusCode::SeeOther);
+    assert_eq!(
+        res.header(LOCATION).unwrap().get(0).unwrap(),
+        expected_target
+    );
+}
+
+#[async_std::test]
+async fn require_authenticated_redirects_with_return_to() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            app.at("/account")
+                .with(require_authenticated())
+                .get(|_req: Request<()>| async { Ok("account") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // The guard redirects to the login path, remembering the page
+            // that we were trying to access.
+            let res = client.get("/account?tab=security").await?;
+            assert_see_other(&res, "/login?return_to=%2Faccount%3Ftab%3Dsecurity");
+
+            // Logging in through that URL sends us back to the page.
+            let res = client
+                .get("/login?return_to=%2Faccount%3Ftab%3Dsecurity")
+                .await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/account?tab=security");
+
+            assert_response(&mut client.get("/account").await?, "account").await;
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn require_authenticated_can_be_checked_in_handlers() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            app.at("/account").get(|req: Request<()>| async move {
+                require_authenticated().check(&req)?;
+                Ok("account")
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/account").await?;
+            assert_see_other(&res, "/login?return_to=%2Faccount");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn require_authenticated_ignores_unsafe_return_to() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url())).await);
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Other sites are not valid return paths, so we end up on the
+            // login landing path instead.
+            let res = client
+                .get("/login?return_to=%2F%2Fevil.example%2Fphish")
+                .await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn require_authenticated_reports_missing_middleware() -> http_types::Result<()> {
+    let mut app = create_test_server();
+    app.at("/account")
+        .with(require_authenticated())
+        .get(|_req: Request<()>| async { Ok("account") });
+
+    let res = app.client().get("/account").await?;
+    assert_eq!(res.status(), StatusCode::InternalServerError);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn require_authenticated_reports_misconfigured_login_path() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_login_path("/signin"),
+            );
+
+            // Guarding the login path itself would cause a redirect loop.
+            app.at("/signin")
+                .with(require_authenticated())
+                .post(|_req: Request<()>| async { Ok("signed in") });
+            app.at("/signin/check").get(|req: Request<()>| async move {
+                require_authenticated().check(&req)?;
+                Ok("checked")
+            });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.post("/signin").await?;
+            assert_eq!(res.status(), StatusCode::InternalServerError);
+
+            // Other routes redirect to the configured login path.
+            let res = client.get("/signin/check").await?;
+            assert_see_other(&res, "/signin?return_to=%2Fsignin%2Fcheck");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn unauthorized_401_strategy_can_be_selected_in_config() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut config = get_config(&emu.issuer_url());
+            config.redirect_strategy = RedirectStrategyKind::Unauthorized401;
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&config)
+                    .await
+                    .with_login_path("/signin"),
+            );
+
+            // API routes use the strategy from the config, while
+            // individual routes can still override the response.
+            app.at("/api/data")
+                .authenticated()
+                .get(|_req: Request<()>| async { Ok("data") });
+            app.at("/page")
+                .authenticated_or(|_req| tide::Redirect::new("/signin").into())
+                .get(|_req: Request<()>| async { Ok("page") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let mut res = client.get("/api/data").await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert_eq!(
+                res.header("WWW-Authenticate").unwrap().get(0).unwrap(),
+                r#"Bearer realm="CLIENT-ID""#
+            );
+            assert_eq!(
+                res.body_json::<serde_json::Value>().await?,
+                serde_json::json!({ "error": "unauthenticated", "login_url": "/signin" })
+            );
+
+            let res = client.get("/page").await?;
+            assert_redirect(&res, "/signin");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn api_challenges_report_the_failure() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut config = get_config(&emu.issuer_url());
+            config.redirect_strategy = RedirectStrategyKind::Unauthorized401;
+            config.realm = Some("example".to_string());
+            config.session_ttl = SessionTtl::Fixed(std::time::Duration::from_secs(1));
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&config).await);
+            app.at("/api/data")
+                .authenticated()
+                .get(|_req: Request<()>| async { Ok("data") });
+            app.at("/api/admin")
+                .authenticated_with_scopes(&["openid", "admin"])
+                .get(|_req: Request<()>| async { Ok("admin") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            // Requests without a session are challenged without an
+            // error.
+            let res = client.get("/api/data").await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert_eq!(
+                res.header("WWW-Authenticate").unwrap().get(0).unwrap(),
+                r#"Bearer realm="example""#
+            );
+
+            let res = client.get("/login").await?;
+            let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+            let callback_url = emu
+                .add_token("atoken", "openid", "id", &authorize_url)
+                .await;
+            let res = client.get(callback_url).await?;
+            assert_redirect(&res, "/");
+
+            // Requests that lack a scope are told which scopes the route
+            // requires.
+            let res = client.get("/api/admin").await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+            assert_eq!(
+                res.header("WWW-Authenticate").unwrap().get(0).unwrap(),
+                r#"Bearer realm="example", error="insufficient_scope", scope="openid admin""#
+            );
+
+            // Requests with an expired session are told that their
+            // credentials are no longer valid.
+            async_std::task::sleep(std::time::Duration::from_millis(1100)).await;
+            let res = client.get("/api/data").await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+            assert_eq!(
+                res.header("WWW-Authenticate").unwrap().get(0).unwrap(),
+                r#"Bearer realm="example", error="invalid_token", error_description="The session has expired.""#
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn http_redirect_strategy_uses_configured_login_path() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_login_path("/signin"),
+            );
+            app.at("/page")
+                .authenticated()
+                .get(|_req: Request<()>| async { Ok("page") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/page").await?;
+            assert_redirect(&res, "/signin");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn unauthenticated_handler_can_be_set_in_config() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut config = get_config(&emu.issuer_url());
+            config.redirect_strategy =
+                RedirectStrategyKind::custom(|req: tide::http::Request| async move {
+                    let wants_json = req
+                        .header("Accept")
+                        .is_some_and(|accept| accept.as_str().contains("application/json"));
+                    if wants_json {
+                        Ok(tide::Response::new(StatusCode::Unauthorized))
+                    } else {
+                        Ok(tide::Redirect::new("/login").into())
+                    }
+                });
+            let mut app = create_test_server();
+            app.with(OpenIdConnectMiddleware::new(&config).await);
+            app.at("/data")
+                .authenticated()
+                .get(|_req: Request<()>| async { Ok("data") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/data").await?;
+            assert_redirect(&res, "/login");
+            let res = client
+                .get("/data")
+                .header("Accept", "application/json")
+                .await?;
+            assert_eq!(res.status(), StatusCode::Unauthorized);
+
+            Ok(())
+        })
+        .await
+}
+
+/// Sends each tenant's users to the tenant's own login path.
+struct TenantLogin;
+
+#[tide::utils::async_trait]
+impl UnauthenticatedHandler for TenantLogin {
+    async fn unauthenticated(&self, req: &tide::http::Request) -> tide::Result<tide::Response> {
+        let tenant = req.url().path_segments().and_then(|mut s| s.next());
+        match tenant {
+            Some(tenant) if !tenant.is_empty() => {
+                Ok(tide::Redirect::new(format!("/login?tenant={}", tenant)).into())
+            }
+            _ => Err(tide::Error::from_str(
+                StatusCode::NotFound,
+                "Unknown tenant.",
+            )),
+        }
+    }
+}
+
+#[async_std::test]
+async fn unauthenticated_handler_receives_request() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_unauthenticated_handler(TenantLogin),
+            );
+            app.at("/:tenant/home")
+                .authenticated()
+                .get(|_req: Request<()>| async { Ok("home") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let res = client.get("/acme/home").await?;
+            assert_redirect(&res, "/login?tenant=acme");
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn redirect_strategies_can_still_be_used() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let mut app = create_test_server();
+            app.with(
+                OpenIdConnectMiddleware::new(&get_config(&emu.issuer_url()))
+                    .await
+                    .with_unauthenticated_redirect_strategy(
+                        ClientSideRefresh::from_path("/login").with_header("X-Login", "/login"),
+                    ),
+            );
+            app.at("/page")
+                .authenticated()
+                .get(|_req: Request<()>| async { Ok("page") });
+            let client = app.client().with(SessionCookieJarMiddleware::default());
+
+            let mut res = client.get("/page").await?;
+            assert_eq!(res.status(), StatusCode::Ok);
+            assert_eq!(res.header("X-Login").unwrap().get(0).unwrap(), "/login");
+            assert!(res.body_string().await?.contains("URL='/login'"));
+
+            Ok(())
+        })
+        .await
+}
+
+async fn get_with_policy(
+    emu: &OpenIdConnectEmulator,
+    middleware: OpenIdConnectMiddleware,
+    granted_scopes: &str,
+) -> http_types::Result<surf::Response> {
+    let mut app = create_test_server();
+    app.with(middleware);
+    let client = app.client().with(SessionCookieJarMiddleware::default());
+
+    // Unauthenticated requests are not affected by the policy.
+    let res = client.get("/").await?;
+    assert_eq!(res.status(), StatusCode::Ok);
+
+    let res = client.get("/login").await?;
+    let authorize_url = ParsedAuthorizeUrl::from_response(&res);
+    let callback_url = emu
+        .add_token("atoken", granted_scopes, "id", &authorize_url)
+        .await;
+    let res = client.get(callback_url).await?;
+    assert_redirect(&res, "/");
+
+    client.get("/").await
+}
+
+#[async_std::test]
+async fn authorization_policy_is_enforced_on_all_routes() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let config = get_config(&emu.issuer_url());
+
+            // Requests that satisfy the policy are allowed.
+            let middleware = OpenIdConnectMiddleware::new(&config)
+                .await
+                .with_scopes(&["admin"])
+                .with_required_scope("admin")
+                .with_required_claim("email_verified", serde_json::json!(true));
+            let mut res = get_with_policy(emu, middleware, "openid admin").await?;
+            assert_response(
+                &mut res,
+                "authed visits=2 access_token=atoken scopes=[\"openid\", \"admin\"] userid=id",
+            )
+            .await;
+
+            // Requests that do not are denied, without saying why.
+            let middleware = OpenIdConnectMiddleware::new(&config)
+                .await
+                .with_required_scope("admin");
+            let mut res = get_with_policy(emu, middleware, "openid").await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+            assert_eq!(
+                res.body_json::<serde_json::Value>().await?,
+                serde_json::json!({
+                    "error": "access_denied",
+                    "error_description": "Access denied",
+                })
+            );
+
+            // The failed requirement can be included in the response.
+            let middleware = OpenIdConnectMiddleware::new(&config)
+                .await
+                .with_required_scope("admin")
+                .with_authorization_failure_details(true);
+            let mut res = get_with_policy(emu, middleware, "openid").await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+            let body: serde_json::Value = res.body_json().await?;
+            assert_eq!(body["error"], "insufficient_scope");
+            assert_eq!(body["scope"], "admin");
+
+            let middleware = OpenIdConnectMiddleware::new(&config)
+                .await
+                .with_required_claim("email_verified", serde_json::json!(false))
+                .with_authorization_failure_details(true);
+            let mut res = get_with_policy(emu, middleware, "openid").await?;
+            assert_eq!(res.status(), StatusCode::Forbidden);
+            assert_eq!(
+                res.body_json::<serde_json::Value>().await?,
+                serde_json::json!({
+                    "error": "access_denied",
+                    "error_description": "Request lacks the required claim: email_verified",
+                    "claim": "email_verified",
+                })
+            );
+
+            Ok(())
+        })
+        .await
+}
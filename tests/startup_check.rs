@@ -0,0 +1,74 @@
+use crate::common::get_config;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use tide::listener::Listener;
+use tide_openidconnect::{LazyDiscoveryConfig, OpenIdConnectMiddleware, RedirectUrl};
+
+pub mod common;
+
+fn redirect_url() -> RedirectUrl {
+    RedirectUrl::new("http://localhost/callback".to_string()).unwrap()
+}
+
+#[async_std::test]
+async fn listen_fails_if_provider_is_unreachable() -> http_types::Result<()> {
+    // Note: the emulator is never started.
+    let emu = OpenIdConnectEmulator::new(redirect_url());
+    let middleware = OpenIdConnectMiddleware::new_lazy(
+        &get_config(&emu.issuer_url()),
+        LazyDiscoveryConfig::default(),
+    )?;
+    let listener = middleware.startup_listener("127.0.0.1:0");
+
+    let mut app = tide::new();
+    app.with(middleware);
+    let error = app.listen(listener).await.unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .starts_with("OpenID Connect provider check failed: "),
+        "{}",
+        error
+    );
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn provider_is_loaded_before_listening() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(redirect_url())
+        .run_with_emulator(|emu| async move {
+            let middleware = OpenIdConnectMiddleware::new_lazy(
+                &get_config(&emu.issuer_url()),
+                LazyDiscoveryConfig::default(),
+            )?;
+            let health_check = middleware.health_check();
+            assert!(!health_check.is_ready());
+            let listener = middleware.startup_listener("127.0.0.1:0");
+
+            let mut app = tide::new();
+            app.with(middleware);
+            let listener = app.bind(listener).await?;
+            assert!(health_check.is_ready());
+            assert_eq!(listener.info().len(), 1);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn startup_check_can_be_disabled() -> http_types::Result<()> {
+    // Note: the emulator is never started.
+    let emu = OpenIdConnectEmulator::new(redirect_url());
+    let mut config = get_config(&emu.issuer_url());
+    config.verify_on_startup = false;
+    let middleware = OpenIdConnectMiddleware::new_lazy(&config, LazyDiscoveryConfig::default())?;
+    let listener = middleware.startup_listener("127.0.0.1:0");
+
+    let mut app = tide::new();
+    app.with(middleware);
+    let listener = app.bind(listener).await?;
+    assert_eq!(listener.info().len(), 1);
+
+    Ok(())
+}
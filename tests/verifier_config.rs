@@ -0,0 +1,140 @@
+#![cfg(feature = "insecure-test-overrides")]
+
+use crate::common::authorizeurl::ParsedAuthorizeUrl;
+use crate::common::cookiejar::SessionCookieJarMiddleware;
+use crate::common::oidc_emulator::OpenIdConnectEmulator;
+use crate::common::{assert_redirect, create_test_server, get_config};
+use http_types::StatusCode;
+use tide_openidconnect::{
+    AuthUrl, Config, CoreJsonWebKeySet, CoreJwsSigningAlgorithm, IssuerUrl, JsonWebKeySetUrl,
+    OpenIdConnectMiddleware, ProviderEndpoints, RedirectUrl, TokenUrl, VerifierConfig,
+};
+use tide_testing::TideTestingExt;
+
+pub mod common;
+
+/// Returns a configuration that uses the emulator's endpoints (without
+/// discovery) with the given JSON Web Key Set URL and keys.
+fn provider_endpoints_config(
+    emu: &OpenIdConnectEmulator,
+    issuer_url: IssuerUrl,
+    jwks_path: &str,
+    jwks: Option<CoreJsonWebKeySet>,
+) -> Config {
+    let issuer = emu.issuer_url().as_str().to_string();
+    let mut config = get_config(&issuer_url);
+    config.provider_endpoints = Some(ProviderEndpoints {
+        authorization_endpoint: AuthUrl::new(format!("{}authorization", issuer)).unwrap(),
+        token_endpoint: TokenUrl::new(format!("{}token/0", issuer)).unwrap(),
+        jwks_uri: JsonWebKeySetUrl::new(format!("{}{}", issuer, jwks_path)).unwrap(),
+        userinfo_endpoint: None,
+        jwks,
+        id_token_signing_algs: vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
+    });
+    config
+}
+
+/// Logs in with the given middleware, where the emulator issues an ID
+/// token with the given nonce (or the login's nonce), and returns the
+/// callback's status.
+async fn login_status(
+    middleware: OpenIdConnectMiddleware,
+    emu: &OpenIdConnectEmulator,
+    nonce: Option<&str>,
+) -> StatusCode {
+    let mut app = create_test_server();
+    app.with(middleware);
+    let client = app.client().with(SessionCookieJarMiddleware::default());
+
+    let res = client.get("/login").await.unwrap();
+    let mut authorize_url = ParsedAuthorizeUrl::from_response(&res);
+    if let Some(nonce) = nonce {
+        authorize_url = authorize_url.with_nonce(Some(nonce.to_string()));
+    }
+    let callback_url = emu
+        .add_token("atoken", "openid", "id", &authorize_url)
+        .await;
+    let res = client.get(callback_url).await.unwrap();
+    if res.status() == StatusCode::Found {
+        assert_redirect(&res, "/");
+    }
+    res.status()
+}
+
+#[async_std::test]
+async fn nonce_check_can_be_skipped() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            let config = get_config(&emu.issuer_url());
+            let middleware = OpenIdConnectMiddleware::new(&config).await;
+            assert_eq!(
+                login_status(middleware.clone(), emu, Some("BADNONCE")).await,
+                StatusCode::Unauthorized
+            );
+
+            let middleware = middleware.with_verifier_config(VerifierConfig {
+                skip_nonce_check: true,
+                ..VerifierConfig::default()
+            });
+            assert_eq!(
+                login_status(middleware, emu, Some("BADNONCE")).await,
+                StatusCode::Found
+            );
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn issuer_check_can_be_skipped() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            // The emulator's tokens are issued by a different issuer.
+            let issuer_url = IssuerUrl::new(format!("{}other/", emu.issuer_url().as_str()))?;
+            let config = provider_endpoints_config(emu, issuer_url, "jwks", None);
+            let middleware = OpenIdConnectMiddleware::new(&config).await;
+            assert_ne!(
+                login_status(middleware.clone(), emu, None).await,
+                StatusCode::Found
+            );
+
+            let middleware = middleware.with_verifier_config(VerifierConfig {
+                skip_issuer_check: true,
+                ..VerifierConfig::default()
+            });
+            assert_eq!(login_status(middleware, emu, None).await, StatusCode::Found);
+
+            Ok(())
+        })
+        .await
+}
+
+#[async_std::test]
+async fn signature_check_can_be_skipped() -> http_types::Result<()> {
+    OpenIdConnectEmulator::new(RedirectUrl::new("http://localhost/callback".to_string()).unwrap())
+        .run_with_emulator(|emu| async move {
+            // None of the emulator's signing keys are known (and the key
+            // set cannot be refreshed).
+            let config = provider_endpoints_config(
+                emu,
+                emu.issuer_url(),
+                "unknown-jwks",
+                Some(CoreJsonWebKeySet::new(vec![])),
+            );
+            let middleware = OpenIdConnectMiddleware::new(&config).await;
+            assert_ne!(
+                login_status(middleware.clone(), emu, None).await,
+                StatusCode::Found
+            );
+
+            let middleware = middleware.with_verifier_config(VerifierConfig {
+                skip_signature_check: true,
+                ..VerifierConfig::default()
+            });
+            assert_eq!(login_status(middleware, emu, None).await, StatusCode::Found);
+
+            Ok(())
+        })
+        .await
+}